@@ -0,0 +1,104 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Counts the heap allocations made per chunk of an upload with tiny
+//! chunks, where message handling rather than IO dominates. Run with
+//! `cargo bench` and compare the allocations per chunk between
+//! revisions.
+
+extern crate czmq;
+extern crate tempdir;
+extern crate zdaemon;
+extern crate zfilexfer;
+
+use czmq::{ZSock, SocketType, ZSys};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::spawn;
+use tempdir::TempDir;
+use zdaemon::Service;
+use zfilexfer::{File, FileOptions, Server};
+
+const FILE_SIZE: usize = 256 * 1024;
+const CHUNK_SIZE: u64 = 64;
+const SLOTS: u32 = 64;
+const RUNS: u32 = 5;
+
+// Counts every allocation made by the client and server threads
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+fn main() {
+    ZSys::init();
+
+    let endpoint = "inproc://bench_hot_path";
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
+    server.set_rcvtimeo(Some(500));
+    let mut client = ZSock::new_dealer(&format!(">{}", endpoint)).unwrap();
+    client.set_rcvtimeo(Some(5000));
+
+    let handle = spawn(move|| {
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(Server::new(server, SLOTS, None).unwrap()).unwrap();
+        let _ = service.start(Some(1000));
+    });
+
+    let tempdir = TempDir::new("bench_hot_path").unwrap();
+    let mut path = tempdir.path().to_owned();
+    path.push("local");
+    fs::File::create(&path).unwrap().write_all(&vec![7; FILE_SIZE]).unwrap();
+
+    let chunks = FILE_SIZE as u64 / CHUNK_SIZE;
+    let mut best = None;
+
+    for run in 0..RUNS {
+        let mut remote = tempdir.path().to_owned();
+        remote.push(&format!("remote{}", run));
+
+        let mut file = File::open(&path, Some(&[FileOptions::ChunkSize(CHUNK_SIZE)])).unwrap();
+        let allocs = ALLOCS.load(Ordering::Relaxed);
+        let bytes = BYTES.load(Ordering::Relaxed);
+        file.send(&mut client, &remote).unwrap();
+        let allocs = (ALLOCS.load(Ordering::Relaxed) - allocs) as u64;
+        let bytes = (BYTES.load(Ordering::Relaxed) - bytes) as u64;
+
+        // The first run also warms up buffer pools and sockets
+        best = Some(best.map_or((allocs, bytes), |b: (u64, u64)| if allocs < b.0 { (allocs, bytes) } else { b }));
+    }
+
+    let (allocs, bytes) = best.unwrap();
+    println!("{} chunks of {} bytes: {} allocations/chunk, {} bytes allocated/chunk (fewest of {} runs)",
+             chunks, CHUNK_SIZE, allocs / chunks, bytes / chunks, RUNS);
+
+    handle.join().unwrap();
+}
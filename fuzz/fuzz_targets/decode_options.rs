@@ -0,0 +1,20 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Decodes the options frame of a NEW request, in either codec.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate zfilexfer;
+
+use zfilexfer::File;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = File::options_protocol(data);
+    let _ = File::options_signature(data);
+});
@@ -0,0 +1,34 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Splits the input into frames and parses them as a client request,
+//! with both decimal and binary chunk indexes.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate zfilexfer;
+
+use zfilexfer::Request;
+
+// Each frame is a one byte length followed by that many bytes, so
+// the fuzzer can grow and shrink frames independently.
+fn frames(mut data: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let len = (len as usize).min(rest.len());
+        frames.push(rest[..len].to_vec());
+        data = &rest[len..];
+    }
+    frames
+}
+
+fuzz_target!(|data: &[u8]| {
+    let frames = frames(data);
+    let _ = Request::parse(&frames, false);
+    let _ = Request::parse(&frames, true);
+});
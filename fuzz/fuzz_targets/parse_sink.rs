@@ -0,0 +1,21 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate zfilexfer;
+
+use zfilexfer::parse_sink;
+
+fuzz_target!(|data: &[u8]| {
+    // Split anywhere, so both frames vary in length
+    if let Some((&at, rest)) = data.split_first() {
+        let (index, success) = rest.split_at((at as usize).min(rest.len()));
+        let _ = parse_sink(&[index.to_vec(), success.to_vec()]);
+    }
+});
@@ -6,183 +6,934 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+use bandwidth::{BandwidthSchedule, Throttle};
 use chunk::Chunk;
+use clock::{Clock, SystemClock};
 use czmq::{ZMsg, ZSock, ZSys};
 use error::{Error, Result};
-use std::sync::{Arc, RwLock};
+use event::hex;
+use protocol;
+use retry::RetryPolicy;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::{JoinHandle, spawn};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-#[cfg(not(test))]
 const CHUNK_TIMEOUT: u64 = 60;
-#[cfg(test)]
-const CHUNK_TIMEOUT: u64 = 1;
+/// Times an expired chunk is reported again if the Arbitrator never
+/// stops its timer
+const RENOTIFY_LIMIT: u32 = 3;
+/// Milliseconds before requesting chunks again that the client's
+/// connection had no room for
+const CONGESTED_RETRY: u64 = 100;
+/// Times a chunk is requested again after its client had no room,
+/// before it's left to time out. A client that has gone never will.
+const MAX_DEFERRALS: u32 = 50;
+
+/// Order in which queued chunks are granted upload slots
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Schedule {
+    /// Chunks are requested in the order they were queued
+    Fifo,
+    /// Clients take turns, so each gets an equal share of slots
+    Fair,
+    /// Transfers with the fewest chunks remaining go first, so small
+    /// files aren't stuck behind a large one
+    ShortestFirst,
+}
 
 pub struct Arbitrator {
     router: ZSock,
-    queue: Arc<RwLock<Vec<TimedChunk>>>,
-    timer_handle: Option<JoinHandle<()>>,
+    queue: Vec<TimedChunk>,
+    timer_handle: Option<JoinHandle<Timer>>,
     timer_comm: ZSock,
+    // Set by the Timer's thread when it stops on a failure
+    timer_failed: Arc<AtomicBool>,
     slots: u32,
+    protocols: HashMap<Vec<u8>, u32>,
+    budget: Option<u64>,
+    buffered: HashMap<Vec<u8>, u64>,
+    client_slots: Option<u32>,
+    client_budget: Option<u64>,
+    // Client identity behind each channel, where it differs
+    identities: HashMap<Vec<u8>, Vec<u8>>,
+    schedule: Schedule,
+    remaining: HashMap<Vec<u8>, u64>,
+    // Kept between reorders so that sorting the queue doesn't
+    // allocate
+    turns: HashMap<Rc<Vec<u8>>, u64>,
+    // Requests sent to the Server that it hasn't yet passed on, as
+    // (router_id, first, last), oldest first
+    sent: VecDeque<(Rc<Vec<u8>>, u64, u64)>,
+    paused: bool,
+    // In-flight chunks beyond the slot count, after it was lowered
+    excess: u32,
+    throttle: Option<Throttle>,
+    clock: Arc<Clock>,
 }
 
 impl Drop for Arbitrator {
     fn drop(&mut self) {
-        // Ignore failure as it means the thread has already
-        // terminated.
-        let _ = self.timer_comm.signal(0);
+        self.stop();
+    }
+}
+
+impl Arbitrator {
+    /// Stop the timer thread, after which chunks no longer time out.
+    /// Dropping the Arbitrator does this too.
+    pub fn stop(&mut self) {
         if let Some(h) = self.timer_handle.take() {
+            // Ignore failure as it means the thread has already
+            // terminated.
+            let _ = self.timer_comm.send_str("$TERM");
             h.join().unwrap();
         }
     }
-}
 
-impl Arbitrator {
     pub fn new(router: ZSock, upload_slots: u32) -> Result<Arbitrator> {
+        Self::with_clock(router, upload_slots, Arc::new(SystemClock))
+    }
+
+    /// Create an Arbitrator whose chunk timeouts follow `clock`
+    pub fn with_clock(router: ZSock, upload_slots: u32, clock: Arc<Clock>) -> Result<Arbitrator> {
         let (comm_front, comm_back) = try!(ZSys::create_pipe());
         comm_front.set_sndtimeo(Some(1000));
         comm_front.set_linger(0);
         comm_back.set_rcvtimeo(Some(1000)); // Remember that this timeout controls the Timer loop speed!
         comm_back.set_linger(0);
 
-        let lock = Arc::new(RwLock::new(Vec::new()));
-        let timer = try!(Timer::new(comm_back, lock.clone()));
+        let timer = try!(Timer::new(comm_back, clock.clone()));
+        let failed = Arc::new(AtomicBool::new(false));
 
         Ok(Arbitrator {
             router: router,
-            queue: lock,
-            timer_handle: Some(spawn(move|| timer.run())),
+            queue: Vec::new(),
+            timer_handle: Some(timer.spawn(failed.clone())),
             timer_comm: comm_front,
+            timer_failed: failed,
             slots: upload_slots,
+            protocols: HashMap::new(),
+            budget: None,
+            buffered: HashMap::new(),
+            client_slots: None,
+            client_budget: None,
+            identities: HashMap::new(),
+            schedule: Schedule::Fifo,
+            remaining: HashMap::new(),
+            turns: HashMap::new(),
+            sent: VecDeque::new(),
+            paused: false,
+            excess: 0,
+            throttle: None,
+            clock: clock,
         })
     }
 
-    pub fn queue(&mut self, chunk: &Chunk, router_id: &[u8]) -> Result<()> {
-        let timed_chunk = TimedChunk::new(router_id, chunk.get_index());
-        {
-            let mut writer = self.queue.write().unwrap();
-            writer.push(timed_chunk);
+    /// Set the protocol version negotiated with a client, which
+    /// determines how chunks are requested. Legacy clients have no
+    /// version and only understand single CHUNK requests.
+    pub fn set_protocol(&mut self, router_id: &[u8], protocol: Option<u32>) {
+        match protocol {
+            Some(v) => self.protocols.insert(router_id.to_vec(), v),
+            None => self.protocols.remove(router_id),
+        };
+    }
+
+    /// Cap the bytes of chunk data each client may have requested
+    /// but not yet written to disk. A chunk larger than the budget is
+    /// still requested once nothing else is outstanding.
+    pub fn set_budget(&mut self, bytes: u64) {
+        self.budget = Some(bytes);
+    }
+
+    /// Cap the chunks each client may have in flight at once, so a
+    /// single client can't hold every upload slot
+    pub fn set_client_slots(&mut self, slots: u32) {
+        self.client_slots = Some(slots);
+    }
+
+    /// Cap the bytes of chunk data that all of a client's channels
+    /// together may have requested but not yet written to disk
+    pub fn set_client_budget(&mut self, bytes: u64) {
+        self.client_budget = Some(bytes);
+    }
+
+    /// Set the client identity behind a channel, so its chunks count
+    /// towards the client's budget. None forgets it.
+    pub fn set_identity(&mut self, router_id: &[u8], identity: Option<&[u8]>) {
+        match identity {
+            Some(identity) if identity != router_id => self.identities.insert(router_id.to_vec(), identity.to_vec()),
+            _ => self.identities.remove(router_id),
+        };
+    }
+
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    /// Limit the rate at which chunk data is requested from all
+    /// clients combined
+    pub fn set_bandwidth(&mut self, schedule: BandwidthSchedule) {
+        self.throttle = Some(Throttle::new(schedule, self.clock.clone()));
+    }
+
+    /// Record how many chunks a client's transfer has left, which
+    /// the ShortestFirst schedule orders by
+    pub fn set_remaining(&mut self, router_id: &[u8], chunks: u64) {
+        if chunks == 0 {
+            self.remaining.remove(router_id);
+            return;
+        }
+
+        // Updated in place, as this is called for every chunk
+        if let Some(remaining) = self.remaining.get_mut(router_id) {
+            *remaining = chunks;
+            return;
+        }
+        self.remaining.insert(router_id.to_vec(), chunks);
+    }
+
+    pub fn queue(&mut self, chunk: &Chunk, len: u64, router_id: &[u8]) -> Result<()> {
+        self.queue_retry(chunk, len, router_id, None)
+    }
+
+    /// Queue a chunk that failed to arrive again. It isn't requested
+    /// until `policy`'s delay has passed, which grows each time the
+    /// same chunk fails.
+    pub fn requeue(&mut self, chunk: &Chunk, len: u64, router_id: &[u8], policy: &RetryPolicy) -> Result<()> {
+        self.queue_retry(chunk, len, router_id, Some(policy))
+    }
+
+    fn queue_retry(&mut self, chunk: &Chunk, len: u64, router_id: &[u8], policy: Option<&RetryPolicy>) -> Result<()> {
+        // A chunk that failed while in flight (e.g. it timed out) is
+        // requested again rather than queued twice. One queued in its
+        // turn after being admitted ahead of it stays in flight.
+        let existing = self.queue.iter().position(|c| *c.router_id == router_id && c.index == chunk.get_index());
+
+        match existing {
+            Some(i) => {
+                if self.queue[i].is_started() && policy.is_some() {
+                    try!(self.stop_timer(router_id, chunk.get_index()));
+                    let mut timed = TimedChunk::new(self.queue[i].router_id.clone(), chunk.get_index(), len, self.clock.now());
+                    if let Some(policy) = policy {
+                        let delay = policy.delay(self.queue[i].failures);
+                        timed.failures = self.queue[i].failures + 1;
+                        timed.not_before = Some(self.clock.now() + delay);
+                        debug!("chunk requeued router_id={} index={} failures={} delay_ms={}",
+                               hex(router_id), chunk.get_index(), timed.failures, delay.as_secs() * 1000 + (delay.subsec_nanos() / 1_000_000) as u64);
+                    }
+                    let old = mem::replace(&mut self.queue[i], timed);
+                    self.unbuffer(router_id, old.len);
+                    self.free_slot();
+                }
+            },
+            None => {
+                let id = self.shared_id(router_id);
+                self.queue.push(TimedChunk::new(id, chunk.get_index(), len, self.clock.now()));
+            },
         }
 
         try!(self.request());
         Ok(())
     }
 
-    pub fn release(&mut self, chunk: &Chunk, router_id: &[u8]) -> Result<()> {
-        let router_id = router_id.to_vec();
-        {
-            let mut queue = self.queue.write().unwrap();
-            let mut index: Option<usize> = None;
-            let mut x = 0;
-            for c in queue.iter_mut() {
-                if c.router_id == router_id && c.index == chunk.get_index() {
-                    index = Some(x);
-                    break;
-                }
+    /// Number of chunks waiting for a slot or in flight
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Queue several (chunk, length) pairs at once, so that contiguous
+    /// chunks can be requested in a single batch.
+    pub fn queue_many<'a, I>(&mut self, chunks: I, router_id: &[u8]) -> Result<()>
+        where I: Iterator<Item = (&'a Chunk, u64)>
+    {
+        let id = self.shared_id(router_id);
+        for (chunk, len) in chunks {
+            self.queue.push(TimedChunk::new(id.clone(), chunk.get_index(), len, self.clock.now()));
+        }
 
-                x += 1;
+        try!(self.request());
+        Ok(())
+    }
+
+    /// Grant a slot to a chunk that arrived without being requested,
+    /// e.g. one a client sent ahead, as if it had been requested now.
+    /// It is refused if requesting it would have exceeded the slots,
+    /// budgets or bandwidth limit, in which case it should be dropped
+    /// and requested in its turn. Either way it is released like any
+    /// other chunk.
+    pub fn admit(&mut self, chunk: &Chunk, len: u64, router_id: &[u8]) -> Result<bool> {
+        let existing = self.queue.iter().position(|c| *c.router_id == router_id && c.index == chunk.get_index());
+
+        // Already requested, so it holds a slot
+        if let Some(i) = existing {
+            if self.queue[i].is_started() {
+                return Ok(true);
             }
+        }
 
-            match index {
-                Some(i) => {
-                    queue.remove(i);
-                    self.slots += 1;
-                },
-                None => return Err(Error::ChunkIndex),
+        if self.paused || self.slots == 0 || !self.within_limits(router_id, len) {
+            return Ok(false);
+        }
+        if let Some(ref mut throttle) = self.throttle {
+            if !throttle.take(len) {
+                return Ok(false);
+            }
+        }
+
+        try!(self.supervise());
+        let i = match existing {
+            Some(i) => i,
+            None => {
+                let id = self.shared_id(router_id);
+                self.queue.push(TimedChunk::new(id, chunk.get_index(), len, self.clock.now()));
+                self.queue.len() - 1
+            },
+        };
+        self.queue[i].requested = Some(self.clock.now());
+        *self.buffered.entry(router_id.to_vec()).or_insert(0) += self.queue[i].len;
+        self.slots -= 1;
+        try!(Self::start_timer(&mut self.timer_comm, router_id, chunk.get_index()));
+        Ok(true)
+    }
+
+    // Whether a client may have another `len` bytes in flight without
+    // exceeding its share of the slots or its budgets
+    fn within_limits(&self, router_id: &[u8], len: u64) -> bool {
+        if let Some(limit) = self.client_slots {
+            let in_flight = self.queue.iter().filter(|c| c.is_started() && *c.router_id == router_id).count() as u32;
+            if in_flight >= limit {
+                return false;
+            }
+        }
+
+        let buffered = self.buffered.get(router_id).cloned().unwrap_or(0);
+        if let Some(budget) = self.budget {
+            if buffered > 0 && buffered + len > budget {
+                return false;
+            }
+        }
+
+        if let Some(budget) = self.client_budget {
+            let identity = self.identities.get(router_id).map_or(router_id, |i| &i[..]);
+            let client: u64 = self.buffered.iter()
+                .filter(|&(id, _)| self.identities.get(id).map_or(&id[..], |i| &i[..]) == identity)
+                .map(|(_, bytes)| *bytes)
+                .sum();
+            if client > 0 && client + len > budget {
+                return false;
             }
         }
 
+        true
+    }
+
+    /// Release a completed chunk, returning how long it waited for a
+    /// slot and how long it was in flight
+    pub fn release(&mut self, chunk: &Chunk, router_id: &[u8]) -> Result<(Duration, Duration)> {
+        let timed = match self.queue.iter().position(|c| *c.router_id == router_id && c.index == chunk.get_index()) {
+            Some(i) => self.queue.remove(i),
+            None => return Err(Error::ChunkIndex),
+        };
+
+        let timing = match timed.requested {
+            Some(requested) => {
+                try!(self.stop_timer(router_id, chunk.get_index()));
+                self.unbuffer(router_id, timed.len);
+                self.free_slot();
+                (requested - timed.queued, self.clock.now() - requested)
+            },
+            None => (self.clock.now() - timed.queued, Duration::new(0, 0)),
+        };
+
         try!(self.request());
+        Ok(timing)
+    }
+
+    /// Stop requesting chunks until unpaused. Chunks already in
+    /// flight are unaffected.
+    pub fn set_paused(&mut self, paused: bool) -> Result<()> {
+        self.paused = paused;
+        self.request()
+    }
+
+    /// Change the number of chunks that may be in flight at once
+    pub fn set_slots(&mut self, slots: u32) -> Result<()> {
+        let in_flight = self.queue.iter().filter(|c| c.is_started()).count() as u32;
+        self.slots = slots.saturating_sub(in_flight);
+        self.excess = in_flight.saturating_sub(slots);
+        self.request()
+    }
+
+    /// Drop every chunk queued for a client, e.g. when its transfer
+    /// is cancelled
+    pub fn cancel(&mut self, router_id: &[u8]) -> Result<()> {
+        let (cancelled, queue) = self.queue.drain(..).partition(|c| *c.router_id == router_id);
+        self.queue = queue;
+
+        for chunk in cancelled.into_iter().filter(|c: &TimedChunk| c.is_started()) {
+            try!(self.stop_timer(router_id, chunk.index));
+            self.free_slot();
+        }
+        self.buffered.remove(router_id);
+        self.remaining.remove(router_id);
+        self.identities.remove(router_id);
+
+        self.request()
+    }
+
+    fn free_slot(&mut self) {
+        if self.excess > 0 {
+            self.excess -= 1;
+        } else {
+            self.slots += 1;
+        }
+    }
+
+    // Queued chunks share one copy of their client's router ID, so
+    // queueing a chunk doesn't allocate
+    fn shared_id(&self, router_id: &[u8]) -> Rc<Vec<u8>> {
+        match self.queue.iter().find(|c| *c.router_id == router_id) {
+            Some(c) => c.router_id.clone(),
+            None => Rc::new(router_id.to_vec()),
+        }
+    }
+
+    fn unbuffer(&mut self, router_id: &[u8], len: u64) {
+        let empty = match self.buffered.get_mut(router_id) {
+            Some(bytes) => {
+                *bytes = bytes.saturating_sub(len);
+                *bytes == 0
+            },
+            None => false,
+        };
+
+        if empty {
+            self.buffered.remove(router_id);
+        }
+    }
+
+    // The Timer keeps its own deadlines, so it never has to share the
+    // queue with the Server thread.
+    fn start_timer(comm: &mut ZSock, router_id: &[u8], index: u64) -> Result<()> {
+        let msg = ZMsg::new();
+        try!(msg.addstr("START"));
+        try!(msg.addbytes(router_id));
+        try!(protocol::add_u64(&msg, index, true));
+        try!(msg.send(comm));
         Ok(())
     }
 
+    fn stop_timer(&mut self, router_id: &[u8], index: u64) -> Result<()> {
+        try!(self.supervise());
+        let msg = ZMsg::new();
+        try!(msg.addstr("STOP"));
+        try!(msg.addbytes(router_id));
+        try!(protocol::add_u64(&msg, index, true));
+        try!(msg.send(&mut self.timer_comm));
+        Ok(())
+    }
+
+    /// Set how often the Timer checks for expired chunks. Defaults to
+    /// once per second.
+    pub fn set_timer_interval(&mut self, millis: u32) -> Result<()> {
+        try!(self.supervise());
+        let msg = ZMsg::new();
+        try!(msg.addstr("INTERVAL"));
+        try!(protocol::add_u64(&msg, millis as u64, true));
+        try!(msg.send(&mut self.timer_comm));
+        Ok(())
+    }
+
+    /// Set how long a requested chunk may take to arrive before it
+    /// is reported as failed. Defaults to 60 seconds.
+    pub fn set_chunk_timeout(&mut self, millis: u32) -> Result<()> {
+        try!(self.supervise());
+        let msg = ZMsg::new();
+        try!(msg.addstr("TIMEOUT"));
+        try!(protocol::add_u64(&msg, millis as u64, true));
+        try!(msg.send(&mut self.timer_comm));
+        Ok(())
+    }
+
+    /// Retry any requests deferred while a client's connection was
+    /// congested or the bandwidth limit was reached
+    pub fn resume(&mut self) -> Result<()> {
+        self.request()
+    }
+
+    /// The oldest request sent to the Server, as (router_id, first,
+    /// last). The Server takes each one as it passes it on.
+    pub fn take_request(&mut self) -> Option<(Rc<Vec<u8>>, u64, u64)> {
+        self.sent.pop_front()
+    }
+
+    /// Put back the chunks of a request that the client's connection
+    /// had no room for, so they're requested again shortly rather
+    /// than time out
+    pub fn defer(&mut self, router_id: &[u8], first: u64, last: u64) -> Result<()> {
+        let retry = self.clock.now() + Duration::from_millis(CONGESTED_RETRY);
+        let mut deferred = Vec::new();
+        for chunk in self.queue.iter_mut() {
+            if *chunk.router_id == router_id && chunk.index >= first && chunk.index <= last &&
+               chunk.is_started() && chunk.deferrals < MAX_DEFERRALS {
+                chunk.deferrals += 1;
+                chunk.requested = None;
+                chunk.not_before = Some(retry);
+                deferred.push((chunk.index, chunk.len));
+            }
+        }
+
+        debug!("chunk requests deferred router_id={} first={} last={}", hex(router_id), first, last);
+        for (index, len) in deferred {
+            try!(self.stop_timer(router_id, index));
+            self.unbuffer(router_id, len);
+            self.free_slot();
+        }
+
+        self.request()
+    }
+
+    // Sort the queue into the order chunks should be requested in, in
+    // place. Ties keep their queue position, so each client's chunks
+    // stay in order.
+    fn reorder(&mut self) {
+        match self.schedule {
+            Schedule::Fifo => return,
+            Schedule::Fair => {
+                // A chunk's turn is the number of chunks queued ahead
+                // of it for the same client
+                self.turns.clear();
+                for (i, c) in self.queue.iter_mut().enumerate() {
+                    let turn = self.turns.entry(c.router_id.clone()).or_insert(0);
+                    *turn += 1;
+                    c.order = (*turn, i);
+                }
+            },
+            Schedule::ShortestFirst => {
+                for (i, c) in self.queue.iter_mut().enumerate() {
+                    c.order = (self.remaining.get(&c.router_id[..]).cloned().unwrap_or(u64::max_value()), i);
+                }
+            },
+        }
+
+        self.queue.sort_unstable_by_key(|c| c.order);
+    }
+
     fn request(&mut self) -> Result<()> {
-        for chunk in self.queue.write().unwrap().iter_mut() {
-            if self.slots == 0 {
+        try!(self.supervise());
+        self.reorder();
+
+        // Contiguous chunks for a versioned client are coalesced into
+        // a single (router_id, first, last) request.
+        let mut batch: Option<(Rc<Vec<u8>>, u64, u64)> = None;
+        let mut throttled = false;
+        let mut backoff: Option<Instant> = None;
+        let now = self.clock.now();
+
+        let mut in_flight: HashMap<Rc<Vec<u8>>, u32> = HashMap::new();
+        if self.client_slots.is_some() {
+            for chunk in self.queue.iter().filter(|c| c.is_started()) {
+                *in_flight.entry(chunk.router_id.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let mut client_buffered: HashMap<Vec<u8>, u64> = HashMap::new();
+        if self.client_budget.is_some() {
+            for (router_id, bytes) in self.buffered.iter() {
+                let identity = self.identities.get(router_id).unwrap_or(router_id);
+                *client_buffered.entry(identity.clone()).or_insert(0) += *bytes;
+            }
+        }
+
+        let queued = self.queue.len();
+        for chunk in self.queue.iter_mut() {
+            if self.paused || self.slots == 0 {
                 break;
             }
 
             if !chunk.is_started() {
+                // Leave a failed chunk queued until its retry delay
+                // has passed
+                if let Some(not_before) = chunk.not_before {
+                    if not_before > now {
+                        backoff = Some(backoff.map_or(not_before, |b| cmp::min(b, not_before)));
+                        continue;
+                    }
+                }
+
+                // Leave the chunk queued if its client already holds
+                // its share of the slots
+                if let Some(limit) = self.client_slots {
+                    if in_flight.get(&chunk.router_id).cloned().unwrap_or(0) >= limit {
+                        continue;
+                    }
+                }
+
+                // Leave the chunk queued if its client already has a
+                // full budget of data outstanding
+                let buffered = self.buffered.get(&chunk.router_id[..]).cloned().unwrap_or(0);
+                if let Some(budget) = self.budget {
+                    if buffered > 0 && buffered + chunk.len > budget {
+                        continue;
+                    }
+                }
+
+                // ...or if its client does, across all of its channels
+                if let Some(budget) = self.client_budget {
+                    let identity = self.identities.get(&chunk.router_id[..]).unwrap_or(&*chunk.router_id);
+                    let client = client_buffered.get(identity).cloned().unwrap_or(0);
+                    if client > 0 && client + chunk.len > budget {
+                        continue;
+                    }
+                }
+                if let Some(ref mut throttle) = self.throttle {
+                    if !throttle.take(chunk.len) {
+                        throttled = true;
+                        break;
+                    }
+                }
+                if buffered > 0 {
+                    *self.buffered.get_mut(&chunk.router_id[..]).unwrap() += chunk.len;
+                } else {
+                    self.buffered.insert(chunk.router_id.to_vec(), chunk.len);
+                }
+                if self.client_budget.is_some() {
+                    let identity = self.identities.get(&chunk.router_id[..]).unwrap_or(&*chunk.router_id);
+                    *client_buffered.entry(identity.clone()).or_insert(0) += chunk.len;
+                }
+
                 self.slots -= 1;
+                if self.slots == 0 {
+                    debug!("upload slots exhausted queued={}", queued);
+                }
+                if self.client_slots.is_some() {
+                    *in_flight.entry(chunk.router_id.clone()).or_insert(0) += 1;
+                }
+                chunk.requested = Some(now);
+                try!(Self::start_timer(&mut self.timer_comm, &chunk.router_id, chunk.index));
 
-                let msg = ZMsg::new();
-                try!(msg.addbytes(&chunk.router_id));
-                try!(msg.addstr("CHUNK"));
-                try!(msg.addstr(&chunk.index.to_string()));
-                try!(msg.send(&mut self.router));
+                if !self.protocols.contains_key(&chunk.router_id[..]) {
+                    try!(send_request(&mut self.router, &chunk.router_id, chunk.index, chunk.index, None));
+                    self.sent.push_back((chunk.router_id.clone(), chunk.index, chunk.index));
+                    continue;
+                }
+
+                if let Some((ref id, _, ref mut last)) = batch {
+                    if *id == chunk.router_id && *last + 1 == chunk.index {
+                        *last = chunk.index;
+                        continue;
+                    }
+                }
+
+                if let Some((id, first, last)) = batch.take() {
+                    let protocol = self.protocols.get(&id[..]).cloned();
+                    try!(send_request(&mut self.router, &id, first, last, protocol));
+                    self.sent.push_back((id, first, last));
+                }
 
-                chunk.start();
+                batch = Some((chunk.router_id.clone(), chunk.index, chunk.index));
             }
         }
 
+        if let Some((id, first, last)) = batch {
+            let protocol = self.protocols.get(&id[..]).cloned();
+            try!(send_request(&mut self.router, &id, first, last, protocol));
+            self.sent.push_back((id, first, last));
+        }
+
+        // Nothing else may happen to call request() again, so have
+        // the Timer wake us once the bandwidth has recovered or a
+        // failed chunk may be retried
+        if throttled {
+            let wait = self.throttle.as_ref().unwrap().wait();
+            try!(self.wake_after(wait));
+        }
+        if let Some(not_before) = backoff {
+            try!(self.wake_after(not_before - now));
+        }
+
+        Ok(())
+    }
+
+    // Restart the Timer if its thread stopped on a failure. It keeps
+    // its deadlines and settings, and any commands sent while it was
+    // down are still waiting on its socket.
+    fn supervise(&mut self) -> Result<()> {
+        if !self.timer_failed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.timer_failed.store(false, Ordering::SeqCst);
+
+        let mut timer = match self.timer_handle.take().map(|h| h.join()) {
+            Some(Ok(timer)) => timer,
+            _ => {
+                error!("timer thread lost, chunks will not time out");
+                return Ok(());
+            },
+        };
+
+        try!(timer.reset());
+        self.timer_handle = Some(timer.spawn(self.timer_failed.clone()));
+        warn!("timer restarted");
+        Ok(())
+    }
+
+    fn wake_after(&mut self, wait: Duration) -> Result<()> {
+        try!(self.supervise());
+        let msg = ZMsg::new();
+        try!(msg.addstr("WAKE"));
+        try!(protocol::add_u64(&msg, wait.as_secs() * 1000 + (wait.subsec_nanos() / 1_000_000) as u64, true));
+        try!(msg.send(&mut self.timer_comm));
         Ok(())
     }
 }
 
+fn send_request(router: &mut ZSock, router_id: &[u8], first: u64, last: u64, protocol: Option<u32>) -> Result<()> {
+    let binary = protocol::binary_ints(protocol);
+    let msg = ZMsg::new();
+    try!(msg.addbytes(router_id));
+
+    if first == last {
+        try!(msg.addstr("CHUNK"));
+        try!(protocol::add_u64(&msg, first, binary));
+    } else if binary {
+        try!(msg.addstr("CHUNKS"));
+        try!(protocol::add_u64(&msg, first, true));
+        try!(protocol::add_u64(&msg, last, true));
+    } else {
+        try!(msg.addstr("CHUNKS"));
+        try!(protocol::add_range(&msg, first, last));
+    }
+
+    try!(msg.send(router));
+    Ok(())
+}
+
 struct Timer {
-    chunks: Arc<RwLock<Vec<TimedChunk>>>,
+    deadlines: HashMap<(Vec<u8>, u64), Deadline>,
+    wake: Option<Instant>,
+    timeout: Duration,
+    clock: Arc<Clock>,
     sink: ZSock,
     comm: ZSock,
 }
 
+// When a chunk's timer next fires, and how many times the chunk has
+// been reported since it expired
+struct Deadline {
+    at: Instant,
+    reported: u32,
+}
+
+impl Deadline {
+    fn new(at: Instant) -> Deadline {
+        Deadline {
+            at: at,
+            reported: 0,
+        }
+    }
+}
+
 impl Timer {
-    fn new(comm: ZSock, chunks: Arc<RwLock<Vec<TimedChunk>>>) -> Result<Timer> {
+    fn new(comm: ZSock, clock: Arc<Clock>) -> Result<Timer> {
         Ok(Timer {
-            chunks: chunks,
-            sink: try!(ZSock::new_push(">inproc://zfilexfer_sink")),
+            deadlines: HashMap::new(),
+            wake: None,
+            timeout: Duration::from_secs(CHUNK_TIMEOUT),
+            clock: clock,
+            sink: try!(Self::connect_sink()),
             comm: comm,
         })
     }
 
-    fn run(mut self) {
+    fn connect_sink() -> Result<ZSock> {
+        Ok(try!(ZSock::new_push(">inproc://zfilexfer_sink")))
+    }
+
+    // Run on a thread of its own, handing the Timer back when it
+    // stops. A failure or panic sets `failed` so the Arbitrator can
+    // restart it.
+    fn spawn(mut self, failed: Arc<AtomicBool>) -> JoinHandle<Timer> {
+        spawn(move|| {
+            let stopped = match panic::catch_unwind(AssertUnwindSafe(|| self.run())) {
+                Ok(Ok(())) => false,
+                Ok(Err(e)) => {
+                    error!("timer stopped error={:?}", e.to_string());
+                    true
+                },
+                Err(_) => {
+                    error!("timer panicked");
+                    true
+                },
+            };
+            failed.store(stopped, Ordering::SeqCst);
+            self
+        })
+    }
+
+    // Replace the sink socket before a restart, as a failed send
+    // most likely broke it
+    fn reset(&mut self) -> Result<()> {
+        self.sink = try!(Self::connect_sink());
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<()> {
         loop {
-            // Terminate on ZSock signal or system signal (SIGTERM)
-            if self.comm.wait().is_ok() || ZSys::is_interrupted() {
-                break;
+            // Terminate on $TERM or system signal (SIGTERM)
+            if ZSys::is_interrupted() {
+                return Ok(());
             }
 
-            for chunk in self.chunks.read().unwrap().iter() {
-                if chunk.is_expired() {
-                    let msg = ZMsg::new();
-                    msg.addbytes(&chunk.router_id).unwrap();
-                    msg.addstr(&chunk.index.to_string()).unwrap();
-                    msg.addstr("0").unwrap();
-                    msg.send(&mut self.sink).unwrap();
+            if let Ok(msg) = ZMsg::recv(&mut self.comm) {
+                match self.command(&msg) {
+                    Ok(true) => (),
+                    Ok(false) => return Ok(()),
+                    // A bad command shouldn't take every deadline
+                    // down with it
+                    Err(e) => warn!("timer ignored command error={:?}", e.to_string()),
                 }
             }
+
+            try!(self.expire());
+        }
+    }
+
+    // Apply a command from the Arbitrator, returning false once it
+    // asks the Timer to terminate
+    fn command(&mut self, msg: &ZMsg) -> Result<bool> {
+        let cmd = try!(msg.popstr().ok_or(Error::InvalidRequest)).unwrap_or(String::new());
+        match cmd.as_ref() {
+            "START" => {
+                let key = try!(Self::pop_key(msg));
+                self.deadlines.insert(key, Deadline::new(self.clock.now() + self.timeout));
+            },
+            "STOP" => {
+                let key = try!(Self::pop_key(msg));
+                self.deadlines.remove(&key);
+            },
+            "INTERVAL" => {
+                let millis = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidRequest));
+                self.comm.set_rcvtimeo(Some(millis as i32));
+            },
+            "TIMEOUT" => {
+                let millis = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidRequest));
+                self.timeout = Duration::from_millis(millis);
+            },
+            // The earliest wake-up wins, as the Arbitrator asks
+            // again for any that are still needed
+            "WAKE" => {
+                let millis = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidRequest));
+                let wake = self.clock.now() + Duration::from_millis(millis);
+                self.wake = Some(self.wake.map_or(wake, |w| cmp::min(w, wake)));
+            },
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn pop_key(msg: &ZMsg) -> Result<(Vec<u8>, u64)> {
+        let router_id = try!(try!(msg.popbytes()).ok_or(Error::InvalidRequest));
+        let index = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidRequest));
+        Ok((router_id, index))
+    }
+
+    // A chunk is reported when its deadline passes. If the Arbitrator
+    // requeues it, it stops this timer and starts a new one, so a
+    // chunk still here was never dealt with. It is reported again
+    // after twice as long each time, up to RENOTIFY_LIMIT times.
+    //
+    // A failed send leaves the chunk due, to be reported once the
+    // Timer is restarted.
+    fn expire(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let expired: Vec<(Vec<u8>, u64)> = self.deadlines.iter()
+                                                         .filter(|&(_, deadline)| deadline.at <= now)
+                                                         .map(|(key, _)| key.clone())
+                                                         .collect();
+
+        for key in expired {
+            let reported = self.deadlines[&key].reported;
+            if reported == 0 {
+                debug!("chunk timed out router_id={} index={}", hex(&key.0), key.1);
+            } else {
+                debug!("chunk still timed out router_id={} index={} reported={}", hex(&key.0), key.1, reported);
+            }
+            try!(self.notify(&key.0, Some(key.1)));
+
+            if reported >= RENOTIFY_LIMIT {
+                warn!("chunk timer abandoned router_id={} index={}", hex(&key.0), key.1);
+                self.deadlines.remove(&key);
+            } else {
+                let deadline = self.deadlines.get_mut(&key).unwrap();
+                deadline.reported += 1;
+                deadline.at = now + self.timeout * (1 << reported);
+            }
+        }
+
+        // A wake-up has an empty router ID, which no client can have
+        if self.wake.map_or(false, |wake| wake <= now) {
+            try!(self.notify(&[], None));
+            self.wake = None;
         }
+
+        Ok(())
+    }
+
+    // Tell the Server's sink that a chunk failed, or with no chunk,
+    // that the Arbitrator should wake
+    fn notify(&mut self, router_id: &[u8], index: Option<u64>) -> Result<()> {
+        let msg = ZMsg::new();
+        try!(msg.addbytes(router_id));
+        if let Some(index) = index {
+            try!(protocol::add_u64(&msg, index, true));
+            try!(msg.addbytes(&[0]));
+        }
+        try!(msg.send(&mut self.sink));
+        Ok(())
     }
 }
 
 struct TimedChunk {
-    router_id: Vec<u8>,
+    router_id: Rc<Vec<u8>>,
     index: u64,
-    timestamp: Option<Instant>,
+    len: u64,
+    queued: Instant,
+    requested: Option<Instant>,
+    /// Times this chunk has failed to arrive
+    failures: u32,
+    /// A failed chunk isn't requested again before this
+    not_before: Option<Instant>,
+    /// Sort key from the last reorder
+    order: (u64, usize),
+    /// Times this chunk's client had no room for its request
+    deferrals: u32,
 }
 
 impl TimedChunk {
-    fn new(router_id: &[u8], index: u64) -> TimedChunk {
+    fn new(router_id: Rc<Vec<u8>>, index: u64, len: u64, queued: Instant) -> TimedChunk {
         TimedChunk {
-            router_id: router_id.to_vec(),
+            router_id: router_id,
             index: index,
-            timestamp: None,
+            len: len,
+            queued: queued,
+            requested: None,
+            failures: 0,
+            not_before: None,
+            order: (0, 0),
+            deferrals: 0,
         }
     }
 
-    fn start(&mut self) {
-        self.timestamp = Some(Instant::now());
-    }
-
     fn is_started(&self) -> bool {
-        self.timestamp.is_some()
-    }
-
-    fn is_expired(&self) -> bool {
-        if self.timestamp.is_some() {
-            self.timestamp.as_ref().unwrap().elapsed().as_secs() >= CHUNK_TIMEOUT
-        } else {
-            false
-        }
+        self.requested.is_some()
     }
 }
 
@@ -190,15 +941,28 @@ impl TimedChunk {
 mod tests {
     use chunk::Chunk;
     use czmq::{ZMsg, ZSock, SocketType, ZSys};
-    use std::cell::RefCell;
+    use handle::Handle;
+    use protocol;
+    use std::collections::HashMap;
     use std::rc::Rc;
-    use std::sync::{Arc, RwLock};
-    use std::thread::{sleep, spawn};
+    use clock::{MockClock, SystemClock};
+    use std::sync::{Arc, Mutex};
     use std::time::{Duration, Instant};
     use super::*;
-    use super::{TimedChunk, Timer};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::{CHUNK_TIMEOUT, Deadline, TimedChunk, Timer};
     use tempfile::tempfile;
 
+    // Wait for the Arbitrator to terminate its Timer
+    fn wait_term(comm: &mut ZSock) {
+        loop {
+            let msg = ZMsg::recv(comm).unwrap();
+            if msg.popstr().unwrap().unwrap() == "$TERM" {
+                break;
+            }
+        }
+    }
+
     #[test]
     fn test_arbitrator_new() {
         ZSys::init();
@@ -210,14 +974,98 @@ mod tests {
     fn test_arbitrator_queue_release() {
         ZSys::init();
 
-        let chunk = Chunk::new(Rc::new(RefCell::new(tempfile().unwrap())), 0);
+        let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
 
         let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 1).unwrap();
-        assert!(arbitrator.queue(&chunk, "abc".as_bytes()).is_ok());
-        assert_eq!(arbitrator.queue.read().unwrap().len(), 1);
+        assert!(arbitrator.queue(&chunk, 1, "abc".as_bytes()).is_ok());
+        assert_eq!(arbitrator.queue.len(), 1);
         assert_eq!(arbitrator.slots, 0);
         assert!(arbitrator.release(&chunk, "abc".as_bytes()).is_ok());
-        assert_eq!(arbitrator.queue.read().unwrap().len(), 0);
+        assert_eq!(arbitrator.queue.len(), 0);
+        assert_eq!(arbitrator.slots, 1);
+    }
+
+    #[test]
+    fn test_arbitrator_admit() {
+        ZSys::init();
+
+        let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
+        let next = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 1);
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 1).unwrap();
+        assert!(arbitrator.admit(&chunk, 1, "abc".as_bytes()).unwrap());
+        assert_eq!(arbitrator.slots, 0);
+        assert_eq!(arbitrator.buffered.get("abc".as_bytes()), Some(&1));
+
+        // Without a free slot the next is refused, until the first is
+        // released
+        assert!(!arbitrator.admit(&next, 1, "abc".as_bytes()).unwrap());
+        assert_eq!(arbitrator.queue.len(), 1);
+        arbitrator.release(&chunk, "abc".as_bytes()).unwrap();
+        assert!(arbitrator.admit(&next, 1, "abc".as_bytes()).unwrap());
+
+        // A chunk admitted again already holds its slot
+        assert!(arbitrator.admit(&next, 1, "abc".as_bytes()).unwrap());
+        assert_eq!(arbitrator.queue.len(), 1);
+
+        // A client with its budget outstanding is refused too
+        arbitrator.set_slots(2).unwrap();
+        arbitrator.set_budget(1);
+        assert!(!arbitrator.admit(&chunk, 1, "abc".as_bytes()).unwrap());
+        assert_eq!(arbitrator.slots, 1);
+    }
+
+    #[test]
+    fn test_arbitrator_supervise() {
+        ZSys::init();
+
+        let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 1).unwrap();
+        arbitrator.queue(&chunk, 1, "abc".as_bytes()).unwrap();
+
+        // Stand in for a Timer that stopped on a failure. The thread
+        // is joined before the flag is set, as a Timer stopping
+        // cleanly clears it.
+        arbitrator.timer_comm.send_str("$TERM").unwrap();
+        let timer = arbitrator.timer_handle.take().unwrap().join().unwrap();
+        arbitrator.timer_handle = Some(::std::thread::spawn(move || timer));
+        arbitrator.timer_failed.store(true, Ordering::SeqCst);
+
+        // The next command restarts it, with its deadline intact
+        arbitrator.set_chunk_timeout(500).unwrap();
+        assert!(!arbitrator.timer_failed.load(Ordering::SeqCst));
+
+        arbitrator.timer_comm.send_str("$TERM").unwrap();
+        let timer = arbitrator.timer_handle.take().unwrap().join().unwrap();
+        assert_eq!(timer.deadlines.len(), 1);
+        assert_eq!(timer.timeout, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_arbitrator_slots_cancel() {
+        ZSys::init();
+
+        let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
+        let next = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 1);
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 2).unwrap();
+        arbitrator.set_paused(true).unwrap();
+        arbitrator.queue(&chunk, 1, "abc".as_bytes()).unwrap();
+        assert_eq!(arbitrator.slots, 2);
+        arbitrator.set_paused(false).unwrap();
+        arbitrator.queue(&next, 1, "abc".as_bytes()).unwrap();
+        assert_eq!(arbitrator.slots, 0);
+
+        // Lowering the slot count below the number in flight waits
+        // for chunks to complete
+        arbitrator.set_slots(1).unwrap();
+        assert_eq!((arbitrator.slots, arbitrator.excess), (0, 1));
+        arbitrator.release(&chunk, "abc".as_bytes()).unwrap();
+        assert_eq!((arbitrator.slots, arbitrator.excess), (0, 0));
+
+        arbitrator.cancel("abc".as_bytes()).unwrap();
+        assert_eq!(arbitrator.queue.len(), 0);
         assert_eq!(arbitrator.slots, 1);
     }
 
@@ -228,24 +1076,39 @@ mod tests {
         let (mut client, router) = ZSys::create_pipe().unwrap();
         client.set_rcvtimeo(Some(500));
 
-        let (comm, thread) = ZSys::create_pipe().unwrap();
+        let (comm, mut thread) = ZSys::create_pipe().unwrap();
 
         let chunks = vec![
-            TimedChunk::new("abc".as_bytes(), 0),
-            TimedChunk::new("abc".as_bytes(), 1),
-            TimedChunk::new("abc".as_bytes(), 2),
-            TimedChunk::new("def".as_bytes(), 0),
-            TimedChunk::new("def".as_bytes(), 1),
-            TimedChunk::new("def".as_bytes(), 2),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 0, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 1, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 2, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 0, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 1, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 2, 1, Instant::now()),
         ];
 
         {
             let mut arbitrator = Arbitrator {
                 router: router,
-                queue: Arc::new(RwLock::new(chunks)),
+                queue: chunks,
                 timer_handle: None,
                 timer_comm: comm,
+                timer_failed: Arc::new(AtomicBool::new(false)),
                 slots: 3,
+                protocols: HashMap::new(),
+                budget: None,
+                buffered: HashMap::new(),
+                client_slots: None,
+                client_budget: None,
+                identities: HashMap::new(),
+                schedule: Schedule::Fifo,
+                remaining: HashMap::new(),
+                turns: HashMap::new(),
+                sent: VecDeque::new(),
+                paused: false,
+                excess: 0,
+                throttle: None,
+                clock: Arc::new(SystemClock),
             };
 
             arbitrator.request().unwrap();
@@ -259,7 +1122,7 @@ mod tests {
 
             assert!(client.recv_str().is_err());
 
-            let chunk = Chunk::new(Rc::new(RefCell::new(tempfile().unwrap())), 0);
+            let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
             arbitrator.release(&chunk, "abc".as_bytes()).unwrap();
             arbitrator.request().unwrap();
 
@@ -269,14 +1132,422 @@ mod tests {
             assert_eq!(msg.popstr().unwrap().unwrap(), "0");
         }
 
-        thread.wait().unwrap();
+        wait_term(&mut thread);
+    }
+
+    #[test]
+    fn test_arbitrator_request_budget() {
+        ZSys::init();
+
+        let (mut client, router) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+
+        let (comm, mut thread) = ZSys::create_pipe().unwrap();
+
+        let chunks = vec![
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 0, 4, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 1, 4, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 2, 4, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 0, 16, Instant::now()),
+        ];
+
+        {
+            let mut arbitrator = Arbitrator {
+                router: router,
+                queue: chunks,
+                timer_handle: None,
+                timer_comm: comm,
+                timer_failed: Arc::new(AtomicBool::new(false)),
+                slots: 10,
+                protocols: HashMap::new(),
+                budget: None,
+                buffered: HashMap::new(),
+                client_slots: None,
+                client_budget: None,
+                identities: HashMap::new(),
+                schedule: Schedule::Fifo,
+                remaining: HashMap::new(),
+                turns: HashMap::new(),
+                sent: VecDeque::new(),
+                paused: false,
+                excess: 0,
+                throttle: None,
+                clock: Arc::new(SystemClock),
+            };
+            arbitrator.set_budget(8);
+
+            arbitrator.request().unwrap();
+
+            // "def" exceeds the budget alone, but has nothing else
+            // outstanding
+            for &(id, index) in [("abc", "0"), ("abc", "1"), ("def", "0")].iter() {
+                let msg = ZMsg::recv(&mut client).unwrap();
+                assert_eq!(&msg.popstr().unwrap().unwrap(), id);
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+                assert_eq!(&msg.popstr().unwrap().unwrap(), index);
+            }
+
+            assert!(client.recv_str().is_err());
+            assert_eq!(arbitrator.buffered.get("abc".as_bytes()), Some(&8));
+
+            let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
+            arbitrator.release(&chunk, "abc".as_bytes()).unwrap();
+
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "abc");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "2");
+
+            arbitrator.release(&chunk, "def".as_bytes()).unwrap();
+            assert!(arbitrator.buffered.get("def".as_bytes()).is_none());
+        }
+
+        wait_term(&mut thread);
+    }
+
+    #[test]
+    fn test_arbitrator_request_client_slots() {
+        ZSys::init();
+
+        let (mut client, router) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+
+        let (comm, mut thread) = ZSys::create_pipe().unwrap();
+
+        let chunks = vec![
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 0, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 1, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 2, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 0, 1, Instant::now()),
+        ];
+
+        {
+            let mut arbitrator = Arbitrator {
+                router: router,
+                queue: chunks,
+                timer_handle: None,
+                timer_comm: comm,
+                timer_failed: Arc::new(AtomicBool::new(false)),
+                slots: 10,
+                protocols: HashMap::new(),
+                budget: None,
+                buffered: HashMap::new(),
+                client_slots: None,
+                client_budget: None,
+                identities: HashMap::new(),
+                schedule: Schedule::Fifo,
+                remaining: HashMap::new(),
+                turns: HashMap::new(),
+                sent: VecDeque::new(),
+                paused: false,
+                excess: 0,
+                throttle: None,
+                clock: Arc::new(SystemClock),
+            };
+            arbitrator.set_client_slots(2);
+
+            arbitrator.request().unwrap();
+
+            // "abc" can't take a third slot, even though seven are free
+            for &(id, index) in [("abc", "0"), ("abc", "1"), ("def", "0")].iter() {
+                let msg = ZMsg::recv(&mut client).unwrap();
+                assert_eq!(&msg.popstr().unwrap().unwrap(), id);
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+                assert_eq!(&msg.popstr().unwrap().unwrap(), index);
+            }
+
+            assert!(client.recv_str().is_err());
+            assert_eq!(arbitrator.slots, 7);
+
+            let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
+            arbitrator.release(&chunk, "abc".as_bytes()).unwrap();
+
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "abc");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "2");
+        }
+
+        wait_term(&mut thread);
+    }
+
+    #[test]
+    fn test_arbitrator_request_client_budget() {
+        ZSys::init();
+
+        let (mut client, router) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+
+        let (comm, mut thread) = ZSys::create_pipe().unwrap();
+
+        let chunks = vec![
+            TimedChunk::new(Rc::new(b"c1".to_vec()), 0, 4, Instant::now()),
+            TimedChunk::new(Rc::new(b"c1".to_vec()), 1, 4, Instant::now()),
+            TimedChunk::new(Rc::new(b"c2".to_vec()), 0, 4, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 0, 16, Instant::now()),
+        ];
+
+        {
+            let mut arbitrator = Arbitrator {
+                router: router,
+                queue: chunks,
+                timer_handle: None,
+                timer_comm: comm,
+                timer_failed: Arc::new(AtomicBool::new(false)),
+                slots: 10,
+                protocols: HashMap::new(),
+                budget: None,
+                buffered: HashMap::new(),
+                client_slots: None,
+                client_budget: None,
+                identities: HashMap::new(),
+                schedule: Schedule::Fifo,
+                remaining: HashMap::new(),
+                turns: HashMap::new(),
+                sent: VecDeque::new(),
+                paused: false,
+                excess: 0,
+                throttle: None,
+                clock: Arc::new(SystemClock),
+            };
+            arbitrator.set_client_budget(8);
+            arbitrator.set_identity(b"c1", Some(b"abc"));
+            arbitrator.set_identity(b"c2", Some(b"abc"));
+            arbitrator.set_identity(b"def", Some(b"def"));
+            assert!(arbitrator.identities.get("def".as_bytes()).is_none());
+
+            arbitrator.request().unwrap();
+
+            // "c2" shares its client's budget with "c1"
+            for &(id, index) in [("c1", "0"), ("c1", "1"), ("def", "0")].iter() {
+                let msg = ZMsg::recv(&mut client).unwrap();
+                assert_eq!(&msg.popstr().unwrap().unwrap(), id);
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+                assert_eq!(&msg.popstr().unwrap().unwrap(), index);
+            }
+
+            assert!(client.recv_str().is_err());
+
+            let chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(tempfile().unwrap()))), 0);
+            arbitrator.release(&chunk, "c1".as_bytes()).unwrap();
+
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "c2");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "0");
+
+            arbitrator.set_identity(b"c2", None);
+            assert!(arbitrator.identities.get("c2".as_bytes()).is_none());
+        }
+
+        wait_term(&mut thread);
+    }
+
+    #[test]
+    fn test_arbitrator_reorder() {
+        ZSys::init();
+
+        let (_client, router) = ZSys::create_pipe().unwrap();
+
+        let (comm, mut thread) = ZSys::create_pipe().unwrap();
+
+        {
+            let mut arbitrator = Arbitrator {
+                router: router,
+                queue: Vec::new(),
+                timer_handle: None,
+                timer_comm: comm,
+                timer_failed: Arc::new(AtomicBool::new(false)),
+                slots: 0,
+                protocols: HashMap::new(),
+                budget: None,
+                buffered: HashMap::new(),
+                client_slots: None,
+                client_budget: None,
+                identities: HashMap::new(),
+                schedule: Schedule::Fifo,
+                remaining: HashMap::new(),
+                turns: HashMap::new(),
+                sent: VecDeque::new(),
+                paused: false,
+                excess: 0,
+                throttle: None,
+                clock: Arc::new(SystemClock),
+            };
+
+            let queue = || vec![
+                TimedChunk::new(Rc::new(b"abc".to_vec()), 0, 1, Instant::now()),
+                TimedChunk::new(Rc::new(b"abc".to_vec()), 1, 1, Instant::now()),
+                TimedChunk::new(Rc::new(b"abc".to_vec()), 2, 1, Instant::now()),
+                TimedChunk::new(Rc::new(b"def".to_vec()), 0, 1, Instant::now()),
+                TimedChunk::new(Rc::new(b"def".to_vec()), 1, 1, Instant::now()),
+            ];
+            let order = |a: &Arbitrator| a.queue.iter().map(|c| (c.router_id[0], c.index)).collect::<Vec<_>>();
+
+            arbitrator.queue = queue();
+            arbitrator.reorder();
+            assert_eq!(order(&arbitrator), vec![(b'a', 0), (b'a', 1), (b'a', 2), (b'd', 0), (b'd', 1)]);
+
+            arbitrator.set_schedule(Schedule::Fair);
+            arbitrator.queue = queue();
+            arbitrator.reorder();
+            assert_eq!(order(&arbitrator), vec![(b'a', 0), (b'd', 0), (b'a', 1), (b'd', 1), (b'a', 2)]);
+
+            // Turns are counted afresh each time
+            arbitrator.reorder();
+            assert_eq!(order(&arbitrator), vec![(b'a', 0), (b'd', 0), (b'a', 1), (b'd', 1), (b'a', 2)]);
+
+            arbitrator.set_schedule(Schedule::ShortestFirst);
+            arbitrator.set_remaining(b"abc", 3);
+            arbitrator.set_remaining(b"def", 2);
+            arbitrator.queue = queue();
+            arbitrator.reorder();
+            assert_eq!(order(&arbitrator), vec![(b'd', 0), (b'd', 1), (b'a', 0), (b'a', 1), (b'a', 2)]);
+
+            arbitrator.set_remaining(b"abc", 1);
+            arbitrator.queue = queue();
+            arbitrator.reorder();
+            assert_eq!(order(&arbitrator), vec![(b'a', 0), (b'a', 1), (b'a', 2), (b'd', 0), (b'd', 1)]);
+
+            arbitrator.set_remaining(b"def", 0);
+            assert!(arbitrator.remaining.get(&b"def"[..]).is_none());
+        }
+
+        wait_term(&mut thread);
+    }
+
+    #[test]
+    fn test_arbitrator_request_congested() {
+        ZSys::init();
+
+        let (_client, router) = ZSys::create_pipe().unwrap();
+
+        let (comm, mut thread) = ZSys::create_pipe().unwrap();
+
+        let chunks = (0..10).map(|i| TimedChunk::new(Rc::new(b"abc".to_vec()), i, 1, Instant::now())).collect();
+
+        {
+            let mut arbitrator = Arbitrator {
+                router: router,
+                queue: chunks,
+                timer_handle: None,
+                timer_comm: comm,
+                timer_failed: Arc::new(AtomicBool::new(false)),
+                slots: 10,
+                protocols: HashMap::new(),
+                budget: None,
+                buffered: HashMap::new(),
+                client_slots: None,
+                client_budget: None,
+                identities: HashMap::new(),
+                schedule: Schedule::Fifo,
+                remaining: HashMap::new(),
+                turns: HashMap::new(),
+                sent: VecDeque::new(),
+                paused: false,
+                excess: 0,
+                throttle: None,
+                clock: Arc::new(SystemClock),
+            };
+
+            arbitrator.request().unwrap();
+            assert_eq!(arbitrator.slots, 0);
+            assert_eq!(arbitrator.sent.len(), 10);
+
+            // The first request couldn't be sent, so its chunk waits
+            // to be requested again
+            let (id, first, last) = arbitrator.take_request().unwrap();
+            assert_eq!((&id[..], first, last), (&b"abc"[..], 0, 0));
+            arbitrator.defer(&id, first, last).unwrap();
+            assert_eq!(arbitrator.slots, 1);
+            assert!(!arbitrator.queue[0].is_started());
+            assert!(arbitrator.queue[0].not_before.is_some());
+            assert_eq!(arbitrator.sent.len(), 9);
+        }
+
+        wait_term(&mut thread);
+    }
+
+    #[test]
+    fn test_arbitrator_request_batch() {
+        ZSys::init();
+
+        let (mut client, router) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+
+        let (comm, mut thread) = ZSys::create_pipe().unwrap();
+
+        let chunks = vec![
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 0, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 1, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"abc".to_vec()), 2, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 0, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"def".to_vec()), 1, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"ghi".to_vec()), 0, 1, Instant::now()),
+            TimedChunk::new(Rc::new(b"ghi".to_vec()), 1, 1, Instant::now()),
+        ];
+
+        {
+            let mut arbitrator = Arbitrator {
+                router: router,
+                queue: chunks,
+                timer_handle: None,
+                timer_comm: comm,
+                timer_failed: Arc::new(AtomicBool::new(false)),
+                slots: 6,
+                protocols: HashMap::new(),
+                budget: None,
+                buffered: HashMap::new(),
+                client_slots: None,
+                client_budget: None,
+                identities: HashMap::new(),
+                schedule: Schedule::Fifo,
+                remaining: HashMap::new(),
+                turns: HashMap::new(),
+                sent: VecDeque::new(),
+                paused: false,
+                excess: 0,
+                throttle: None,
+                clock: Arc::new(SystemClock),
+            };
+            arbitrator.set_protocol("abc".as_bytes(), Some(1));
+            arbitrator.set_protocol("ghi".as_bytes(), Some(protocol::BINARY_INTS));
+
+            arbitrator.request().unwrap();
+
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "abc");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNKS");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "0-2");
+
+            // "def" is a legacy client, so gets plain CHUNK requests
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "def");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "0");
+
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "def");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "1");
+
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "ghi");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNKS");
+            assert_eq!(protocol::pop_u64(&msg, true), Some(0));
+            assert_eq!(protocol::pop_u64(&msg, true), Some(1));
+
+            assert!(client.recv_str().is_err());
+        }
+
+        wait_term(&mut thread);
     }
 
     #[test]
     fn test_timer_new() {
         ZSys::init();
 
-        assert!(Timer::new(ZSock::new(SocketType::REQ), Arc::new(RwLock::new(Vec::new()))).is_ok());
+        assert!(Timer::new(ZSock::new(SocketType::REQ), Arc::new(SystemClock)).is_ok());
     }
 
     #[test]
@@ -284,41 +1555,204 @@ mod tests {
         ZSys::init();
 
         let (mut client, server) = ZSys::create_pipe().unwrap();
-        let (comm, thread) = ZSys::create_pipe().unwrap();
-        client.set_rcvtimeo(Some(1500));
-        thread.set_rcvtimeo(Some(1000));
+        let (mut comm, thread) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(200));
+        thread.set_rcvtimeo(Some(10));
 
-        let mut c = TimedChunk::new("abc".as_bytes(), 0);
-        c.start();
+        let clock = MockClock::new();
+        let mut deadlines = HashMap::new();
+        deadlines.insert(("abc".as_bytes().to_vec(), 0), Deadline::new(clock.now() + Duration::from_secs(CHUNK_TIMEOUT)));
 
         let timer = Timer {
-            chunks: Arc::new(RwLock::new(vec![
-                c,
-            ])),
+            deadlines: deadlines,
+            wake: None,
+            timeout: Duration::from_secs(CHUNK_TIMEOUT),
+            clock: Arc::new(clock.clone()),
             sink: server,
             comm: thread,
         };
-        let handle = spawn(|| timer.run());
+        let handle = timer.spawn(Arc::new(AtomicBool::new(false)));
+
+        assert!(ZMsg::recv(&mut client).is_err());
+        clock.advance(Duration::from_secs(CHUNK_TIMEOUT));
 
         let msg = ZMsg::recv(&mut client).unwrap();
         assert_eq!(msg.popstr().unwrap().unwrap(), "abc");
-        assert_eq!(msg.popstr().unwrap().unwrap(), "0");
-        assert_eq!(msg.popstr().unwrap().unwrap(), "0");
+        assert_eq!(protocol::pop_u64(&msg, true), Some(0));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![0]);
 
-        comm.signal(0).unwrap();
+        // Expired chunks are only reported once
+        assert!(ZMsg::recv(&mut client).is_err());
+
+        comm.send_str("$TERM").unwrap();
         handle.join().unwrap();
     }
 
     #[test]
-    fn test_chunk_is_expired() {
-        let timed = TimedChunk {
-            router_id: vec![97, 98, 99],
-            index: 0,
-            timestamp: Some(Instant::now()),
+    fn test_timer_renotify() {
+        ZSys::init();
+
+        let (mut client, server) = ZSys::create_pipe().unwrap();
+        let (mut comm, thread) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(200));
+        thread.set_rcvtimeo(Some(10));
+
+        let clock = MockClock::new();
+        let mut deadlines = HashMap::new();
+        deadlines.insert(("abc".as_bytes().to_vec(), 0), Deadline::new(clock.now() + Duration::from_secs(1)));
+
+        let timer = Timer {
+            deadlines: deadlines,
+            wake: None,
+            timeout: Duration::from_secs(1),
+            clock: Arc::new(clock.clone()),
+            sink: server,
+            comm: thread,
         };
+        let failed = Arc::new(AtomicBool::new(false));
+        let handle = timer.spawn(failed.clone());
+
+        // A chunk whose timer is never stopped is reported again after
+        // 1s, 2s and 4s, then forgotten
+        for &secs in [1, 1, 2, 4].iter() {
+            clock.advance(Duration::from_secs(secs - 1));
+            assert!(ZMsg::recv(&mut client).is_err());
+            clock.advance(Duration::from_secs(1));
+
+            let msg = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "abc");
+        }
+
+        clock.advance(Duration::from_secs(60));
+        assert!(ZMsg::recv(&mut client).is_err());
+
+        comm.send_str("$TERM").unwrap();
+        let timer = handle.join().unwrap();
+        assert!(timer.deadlines.is_empty());
+        assert!(!failed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_timer_interval() {
+        ZSys::init();
 
-        sleep(Duration::from_secs(1));
+        let (mut client, server) = ZSys::create_pipe().unwrap();
+        let (mut comm, thread) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(2000));
+        thread.set_rcvtimeo(Some(5000));
 
-        assert!(timed.is_expired());
+        let clock = MockClock::new();
+        let timer = Timer {
+            deadlines: HashMap::new(),
+            wake: None,
+            timeout: Duration::from_secs(CHUNK_TIMEOUT),
+            clock: Arc::new(clock.clone()),
+            sink: server,
+            comm: thread,
+        };
+        let handle = timer.spawn(Arc::new(AtomicBool::new(false)));
+
+        let msg = ZMsg::new();
+        msg.addstr("INTERVAL").unwrap();
+        protocol::add_u64(&msg, 100, true).unwrap();
+        msg.send(&mut comm).unwrap();
+
+        let msg = ZMsg::new();
+        msg.addstr("START").unwrap();
+        msg.addstr("abc").unwrap();
+        protocol::add_u64(&msg, 0, true).unwrap();
+        msg.send(&mut comm).unwrap();
+
+        // Without a finer interval, this wouldn't be noticed for 5s
+        clock.advance(Duration::from_secs(CHUNK_TIMEOUT));
+
+        let msg = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "abc");
+
+        comm.send_str("$TERM").unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_timer_wake() {
+        ZSys::init();
+
+        let (mut client, server) = ZSys::create_pipe().unwrap();
+        let (mut comm, thread) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(200));
+        thread.set_rcvtimeo(Some(10));
+
+        let clock = MockClock::new();
+        let timer = Timer {
+            deadlines: HashMap::new(),
+            wake: None,
+            timeout: Duration::from_secs(CHUNK_TIMEOUT),
+            clock: Arc::new(clock.clone()),
+            sink: server,
+            comm: thread,
+        };
+        let handle = timer.spawn(Arc::new(AtomicBool::new(false)));
+
+        let msg = ZMsg::new();
+        msg.addstr("WAKE").unwrap();
+        protocol::add_u64(&msg, 200, true).unwrap();
+        msg.send(&mut comm).unwrap();
+
+        assert!(ZMsg::recv(&mut client).is_err());
+        clock.advance(Duration::from_millis(200));
+
+        let msg = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(msg.size(), 1);
+        assert!(msg.popbytes().unwrap().unwrap().is_empty());
+
+        comm.send_str("$TERM").unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_timer_start_stop() {
+        ZSys::init();
+
+        let (mut client, server) = ZSys::create_pipe().unwrap();
+        let (mut comm, thread) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(200));
+        thread.set_rcvtimeo(Some(10));
+
+        let clock = MockClock::new();
+        let timer = Timer {
+            deadlines: HashMap::new(),
+            wake: None,
+            timeout: Duration::from_secs(CHUNK_TIMEOUT),
+            clock: Arc::new(clock.clone()),
+            sink: server,
+            comm: thread,
+        };
+        let handle = timer.spawn(Arc::new(AtomicBool::new(false)));
+
+        // A malformed command is ignored
+        let msg = ZMsg::new();
+        msg.addstr("START").unwrap();
+        msg.send(&mut comm).unwrap();
+
+        for &(cmd, router_id, index) in [("START", "abc", 0), ("START", "def", 1), ("STOP", "abc", 0)].iter() {
+            let msg = ZMsg::new();
+            msg.addstr(cmd).unwrap();
+            msg.addstr(router_id).unwrap();
+            protocol::add_u64(&msg, index, true).unwrap();
+            msg.send(&mut comm).unwrap();
+        }
+
+        // Nothing expires until the clock reaches the deadline
+        clock.advance(Duration::from_secs(CHUNK_TIMEOUT - 1));
+        assert!(ZMsg::recv(&mut client).is_err());
+        clock.advance(Duration::from_secs(1));
+
+        let msg = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "def");
+        assert_eq!(protocol::pop_u64(&msg, true), Some(1));
+        assert!(ZMsg::recv(&mut client).is_err());
+
+        comm.send_str("$TERM").unwrap();
+        handle.join().unwrap();
     }
 }
@@ -0,0 +1,167 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Attributes that a client can ask the server to give an uploaded
+//! file: its permissions, owner, group and modification time.
+
+use error::Result;
+#[cfg(unix)]
+use error::Error;
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::fs;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+#[cfg(unix)]
+use std::ptr;
+use std::time::UNIX_EPOCH;
+
+/// Only permission bits are applied, so a client can't upload a
+/// setuid file
+#[cfg(unix)]
+const MODE_MASK: u32 = 0o777;
+/// Room for the strings that passwd and group entries point into
+#[cfg(unix)]
+const ENTRY_BUF_SIZE: usize = 16384;
+
+/// Modification time of a file as seconds and nanoseconds since the
+/// Unix epoch, or None if it's earlier than that
+pub fn mtime(meta: &fs::Metadata) -> Option<(u64, u32)> {
+    meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| (d.as_secs(), d.subsec_nanos()))
+}
+
+/// Apply the requested attributes to the file at `path`. Owners and
+/// groups may be names or numeric IDs.
+#[cfg(unix)]
+pub fn apply(path: &Path, mode: Option<u32>, owner: Option<&str>, group: Option<&str>, mtime: Option<(u64, u32)>) -> Result<()> {
+    let c_path = try!(CString::new(path.as_os_str().as_bytes()).or(Err(Error::InvalidFilePath)));
+
+    // A new owner may not be allowed to change the mode, so the mode
+    // is set after
+    if owner.is_some() || group.is_some() {
+        // -1 leaves the ID unchanged
+        let uid = match owner {
+            Some(name) => try!(user_id(name)),
+            None => !0,
+        };
+        let gid = match group {
+            Some(name) => try!(group_id(name)),
+            None => !0,
+        };
+        if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    if let Some(mode) = mode {
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(mode & MODE_MASK)));
+    }
+
+    // The access time is set to match, as utimes() can't leave it be
+    if let Some((secs, nanos)) = mtime {
+        let time = libc::timeval {
+            tv_sec: secs as libc::time_t,
+            tv_usec: (nanos / 1000) as libc::suseconds_t,
+        };
+        if unsafe { libc::utimes(c_path.as_ptr(), [time, time].as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(())
+}
+
+// Windows has no Unix permissions or owners to apply
+#[cfg(not(unix))]
+pub fn apply(_: &Path, _: Option<u32>, _: Option<&str>, _: Option<&str>, _: Option<(u64, u32)>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn user_id(name: &str) -> Result<libc::uid_t> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let c_name = try!(CString::new(name).or(Err(Error::UnknownOwner)));
+    let mut buf = vec![0; ENTRY_BUF_SIZE];
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    let rc = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+
+    if rc != 0 || found.is_null() {
+        Err(Error::UnknownOwner)
+    } else {
+        Ok(entry.pw_uid)
+    }
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> Result<libc::gid_t> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let c_name = try!(CString::new(name).or(Err(Error::UnknownOwner)));
+    let mut buf = vec![0; ENTRY_BUF_SIZE];
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    let rc = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+
+    if rc != 0 || found.is_null() {
+        Err(Error::UnknownOwner)
+    } else {
+        Ok(entry.gr_gid)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use libc;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use super::*;
+    use super::{group_id, user_id};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_apply() {
+        let tempdir = TempDir::new("attrs_test_apply").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap();
+
+        // Changing to the current owner is always allowed
+        let uid = unsafe { libc::getuid() }.to_string();
+        apply(&path, Some(0o4640), Some(&uid), None, Some((1000000000, 500000000))).unwrap();
+
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(meta.uid().to_string(), uid);
+        assert_eq!(mtime(&meta), Some((1000000000, 500000000)));
+    }
+
+    #[test]
+    fn test_ids() {
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert_eq!(user_id("1234").unwrap(), 1234);
+        assert!(user_id("no-such-user").is_err());
+        assert_eq!(group_id("5678").unwrap(), 5678);
+        assert!(group_id("no-such-group").is_err());
+    }
+}
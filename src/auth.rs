@@ -0,0 +1,172 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use czmq::{SocketType, ZAuth, ZCert, ZSock};
+use error::{Error, Result};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// ZAP domain used for CURVE authentication
+const ZAP_DOMAIN: &'static str = "zfilexfer";
+/// Placeholder secret for certificates that only hold a public key
+const NULL_SECRET: &'static str = "0000000000000000000000000000000000000000";
+
+/// The server's CURVE certificate and the allowlist of client public
+/// keys.
+///
+/// The certificate is stored in the usual czmq format, with the
+/// secret key in `<cert_path>_secret`. Clients are allowed by putting
+/// their public certificates in the clients directory.
+pub struct ServerAuth {
+    cert: ZCert,
+    cert_path: String,
+    clients_dir: String,
+    zauth: ZAuth,
+}
+
+impl ServerAuth {
+    /// Load the server certificate, generating and saving one if it
+    /// doesn't exist yet, and allow the clients in `clients_dir`.
+    pub fn new(cert_path: &str, clients_dir: &str) -> Result<ServerAuth> {
+        let cert = if Path::new(cert_path).exists() {
+            try!(ZCert::load(cert_path))
+        } else {
+            try!(generate(cert_path))
+        };
+
+        try!(fs::create_dir_all(clients_dir));
+        let mut zauth = try!(ZAuth::new());
+        try!(zauth.load_curve(Some(clients_dir)));
+
+        Ok(ServerAuth {
+            cert: cert,
+            cert_path: cert_path.into(),
+            clients_dir: clients_dir.into(),
+            zauth: zauth,
+        })
+    }
+
+    /// The server's public key, for clients to pin
+    pub fn public_key(&self) -> &str {
+        self.cert.public_txt()
+    }
+
+    /// Make `sock` a CURVE server that only accepts allowed clients.
+    /// ZMQ only applies this to endpoints bound afterwards, so use
+    /// `bind()` for a socket that isn't bound yet.
+    pub fn apply(&self, sock: &mut ZSock) {
+        self.cert.apply(sock);
+        sock.set_curve_server(true);
+        sock.set_zap_domain(ZAP_DOMAIN);
+    }
+
+    /// A ROUTER socket for `Server::new()`, made a CURVE server
+    /// before it's bound to `endpoint`
+    pub fn bind(&self, endpoint: &str) -> Result<ZSock> {
+        let mut sock = ZSock::new(SocketType::ROUTER);
+        self.apply(&mut sock);
+        try!(sock.bind(endpoint));
+        Ok(sock)
+    }
+
+    /// Replace the certificate with a new one. The old certificate is
+    /// kept as `<cert_path>.<unix time>`. Endpoints bound afterwards
+    /// use the new key, while those already bound keep the old one.
+    pub fn rotate(&mut self) -> Result<()> {
+        // The new certificate is saved before the old one is moved,
+        // so a failure leaves the old one in place
+        let new_path = format!("{}.new", self.cert_path);
+        let cert = try!(generate(&new_path));
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let old_path = format!("{}.{}", self.cert_path, time);
+        try!(fs::rename(&self.cert_path, &old_path));
+        try!(fs::rename(&format!("{}_secret", self.cert_path), &format!("{}_secret", old_path)));
+        try!(fs::rename(&new_path, &self.cert_path));
+        try!(fs::rename(&format!("{}_secret", new_path), &format!("{}_secret", self.cert_path)));
+
+        self.cert = cert;
+        Ok(())
+    }
+
+    /// Allow a client's public key, saving it in the clients
+    /// directory as `<name>.key`
+    pub fn allow_client(&mut self, name: &str, public_key: &str) -> Result<()> {
+        let cert = try!(ZCert::from_txt(public_key, NULL_SECRET));
+        try!(cert.save_public(&try!(self.client_path(name))));
+        self.reload()
+    }
+
+    /// Remove a client previously allowed with `allow_client()`
+    pub fn revoke_client(&mut self, name: &str) -> Result<()> {
+        try!(fs::remove_file(try!(self.client_path(name))));
+        self.reload()
+    }
+
+    /// Re-read the clients directory, e.g. after certificates have
+    /// been added or removed by another process
+    pub fn reload(&mut self) -> Result<()> {
+        try!(self.zauth.load_curve(Some(&self.clients_dir)));
+        Ok(())
+    }
+
+    fn client_path(&self, name: &str) -> Result<String> {
+        // Names become file names, so must not escape the directory
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(Error::InvalidRequest);
+        }
+
+        Ok(format!("{}/{}.key", self.clients_dir, name))
+    }
+}
+
+fn generate(cert_path: &str) -> Result<ZCert> {
+    let cert = try!(ZCert::new());
+    try!(cert.save(cert_path));
+    Ok(cert)
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::ZSys;
+    use std::path::Path;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_keys() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("auth_test_keys").unwrap();
+        let cert_path = format!("{}/server.cert", tempdir.path().to_str().unwrap());
+        let clients_dir = format!("{}/clients", tempdir.path().to_str().unwrap());
+
+        let mut auth = ServerAuth::new(&cert_path, &clients_dir).unwrap();
+        assert!(Path::new(&cert_path).exists());
+        assert!(Path::new(&format!("{}_secret", cert_path)).exists());
+        let key = auth.public_key().to_string();
+
+        // Reloading keeps the saved certificate
+        let auth2 = ServerAuth::new(&cert_path, &clients_dir).unwrap();
+        assert_eq!(auth2.public_key(), key);
+
+        auth.rotate().unwrap();
+        assert!(auth.public_key() != key);
+        let auth2 = ServerAuth::new(&cert_path, &clients_dir).unwrap();
+        assert_eq!(auth2.public_key(), auth.public_key());
+        assert!(!Path::new(&format!("{}.new", cert_path)).exists());
+        assert!(auth.bind("inproc://auth_test_keys").is_ok());
+
+        auth.allow_client("agent", &key).unwrap();
+        assert!(Path::new(&format!("{}/agent.key", clients_dir)).exists());
+        auth.revoke_client("agent").unwrap();
+        assert!(!Path::new(&format!("{}/agent.key", clients_dir)).exists());
+        assert!(auth.allow_client("../agent", &key).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use clock::Clock;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86400;
+const SECS_PER_HOUR: u64 = 3600;
+
+/// Upload rate limits that change with the time of day, e.g. 10 MB/s
+/// during business hours and unlimited at night.
+///
+/// Times are hours of the day in UTC. The first rule that covers the
+/// current hour applies, otherwise the default rate does. A rate of
+/// 0 pauses uploads.
+#[derive(Clone, Debug)]
+pub struct BandwidthSchedule {
+    default: Option<u64>,
+    rules: Vec<(u32, u32, Option<u64>)>,
+}
+
+impl BandwidthSchedule {
+    /// Create a schedule with a default rate in bytes per second, or
+    /// None for unlimited
+    pub fn new(default: Option<u64>) -> BandwidthSchedule {
+        BandwidthSchedule {
+            default: default,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Use `rate` from hour `start` until hour `end`. If `end` is
+    /// before `start`, the rule runs past midnight.
+    pub fn add(mut self, start: u32, end: u32, rate: Option<u64>) -> BandwidthSchedule {
+        self.rules.push((start % 24, end % 24, rate));
+        self
+    }
+
+    /// The rate at `secs` seconds past midnight
+    pub fn rate_at(&self, secs: u64) -> Option<u64> {
+        let hour = (secs % SECS_PER_DAY / SECS_PER_HOUR) as u32;
+        let rule = self.rules.iter().find(|&&(start, end, _)| {
+            if start <= end {
+                hour >= start && hour < end
+            } else {
+                hour >= start || hour < end
+            }
+        });
+
+        match rule {
+            Some(&(_, _, rate)) => rate,
+            None => self.default,
+        }
+    }
+
+    /// The rate that applies now
+    pub fn current_rate(&self) -> Option<u64> {
+        self.rate_at(now_secs())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// A token bucket that meters requested bytes against a schedule.
+///
+/// The bucket holds at most one second's worth of bytes. A chunk is
+/// allowed whenever the bucket isn't in debt, so chunks larger than
+/// the rate still get through, just less often.
+pub struct Throttle {
+    schedule: BandwidthSchedule,
+    tokens: i64,
+    updated: Instant,
+    clock: Arc<Clock>,
+}
+
+impl Throttle {
+    /// Create a Throttle that refills as `clock` moves on
+    pub fn new(schedule: BandwidthSchedule, clock: Arc<Clock>) -> Throttle {
+        Throttle {
+            schedule: schedule,
+            tokens: 0,
+            updated: clock.now(),
+            clock: clock,
+        }
+    }
+
+    /// Take `len` bytes from the bucket, returning false if the
+    /// caller must wait
+    pub fn take(&mut self, len: u64) -> bool {
+        let rate = match self.schedule.current_rate() {
+            Some(0) => {
+                self.tokens = 0;
+                self.updated = self.clock.now();
+                return false;
+            },
+            Some(rate) => rate,
+            None => {
+                self.tokens = 0;
+                return true;
+            },
+        };
+
+        self.refill(rate);
+        if self.tokens < 0 {
+            return false;
+        }
+
+        self.tokens -= len as i64;
+        true
+    }
+
+    /// How long until the bucket is out of debt at the current rate.
+    /// While paused, that's until the next hour, when the rate may
+    /// change.
+    pub fn wait(&self) -> Duration {
+        match self.schedule.current_rate() {
+            Some(0) => Duration::from_secs(SECS_PER_HOUR - now_secs() % SECS_PER_HOUR),
+            Some(rate) if self.tokens < 0 => Duration::from_millis((-self.tokens) as u64 * 1000 / rate),
+            _ => Duration::new(0, 0),
+        }
+    }
+
+    fn refill(&mut self, rate: u64) {
+        let now = self.clock.now();
+        let elapsed = now - self.updated;
+        let millis = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+        let earned = millis.saturating_mul(rate) / 1000;
+
+        // Leave time that didn't earn a whole byte to the next refill
+        if earned > 0 {
+            self.tokens = (self.tokens + earned as i64).min(rate as i64);
+            self.updated = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clock::MockClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_rate_at() {
+        let schedule = BandwidthSchedule::new(None)
+                            .add(9, 17, Some(10_000_000))
+                            .add(22, 2, Some(1000));
+
+        assert_eq!(schedule.rate_at(8 * 3600), None);
+        assert_eq!(schedule.rate_at(9 * 3600), Some(10_000_000));
+        assert_eq!(schedule.rate_at(17 * 3600 - 1), Some(10_000_000));
+        assert_eq!(schedule.rate_at(17 * 3600), None);
+        assert_eq!(schedule.rate_at(23 * 3600), Some(1000));
+        assert_eq!(schedule.rate_at(SECS_PER_DAY + 3600), Some(1000));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let clock = MockClock::new();
+        let mut throttle = Throttle::new(BandwidthSchedule::new(None), Arc::new(clock.clone()));
+        assert!(throttle.take(u64::max_value() / 2));
+        assert!(throttle.take(1));
+
+        // The first chunk goes straight away, then the bucket is in
+        // debt until it refills
+        let mut throttle = Throttle::new(BandwidthSchedule::new(Some(1000)), Arc::new(clock.clone()));
+        assert!(throttle.take(1500));
+        assert!(!throttle.take(1));
+        assert_eq!(throttle.wait(), Duration::from_millis(1500));
+
+        clock.advance(Duration::from_millis(1500));
+        assert!(throttle.take(1));
+
+        // Nothing gets through while paused, until the next hour
+        let mut throttle = Throttle::new(BandwidthSchedule::new(Some(0)), Arc::new(clock.clone()));
+        assert!(!throttle.take(1));
+        let wait = throttle.wait();
+        assert!(wait > Duration::new(0, 0) && wait <= Duration::from_secs(3600));
+    }
+}
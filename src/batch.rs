@@ -0,0 +1,338 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Sets of files that are uploaded all or nothing. The client
+//! declares every file up front, and the server stages each one
+//! beside its destination until the whole set has been verified.
+
+use codec::{Codec, JsonCodec};
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use file::{self, File, Options};
+use protocol;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Most files a BATCH request may declare
+pub const MAX_FILES: usize = 10000;
+
+/// A file declared in a BATCH request
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct BatchFile {
+    /// Destination path
+    pub path: String,
+    pub size: u64,
+    pub crc: u64,
+}
+
+pub struct Batch {
+    /// Local paths and the remote paths to send them to
+    files: Vec<(PathBuf, PathBuf)>,
+    options: Vec<Options>,
+    results: Vec<(PathBuf, Result<()>)>,
+}
+
+impl Batch {
+    /// Start an empty batch. `options` apply to each file in it.
+    pub fn new(options: Option<&[Options]>) -> Batch {
+        Batch {
+            files: Vec::new(),
+            options: options.map_or(Vec::new(), |o| o.to_vec()),
+            results: Vec::new(),
+        }
+    }
+
+    /// Add a local file to send to `remote_path`
+    pub fn add<P: AsRef<Path>, R: AsRef<Path>>(&mut self, path: P, remote_path: R) {
+        self.files.push((path.as_ref().to_owned(), remote_path.as_ref().to_owned()));
+    }
+
+    /// Send every file in the batch, of which there may be up to
+    /// `MAX_FILES`. The server only moves them into place once all of
+    /// them have arrived and verified, replacing any existing files. If any file fails, the server discards
+    /// them all and this returns `Error::FileFail`. See
+    /// `get_results()` for which files failed.
+    pub fn send(&mut self, sock: &mut ZSock) -> Result<()> {
+        self.results.clear();
+
+        let mut files = Vec::with_capacity(self.files.len());
+        let mut declared = Vec::with_capacity(self.files.len());
+        for &(ref path, ref remote_path) in self.files.iter() {
+            let file = try!(File::open(path, Some(&self.options)));
+            declared.push(BatchFile {
+                path: try!(String::from_utf8(try!(protocol::encode_path(remote_path))).or(Err(Error::InvalidFilePath))),
+                size: file.get_size(),
+                crc: file.get_crc(),
+            });
+            files.push(file);
+        }
+
+        let manifest = try!(JsonCodec.encode(&declared));
+        try!(request(sock, "BATCH", Some(&manifest)));
+
+        for (mut file, &(_, ref remote_path)) in files.into_iter().zip(self.files.iter()) {
+            let result = file.send(sock, remote_path);
+            self.results.push((remote_path.clone(), result));
+        }
+
+        if self.results.iter().all(|&(_, ref result)| result.is_ok()) {
+            request(sock, "BATCH-COMMIT", None)
+        } else {
+            try!(request(sock, "BATCH-ABORT", None));
+            Err(Error::FileFail)
+        }
+    }
+
+    /// Result of sending each file in the most recent send, by
+    /// remote path
+    pub fn get_results(&self) -> &[(PathBuf, Result<()>)] {
+        &self.results
+    }
+}
+
+fn request(sock: &mut ZSock, action: &str, manifest: Option<&[u8]>) -> Result<()> {
+    let msg = ZMsg::new();
+    try!(msg.addstr(action));
+    if let Some(manifest) = manifest {
+        try!(msg.addbytes(manifest));
+    }
+    try!(msg.send(sock));
+
+    let reply = try!(ZMsg::recv(sock));
+    match try!(reply.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
+        "Ok" => Ok(()),
+        "Err" => Err(protocol::pop_err(&reply)),
+        _ => Err(Error::InvalidReply),
+    }
+}
+
+/// A hidden path beside `dest` for staging its new contents. It is
+/// on the same filesystem, so committing is a rename.
+pub fn staging_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    let mut counter: u16 = 0;
+
+    loop {
+        let path = dest.with_file_name(&format!(".{}.batch{}", name, counter));
+        if fs::symlink_metadata(&path).is_err() {
+            return path;
+        }
+        counter += 1;
+    }
+}
+
+/// Whether a path is named like those from `staging_path()`
+pub fn is_staging_name(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return false,
+    };
+
+    match name.rfind(".batch") {
+        Some(at) if at > 1 && name.starts_with('.') => {
+            let counter = &name[at + ".batch".len()..];
+            !counter.is_empty() && counter.bytes().all(|b| b.is_ascii_digit())
+        },
+        _ => false,
+    }
+}
+
+/// Delete the files that batches staged in `dir` and its
+/// subdirectories. Batches don't outlive the server, so any found at
+/// startup can never be committed.
+pub fn remove_staged(dir: &Path) -> Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        let file_type = try!(entry.file_type());
+
+        if file_type.is_dir() {
+            try!(remove_staged(&path));
+        } else if file_type.is_file() && is_staging_name(&path) {
+            warn!("removing uncommitted batch file path={:?}", path);
+            try!(fs::remove_file(&path));
+        }
+    }
+
+    Ok(())
+}
+
+/// Move each staged file to its destination. Existing files are
+/// moved aside first and put back if any move fails, so either every
+/// destination is replaced or none are. If `durable`, each
+/// destination's directory is synced before the files moved aside
+/// are deleted, so the moves survive a crash.
+pub fn commit(files: &[(PathBuf, PathBuf)], durable: bool) -> Result<()> {
+    if files.iter().any(|&(_, ref dest)| fs::symlink_metadata(dest).map(|m| m.is_dir()).unwrap_or(false)) {
+        return Err(Error::InvalidFilePath);
+    }
+
+    let mut placed = Vec::with_capacity(files.len());
+    let mut result = Ok(());
+    for &(ref staging, ref dest) in files.iter() {
+        match place(staging, dest) {
+            Ok(old) => placed.push((staging, dest, old)),
+            Err(e) => {
+                result = Err(e);
+                break;
+            },
+        }
+    }
+
+    if result.is_ok() && durable {
+        let mut dirs: Vec<&Path> = files.iter().map(|&(_, ref dest)| dest.parent().unwrap_or(Path::new(""))).collect();
+        dirs.sort();
+        dirs.dedup();
+        result = dirs.into_iter().map(file::sync_dir).collect();
+    }
+
+    if let Err(e) = result {
+        for &(staging, dest, ref old) in placed.iter().rev() {
+            let _ = fs::rename(dest, staging);
+            if let Some(ref old) = *old {
+                let _ = fs::rename(old, dest);
+            }
+        }
+        return Err(e);
+    }
+
+    for &(_, _, ref old) in placed.iter() {
+        if let Some(ref old) = *old {
+            let _ = fs::remove_file(old);
+        }
+    }
+    Ok(())
+}
+
+// Move a staged file into place, returning where the file it
+// replaced was moved to
+fn place(staging: &Path, dest: &Path) -> Result<Option<PathBuf>> {
+    let old = if fs::symlink_metadata(dest).is_ok() {
+        let old = staging_path(dest);
+        try!(fs::rename(dest, &old));
+        Some(old)
+    } else {
+        None
+    };
+
+    if let Err(e) = fs::rename(staging, dest) {
+        if let Some(ref old) = old {
+            let _ = fs::rename(old, dest);
+        }
+        return Err(e.into());
+    }
+
+    Ok(old)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use super::*;
+    use tempdir::TempDir;
+
+    fn write(path: &Path, content: &str) {
+        fs::File::create(path).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        let mut content = String::new();
+        fs::File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_staging_path() {
+        let tempdir = TempDir::new("batch_test_staging_path").unwrap();
+        let dest = tempdir.path().join("file");
+
+        let staging = staging_path(&dest);
+        assert_eq!(staging, tempdir.path().join(".file.batch0"));
+        write(&staging, "");
+        assert_eq!(staging_path(&dest), tempdir.path().join(".file.batch1"));
+    }
+
+    #[test]
+    fn test_is_staging_name() {
+        assert!(is_staging_name(Path::new("/tmp/.file.batch0")));
+        assert!(is_staging_name(Path::new(".a.b.batch12")));
+        assert!(!is_staging_name(Path::new("/tmp/file.batch0")));
+        assert!(!is_staging_name(Path::new("/tmp/.file.batch")));
+        assert!(!is_staging_name(Path::new("/tmp/.file.batchx")));
+        assert!(!is_staging_name(Path::new("/tmp/.batch0")));
+    }
+
+    #[test]
+    fn test_remove_staged() {
+        let tempdir = TempDir::new("batch_test_remove_staged").unwrap();
+        fs::create_dir(tempdir.path().join("sub")).unwrap();
+        let kept = tempdir.path().join("file");
+        write(&kept, "");
+        write(&staging_path(&kept), "");
+        write(&staging_path(&tempdir.path().join("sub/file")), "");
+
+        remove_staged(tempdir.path()).unwrap();
+        assert!(kept.exists());
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 2);
+        assert_eq!(fs::read_dir(tempdir.path().join("sub")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_commit() {
+        let tempdir = TempDir::new("batch_test_commit").unwrap();
+        let a = tempdir.path().join("a");
+        let b = tempdir.path().join("b");
+        write(&a, "old");
+
+        let files = vec![(staging_path(&a), a.clone()), (staging_path(&b), b.clone())];
+        write(&files[0].0, "new a");
+        write(&files[1].0, "new b");
+
+        commit(&files, false).unwrap();
+        assert_eq!(read(&a), "new a");
+        assert_eq!(read(&b), "new b");
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 2);
+
+        // Durably, into more than one directory
+        let sub = tempdir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        let c = sub.join("c");
+        let files = vec![(staging_path(&a), a.clone()), (staging_path(&c), c.clone())];
+        write(&files[0].0, "newer a");
+        write(&files[1].0, "new c");
+        commit(&files, true).unwrap();
+        assert_eq!(read(&a), "newer a");
+        assert_eq!(read(&c), "new c");
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_commit_rollback() {
+        let tempdir = TempDir::new("batch_test_commit_rollback").unwrap();
+        let a = tempdir.path().join("a");
+        let b = tempdir.path().join("b");
+        write(&a, "old");
+
+        // The second file was never staged, so the first is put back
+        let files = vec![(staging_path(&a), a.clone()), (staging_path(&b), b.clone())];
+        write(&files[0].0, "new a");
+
+        assert!(commit(&files, false).is_err());
+        assert_eq!(read(&a), "old");
+        assert_eq!(read(&files[0].0), "new a");
+        assert!(!b.exists());
+
+        // A directory can't be replaced by a file
+        fs::create_dir(&b).unwrap();
+        write(&files[1].0, "new b");
+        assert!(commit(&files, false).is_err());
+        assert_eq!(read(&a), "old");
+    }
+}
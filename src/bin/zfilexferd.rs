@@ -0,0 +1,250 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A standalone server, for running a file transfer endpoint without
+//! writing a daemon around the library. Run it with the path to a
+//! JSON config file, e.g.
+//!
+//! ```json
+//! {
+//!     "endpoint": "tcp://*:7101",
+//!     "upload_slots": 8,
+//!     "allowed_paths": ["/srv/uploads"],
+//!     "max_file_size": 1073741824,
+//!     "recover": ["/srv/uploads"]
+//! }
+//! ```
+//!
+//! Only `endpoint` and `allowed_paths` are required, and unknown
+//! fields are refused. On SIGINT or SIGTERM the server stops taking
+//! new transfers, and exits once those in progress have finished. A
+//! second signal cancels them instead. Cancelled uploads resume when
+//! their clients send them again if their directories are given in
+//! `recover`.
+
+extern crate ctrlc;
+extern crate czmq;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate zdaemon;
+extern crate zfilexfer;
+
+use czmq::{ZSock, ZSys};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+use zdaemon::{Endpoint, Error as DError, Service};
+use zfilexfer::{Server, ServerOptions, Shutdown};
+
+/// Chunks that may be in flight across all clients, unless the config
+/// says otherwise
+const UPLOAD_SLOTS: u32 = 8;
+/// Where the signal handler reports SIGINT and SIGTERM
+const SIGNALS: &'static str = "inproc://zfilexferd-signals";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// ZMQ endpoint to bind, e.g. `tcp://*:7101` or
+    /// `ipc:///run/zfilexfer.sock`
+    endpoint: String,
+    upload_slots: Option<u32>,
+    /// Directories that uploads may be written under. At least one
+    /// must be given.
+    allowed_paths: Vec<String>,
+    chunk_timeout: Option<u32>,
+    #[serde(default)]
+    durable: bool,
+    /// Milliseconds between client heartbeats
+    heartbeat: Option<u32>,
+    max_file_size: Option<u64>,
+    max_transfers: Option<u32>,
+    max_transfers_per_client: Option<u32>,
+    quota: Option<u64>,
+    #[serde(default)]
+    recover: Vec<String>,
+    staging_dir: Option<String>,
+    workers: Option<u32>,
+}
+
+impl Config {
+    fn load(path: &str) -> Result<Config, String> {
+        let mut encoded = Vec::new();
+        try!(fs::File::open(path).and_then(|mut fh| fh.read_to_end(&mut encoded))
+            .map_err(|e| format!("Could not read {}: {}", path, e)));
+        Config::parse(&encoded).map_err(|e| format!("Invalid config {}: {}", path, e))
+    }
+
+    // A server that could write anywhere is never what was meant
+    fn parse(encoded: &[u8]) -> Result<Config, String> {
+        let config: Config = try!(serde_json::from_slice(encoded).map_err(|e| e.to_string()));
+        if config.allowed_paths.is_empty() {
+            return Err("allowed_paths must name at least one directory".into());
+        }
+        Ok(config)
+    }
+
+    fn server_options(&self) -> Vec<ServerOptions> {
+        let mut options = Vec::new();
+        for path in self.allowed_paths.iter() {
+            options.push(ServerOptions::AllowedPath(path.clone()));
+        }
+        if let Some(millis) = self.chunk_timeout {
+            options.push(ServerOptions::ChunkTimeout(millis));
+        }
+        if self.durable {
+            options.push(ServerOptions::Durable);
+        }
+        if let Some(millis) = self.heartbeat {
+            options.push(ServerOptions::Heartbeat(millis));
+        }
+        if let Some(size) = self.max_file_size {
+            options.push(ServerOptions::MaxFileSize(size));
+        }
+        if let Some(n) = self.max_transfers {
+            options.push(ServerOptions::MaxTransfers(n));
+        }
+        if let Some(n) = self.max_transfers_per_client {
+            options.push(ServerOptions::MaxTransfersPerClient(n));
+        }
+        if let Some(bytes) = self.quota {
+            options.push(ServerOptions::Quota(bytes));
+        }
+        for dir in self.recover.iter() {
+            options.push(ServerOptions::Recover(dir.clone()));
+        }
+        if let Some(ref dir) = self.staging_dir {
+            options.push(ServerOptions::StagingDir(dir.clone()));
+        }
+        if let Some(n) = self.workers {
+            options.push(ServerOptions::Workers(n));
+        }
+        options
+    }
+}
+
+// The server, with the socket that signals arrive on. The first
+// drains the server and the second aborts its transfers, and the
+// service is stopped once nothing is left in progress.
+struct Daemon {
+    server: Server,
+    signals: ZSock,
+    service: ZSock,
+    shutdown: Option<Shutdown>,
+}
+
+impl Endpoint for Daemon {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        let mut socks = self.server.get_sockets();
+        socks.push(&mut self.signals);
+        socks
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> Result<(), DError> {
+        if *sock == self.signals {
+            let _ = try!(sock.recv_str());
+            let mode = match self.shutdown {
+                None => Shutdown::Drain,
+                Some(_) => Shutdown::Abort,
+            };
+            let _ = writeln!(io::stderr(), "zfilexferd: shutting down mode={:?}", mode);
+            try!(self.server.shutdown(mode).map_err(|e| -> DError { e.into() }));
+            self.shutdown = Some(mode);
+        } else {
+            try!(self.server.recv(sock));
+        }
+
+        if self.shutdown.is_some() && self.server.is_drained() {
+            try!(self.service.send_str("$TERM"));
+        }
+        Ok(())
+    }
+}
+
+fn run(config: Config) -> Result<(), String> {
+    ZSys::init();
+
+    // Replaces CZMQ's own handler, which would stop the service
+    // without letting transfers finish
+    let signals = try!(ZSock::new_pull(&format!("@{}", SIGNALS)).map_err(|e| e.to_string()));
+    try!(ctrlc::set_handler(|| {
+        if let Ok(mut push) = ZSock::new_push(&format!(">{}", SIGNALS)) {
+            let _ = push.send_str("SIGNAL");
+        }
+    }).map_err(|e| format!("Could not handle signals: {}", e)));
+
+    let router = try!(ZSock::new_router(&format!("@{}", config.endpoint))
+        .map_err(|e| format!("Could not bind {}: {}", config.endpoint, e)));
+    let options = config.server_options();
+    let server = try!(Server::new(router, config.upload_slots.unwrap_or(UPLOAD_SLOTS), Some(&options))
+        .map_err(|e| format!("Could not start server: {}", e)));
+
+    let (front, back) = try!(ZSys::create_pipe().map_err(|e| e.to_string()));
+    let mut service = try!(Service::new(back).map_err(|e| e.to_string()));
+    try!(service.add_endpoint(Daemon {
+        server: server,
+        signals: signals,
+        service: front,
+        shutdown: None,
+    }).map_err(|e| e.to_string()));
+    service.start(None).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            let _ = writeln!(io::stderr(), "Usage: zfilexferd <config.json>");
+            process::exit(2);
+        },
+    };
+
+    if let Err(e) = Config::load(&path).and_then(run) {
+        let _ = writeln!(io::stderr(), "zfilexferd: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use super::*;
+
+    #[test]
+    fn test_server_options() {
+        let config: Config = serde_json::from_str(r#"{"endpoint":"tcp://*:7101","allowed_paths":[]}"#).unwrap();
+        assert_eq!(config.upload_slots, None);
+        assert!(config.server_options().is_empty());
+
+        let config: Config = serde_json::from_str(r#"{
+            "endpoint": "ipc:///tmp/zfilexferd",
+            "allowed_paths": ["/a", "/b"],
+            "durable": true,
+            "max_file_size": 10,
+            "recover": ["/a"]
+        }"#).unwrap();
+        assert_eq!(config.server_options().len(), 5);
+
+        assert!(serde_json::from_str::<Config>(r#"{"upload_slots":1}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(Config::parse(br#"{"endpoint":"tcp://*:7101","allowed_paths":["/a"]}"#).is_ok());
+
+        // Writing anywhere must not be the default
+        assert!(Config::parse(br#"{"endpoint":"tcp://*:7101"}"#).is_err());
+        assert!(Config::parse(br#"{"endpoint":"tcp://*:7101","allowed_paths":[]}"#).is_err());
+
+        // Nor may a misspelt field be ignored
+        assert!(Config::parse(br#"{"endpoint":"tcp://*:7101","allowed_paths":["/a"],"quotaa":1}"#).is_err());
+    }
+}
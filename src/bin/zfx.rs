@@ -0,0 +1,209 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A command line client, for transfers from scripts and the shell:
+//!
+//! ```text
+//! zfx put [flags] <local> <endpoint> <remote>
+//! zfx get [flags] <endpoint> <remote> <local>
+//! ```
+
+extern crate czmq;
+extern crate zfilexfer;
+
+use czmq::ZSys;
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use zfilexfer::{Algorithm, Client, ClientOptions, FileOptions};
+
+const USAGE: &'static str = "Usage:
+    zfx put [flags] <local> <endpoint> <remote>
+    zfx get [flags] <endpoint> <remote> <local>
+
+Flags:
+    --backup <suffix>     Keep the file being replaced, with this suffix (put)
+    --chunk-size <bytes>  Size of each chunk sent (put)
+    --compress <gzip|zlib>
+                          Compress chunks on the wire (put)
+    --connections <n>     Spread chunks across this many connections (put)
+    --quiet               Don't show progress
+    --server-key <key>    Pin the server's CURVE public key
+    --timeout <millis>    Give up when the server stops replying";
+
+/// Width of the progress bar, in characters
+const BAR_WIDTH: u64 = 40;
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Put { local: String, endpoint: String, remote: String },
+    Get { endpoint: String, remote: String, local: String },
+}
+
+struct Args {
+    command: Command,
+    client_options: Vec<ClientOptions>,
+    file_options: Vec<FileOptions>,
+    quiet: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut client_options = Vec::new();
+    let mut file_options = Vec::new();
+    let mut quiet = false;
+    let mut positional = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        if arg == "--quiet" {
+            quiet = true;
+            continue;
+        }
+
+        let value = try!(iter.next().ok_or(format!("{} needs a value", arg)));
+        match arg.as_ref() {
+            "--backup" => file_options.push(FileOptions::BackupExisting(value.clone())),
+            "--chunk-size" => file_options.push(FileOptions::ChunkSize(try!(number(arg, value)))),
+            "--compress" => file_options.push(FileOptions::Compress(match value.as_ref() {
+                "gzip" => Algorithm::Gzip,
+                "zlib" => Algorithm::Zlib,
+                _ => return Err(format!("Unknown compression {}", value)),
+            })),
+            "--connections" => client_options.push(ClientOptions::Connections(try!(number(arg, value)))),
+            "--server-key" => client_options.push(ClientOptions::ServerKey(value.clone())),
+            "--timeout" => client_options.push(ClientOptions::Timeout(try!(number(arg, value)))),
+            _ => return Err(format!("Unknown flag {}", arg)),
+        }
+    }
+
+    let command = match (positional.first().map(|c| &c[..]), positional.len()) {
+        (Some("put"), 4) => Command::Put {
+            local: positional[1].clone(),
+            endpoint: positional[2].clone(),
+            remote: positional[3].clone(),
+        },
+        (Some("get"), 4) => Command::Get {
+            endpoint: positional[1].clone(),
+            remote: positional[2].clone(),
+            local: positional[3].clone(),
+        },
+        _ => return Err(USAGE.into()),
+    };
+
+    Ok(Args {
+        command: command,
+        client_options: client_options,
+        file_options: file_options,
+        quiet: quiet,
+    })
+}
+
+fn number<T: ::std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().or(Err(format!("{} needs a number, not {}", flag, value)))
+}
+
+// e.g. "[##########..............................]  25% 3/12 chunks"
+fn progress_bar(sent: u64, total: u64) -> String {
+    let filled = if total == 0 { BAR_WIDTH } else { sent * BAR_WIDTH / total };
+    let percent = if total == 0 { 100 } else { sent * 100 / total };
+    format!("[{}{}] {:3}% {}/{} chunks",
+            "#".repeat(filled as usize), ".".repeat((BAR_WIDTH - filled) as usize), percent, sent, total)
+}
+
+fn run(args: Args) -> Result<(), String> {
+    ZSys::init();
+
+    let quiet = args.quiet;
+    match args.command {
+        Command::Put { local, endpoint, remote } => {
+            let mut client = try!(Client::connect(&endpoint, Some(&args.client_options)).map_err(|e| e.to_string()));
+            let mut last = None;
+            let stats = try!(client.send_file_with_progress(&local, &remote, Some(&args.file_options), |sent, total, _| {
+                // Only redraw when the bar would change
+                let percent = if total == 0 { 100 } else { sent * 100 / total };
+                if !quiet && last != Some(percent) {
+                    last = Some(percent);
+                    let _ = write!(io::stderr(), "\r{}", progress_bar(sent, total));
+                }
+            }).map_err(|e| e.to_string()));
+
+            if !quiet {
+                let _ = writeln!(io::stderr(), "\n{} bytes sent in {} ms", stats.bytes_sent, stats.elapsed);
+            }
+        },
+        Command::Get { endpoint, remote, local } => {
+            let mut client = try!(Client::connect(&endpoint, Some(&args.client_options)).map_err(|e| e.to_string()));
+            try!(client.fetch_file(&remote, &local).map_err(|e| e.to_string()));
+            if !quiet {
+                let _ = writeln!(io::stderr(), "{} saved to {}", remote, local);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            process::exit(2);
+        },
+    };
+
+    if let Err(e) = run(args) {
+        let _ = writeln!(io::stderr(), "zfx: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = parse_args(&args("put --chunk-size 1024 a tcp://host:7101 /b --quiet --compress gzip")).unwrap();
+        assert_eq!(parsed.command, Command::Put {
+            local: "a".into(),
+            endpoint: "tcp://host:7101".into(),
+            remote: "/b".into(),
+        });
+        assert_eq!(parsed.file_options.len(), 2);
+        assert!(parsed.quiet);
+
+        let parsed = parse_args(&args("get --timeout 500 tcp://host:7101 /b a")).unwrap();
+        assert_eq!(parsed.command, Command::Get {
+            endpoint: "tcp://host:7101".into(),
+            remote: "/b".into(),
+            local: "a".into(),
+        });
+        assert_eq!(parsed.client_options.len(), 1);
+
+        assert!(parse_args(&args("put a b")).is_err());
+        assert!(parse_args(&args("put a b c --chunk-size")).is_err());
+        assert!(parse_args(&args("put a b c --chunk-size big")).is_err());
+        assert!(parse_args(&args("put a b c --compress lz4")).is_err());
+        assert!(parse_args(&args("move a b c")).is_err());
+    }
+
+    #[test]
+    fn test_progress_bar() {
+        assert_eq!(progress_bar(3, 12), format!("[{}{}]  25% 3/12 chunks", "#".repeat(10), ".".repeat(30)));
+        assert_eq!(progress_bar(0, 0), format!("[{}] 100% 0/0 chunks", "#".repeat(40)));
+    }
+}
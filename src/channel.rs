@@ -0,0 +1,222 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Channels, which let a client run several transfers at once over
+//! one socket. Messages on a channel start with a CHANNEL frame and a
+//! tag chosen by the client, and the server treats each channel as a
+//! client of its own.
+
+use czmq::{self, ZMsg, ZSock};
+use error::{Error, Result};
+use std::collections::HashMap;
+use std::result::Result as StdResult;
+use zdaemon::{Endpoint, Error as DError};
+
+pub const CHANNEL: &'static [u8] = b"CHANNEL";
+
+/// Server-side map of the keys that stand in for a channel's router
+/// ID. Keys start with a zero byte, which peers can't choose for
+/// their own identity.
+pub struct Channels {
+    keys: HashMap<Vec<u8>, (Vec<u8>, Vec<u8>)>,
+    // CURVE User-Id each router identity authenticated with
+    users: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Channels {
+    pub fn new() -> Channels {
+        Channels {
+            keys: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+
+    /// Record the User-Id that ZAP gave a router identity's latest
+    /// message, which is its public key when it connected with CURVE
+    pub fn set_user(&mut self, router_id: &[u8], user_id: Option<String>) {
+        match user_id {
+            Some(user_id) => { self.users.insert(router_id.to_vec(), user_id.into_bytes()); },
+            None => { self.users.remove(router_id); },
+        }
+    }
+
+    /// User-Id of the client behind a key, if it authenticated
+    pub fn user_id(&self, key: &[u8]) -> Option<&[u8]> {
+        self.users.get(self.identity(key)).map(|u| &u[..])
+    }
+
+    /// Take the channel frames off a message from `router_id`,
+    /// returning the key to use as the router ID and the remaining
+    /// frames. A message without them is returned as it is.
+    pub fn open(&mut self, router_id: Vec<u8>, mut frames: Vec<Vec<u8>>) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        if frames.first().map_or(true, |f| f != CHANNEL) {
+            if self.keys.contains_key(&router_id) {
+                return Err(Error::InvalidRequest);
+            }
+            return Ok((router_id, frames));
+        }
+
+        if frames.len() < 3 || frames[1].is_empty() || router_id.len() > u8::max_value() as usize {
+            return Err(Error::InvalidRequest);
+        }
+
+        let rest = frames.split_off(2);
+        let tag = frames.pop().unwrap();
+
+        // The length keeps keys from different routers apart
+        let mut key = vec![0, router_id.len() as u8];
+        key.extend_from_slice(&router_id);
+        key.extend_from_slice(&tag);
+        self.keys.insert(key.clone(), (router_id, tag));
+
+        Ok((key, rest))
+    }
+
+    /// Router identity behind a key, which is the key itself for a
+    /// client that isn't using channels
+    pub fn identity<'a>(&'a self, key: &'a [u8]) -> &'a [u8] {
+        self.keys.get(key).map_or(key, |&(ref router_id, _)| &router_id[..])
+    }
+
+    /// Who to count a key's usage against: the client's User-Id if it
+    /// authenticated, or else its router identity
+    pub fn client<'a>(&'a self, key: &'a [u8]) -> &'a [u8] {
+        self.user_id(key).unwrap_or(self.identity(key))
+    }
+
+    /// Forget a channel once it has nothing in progress. A client
+    /// using it again opens it again.
+    pub fn close(&mut self, key: &[u8]) {
+        let router_id = match self.keys.remove(key) {
+            Some((router_id, _)) => router_id,
+            None => key.to_vec(),
+        };
+        if !self.keys.values().any(|&(ref r, _)| *r == router_id) {
+            self.users.remove(&router_id);
+        }
+    }
+
+    /// Send a message whose first frame is a key, addressing it to the
+    /// client and channel behind the key
+    pub fn send(&self, msg: ZMsg, router: &mut ZSock) -> StdResult<(), czmq::Error> {
+        let key = try!(msg.popbytes()).unwrap_or(Vec::new());
+
+        match self.keys.get(&key) {
+            Some(&(ref router_id, ref tag)) => {
+                try!(msg.pushbytes(tag));
+                try!(msg.pushbytes(CHANNEL));
+                try!(msg.pushbytes(router_id));
+            },
+            None => try!(msg.pushbytes(&key)),
+        }
+
+        msg.send(router)
+    }
+}
+
+/// A zdaemon endpoint that runs transfers from many local sockets
+/// over one connection to a server.
+///
+/// Bind `front` as a ROUTER for local DEALERs to connect to, and pass
+/// a socket from `connect()` as `back`. Each local socket gets a
+/// channel of its own, so a `File::send()` on each can run at once.
+pub struct Multiplexer {
+    front: ZSock,
+    back: ZSock,
+}
+
+impl Multiplexer {
+    pub fn new(front: ZSock, back: ZSock) -> Multiplexer {
+        Multiplexer {
+            front: front,
+            back: back,
+        }
+    }
+}
+
+impl Endpoint for Multiplexer {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.front, &mut self.back]
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let msg = try!(ZMsg::recv(sock));
+
+        // The front's router ID for a local socket is its channel tag
+        if *sock == self.front {
+            try!(msg.pushbytes(CHANNEL));
+            try!(msg.send(&mut self.back));
+        } else {
+            // Nothing can be done with a reply outside any channel
+            if try!(msg.popbytes()).map_or(true, |f| f != CHANNEL) {
+                return Ok(());
+            }
+            try!(msg.send(&mut self.front));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(strs: &[&str]) -> Vec<Vec<u8>> {
+        strs.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_open() {
+        let mut channels = Channels::new();
+
+        let (key, rest) = channels.open(b"abc".to_vec(), frames(&["DESCRIBE"])).unwrap();
+        assert_eq!((key, rest), (b"abc".to_vec(), frames(&["DESCRIBE"])));
+
+        let (key, rest) = channels.open(b"abc".to_vec(), frames(&["CHANNEL", "1", "CHUNK", "0", "a"])).unwrap();
+        assert_eq!(key, b"\x00\x03abc1".to_vec());
+        assert_eq!(rest, frames(&["CHUNK", "0", "a"]));
+        assert_eq!(channels.identity(&key), b"abc");
+        assert_eq!(channels.identity(b"abc"), b"abc");
+
+        // Another router's channel gets another key
+        let (other, _) = channels.open(b"ab".to_vec(), frames(&["CHANNEL", "c1", "DESCRIBE"])).unwrap();
+        assert!(other != key);
+
+        // A peer can't pass itself off as a channel
+        assert!(channels.open(key.clone(), frames(&["DESCRIBE"])).is_err());
+        assert!(channels.open(b"abc".to_vec(), frames(&["CHANNEL", "1"])).is_err());
+        assert!(channels.open(b"abc".to_vec(), frames(&["CHANNEL", "", "DESCRIBE"])).is_err());
+
+        channels.close(&key);
+        assert_eq!(channels.identity(&key), &key[..]);
+    }
+
+    #[test]
+    fn test_user_id() {
+        let mut channels = Channels::new();
+        channels.set_user(b"abc", Some("key".into()));
+        let (key, _) = channels.open(b"abc".to_vec(), frames(&["CHANNEL", "1", "DESCRIBE"])).unwrap();
+        let (other, _) = channels.open(b"abc".to_vec(), frames(&["CHANNEL", "2", "DESCRIBE"])).unwrap();
+        assert_eq!(channels.user_id(&key), Some(&b"key"[..]));
+        assert_eq!(channels.user_id(b"abc"), Some(&b"key"[..]));
+        assert_eq!(channels.user_id(b"def"), None);
+        assert_eq!(channels.client(&key), b"key");
+        assert_eq!(channels.client(b"def"), b"def");
+
+        // Forgotten along with the router's last channel
+        channels.close(&key);
+        assert_eq!(channels.user_id(&other), Some(&b"key"[..]));
+        channels.close(&other);
+        assert_eq!(channels.user_id(b"abc"), None);
+
+        channels.set_user(b"abc", Some("key".into()));
+        channels.set_user(b"abc", None);
+        assert_eq!(channels.user_id(b"abc"), None);
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Fault injection, for checking that transfers survive an
+//! unreliable network before relying on them.
+
+use czmq::{ZMsg, ZSock};
+use error::Result;
+use std::result::Result as StdResult;
+use std::thread::sleep;
+use std::time::Duration;
+use zdaemon::{Endpoint, Error as DError};
+
+/// Probabilities (0 to 1) of each fault happening to a message
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    /// The message is never delivered
+    pub drop: f64,
+    /// The message is delivered twice
+    pub duplicate: f64,
+    /// The message is held back and delivered after the next one
+    pub reorder: f64,
+    /// The message is delivered after a random delay of up to
+    /// `max_delay`
+    pub delay: f64,
+    pub max_delay: Duration,
+    /// Seeds the random faults, so that a failing run can be repeated
+    pub seed: u64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> ChaosConfig {
+        ChaosConfig {
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(100),
+            seed: 1,
+        }
+    }
+}
+
+/// Applies faults to a stream of messages in one direction
+struct Chaos {
+    config: ChaosConfig,
+    state: u64,
+    held: Option<Vec<Vec<u8>>>,
+}
+
+impl Chaos {
+    fn new(config: ChaosConfig, stream: u64) -> Chaos {
+        Chaos {
+            // Xorshift gets stuck at zero
+            state: (config.seed ^ stream).wrapping_mul(0x9e3779b97f4a7c15) | 1,
+            config: config,
+            held: None,
+        }
+    }
+
+    fn send(&mut self, frames: Vec<Vec<u8>>, sock: &mut ZSock) -> Result<()> {
+        let (drop, duplicate, reorder, delay) = (self.config.drop, self.config.duplicate, self.config.reorder, self.config.delay);
+
+        if self.chance(drop) {
+            return Ok(());
+        }
+
+        if self.chance(delay) {
+            let max = self.config.max_delay;
+            let millis = max.as_secs() * 1000 + (max.subsec_nanos() / 1_000_000) as u64;
+            sleep(Duration::from_millis((millis as f64 * self.random()) as u64));
+        }
+
+        if self.held.is_none() && self.chance(reorder) {
+            self.held = Some(frames);
+            return Ok(());
+        }
+
+        if self.chance(duplicate) {
+            try!(send_frames(&frames, sock));
+        }
+        try!(send_frames(&frames, sock));
+
+        if let Some(held) = self.held.take() {
+            try!(send_frames(&held, sock));
+        }
+
+        Ok(())
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.random() < probability
+    }
+
+    // Xorshift64*, which is plenty for choosing faults
+    fn random(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545f4914f6cdd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A zdaemon endpoint that relays messages between a client and a
+/// server, injecting faults into chunk traffic.
+///
+/// Bind `front` as a DEALER for the client to connect to, and connect
+/// `back` to the server. Only CHUNK and CHUNKS messages are disturbed.
+/// The protocol recovers from losing those, but not from losing the
+/// messages that set up or finish a transfer.
+pub struct ChaosProxy {
+    front: ZSock,
+    back: ZSock,
+    upstream: Chaos,
+    downstream: Chaos,
+}
+
+impl ChaosProxy {
+    pub fn new(front: ZSock, back: ZSock, config: ChaosConfig) -> ChaosProxy {
+        ChaosProxy {
+            front: front,
+            back: back,
+            upstream: Chaos::new(config.clone(), 0),
+            downstream: Chaos::new(config, 1),
+        }
+    }
+}
+
+impl Endpoint for ChaosProxy {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.front, &mut self.back]
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let msg = try!(ZMsg::recv(sock));
+        let mut frames = Vec::new();
+        while let Some(frame) = try!(msg.popbytes()) {
+            frames.push(frame);
+        }
+
+        let is_chunk = frames.first().map_or(false, |f| f == b"CHUNK" || f == b"CHUNKS");
+        let (chaos, dest) = if *sock == self.front {
+            (&mut self.upstream, &mut self.back)
+        } else {
+            (&mut self.downstream, &mut self.front)
+        };
+
+        let result = if is_chunk {
+            chaos.send(frames, dest)
+        } else {
+            send_frames(&frames, dest)
+        };
+
+        if let Err(e) = result {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+fn send_frames(frames: &[Vec<u8>], sock: &mut ZSock) -> Result<()> {
+    let msg = ZMsg::new();
+    for frame in frames {
+        try!(msg.addbytes(frame));
+    }
+    try!(msg.send(sock));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZMsg, ZSys};
+    use super::{Chaos, ChaosConfig};
+
+    fn deliveries(config: ChaosConfig, count: u8) -> Vec<u8> {
+        ZSys::init();
+
+        let (mut sender, mut receiver) = ZSys::create_pipe().unwrap();
+        receiver.set_rcvtimeo(Some(100));
+
+        let mut chaos = Chaos::new(config, 0);
+        for i in 0..count {
+            chaos.send(vec![vec![i]], &mut sender).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Ok(msg) = ZMsg::recv(&mut receiver) {
+            received.push(msg.popbytes().unwrap().unwrap()[0]);
+        }
+        received
+    }
+
+    #[test]
+    fn test_chaos() {
+        assert_eq!(deliveries(ChaosConfig::default(), 3), vec![0, 1, 2]);
+        assert_eq!(deliveries(ChaosConfig { drop: 1.0, ..ChaosConfig::default() }, 3), Vec::<u8>::new());
+        assert_eq!(deliveries(ChaosConfig { duplicate: 1.0, ..ChaosConfig::default() }, 2), vec![0, 0, 1, 1]);
+        // Held messages go after the next one, which is never held
+        assert_eq!(deliveries(ChaosConfig { reorder: 1.0, ..ChaosConfig::default() }, 4), vec![1, 0, 3, 2]);
+    }
+
+    #[test]
+    fn test_random() {
+        let mut a = Chaos::new(ChaosConfig::default(), 0);
+        let mut b = Chaos::new(ChaosConfig::default(), 0);
+        for _ in 0..100 {
+            let r = a.random();
+            assert!(r >= 0.0 && r < 1.0);
+            assert_eq!(r, b.random());
+        }
+    }
+}
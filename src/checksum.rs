@@ -0,0 +1,163 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A cache of local files' CRCs and digests. Opening a file reads all
+//! of it for these, which the server is told before any chunk is
+//! sent, so a large file sent to many servers would otherwise be read
+//! twice per transfer.
+
+use attrs;
+use error::Result;
+use file::{self, File, Options};
+use hash::HashAlgorithm;
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    /// Seconds and nanoseconds since the Unix epoch
+    mtime: (u64, u32),
+    range: Option<(u64, u64)>,
+    crc: u64,
+    /// Caches saved before digests were kept have none
+    #[serde(default)]
+    digest: Option<(HashAlgorithm, Vec<u8>)>,
+}
+
+/// CRCs and digests of files opened through the cache, which are
+/// reused while a file's size and modification time stay the same
+pub struct ChecksumCache {
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl ChecksumCache {
+    pub fn new() -> ChecksumCache {
+        ChecksumCache {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Read a cache written by `save()`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ChecksumCache> {
+        let mut encoded = Vec::new();
+        try!(try!(fs::File::open(path)).read_to_end(&mut encoded));
+        let entries: Vec<Entry> = try!(serde_json::from_slice(&encoded));
+        Ok(ChecksumCache {
+            entries: entries.into_iter().map(|e| (e.path.clone(), e)).collect(),
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let entries: Vec<&Entry> = self.entries.values().collect();
+        let mut fh = try!(fs::File::create(path));
+        try!(fh.write_all(&try!(serde_json::to_vec(&entries))));
+        Ok(())
+    }
+
+    /// Open a file for sending as `File::open()` does, reusing its
+    /// CRC, and digest if it needs one, if it is unchanged since it
+    /// was last opened through the cache
+    pub fn open<P: AsRef<Path>>(&mut self, path: P, options: Option<&[Options]>) -> Result<File> {
+        let path = fs::canonicalize(path.as_ref()).unwrap_or(path.as_ref().to_owned());
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => return File::open(&path, options),
+        };
+        let mtime = match attrs::mtime(&meta) {
+            Some(mtime) => mtime,
+            None => return File::open(&path, options),
+        };
+        let range = options.and_then(|opts| opts.iter().filter_map(|opt| match opt {
+            &Options::Range(offset, len) => Some((offset, len)),
+            _ => None,
+        }).last());
+
+        let algorithm = file::digest_algorithm(options);
+
+        let known = self.entries.get(&path)
+            .and_then(|e| if e.size == meta.len() && e.mtime == mtime && e.range == range { Some((e.crc, e.digest.clone())) } else { None });
+        match (known, algorithm) {
+            (Some((crc, _)), None) => return File::open_with_known_crc(&path, crc, options),
+            (Some((crc, Some((cached, digest)))), Some(algorithm)) if cached == algorithm => {
+                return File::open_with_known_digest(&path, crc, digest, options);
+            },
+            _ => (),
+        }
+
+        let file = try!(File::open(&path, options));
+        self.entries.insert(path.clone(), Entry {
+            path: path,
+            size: meta.len(),
+            mtime: mtime,
+            range: range,
+            crc: file.get_crc(),
+            digest: algorithm.and_then(|a| file.get_digest().map(|d| (a, d.to_vec()))),
+        });
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_open() {
+        let tempdir = TempDir::new("checksum_test_open").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+        let crc = File::checksum(&path).unwrap();
+
+        let mut cache = ChecksumCache::new();
+        assert_eq!(cache.open(&path, None).unwrap().get_crc(), crc);
+
+        // An unchanged file isn't read again
+        let key = fs::canonicalize(&path).unwrap();
+        cache.entries.get_mut(&key).unwrap().crc = 1;
+        assert_eq!(cache.open(&path, None).unwrap().get_crc(), 1);
+        assert!(cache.open(&path, Some(&[Options::Range(1, 2)])).unwrap().get_crc() != 1);
+
+        // Nor is one whose CRC was saved
+        cache.entries.get_mut(&key).unwrap().crc = 2;
+        let saved = tempdir.path().join("cache");
+        cache.save(&saved).unwrap();
+        let mut cache = ChecksumCache::load(&saved).unwrap();
+        assert_eq!(cache.open(&path, Some(&[Options::Range(1, 2)])).unwrap().get_crc(), 2);
+
+        fs::File::create(&path).unwrap().write_all(b"abcd").unwrap();
+        assert_eq!(cache.open(&path, None).unwrap().get_crc(), File::checksum(&path).unwrap());
+    }
+
+    #[test]
+    fn test_open_digest() {
+        let tempdir = TempDir::new("checksum_test_open_digest").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+        let key = fs::canonicalize(&path).unwrap();
+        let sha256 = [Options::Hash(HashAlgorithm::Sha256)];
+
+        // A file opened without a digest is read again for one
+        let mut cache = ChecksumCache::new();
+        cache.open(&path, None).unwrap();
+        let digest = cache.open(&path, Some(&sha256)).unwrap().get_digest().unwrap().to_vec();
+        assert_eq!(cache.entries[&key].digest, Some((HashAlgorithm::Sha256, digest)));
+
+        // But not once it has one, for the same algorithm
+        cache.entries.get_mut(&key).unwrap().digest = Some((HashAlgorithm::Sha256, vec![1]));
+        assert_eq!(cache.open(&path, Some(&sha256)).unwrap().get_digest(), Some(&[1][..]));
+        assert!(cache.open(&path, Some(&[Options::Hash(HashAlgorithm::Blake2b)])).unwrap().get_digest() != Some(&[1][..]));
+    }
+}
@@ -6,71 +6,139 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+use compress::{self, Algorithm};
 use czmq::{ZMsg, ZSock};
-use error::Result;
+use error::{Error, Result};
+use event::hex;
+use handle::Handle;
+use memmap::{Mmap, Protection};
+use protocol;
 use std::cell::RefCell;
-use std::fs;
+use std::cmp::{self, Ordering};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// Chunks at least this big are sent straight from a memory map
+/// rather than being copied into an intermediate buffer first.
+const MMAP_THRESHOLD: u64 = 1024 * 1024; // 1Mb
+/// Maximum number of idle buffers kept for reuse per thread
+const POOL_SIZE: usize = 16;
+
+thread_local!(static POOL: RefCell<BufferPool> = RefCell::new(BufferPool::new(POOL_SIZE)));
+
+/// A pool of byte buffers shared by chunk reads and writes, so that
+/// each chunk transfer doesn't allocate a fresh Vec.
+struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    fn new(capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Vec::new(),
+            capacity: capacity,
+        }
+    }
+
+    /// Take a zeroed buffer of `size` bytes, reusing a pooled one
+    /// if possible.
+    fn take(&mut self, size: usize) -> Vec<u8> {
+        let mut buf = self.buffers.pop().unwrap_or_else(Vec::new);
+        buf.clear();
+        buf.resize(size, 0);
+        buf
+    }
+
+    /// Return a buffer to the pool. Buffers beyond the pool's
+    /// capacity are dropped.
+    fn give(&mut self, buf: Vec<u8>) {
+        if self.buffers.len() < self.capacity {
+            self.buffers.push(buf);
+        }
+    }
+}
 
 pub struct Chunk {
-    fh: Rc<RefCell<fs::File>>,
+    fh: Arc<Mutex<Handle>>,
     index: u64,
 }
 
 impl Chunk {
-    pub fn new(file: Rc<RefCell<fs::File>>, index: u64) -> Chunk {
+    pub fn new(file: Arc<Mutex<Handle>>, index: u64) -> Chunk {
         Chunk {
             fh: file,
             index: index,
         }
     }
 
-    pub fn send(&mut self, sock: &mut ZSock, chunk_size: u64, file_size: u64) -> Result<()> {
-        let start = chunk_size * self.index;
-        let buf_size = if (start + chunk_size) > file_size {
-            file_size - start
-        } else {
-            chunk_size
-        };
-
-        let mut fh = self.fh.borrow_mut();
-        try!(fh.seek(SeekFrom::Start(start)));
-
-        let mut buf = Vec::with_capacity(buf_size as usize);
-        unsafe { buf.set_len(buf_size as usize); }
-        try!(fh.read_exact(&mut buf));
-
+    /// Send this chunk, its data compressed with `compression` and
+    /// sent as a hole if it's all zeros and `sparse`, if the peer
+    /// agreed to them
+    pub fn send(&mut self, sock: &mut ZSock, layout: &Layout, binary: bool, compression: Option<Algorithm>, sparse: bool) -> Result<()> {
         let msg = ZMsg::new();
         try!(msg.addstr("CHUNK"));
-        try!(msg.addstr(&self.index.to_string()));
-        try!(msg.addbytes(&buf));
+        try!(protocol::add_u64(&msg, self.index, binary));
+        try!(self.add_data(&msg, layout, compression, sparse));
         try!(msg.send(sock));
         Ok(())
     }
 
-    pub fn recv(&mut self, router_id: &[u8], data: Vec<u8>, chunk_size: u64) -> Result<()> {
-        let sock = try!(ZSock::new_push(">inproc://zfilexfer_sink"));
-        sock.set_sndtimeo(Some(1000));
+    /// Append this chunk's index and data to a batched CHUNKS message
+    pub fn add_to(&mut self, msg: &ZMsg, layout: &Layout, binary: bool, compression: Option<Algorithm>, sparse: bool) -> Result<()> {
+        try!(protocol::add_u64(msg, self.index, binary));
+        try!(self.add_data(msg, layout, compression, sparse));
+        Ok(())
+    }
+
+    fn add_data(&mut self, msg: &ZMsg, layout: &Layout, compression: Option<Algorithm>, sparse: bool) -> Result<()> {
+        let start = layout.offset(self.index);
+        let buf_size = layout.len(self.index);
+
+        // Only a file on disk can be mapped
+        if buf_size >= MMAP_THRESHOLD && self.fh.lock().unwrap().file().is_some() {
+            let fh = self.fh.lock().unwrap();
+            let map = try!(Mmap::open_with_offset(fh.file().unwrap(), Protection::Read, start as usize, buf_size as usize));
+            // This is only unsafe if the file is modified while
+            // mapped, which would corrupt the chunk either way.
+            try!(add_frame(msg, unsafe { map.as_slice() }, compression, sparse));
+        } else {
+            let mut fh = self.fh.lock().unwrap();
+            try!(fh.seek(SeekFrom::Start(start)));
+
+            let mut buf = POOL.with(|p| p.borrow_mut().take(buf_size as usize));
+            let result = fh.read_exact(&mut buf);
+            if result.is_ok() {
+                try!(add_frame(msg, &buf, compression, sparse));
+            }
+            POOL.with(|p| p.borrow_mut().give(buf));
+            try!(result);
+        }
 
-        self.do_recv(router_id, data, chunk_size, sock)
+        Ok(())
     }
 
-    pub fn do_recv(&mut self, router_id: &[u8], data: Vec<u8>, chunk_size: u64, mut sock: ZSock) -> Result<()> {
+    /// Write received data to the file and report the outcome to the
+    /// given sink. A hole is skipped, as the file starts out sparse.
+    pub fn recv(&mut self, router_id: &[u8], data: Vec<u8>, layout: &Layout, sink: &mut ZSock) -> Result<()> {
         let result = || -> Result<()> {
-            let mut fh = self.fh.borrow_mut();
-            try!(fh.seek(SeekFrom::Start((self.index * chunk_size) as u64)));
+            if data.is_empty() {
+                return Ok(());
+            }
+            let mut fh = self.fh.lock().unwrap();
+            try!(fh.seek(SeekFrom::Start(layout.offset(self.index))));
             try!(fh.write_all(&data));
             Ok(())
         }();
 
-        let msg = ZMsg::new();
-        try!(msg.addbytes(router_id));
-        try!(msg.addstr(&self.index.to_string()));
-        try!(msg.addstr(if result.is_ok() { "1" } else { "0" }));
-        try!(msg.send(&mut sock));
+        if let Err(ref e) = result {
+            warn!("chunk write failed router_id={} index={} offset={} error={:?}", hex(router_id), self.index, layout.offset(self.index), e.to_string());
+        }
 
-        Ok(())
+        // The received frame is done with, so keep it for the next chunk
+        POOL.with(|p| p.borrow_mut().give(data));
+
+        send_sink(sink, router_id, self.index, result.is_ok())
     }
 
     pub fn get_index(&self) -> u64 {
@@ -78,15 +146,407 @@ impl Chunk {
     }
 }
 
+/// Maps chunk indexes to byte ranges of a file. The chunk size can be
+/// renegotiated part way through a transfer, so the layout is a list
+/// of segments, each with its own chunk size.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    size: u64,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Segment {
+    first: u64,
+    offset: u64,
+    chunk_size: u64,
+}
+
+impl Layout {
+    pub fn new(size: u64, chunk_size: u64) -> Layout {
+        Self::window(0, size, chunk_size)
+    }
+
+    /// Layout of the `size` bytes starting at `offset`, for sending
+    /// part of a file
+    pub fn window(offset: u64, size: u64, chunk_size: u64) -> Layout {
+        Layout {
+            size: offset + size,
+            segments: vec![Segment {
+                first: 0,
+                offset: offset,
+                chunk_size: chunk_size,
+            }],
+        }
+    }
+
+    fn segment(&self, index: u64) -> &Segment {
+        self.segments.iter().rev().find(|s| s.first <= index).unwrap()
+    }
+
+    /// Byte offset of a chunk within the file
+    pub fn offset(&self, index: u64) -> u64 {
+        let segment = self.segment(index);
+        segment.offset + (index - segment.first) * segment.chunk_size
+    }
+
+    /// Length of a chunk, which is short if it's the last one
+    pub fn len(&self, index: u64) -> u64 {
+        let offset = self.offset(index);
+        cmp::min(self.segment(index).chunk_size, self.size.saturating_sub(offset))
+    }
+
+    /// Total number of chunks in the file
+    pub fn count(&self) -> u64 {
+        let last = self.segments.last().unwrap();
+        last.first + chunk_count(self.size - last.offset, last.chunk_size)
+    }
+
+    /// Chunk size used by the final segment
+    pub fn chunk_size(&self) -> u64 {
+        self.segments.last().unwrap().chunk_size
+    }
+
+    /// Change the chunk size for chunks from `first` onwards
+    pub fn resize(&mut self, first: u64, chunk_size: u64) -> Result<()> {
+        if chunk_size == 0 || first < self.segments.last().unwrap().first || first > self.count() {
+            return Err(Error::ChunkSize);
+        }
+
+        let offset = self.offset(first);
+        if self.segments.last().unwrap().first == first {
+            self.segments.pop();
+        }
+
+        self.segments.push(Segment {
+            first: first,
+            offset: offset,
+            chunk_size: chunk_size,
+        });
+
+        Ok(())
+    }
+}
+
+/// Lazily yields chunk handles for a range of indexes, so a sender
+/// never holds more chunks than it is currently sending.
+pub struct Chunks {
+    fh: Arc<Mutex<Handle>>,
+    next: u64,
+    end: u64,
+}
+
+impl Chunks {
+    /// Iterate over chunks `first..end`
+    pub fn new(file: Arc<Mutex<Handle>>, first: u64, end: u64) -> Chunks {
+        Chunks {
+            fh: file,
+            next: first,
+            end: end,
+        }
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Chunk> {
+        if self.next < self.end {
+            self.next += 1;
+            Some(Chunk::new(self.fh.clone(), self.next - 1))
+        } else {
+            None
+        }
+    }
+}
+
+/// A set of chunk indexes, stored as sorted, non-overlapping
+/// half-open ranges. Memory scales with how fragmented the set is
+/// rather than with the number of chunks in a file.
+pub struct ChunkSet {
+    ranges: Vec<(u64, u64)>,
+    len: u64,
+}
+
+impl ChunkSet {
+    /// Create a set containing indexes `0..count`
+    pub fn new(count: u64) -> ChunkSet {
+        ChunkSet {
+            ranges: if count > 0 { vec![(0, count)] } else { Vec::new() },
+            len: count,
+        }
+    }
+
+    /// Rebuild a set from its half-open ranges, e.g. as saved in a
+    /// staging manifest. Returns None unless the ranges are sorted,
+    /// non-empty, don't touch and end by `count`.
+    pub fn from_ranges(ranges: &[(u64, u64)], count: u64) -> Option<ChunkSet> {
+        let mut prev_end = None;
+        for &(start, end) in ranges {
+            if start >= end || end > count || prev_end.map_or(false, |prev| start <= prev) {
+                return None;
+            }
+            prev_end = Some(end);
+        }
+
+        Some(ChunkSet {
+            ranges: ranges.to_vec(),
+            len: ranges.iter().fold(0, |acc, &(start, end)| acc + end - start),
+        })
+    }
+
+    fn find(&self, index: u64) -> Option<usize> {
+        self.ranges.binary_search_by(|&(start, end)| {
+            if index < start {
+                Ordering::Greater
+            } else if index >= end {
+                Ordering::Less
+            } else {
+                Ordering::Equal
+            }
+        }).ok()
+    }
+
+    /// Number of chunks in the set
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn contains(&self, index: u64) -> bool {
+        self.find(index).is_some()
+    }
+
+    /// Remove an index from the set, returning whether it was present
+    pub fn remove(&mut self, index: u64) -> bool {
+        let pos = match self.find(index) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        let (start, end) = self.ranges[pos];
+        if start == index && end == index + 1 {
+            self.ranges.remove(pos);
+        } else if start == index {
+            self.ranges[pos].0 = index + 1;
+        } else if end == index + 1 {
+            self.ranges[pos].1 = index;
+        } else {
+            self.ranges[pos].1 = index;
+            self.ranges.insert(pos + 1, (index + 1, end));
+        }
+
+        self.len -= 1;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Half-open ranges of indexes in the set
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+
+    /// Replace every index from `first` onwards with `first..end`
+    pub fn set_tail(&mut self, first: u64, end: u64) {
+        self.ranges.retain(|&(start, _)| start < first);
+
+        let merge = match self.ranges.last_mut() {
+            Some(last) => {
+                if last.1 > first {
+                    last.1 = first;
+                }
+                last.1 == first
+            },
+            None => false,
+        };
+
+        if first < end {
+            if merge {
+                self.ranges.last_mut().unwrap().1 = end;
+            } else {
+                self.ranges.push((first, end));
+            }
+        }
+
+        self.len = self.ranges.iter().fold(0, |acc, &(start, end)| acc + end - start);
+    }
+}
+
+/// Append chunk data to a message, compressing it first if asked. If
+/// `sparse`, data that's all zeros is sent as an empty frame instead.
+pub fn add_frame(msg: &ZMsg, data: &[u8], compression: Option<Algorithm>, sparse: bool) -> Result<()> {
+    if sparse && is_hole(data) {
+        try!(msg.addbytes(&[]));
+        return Ok(());
+    }
+
+    match compression {
+        Some(algorithm) => try!(msg.addbytes(&try!(compress::compress(algorithm, data)))),
+        None => try!(msg.addbytes(data)),
+    }
+    Ok(())
+}
+
+/// Whether chunk data can be sent as a hole
+pub fn is_hole(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == 0)
+}
+
+/// Report the outcome of a chunk write to the server's sink
+pub fn send_sink(sock: &mut ZSock, router_id: &[u8], index: u64, success: bool) -> Result<()> {
+    let msg = ZMsg::new();
+    try!(msg.addbytes(router_id));
+    try!(protocol::add_u64(&msg, index, true));
+    try!(msg.addbytes(if success { &[1] } else { &[0] }));
+    try!(msg.send(sock));
+    Ok(())
+}
+
+/// Number of chunks needed to hold `size` bytes
+pub fn chunk_count(size: u64, chunk_size: u64) -> u64 {
+    (size + chunk_size - 1) / chunk_size
+}
+
 #[cfg(test)]
 mod tests {
+    use compress::{self, Algorithm};
     use czmq::{ZMsg, ZSys};
-    use std::cell::RefCell;
+    use handle::Handle;
+    use protocol;
     use std::fs::OpenOptions;
-    use std::io::{Read, Seek, SeekFrom, Write};
-    use std::rc::Rc;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
     use super::*;
+    use super::{BufferPool, MMAP_THRESHOLD};
     use tempdir::TempDir;
+    use tempfile::tempfile;
+
+    #[test]
+    fn test_chunk_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Chunk>();
+        assert_send::<Chunks>();
+    }
+
+    #[test]
+    fn test_chunks() {
+        let fh = Arc::new(Mutex::new(Handle::File(tempfile().unwrap())));
+        let indexes: Vec<u64> = Chunks::new(fh.clone(), 2, 5).map(|c| c.get_index()).collect();
+        assert_eq!(indexes, vec![2, 3, 4]);
+        assert_eq!(Chunks::new(fh, 3, 3).count(), 0);
+    }
+
+    #[test]
+    fn test_chunk_set() {
+        let mut set = ChunkSet::new(5);
+        assert_eq!(set.len, 5);
+        assert!(set.contains(0));
+        assert!(set.contains(4));
+        assert!(!set.contains(5));
+
+        assert!(set.remove(2));
+        assert!(!set.remove(2));
+        assert!(!set.contains(2));
+        assert!(set.contains(1));
+        assert!(set.contains(3));
+        assert_eq!(set.ranges, vec![(0, 2), (3, 5)]);
+
+        assert!(set.remove(0));
+        assert!(set.remove(4));
+        assert_eq!(set.ranges, vec![(1, 2), (3, 4)]);
+
+        assert!(set.remove(1));
+        assert!(set.remove(3));
+        assert!(set.is_empty());
+        assert!(ChunkSet::new(0).is_empty());
+    }
+
+    #[test]
+    fn test_chunk_set_from_ranges() {
+        let set = ChunkSet::from_ranges(&[(0, 2), (4, 5)], 5).unwrap();
+        assert_eq!(set.len(), 3);
+        assert!(set.contains(1));
+        assert!(!set.contains(2));
+        assert!(ChunkSet::from_ranges(&[], 5).unwrap().is_empty());
+
+        assert!(ChunkSet::from_ranges(&[(0, 6)], 5).is_none());
+        assert!(ChunkSet::from_ranges(&[(2, 2)], 5).is_none());
+        assert!(ChunkSet::from_ranges(&[(2, 4), (0, 1)], 5).is_none());
+        assert!(ChunkSet::from_ranges(&[(0, 2), (2, 4)], 5).is_none());
+    }
+
+    #[test]
+    fn test_chunk_set_tail() {
+        let mut set = ChunkSet::new(6);
+        set.remove(1);
+        set.set_tail(4, 10);
+        assert_eq!(set.ranges(), &[(0, 1), (2, 10)]);
+        assert_eq!(set.len, 9);
+
+        set.set_tail(2, 3);
+        assert_eq!(set.ranges(), &[(0, 1), (2, 3)]);
+        set.set_tail(1, 1);
+        assert_eq!(set.ranges(), &[(0, 1)]);
+        assert_eq!(set.len, 1);
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut layout = Layout::new(10, 2);
+        assert_eq!(layout.count(), 5);
+        assert_eq!(layout.offset(3), 6);
+
+        layout.resize(2, 3).unwrap();
+        assert_eq!(layout.chunk_size(), 3);
+        assert_eq!(layout.count(), 4);
+        assert_eq!(layout.offset(1), 2);
+        assert_eq!(layout.offset(2), 4);
+        assert_eq!(layout.offset(3), 7);
+        assert_eq!(layout.len(3), 3);
+
+        layout.resize(3, 2).unwrap();
+        assert_eq!(layout.count(), 5);
+        assert_eq!(layout.offset(4), 9);
+        assert_eq!(layout.len(4), 1);
+
+        assert!(layout.resize(2, 4).is_err());
+        assert!(layout.resize(6, 4).is_err());
+        assert!(layout.resize(4, 0).is_err());
+    }
+
+    #[test]
+    fn test_layout_window() {
+        let layout = Layout::window(100, 5, 2);
+        assert_eq!(layout.count(), 3);
+        assert_eq!(layout.offset(0), 100);
+        assert_eq!(layout.offset(2), 104);
+        assert_eq!(layout.len(2), 1);
+    }
+
+    #[test]
+    fn test_chunk_count() {
+        assert_eq!(chunk_count(0, 2), 0);
+        assert_eq!(chunk_count(3, 2), 2);
+        assert_eq!(chunk_count(4, 2), 2);
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut pool = BufferPool::new(1);
+
+        let buf = pool.take(4);
+        assert_eq!(buf, vec![0; 4]);
+        let ptr = buf.as_ptr();
+        pool.give(buf);
+        pool.give(vec![1]);
+        assert_eq!(pool.buffers.len(), 1);
+
+        let buf = pool.take(2);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf, vec![0; 2]);
+    }
 
     #[test]
     fn test_recv() {
@@ -95,21 +555,22 @@ mod tests {
         let tempdir = TempDir::new("chunk_test_create_recv").unwrap();
         let path = format!("{}/test", tempdir.path().to_str().unwrap());
 
-        let fh = Rc::new(RefCell::new(OpenOptions::new().create(true).read(true).write(true).open(&path).unwrap()));
-        fh.borrow().set_len(6).unwrap();
+        let fh = OpenOptions::new().create(true).read(true).write(true).open(&path).unwrap();
+        fh.set_len(6).unwrap();
+        let fh = Arc::new(Mutex::new(Handle::File(fh)));
 
-        let (thread, mut sink) = ZSys::create_pipe().unwrap();
+        let (mut thread, mut sink) = ZSys::create_pipe().unwrap();
         let mut chunk = Chunk::new(fh.clone(), 1);
-        chunk.do_recv("abc".as_bytes(), "abc".as_bytes().to_vec(), 3, thread).unwrap();
+        chunk.recv("abc".as_bytes(), "abc".as_bytes().to_vec(), &Layout::new(6, 3), &mut thread).unwrap();
 
         let msg = ZMsg::recv(&mut sink).unwrap();
-        let _ = msg.popstr();
-        let _ = msg.popstr();
-        assert_eq!(msg.popstr().unwrap().unwrap(), "1");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "abc");
+        assert_eq!(protocol::pop_u64(&msg, true), Some(1));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![1]);
 
         let mut content = Vec::new();
-        fh.borrow_mut().seek(SeekFrom::Start(0)).unwrap();
-        fh.borrow_mut().read_to_end(&mut content).unwrap();
+        fh.lock().unwrap().seek(SeekFrom::Start(0)).unwrap();
+        fh.lock().unwrap().read_to_end(&mut content).unwrap();
         assert_eq!(content, vec![0, 0, 0, 97, 98, 99]);
     }
 
@@ -125,12 +586,93 @@ mod tests {
 
         let (mut client, mut server) = ZSys::create_pipe().unwrap();
 
-        let mut chunk = Chunk::new(Rc::new(RefCell::new(fh)), 0);
-        chunk.send(&mut client, 2, 3).unwrap();
+        let mut chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(fh))), 0);
+        chunk.send(&mut client, &Layout::new(3, 2), false, None, false).unwrap();
 
         let msg = ZMsg::recv(&mut server).unwrap();
         assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
         assert_eq!(&msg.popstr().unwrap().unwrap(), "0");
         assert_eq!(&msg.popstr().unwrap().unwrap(), "ab");
+
+        chunk.send(&mut client, &Layout::new(3, 2), true, None, false).unwrap();
+
+        let msg = ZMsg::recv(&mut server).unwrap();
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+        assert_eq!(protocol::pop_u64(&msg, true), Some(0));
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "ab");
+    }
+
+    #[test]
+    fn test_send_mmap() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("chunk_test_send_mmap").unwrap();
+        let path = format!("{}/test", tempdir.path().to_str().unwrap());
+
+        let mut fh = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let content: Vec<u8> = (0..MMAP_THRESHOLD * 2 + 1).map(|i| (i % 251) as u8).collect();
+        fh.write_all(&content).unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+
+        let mut chunk = Chunk::new(Arc::new(Mutex::new(Handle::File(fh))), 1);
+        chunk.send(&mut client, &Layout::new(content.len() as u64, MMAP_THRESHOLD), false, None, false).unwrap();
+
+        let msg = ZMsg::recv(&mut server).unwrap();
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "1");
+        assert_eq!(&msg.popbytes().unwrap().unwrap()[..], &content[MMAP_THRESHOLD as usize..MMAP_THRESHOLD as usize * 2]);
+
+        // A reader can't be mapped, so is read as usual
+        let reader = Handle::Reader(Box::new(Cursor::new(content.clone())));
+        let mut chunk = Chunk::new(Arc::new(Mutex::new(reader)), 1);
+        chunk.send(&mut client, &Layout::new(content.len() as u64, MMAP_THRESHOLD), false, None, false).unwrap();
+
+        let msg = ZMsg::recv(&mut server).unwrap();
+        msg.popstr().unwrap().unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(&msg.popbytes().unwrap().unwrap()[..], &content[MMAP_THRESHOLD as usize..MMAP_THRESHOLD as usize * 2]);
+    }
+
+    #[test]
+    fn test_add_to() {
+        let tempdir = TempDir::new("chunk_test_add_to").unwrap();
+        let path = format!("{}/test", tempdir.path().to_str().unwrap());
+
+        let mut fh = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        fh.write_all("abc".as_bytes()).unwrap();
+        let fh = Arc::new(Mutex::new(Handle::File(fh)));
+
+        let msg = ZMsg::new();
+        Chunk::new(fh.clone(), 0).add_to(&msg, &Layout::new(3, 2), false, None, false).unwrap();
+        Chunk::new(fh.clone(), 1).add_to(&msg, &Layout::new(3, 2), false, None, false).unwrap();
+
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "0");
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "ab");
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "1");
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "c");
+
+        let msg = ZMsg::new();
+        Chunk::new(fh.clone(), 0).add_to(&msg, &Layout::new(3, 2), false, Some(Algorithm::Zlib), false).unwrap();
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "0");
+        assert_eq!(compress::decompress(Algorithm::Zlib, &msg.popbytes().unwrap().unwrap(), 2).unwrap(), b"ab");
+    }
+
+    #[test]
+    fn test_add_to_sparse() {
+        let fh = Arc::new(Mutex::new(Handle::Reader(Box::new(Cursor::new(vec![0, 0, 0, 99])))));
+        let layout = Layout::new(4, 2);
+
+        let msg = ZMsg::new();
+        Chunk::new(fh.clone(), 0).add_to(&msg, &layout, false, Some(Algorithm::Zlib), true).unwrap();
+        Chunk::new(fh.clone(), 1).add_to(&msg, &layout, false, None, true).unwrap();
+        Chunk::new(fh.clone(), 0).add_to(&msg, &layout, false, None, false).unwrap();
+
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "0");
+        assert_eq!(msg.popbytes().unwrap().unwrap(), Vec::<u8>::new());
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "1");
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![0, 99]);
+        assert_eq!(&msg.popstr().unwrap().unwrap(), "0");
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![0, 0]);
     }
 }
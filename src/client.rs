@@ -0,0 +1,284 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use czmq::{SocketType, ZCert, ZSock};
+use error::{Error, Result};
+use file::{File, Options as FileOptions, TransferStats};
+use libc;
+use server::{Description, Quota};
+use std::io;
+use std::path::Path;
+
+/// Milliseconds to wait for a pinned server to answer
+const VERIFY_TIMEOUT: i32 = 5000;
+const Z85_CHARS: &'static str = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ.-:+=^!/*?&<>()[]{}@%$#";
+
+#[derive(Clone)]
+pub enum Options {
+    /// Authenticate to a pinned server with the certificate at this
+    /// path. Without it, a temporary certificate is generated.
+    Cert(String),
+    /// Spread each upload's chunks across this many connections to
+    /// the server, which helps on links with a long round trip. A
+    /// server without striped chunks is sent them all on one.
+    Connections(u32),
+    /// Pin the server's CURVE public key (40 character Z85 string)
+    ServerKey(String),
+    /// Pin the public key from the server certificate at this path
+    ServerKeyFile(String),
+    /// Connect via a SOCKS5 proxy, e.g. "bastion.example.com:1080"
+    SocksProxy(String),
+    /// Milliseconds a `Client` waits for each reply before giving up
+    /// on the operation. Without it, a client waits forever.
+    Timeout(u32),
+}
+
+/// A connection to a zfilexfer server that owns its socket.
+///
+/// When an operation times out, the socket is replaced with a new
+/// connection, so a late reply can't be mistaken for the answer to
+/// the next operation.
+pub struct Client {
+    endpoint: String,
+    options: Vec<Options>,
+    timeout: Option<u32>,
+    connections: u32,
+    sock: ZSock,
+}
+
+impl Client {
+    pub fn connect(endpoint: &str, options: Option<&[Options]>) -> Result<Client> {
+        let options = options.map_or(Vec::new(), |o| o.to_vec());
+        let timeout = options.iter().filter_map(|o| match *o {
+            Options::Timeout(ms) => Some(ms),
+            _ => None,
+        }).last();
+        let connections = options.iter().filter_map(|o| match *o {
+            Options::Connections(n) => Some(n),
+            _ => None,
+        }).last().unwrap_or(1);
+
+        let mut client = Client {
+            endpoint: endpoint.to_string(),
+            sock: try!(connect(endpoint, Some(&options))),
+            options: options,
+            timeout: timeout,
+            connections: connections,
+        };
+        client.set_timeout();
+        Ok(client)
+    }
+
+    /// Replace the socket with a new connection to the same server.
+    /// Replies still on their way to the old socket are lost.
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.sock = try!(connect(&self.endpoint, Some(&self.options)));
+        self.set_timeout();
+        Ok(())
+    }
+
+    /// The underlying socket, for requests without a method here
+    pub fn sock(&mut self) -> &mut ZSock {
+        &mut self.sock
+    }
+
+    /// Upload the file at `local_path` to `remote_path`
+    pub fn send_file<P, Q>(&mut self, local_path: P, remote_path: Q, options: Option<&[FileOptions]>) -> Result<TransferStats>
+        where P: AsRef<Path>, Q: AsRef<Path>
+    {
+        self.send_file_with_progress(local_path, remote_path, options, |_, _, _| ())
+    }
+
+    /// As `send_file()`, calling `progress` as
+    /// `File::send_with_progress()` does
+    pub fn send_file_with_progress<P, Q, F>(&mut self, local_path: P, remote_path: Q, options: Option<&[FileOptions]>, progress: F) -> Result<TransferStats>
+        where P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(u64, u64, u64)
+    {
+        let mut file = try!(File::open(local_path, options));
+        for _ in 1..self.connections {
+            let stripe = try!(connect(&self.endpoint, Some(&self.options)));
+            apply_timeout(&stripe, self.timeout);
+            file.add_stripe(stripe);
+        }
+        try!(self.run(|sock| file.send_with_progress(sock, remote_path, progress)));
+        Ok(file.get_stats())
+    }
+
+    /// Download `remote_path` from the server to `local_path`
+    pub fn fetch_file<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, remote_path: P, local_path: Q) -> Result<()> {
+        self.run(|sock| File::fetch(sock, remote_path, local_path))
+    }
+
+    pub fn describe(&mut self) -> Result<Description> {
+        self.run(|sock| Description::request(sock))
+    }
+
+    pub fn quota(&mut self) -> Result<Quota> {
+        self.run(|sock| Quota::request(sock))
+    }
+
+    // Run an operation on the socket. With a timeout set, a socket
+    // error from running out of time means the server stopped
+    // replying. An operation may time out by its own options too, and
+    // may change the socket's timeouts to do it.
+    fn run<T, F: FnOnce(&mut ZSock) -> Result<T>>(&mut self, op: F) -> Result<T> {
+        match op(&mut self.sock) {
+            Err(Error::Czmq(_)) if self.timeout.is_some() && timed_out() => {
+                try!(self.reconnect());
+                Err(Error::Timeout)
+            },
+            Err(Error::Timeout) => {
+                try!(self.reconnect());
+                Err(Error::Timeout)
+            },
+            result => {
+                self.set_timeout();
+                result
+            },
+        }
+    }
+
+    fn set_timeout(&mut self) {
+        apply_timeout(&self.sock, self.timeout);
+    }
+}
+
+fn apply_timeout(sock: &ZSock, timeout: Option<u32>) {
+    let timeout = timeout.map(|ms| ms as i32);
+    sock.set_sndtimeo(timeout);
+    sock.set_rcvtimeo(timeout);
+}
+
+// ZMQ fails a send or receive that runs out of time with EAGAIN, and
+// leaves it in errno
+fn timed_out() -> bool {
+    io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN)
+}
+
+/// Create a DEALER socket connected to a zfilexfer server.
+///
+/// If the server's key is pinned, the connection is encrypted with
+/// CURVE and checked before it is returned. An imposter can't
+/// complete the handshake, so connecting fails rather than leaving
+/// uploads to hang.
+pub fn connect(endpoint: &str, options: Option<&[Options]>) -> Result<ZSock> {
+    let mut sock = ZSock::new(SocketType::DEALER);
+    let mut cert_path = None;
+    let mut server_key = None;
+
+    if let Some(options) = options {
+        for opt in options {
+            match opt {
+                &Options::Cert(ref path) => cert_path = Some(path),
+                // Only used by `Client`
+                &Options::Connections(_) => (),
+                &Options::ServerKey(ref key) => server_key = Some(key.clone()),
+                &Options::ServerKeyFile(ref path) => {
+                    let cert = try!(ZCert::load(path));
+                    server_key = Some(cert.public_txt().to_string());
+                },
+                &Options::SocksProxy(ref proxy) => {
+                    // ZMQ only proxies TCP connections
+                    if !endpoint.starts_with("tcp://") {
+                        return Err(Error::ProxyTransport);
+                    }
+
+                    sock.set_socks_proxy(Some(proxy));
+                },
+                // Only used by `Client`
+                &Options::Timeout(_) => (),
+            }
+        }
+    }
+
+    if let Some(ref key) = server_key {
+        if !is_z85_key(key) {
+            return Err(Error::ServerKey);
+        }
+
+        let cert = match cert_path {
+            Some(path) => try!(ZCert::load(path)),
+            None => try!(ZCert::new()),
+        };
+        cert.apply(&mut sock);
+        sock.set_curve_serverkey(key);
+    }
+
+    try!(sock.connect(endpoint));
+
+    if server_key.is_some() {
+        try!(verify(&mut sock));
+    }
+
+    Ok(sock)
+}
+
+// Make a round trip to the server, which only succeeds once the
+// CURVE handshake has proven that it holds the pinned key. The
+// socket gets back whatever receive timeout it had afterwards.
+fn verify(sock: &mut ZSock) -> Result<()> {
+    let rcvtimeo = sock.rcvtimeo();
+    sock.set_rcvtimeo(Some(VERIFY_TIMEOUT));
+    let result = Description::request(sock);
+    sock.set_rcvtimeo(rcvtimeo);
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(Error::Czmq(_)) => Err(Error::UnverifiedServer),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_z85_key(key: &str) -> bool {
+    key.len() == 40 && key.chars().all(|c| Z85_CHARS.contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZMsg, ZSock, ZSys};
+    use error::Error;
+    use super::*;
+
+    #[test]
+    fn test_connect() {
+        ZSys::init();
+
+        assert!(connect("inproc://client_test_connect", None).is_ok());
+        assert!(connect("inproc://client_test_connect", Some(&[Options::SocksProxy("127.0.0.1:1080".into())])).is_err());
+        assert!(connect("tcp://127.0.0.1:7357", Some(&[Options::SocksProxy("127.0.0.1:1080".into())])).is_ok());
+        assert!(connect("inproc://client_test_connect", Some(&[Options::ServerKey("moo".into())])).is_err());
+    }
+
+    #[test]
+    fn test_client_timeout() {
+        ZSys::init();
+
+        let mut router = ZSock::new_router("inproc://client_test_client_timeout").unwrap();
+        router.set_rcvtimeo(Some(500));
+
+        let mut client = Client::connect("inproc://client_test_client_timeout", Some(&[Options::Timeout(50)])).unwrap();
+        for _ in 0..2 {
+            match client.describe() {
+                Err(Error::Timeout) => (),
+                _ => panic!("Expected Timeout error"),
+            }
+        }
+
+        // Each attempt was made on a new connection
+        let first = ZMsg::recv(&mut router).unwrap().popbytes().unwrap().unwrap();
+        let second = ZMsg::recv(&mut router).unwrap().popbytes().unwrap().unwrap();
+        assert!(first != second);
+    }
+
+    #[test]
+    fn test_is_z85_key() {
+        assert!(super::is_z85_key("rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7"));
+        assert!(!super::is_z85_key("rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf"));
+        assert!(!super::is_z85_key("rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf~"));
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of the current time for chunk timeouts. Tests can swap
+/// in a MockClock to expire chunks without waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it is advanced. Clones share the
+/// same time.
+#[derive(Clone)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::new(0, 0))),
+        }
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        shared.advance(Duration::new(60, 0));
+        assert_eq!(clock.now() - start, Duration::new(60, 0));
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use bincode;
+use error::{Error, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+/// First byte of every binary encoded field. JSON fields always
+/// start with '{', so the two can be told apart on the wire.
+const BINARY_MAGIC: u8 = 0;
+
+/// Encodes structured fields (options, descriptions etc.) for the
+/// wire.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(try!(serde_json::to_vec(value)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(try!(serde_json::from_slice(data)))
+    }
+}
+
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut data = vec![BINARY_MAGIC];
+        // Big endian, as bincode's rustc-serialize encoder was, so
+        // older peers can still read it
+        data.extend(try!(bincode::config().big_endian().serialize(value)));
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        if data.first() != Some(&BINARY_MAGIC) {
+            return Err(Error::InvalidRequest);
+        }
+
+        // Data comes from the network, so never read more than was
+        // sent, whatever the length prefixes claim
+        let data = &data[1..];
+        Ok(try!(bincode::config().big_endian().limit(data.len() as u64).deserialize_from(data)))
+    }
+}
+
+impl BinaryCodec {
+    /// Decode a struct from a peer that may not know about all of
+    /// its fields, along with how many were missing. New fields go
+    /// last and are all `Option`s, so up to `max_missing` are read as
+    /// None from the end. Each None takes a single zero byte, and
+    /// every one added must be read as a field.
+    pub fn decode_partial<T: DeserializeOwned>(&self, data: &[u8], max_missing: usize) -> Result<(T, usize)> {
+        if data.first() != Some(&BINARY_MAGIC) {
+            return Err(Error::InvalidRequest);
+        }
+
+        let mut padded = data[1..].to_vec();
+        for missing in 0..max_missing + 1 {
+            let limit = padded.len() as u64;
+            let mut reader = &padded[..];
+            if let Ok(value) = bincode::config().big_endian().limit(limit).deserialize_from(&mut reader) {
+                // Fields from a newer peer are left unread
+                if missing == 0 || reader.is_empty() {
+                    return Ok((value, missing));
+                }
+            }
+            padded.push(0);
+        }
+        Err(Error::InvalidRequest)
+    }
+}
+
+/// The codecs that can be negotiated between peers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireCodec {
+    Json,
+    Binary,
+}
+
+impl WireCodec {
+    /// Codecs supported by this version, in order of preference
+    pub fn supported() -> Vec<WireCodec> {
+        vec![WireCodec::Binary, WireCodec::Json]
+    }
+
+    /// Detect the codec used to encode a field
+    pub fn detect(data: &[u8]) -> WireCodec {
+        if data.first() == Some(&BINARY_MAGIC) {
+            WireCodec::Binary
+        } else {
+            WireCodec::Json
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WireCodec> {
+        match name {
+            "json" => Some(WireCodec::Json),
+            "binary" => Some(WireCodec::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            WireCodec::Json => "json",
+            WireCodec::Binary => "binary",
+        }
+    }
+}
+
+impl Codec for WireCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match *self {
+            WireCodec::Json => JsonCodec.encode(value),
+            WireCodec::Binary => BinaryCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match *self {
+            WireCodec::Json => JsonCodec.decode(data),
+            WireCodec::Binary => BinaryCodec.decode(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Test {
+        name: String,
+        size: Option<u64>,
+    }
+
+    // `Test` with a field added, as a newer peer would have it
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct TestV2 {
+        name: String,
+        size: Option<u64>,
+        mode: Option<u32>,
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let value = Test { name: "moo".into(), size: Some(123) };
+
+        for codec in WireCodec::supported() {
+            let encoded = codec.encode(&value).unwrap();
+            assert_eq!(WireCodec::detect(&encoded), codec);
+            assert_eq!(codec.decode::<Test>(&encoded).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_decode_wrong_codec() {
+        let encoded = WireCodec::Json.encode(&Test { name: "moo".into(), size: None }).unwrap();
+        assert!(WireCodec::Binary.decode::<Test>(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let old = BinaryCodec.encode(&Test { name: "moo".into(), size: Some(1) }).unwrap();
+        assert!(BinaryCodec.decode::<TestV2>(&old).is_err());
+        assert_eq!(BinaryCodec.decode_partial::<TestV2>(&old, 1).unwrap(),
+                   (TestV2 { name: "moo".into(), size: Some(1), mode: None }, 1));
+
+        // Only as many fields as allowed may be missing
+        let older = BinaryCodec.encode(&"moo").unwrap();
+        assert!(BinaryCodec.decode_partial::<TestV2>(&older, 1).is_err());
+        assert_eq!(BinaryCodec.decode_partial::<TestV2>(&older, 2).unwrap().1, 2);
+
+        // Nor are zeros added to make up a field cut short
+        let mut cut = old.clone();
+        cut.pop();
+        assert!(BinaryCodec.decode_partial::<TestV2>(&cut, 1).is_err());
+
+        // Fields from a newer peer are left unread
+        let new = BinaryCodec.encode(&TestV2 { name: "moo".into(), size: Some(1), mode: Some(2) }).unwrap();
+        assert_eq!(BinaryCodec.decode::<Test>(&new).unwrap(), Test { name: "moo".into(), size: Some(1) });
+    }
+
+    #[test]
+    fn test_names() {
+        for codec in WireCodec::supported() {
+            assert_eq!(WireCodec::from_name(codec.name()), Some(codec));
+        }
+        assert_eq!(WireCodec::from_name("moo"), None);
+    }
+}
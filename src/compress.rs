@@ -0,0 +1,76 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Compression of chunk data on the wire. Each chunk is compressed
+//! on its own, so chunks can still be sent, retried and written in
+//! any order.
+
+use error::{Error, Result};
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{Read, Write};
+
+/// Algorithms for compressing chunks
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Algorithm {
+    Gzip,
+    Zlib,
+}
+
+pub fn compress(algorithm: Algorithm, data: &[u8]) -> Result<Vec<u8>> {
+    match algorithm {
+        Algorithm::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+            try!(encoder.write_all(data));
+            Ok(try!(encoder.finish()))
+        },
+        Algorithm::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+            try!(encoder.write_all(data));
+            Ok(try!(encoder.finish()))
+        },
+    }
+}
+
+/// Decompress a chunk that should hold `len` bytes. Data that
+/// decompresses to any other length is rejected, and no more than a
+/// byte past `len` is ever decompressed.
+pub fn decompress(algorithm: Algorithm, data: &[u8], len: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let result = match algorithm {
+        Algorithm::Gzip => GzDecoder::new(data).and_then(|d| d.take(len + 1).read_to_end(&mut buf)),
+        Algorithm::Zlib => ZlibDecoder::new(data).take(len + 1).read_to_end(&mut buf),
+    };
+
+    match result {
+        Ok(n) if n as u64 == len => Ok(buf),
+        _ => Err(Error::Decompress),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"key = value\nkey = value\nkey = value\n".to_vec();
+
+        for &algorithm in [Algorithm::Gzip, Algorithm::Zlib].iter() {
+            let compressed = compress(algorithm, &data).unwrap();
+            assert!(compressed != data);
+            assert_eq!(decompress(algorithm, &compressed, data.len() as u64).unwrap(), data);
+
+            // Too long, too short and garbage
+            assert!(decompress(algorithm, &compressed, data.len() as u64 - 1).is_err());
+            assert!(decompress(algorithm, &compressed, data.len() as u64 + 1).is_err());
+            assert!(decompress(algorithm, b"moo", 3).is_err());
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Directory trees, sent one file at a time. The server stages the
+//! files in a hidden directory beside the destination, which
+//! replaces the destination only once every file has arrived.
+
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use file::{File, Options};
+#[cfg(target_os = "linux")]
+use libc;
+use protocol;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+// Flag for renameat2() to swap its paths, from linux/fs.h
+#[cfg(target_os = "linux")]
+const RENAME_EXCHANGE: libc::c_uint = 2;
+
+pub struct Dir {
+    path: PathBuf,
+    /// Files, relative to `path`
+    files: Vec<PathBuf>,
+    /// Directories with nothing in them, relative to `path`
+    empty_dirs: Vec<PathBuf>,
+    options: Vec<Options>,
+    results: Vec<(PathBuf, Result<()>)>,
+}
+
+impl Dir {
+    /// Walk a local directory for sending. `options` apply to each
+    /// file in the tree.
+    pub fn open<P: AsRef<Path>>(path: P, options: Option<&[Options]>) -> Result<Dir> {
+        let meta = try!(fs::metadata(&path).or(Err(Error::InvalidFilePath)));
+        if !meta.is_dir() {
+            return Err(Error::InvalidFilePath);
+        }
+
+        let mut dir = Dir {
+            path: path.as_ref().to_owned(),
+            files: Vec::new(),
+            empty_dirs: Vec::new(),
+            options: options.map_or(Vec::new(), |o| o.to_vec()),
+            results: Vec::new(),
+        };
+        try!(dir.walk(Path::new("")));
+        Ok(dir)
+    }
+
+    // Symlinks aren't followed into directories, so a link back up
+    // the tree can't loop. A link to a file is sent as that file.
+    fn walk(&mut self, relative: &Path) -> Result<()> {
+        let mut entries = Vec::new();
+        for entry in try!(fs::read_dir(self.path.join(relative))) {
+            let entry = try!(entry);
+            entries.push((relative.join(entry.file_name()), try!(entry.file_type()).is_dir()));
+        }
+
+        if entries.is_empty() && relative != Path::new("") {
+            self.empty_dirs.push(relative.to_owned());
+        }
+
+        entries.sort();
+        for (path, is_dir) in entries {
+            if is_dir {
+                try!(self.walk(&path));
+            } else {
+                self.files.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send the tree to `remote_path` on the server. If any file
+    /// fails, the server discards the whole tree and this returns
+    /// `Error::FileFail`. See `get_results()` for which files failed.
+    pub fn send<P: AsRef<Path>>(&mut self, sock: &mut ZSock, remote_path: P) -> Result<()> {
+        let remote_path = remote_path.as_ref();
+        self.results.clear();
+
+        try!(request(sock, "DIR", Some(remote_path)));
+
+        for dir in self.empty_dirs.iter() {
+            try!(request(sock, "MKDIR", Some(&remote_path.join(dir))));
+        }
+
+        for relative in self.files.iter() {
+            let result = File::open(self.path.join(relative), Some(&self.options))
+                              .and_then(|mut file| file.send(sock, remote_path.join(relative)));
+            self.results.push((relative.clone(), result));
+        }
+
+        if self.results.iter().all(|&(_, ref result)| result.is_ok()) {
+            request(sock, "DIR-COMMIT", None)
+        } else {
+            try!(request(sock, "DIR-ABORT", None));
+            Err(Error::FileFail)
+        }
+    }
+
+    /// Result of sending each file in the most recent send, by path
+    /// relative to the directory
+    pub fn get_results(&self) -> &[(PathBuf, Result<()>)] {
+        &self.results
+    }
+}
+
+fn request(sock: &mut ZSock, action: &str, path: Option<&Path>) -> Result<()> {
+    let msg = ZMsg::new();
+    try!(msg.addstr(action));
+    if let Some(path) = path {
+        try!(msg.addbytes(&try!(protocol::encode_path(path))));
+    }
+    try!(msg.send(sock));
+
+    let reply = try!(ZMsg::recv(sock));
+    match try!(reply.popstr().and_then(|s| s.ok()).ok_or(Error::InvalidReply)).as_ref() {
+        "Ok" => Ok(()),
+        "Err" => Err(protocol::pop_err(&reply)),
+        _ => Err(Error::InvalidReply),
+    }
+}
+
+/// A hidden directory beside `dest` for staging its new contents
+pub fn staging_dir(dest: &Path) -> PathBuf {
+    let name = dest.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    let mut counter: u16 = 0;
+
+    loop {
+        let path = dest.with_file_name(&format!(".{}.dir{}", name, counter));
+        if !path.exists() {
+            return path;
+        }
+        counter += 1;
+    }
+}
+
+/// Move a staged tree to `dest`, replacing anything already there.
+/// The two are swapped in one rename where the platform allows,
+/// otherwise the old tree is moved aside first, so `dest` is only
+/// missing between two renames. The old tree is then removed.
+pub fn commit(staging: &Path, dest: &Path) -> Result<()> {
+    if fs::symlink_metadata(dest).is_err() {
+        if let Some(parent) = dest.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        try!(fs::rename(staging, dest));
+        return Ok(());
+    }
+
+    // Swapped, the old tree takes the staged tree's place
+    let old = match exchange(staging, dest) {
+        Ok(()) => staging.to_owned(),
+        Err(_) => {
+            let old = staging_dir(dest);
+            try!(fs::rename(dest, &old));
+            if let Err(e) = fs::rename(staging, dest) {
+                let _ = fs::rename(&old, dest);
+                return Err(e.into());
+            }
+            old
+        },
+    };
+
+    // The new tree is in place, so failing to clear up after the old
+    // one doesn't fail the commit
+    if let Err(e) = remove_tree(&old) {
+        warn!("old tree not removed path={} error={:?}", old.display(), e.to_string());
+    }
+    Ok(())
+}
+
+/// Remove a file, or a directory and everything in it. Symlinks are
+/// removed rather than followed, so one in the tree can't lead to
+/// files outside it.
+pub fn remove_tree(path: &Path) -> Result<()> {
+    if !try!(fs::symlink_metadata(path)).is_dir() {
+        try!(fs::remove_file(path));
+        return Ok(());
+    }
+
+    for entry in try!(fs::read_dir(path)) {
+        try!(remove_tree(&try!(entry).path()));
+    }
+    try!(fs::remove_dir(path));
+    Ok(())
+}
+
+// Swap two paths in one step
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = try!(CString::new(a.as_os_str().as_bytes()).or(Err(io::Error::from(io::ErrorKind::InvalidInput))));
+    let b = try!(CString::new(b.as_os_str().as_bytes()).or(Err(io::Error::from(io::ErrorKind::InvalidInput))));
+
+    if unsafe { libc::syscall(libc::SYS_renameat2, libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), RENAME_EXCHANGE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Other))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_open() {
+        let tempdir = TempDir::new("dir_test_open").unwrap();
+        fs::create_dir_all(tempdir.path().join("a/empty")).unwrap();
+        fs::create_dir(tempdir.path().join("b")).unwrap();
+        fs::File::create(tempdir.path().join("a/file")).unwrap();
+        fs::File::create(tempdir.path().join("top")).unwrap();
+
+        let dir = Dir::open(tempdir.path(), None).unwrap();
+        assert_eq!(dir.files, vec![PathBuf::from("a/file"), PathBuf::from("top")]);
+        assert_eq!(dir.empty_dirs, vec![PathBuf::from("a/empty"), PathBuf::from("b")]);
+
+        assert!(Dir::open(tempdir.path().join("top"), None).is_err());
+        assert!(Dir::open(tempdir.path().join("missing"), None).is_err());
+    }
+
+    #[test]
+    fn test_commit() {
+        let tempdir = TempDir::new("dir_test_commit").unwrap();
+        let dest = tempdir.path().join("sub/dest");
+
+        let staging = staging_dir(&dest);
+        assert_eq!(staging, tempdir.path().join("sub/.dest.dir0"));
+        fs::create_dir_all(&staging).unwrap();
+        fs::File::create(staging.join("new")).unwrap().write_all(b"new").unwrap();
+        commit(&staging, &dest).unwrap();
+        assert!(dest.join("new").exists());
+
+        // An existing tree is replaced, not merged
+        let staging = staging_dir(&dest);
+        fs::create_dir(&staging).unwrap();
+        fs::File::create(staging.join("newer")).unwrap().write_all(b"newer").unwrap();
+        commit(&staging, &dest).unwrap();
+        assert!(!dest.join("new").exists());
+        assert!(!staging.exists());
+
+        let mut content = String::new();
+        fs::File::open(dest.join("newer")).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "newer");
+        assert_eq!(fs::read_dir(tempdir.path().join("sub")).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_tree() {
+        use std::os::unix::fs::symlink;
+
+        let tempdir = TempDir::new("dir_test_remove_tree").unwrap();
+        let outside = tempdir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::File::create(outside.join("keep")).unwrap();
+
+        let tree = tempdir.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::File::create(tree.join("sub/file")).unwrap();
+        symlink(&outside, tree.join("link")).unwrap();
+
+        remove_tree(&tree).unwrap();
+        assert!(!tree.exists());
+        assert!(outside.join("keep").exists());
+    }
+}
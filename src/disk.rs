@@ -0,0 +1,98 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Free space on the filesystem an upload is staged on, so that a
+//! server can turn away uploads that won't fit.
+
+use error::Result;
+#[cfg(unix)]
+use error::Error;
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Bytes that unprivileged users may still write to the filesystem
+/// holding `path`. The nearest existing directory is used, as an
+/// upload's directories may not exist yet.
+#[cfg(unix)]
+pub fn available(path: &Path) -> Result<u64> {
+    let existing = nearest_existing(path);
+    let c_path = try!(CString::new(existing.as_os_str().as_bytes()).or(Err(Error::InvalidFilePath)));
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+// Free space isn't checked on Windows
+#[cfg(not(unix))]
+pub fn available(_: &Path) -> Result<u64> {
+    Ok(u64::max_value())
+}
+
+/// ID of the filesystem holding `path`, found as by `available()`,
+/// or None if it can't be told
+#[cfg(unix)]
+pub fn device(path: &Path) -> Option<u64> {
+    fs::metadata(nearest_existing(path)).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device(_: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn nearest_existing(path: &Path) -> &Path {
+    let mut existing = path;
+    while !existing.exists() {
+        match existing.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => existing = parent,
+            _ => return Path::new("."),
+        }
+    }
+    existing
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_available() {
+        let tempdir = TempDir::new("disk_test_available").unwrap();
+        let space = available(tempdir.path()).unwrap();
+        assert!(space > 0);
+
+        // Missing directories are on their parent's filesystem
+        let missing = available(&tempdir.path().join("a/b/file")).unwrap();
+        assert!(missing > 0);
+    }
+
+    #[test]
+    fn test_device() {
+        let tempdir = TempDir::new("disk_test_device").unwrap();
+        assert!(device(tempdir.path()).is_some());
+        assert_eq!(device(&tempdir.path().join("a/b/file")), device(tempdir.path()));
+    }
+}
@@ -6,8 +6,9 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+use bincode;
 use czmq;
-use rustc_serialize::json;
+use serde_json;
 use std::{convert, error, fmt, io, result, str};
 use zdaemon;
 
@@ -15,44 +16,328 @@ pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    Binary(bincode::Error),
+    BadSignature,
+    Busy(u32),
+    Cancelled,
     ChunkFail,
     ChunkIndex,
+    ChunkSize,
     Czmq(czmq::Error),
+    Decompress,
+    DurableUnsupported,
+    Expired,
     FailChecksum,
+    FailDigest,
+    FileExists,
     FileFail,
+    FileSize,
+    HashUnsupported,
+    IfExistsUnsupported,
     InvalidFileOpts,
     InvalidFilePath,
+    InvalidRecording,
     InvalidReply,
     InvalidRequest,
     Io(io::Error),
-    JsonEncoder(json::EncoderError),
-    JsonDecoder(json::DecoderError),
+    Json(serde_json::Error),
+    LegacyPeer,
     ModeRecv,
     ModeSend,
+    NoSpace,
+    OffsetWriteUnsupported,
+    PathNotAllowed,
+    ProxyTransport,
+    QuotaExceeded,
+    ServerKey,
+    ShuttingDown,
+    SigningKey,
+    SpecialFile,
+    SymlinkUnsupported,
+    Timeout,
+    Truncated,
+    Unauthorized,
+    UnknownOwner,
+    UnsafeFileName,
+    UnsupportedVersion(u32),
+    UnverifiedServer,
     UploadError(String),
+    WebhookUrl,
 }
 
 unsafe impl Send for Error {}
 unsafe impl Sync for Error {}
 
+/// Codes that identify a server's error in its Err reply, so that a
+/// client can tell errors apart without matching their descriptions.
+/// A code with a value is followed by it in the next frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    BadSignature,
+    Busy(u32),
+    Cancelled,
+    ChunkFail,
+    ChunkIndex,
+    ChunkSize,
+    Decompress,
+    DurableUnsupported,
+    Expired,
+    FailChecksum,
+    FailDigest,
+    FileExists,
+    FileFail,
+    FileSize,
+    HashUnsupported,
+    IfExistsUnsupported,
+    InvalidFileOpts,
+    InvalidFilePath,
+    InvalidRequest,
+    LegacyPeer,
+    ModeRecv,
+    ModeSend,
+    NoSpace,
+    OffsetWriteUnsupported,
+    PathNotAllowed,
+    QuotaExceeded,
+    ShuttingDown,
+    SpecialFile,
+    SymlinkUnsupported,
+    Unauthorized,
+    UnknownOwner,
+    UnsafeFileName,
+    UnsupportedVersion(u32),
+}
+
+impl ErrorCode {
+    /// The code as it's sent on the wire
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorCode::BadSignature => "BAD_SIGNATURE",
+            ErrorCode::Busy(_) => "BUSY",
+            ErrorCode::Cancelled => "CANCELLED",
+            ErrorCode::ChunkFail => "CHUNK_FAIL",
+            ErrorCode::ChunkIndex => "CHUNK_INDEX",
+            ErrorCode::ChunkSize => "CHUNK_SIZE",
+            ErrorCode::Decompress => "DECOMPRESS",
+            ErrorCode::DurableUnsupported => "DURABLE_UNSUPPORTED",
+            ErrorCode::Expired => "EXPIRED",
+            ErrorCode::FailChecksum => "FAIL_CHECKSUM",
+            ErrorCode::FailDigest => "FAIL_DIGEST",
+            ErrorCode::FileExists => "FILE_EXISTS",
+            ErrorCode::FileFail => "FILE_FAIL",
+            ErrorCode::FileSize => "FILE_SIZE",
+            ErrorCode::HashUnsupported => "HASH_UNSUPPORTED",
+            ErrorCode::IfExistsUnsupported => "IF_EXISTS_UNSUPPORTED",
+            ErrorCode::InvalidFileOpts => "INVALID_FILE_OPTS",
+            ErrorCode::InvalidFilePath => "INVALID_FILE_PATH",
+            ErrorCode::InvalidRequest => "INVALID_REQUEST",
+            ErrorCode::LegacyPeer => "LEGACY_PEER",
+            ErrorCode::ModeRecv => "MODE_RECV",
+            ErrorCode::ModeSend => "MODE_SEND",
+            ErrorCode::NoSpace => "NO_SPACE",
+            ErrorCode::OffsetWriteUnsupported => "OFFSET_WRITE_UNSUPPORTED",
+            ErrorCode::PathNotAllowed => "PATH_NOT_ALLOWED",
+            ErrorCode::QuotaExceeded => "QUOTA_EXCEEDED",
+            ErrorCode::ShuttingDown => "SHUTTING_DOWN",
+            ErrorCode::SpecialFile => "SPECIAL_FILE",
+            ErrorCode::SymlinkUnsupported => "SYMLINK_UNSUPPORTED",
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::UnknownOwner => "UNKNOWN_OWNER",
+            ErrorCode::UnsafeFileName => "UNSAFE_FILE_NAME",
+            ErrorCode::UnsupportedVersion(_) => "UNSUPPORTED_VERSION",
+        }
+    }
+
+    /// The value sent after the code, if it has one
+    pub fn value(&self) -> Option<u32> {
+        match *self {
+            ErrorCode::Busy(v) | ErrorCode::UnsupportedVersion(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Parse a code, and the value that followed it, from the wire.
+    /// Codes added by newer servers aren't known, and neither is one
+    /// missing its value, so give None.
+    pub fn parse(code: &str, value: Option<u32>) -> Option<ErrorCode> {
+        Some(match code {
+            "BAD_SIGNATURE" => ErrorCode::BadSignature,
+            "BUSY" => match value {
+                Some(secs) => ErrorCode::Busy(secs),
+                None => return None,
+            },
+            "CANCELLED" => ErrorCode::Cancelled,
+            "CHUNK_FAIL" => ErrorCode::ChunkFail,
+            "CHUNK_INDEX" => ErrorCode::ChunkIndex,
+            "CHUNK_SIZE" => ErrorCode::ChunkSize,
+            "DECOMPRESS" => ErrorCode::Decompress,
+            "DURABLE_UNSUPPORTED" => ErrorCode::DurableUnsupported,
+            "EXPIRED" => ErrorCode::Expired,
+            "FAIL_CHECKSUM" => ErrorCode::FailChecksum,
+            "FAIL_DIGEST" => ErrorCode::FailDigest,
+            "FILE_EXISTS" => ErrorCode::FileExists,
+            "FILE_FAIL" => ErrorCode::FileFail,
+            "FILE_SIZE" => ErrorCode::FileSize,
+            "HASH_UNSUPPORTED" => ErrorCode::HashUnsupported,
+            "IF_EXISTS_UNSUPPORTED" => ErrorCode::IfExistsUnsupported,
+            "INVALID_FILE_OPTS" => ErrorCode::InvalidFileOpts,
+            "INVALID_FILE_PATH" => ErrorCode::InvalidFilePath,
+            "INVALID_REQUEST" => ErrorCode::InvalidRequest,
+            "LEGACY_PEER" => ErrorCode::LegacyPeer,
+            "MODE_RECV" => ErrorCode::ModeRecv,
+            "MODE_SEND" => ErrorCode::ModeSend,
+            "NO_SPACE" => ErrorCode::NoSpace,
+            "OFFSET_WRITE_UNSUPPORTED" => ErrorCode::OffsetWriteUnsupported,
+            "PATH_NOT_ALLOWED" => ErrorCode::PathNotAllowed,
+            "QUOTA_EXCEEDED" => ErrorCode::QuotaExceeded,
+            "SHUTTING_DOWN" => ErrorCode::ShuttingDown,
+            "SPECIAL_FILE" => ErrorCode::SpecialFile,
+            "SYMLINK_UNSUPPORTED" => ErrorCode::SymlinkUnsupported,
+            "UNAUTHORIZED" => ErrorCode::Unauthorized,
+            "UNKNOWN_OWNER" => ErrorCode::UnknownOwner,
+            "UNSAFE_FILE_NAME" => ErrorCode::UnsafeFileName,
+            "UNSUPPORTED_VERSION" => match value {
+                Some(min) => ErrorCode::UnsupportedVersion(min),
+                None => return None,
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl Error {
+    /// Code to send with this error in an Err reply. Errors a client
+    /// can't act on, like the server's own IO errors, have none.
+    pub fn code(&self) -> Option<ErrorCode> {
+        Some(match *self {
+            Error::BadSignature => ErrorCode::BadSignature,
+            Error::Busy(secs) => ErrorCode::Busy(secs),
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::ChunkFail => ErrorCode::ChunkFail,
+            Error::ChunkIndex => ErrorCode::ChunkIndex,
+            Error::ChunkSize => ErrorCode::ChunkSize,
+            Error::Decompress => ErrorCode::Decompress,
+            Error::DurableUnsupported => ErrorCode::DurableUnsupported,
+            Error::Expired => ErrorCode::Expired,
+            Error::FailChecksum => ErrorCode::FailChecksum,
+            Error::FailDigest => ErrorCode::FailDigest,
+            Error::FileExists => ErrorCode::FileExists,
+            Error::FileFail => ErrorCode::FileFail,
+            Error::FileSize => ErrorCode::FileSize,
+            Error::HashUnsupported => ErrorCode::HashUnsupported,
+            Error::IfExistsUnsupported => ErrorCode::IfExistsUnsupported,
+            Error::InvalidFileOpts => ErrorCode::InvalidFileOpts,
+            Error::InvalidFilePath => ErrorCode::InvalidFilePath,
+            Error::InvalidRequest => ErrorCode::InvalidRequest,
+            Error::LegacyPeer => ErrorCode::LegacyPeer,
+            Error::ModeRecv => ErrorCode::ModeRecv,
+            Error::ModeSend => ErrorCode::ModeSend,
+            Error::NoSpace => ErrorCode::NoSpace,
+            Error::OffsetWriteUnsupported => ErrorCode::OffsetWriteUnsupported,
+            Error::PathNotAllowed => ErrorCode::PathNotAllowed,
+            Error::QuotaExceeded => ErrorCode::QuotaExceeded,
+            Error::ShuttingDown => ErrorCode::ShuttingDown,
+            Error::SpecialFile => ErrorCode::SpecialFile,
+            Error::SymlinkUnsupported => ErrorCode::SymlinkUnsupported,
+            Error::Unauthorized => ErrorCode::Unauthorized,
+            Error::UnknownOwner => ErrorCode::UnknownOwner,
+            Error::UnsafeFileName => ErrorCode::UnsafeFileName,
+            Error::UnsupportedVersion(min) => ErrorCode::UnsupportedVersion(min),
+            _ => return None,
+        })
+    }
+}
+
+impl convert::From<ErrorCode> for Error {
+    fn from(code: ErrorCode) -> Error {
+        match code {
+            ErrorCode::BadSignature => Error::BadSignature,
+            ErrorCode::Busy(secs) => Error::Busy(secs),
+            ErrorCode::Cancelled => Error::Cancelled,
+            ErrorCode::ChunkFail => Error::ChunkFail,
+            ErrorCode::ChunkIndex => Error::ChunkIndex,
+            ErrorCode::ChunkSize => Error::ChunkSize,
+            ErrorCode::Decompress => Error::Decompress,
+            ErrorCode::DurableUnsupported => Error::DurableUnsupported,
+            ErrorCode::Expired => Error::Expired,
+            ErrorCode::FailChecksum => Error::FailChecksum,
+            ErrorCode::FailDigest => Error::FailDigest,
+            ErrorCode::FileExists => Error::FileExists,
+            ErrorCode::FileFail => Error::FileFail,
+            ErrorCode::FileSize => Error::FileSize,
+            ErrorCode::HashUnsupported => Error::HashUnsupported,
+            ErrorCode::IfExistsUnsupported => Error::IfExistsUnsupported,
+            ErrorCode::InvalidFileOpts => Error::InvalidFileOpts,
+            ErrorCode::InvalidFilePath => Error::InvalidFilePath,
+            ErrorCode::InvalidRequest => Error::InvalidRequest,
+            ErrorCode::LegacyPeer => Error::LegacyPeer,
+            ErrorCode::ModeRecv => Error::ModeRecv,
+            ErrorCode::ModeSend => Error::ModeSend,
+            ErrorCode::NoSpace => Error::NoSpace,
+            ErrorCode::OffsetWriteUnsupported => Error::OffsetWriteUnsupported,
+            ErrorCode::PathNotAllowed => Error::PathNotAllowed,
+            ErrorCode::QuotaExceeded => Error::QuotaExceeded,
+            ErrorCode::ShuttingDown => Error::ShuttingDown,
+            ErrorCode::SpecialFile => Error::SpecialFile,
+            ErrorCode::SymlinkUnsupported => Error::SymlinkUnsupported,
+            ErrorCode::Unauthorized => Error::Unauthorized,
+            ErrorCode::UnknownOwner => Error::UnknownOwner,
+            ErrorCode::UnsafeFileName => Error::UnsafeFileName,
+            ErrorCode::UnsupportedVersion(min) => Error::UnsupportedVersion(min),
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Error::Binary(ref e) => write!(f, "Binary codec error: {}", e),
+            Error::BadSignature => write!(f, "Transfer is not signed by a trusted key"),
+            Error::Busy(secs) => write!(f, "Server is busy, retry after {} seconds", secs),
+            Error::Cancelled => write!(f, "Transfer was cancelled by the server"),
             Error::ChunkFail => write!(f, "Failed to save chunk to file"),
             Error::ChunkIndex => write!(f, "Chunk index not in file"),
+            Error::ChunkSize => write!(f, "Chunk size is outside server limits"),
             Error::Czmq(ref e) => write!(f, "CZMQ error: {}", e),
+            Error::Decompress => write!(f, "Chunk could not be decompressed to its expected size"),
+            Error::DurableUnsupported => write!(f, "Peer cannot sync files to disk before replying"),
+            Error::Expired => write!(f, "Transfer expired after its client stopped responding"),
             Error::FailChecksum => write!(f, "Uploaded file does not match expected CRC"),
+            Error::FailDigest => write!(f, "Uploaded file does not match expected digest"),
+            Error::FileExists => write!(f, "Destination file already exists"),
             Error::FileFail => write!(f, "Failed to upload file"),
+            Error::FileSize => write!(f, "File size exceeds server limit"),
+            Error::HashUnsupported => write!(f, "Peer cannot verify the requested hash"),
+            Error::IfExistsUnsupported => write!(f, "Peer cannot honour the requested policy for existing files"),
             Error::InvalidFileOpts => write!(f, "Invalid file options"),
             Error::InvalidFilePath => write!(f, "Path does not exist or is not a file"),
+            Error::InvalidRecording => write!(f, "Not a session recording, or it is truncated"),
             Error::InvalidReply => write!(f, "Invalid reply"),
             Error::InvalidRequest => write!(f, "Invalid request"),
             Error::Io(ref e) => write!(f, "IO error: {}", e),
-            Error::JsonEncoder(ref e) => write!(f, "JSON encoder error: {}", e),
-            Error::JsonDecoder(ref e) => write!(f, "JSON decoder error: {}", e),
+            Error::Json(ref e) => write!(f, "JSON codec error: {}", e),
+            Error::LegacyPeer => write!(f, "Peer does not support protocol versioning"),
             Error::ModeRecv => write!(f, "Struct is in wrong mode for receiving"),
             Error::ModeSend => write!(f, "Struct is in wrong mode for sending"),
+            Error::OffsetWriteUnsupported => write!(f, "Peer cannot append or write at an offset"),
+            Error::NoSpace => write!(f, "Not enough free disk space on the server"),
+            Error::PathNotAllowed => write!(f, "Uploads to this path are not allowed"),
+            Error::ProxyTransport => write!(f, "SOCKS5 proxies are only supported for TCP endpoints"),
+            Error::QuotaExceeded => write!(f, "Upload would exceed the client's quota"),
+            Error::ServerKey => write!(f, "Server key must be a 40 character Z85 string"),
+            Error::ShuttingDown => write!(f, "Server is shutting down"),
+            Error::SigningKey => write!(f, "Signing key must be a 32 byte ed25519 seed followed by its public key"),
+            Error::SpecialFile => write!(f, "FIFOs, devices and sockets cannot be transferred"),
+            Error::SymlinkUnsupported => write!(f, "Peer cannot recreate symlinks"),
+            Error::Timeout => write!(f, "Server did not reply in time"),
+            Error::Truncated => write!(f, "File shrank while it was being followed"),
+            Error::Unauthorized => write!(f, "Identity is not authorized for this action"),
+            Error::UnknownOwner => write!(f, "Owner or group does not exist on the server"),
+            Error::UnsafeFileName => write!(f, "Destination file name is not allowed"),
+            Error::UnsupportedVersion(min) => write!(f, "Peer requires protocol version {} or later", min),
+            Error::UnverifiedServer => write!(f, "Server could not be verified with the pinned key"),
             Error::UploadError(ref e) => write!(f, "Could not upload file: {}", e),
+            Error::WebhookUrl => write!(f, "Webhook URL must be an http:// or https:// URL with a host"),
         }
     }
 }
@@ -60,25 +345,63 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::Binary(ref e) => e.description(),
+            Error::BadSignature => "Transfer is not signed by a trusted key",
+            Error::Busy(_) => "Server is busy",
+            Error::Cancelled => "Transfer was cancelled by the server",
             Error::ChunkFail => "Failed to save chunk to file",
             Error::ChunkIndex => "Chunk index not in file",
+            Error::ChunkSize => "Chunk size is outside server limits",
             Error::Czmq(ref e) => e.description(),
+            Error::Decompress => "Chunk could not be decompressed to its expected size",
+            Error::DurableUnsupported => "Peer cannot sync files to disk before replying",
+            Error::Expired => "Transfer expired after its client stopped responding",
             Error::FailChecksum => "Uploaded file does not match expected CRC",
+            Error::FailDigest => "Uploaded file does not match expected digest",
+            Error::FileExists => "Destination file already exists",
             Error::FileFail => "Failed to upload file",
+            Error::FileSize => "File size exceeds server limit",
+            Error::HashUnsupported => "Peer cannot verify the requested hash",
+            Error::IfExistsUnsupported => "Peer cannot honour the requested policy for existing files",
             Error::InvalidFileOpts => "Invalid file options",
             Error::InvalidFilePath => "Path does not exist or is not a file",
+            Error::InvalidRecording => "Not a session recording, or it is truncated",
             Error::InvalidReply => "Invalid reply",
             Error::InvalidRequest => "Invalid request",
             Error::Io(ref e) => e.description(),
-            Error::JsonEncoder(ref e) => e.description(),
-            Error::JsonDecoder(ref e) => e.description(),
+            Error::Json(ref e) => e.description(),
+            Error::LegacyPeer => "Peer does not support protocol versioning",
             Error::ModeRecv => "Struct is in wrong mode for receiving",
             Error::ModeSend => "Struct is in wrong mode for sending",
+            Error::OffsetWriteUnsupported => "Peer cannot append or write at an offset",
+            Error::NoSpace => "Not enough free disk space on the server",
+            Error::PathNotAllowed => "Uploads to this path are not allowed",
+            Error::ProxyTransport => "SOCKS5 proxies are only supported for TCP endpoints",
+            Error::QuotaExceeded => "Upload would exceed the client's quota",
+            Error::ServerKey => "Server key must be a 40 character Z85 string",
+            Error::ShuttingDown => "Server is shutting down",
+            Error::SigningKey => "Signing key must be a 32 byte ed25519 seed followed by its public key",
+            Error::SpecialFile => "FIFOs, devices and sockets cannot be transferred",
+            Error::SymlinkUnsupported => "Peer cannot recreate symlinks",
+            Error::Timeout => "Server did not reply in time",
+            Error::Truncated => "File shrank while it was being followed",
+            Error::Unauthorized => "Identity is not authorized for this action",
+            Error::UnknownOwner => "Owner or group does not exist on the server",
+            Error::UnsafeFileName => "Destination file name is not allowed",
+            Error::UnsupportedVersion(_) => "Peer requires a later protocol version",
+            Error::UnverifiedServer => "Server could not be verified with the pinned key",
             Error::UploadError(ref e) => e,
+            Error::WebhookUrl => "Webhook URL must be an http:// or https:// URL with a host",
         }
     }
 }
 
+impl convert::From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Error {
+        Error::Binary(err)
+    }
+}
+
 impl convert::From<czmq::Error> for Error {
     fn from(err: czmq::Error) -> Error {
         Error::Czmq(err)
@@ -91,15 +414,9 @@ impl convert::From<io::Error> for Error {
     }
 }
 
-impl convert::From<json::EncoderError> for Error {
-    fn from(err: json::EncoderError) -> Error {
-        Error::JsonEncoder(err)
-    }
-}
-
-impl convert::From<json::DecoderError> for Error {
-    fn from(err: json::DecoderError) -> Error {
-        Error::JsonDecoder(err)
+impl convert::From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
     }
 }
 
@@ -111,12 +428,19 @@ impl convert::Into<zdaemon::Error> for Error {
 
 #[cfg(test)]
 mod tests {
+    use bincode;
     use czmq::{ZSock, SocketType, ZSys};
-    use rustc_serialize::json::{DecoderError, EncoderError};
+    use serde_json;
     use std::fs::metadata;
     use super::*;
     use zdaemon;
 
+    #[test]
+    fn test_convert_binary_decode() {
+        let e = bincode::deserialize::<u64>(&[]).unwrap_err();
+        Error::from(e);
+    }
+
     #[test]
     fn test_convert_czmq() {
         ZSys::init();
@@ -136,15 +460,37 @@ mod tests {
     }
 
     #[test]
-    fn test_convert_json_encode() {
-        let e = EncoderError::BadHashmapKey;
+    fn test_convert_json() {
+        let e = serde_json::from_str::<u64>("").unwrap_err();
         Error::from(e);
     }
 
     #[test]
-    fn test_convert_json_decode() {
-        let e = DecoderError::EOF;
-        Error::from(e);
+    fn test_error_code() {
+        let code = Error::FailChecksum.code().unwrap();
+        assert_eq!(ErrorCode::parse(code.as_str(), code.value()), Some(code));
+        match Error::from(code) {
+            Error::FailChecksum => (),
+            _ => panic!("Expected FailChecksum error"),
+        }
+
+        // A client can tell it was dropped for going quiet
+        match Error::from(ErrorCode::parse("EXPIRED", None).unwrap()) {
+            Error::Expired => (),
+            _ => panic!("Expected Expired error"),
+        }
+
+        // Every error a server can send about a request has a code
+        for e in [Error::Busy(5), Error::DurableUnsupported, Error::HashUnsupported, Error::IfExistsUnsupported,
+                  Error::ModeRecv, Error::ModeSend, Error::OffsetWriteUnsupported, Error::UnsupportedVersion(12)].iter() {
+            let code = e.code().unwrap();
+            assert_eq!(Error::from(ErrorCode::parse(code.as_str(), code.value()).unwrap()).to_string(), e.to_string());
+        }
+
+        // A code that should have a value isn't known without one
+        assert!(ErrorCode::parse("UNSUPPORTED_VERSION", None).is_none());
+        assert!(Error::Timeout.code().is_none());
+        assert!(ErrorCode::parse("NEW_CODE", None).is_none());
     }
 
     #[test]
@@ -0,0 +1,306 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use error::Result;
+use file::Timings;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use transfer::TransferId;
+
+/// Something notable that happened to a transfer
+#[derive(Debug)]
+pub enum Event<'a> {
+    TransferStarted { id: TransferId, identity: &'a [u8], path: &'a Path, size: u64 },
+    /// A chunk was written to the temporary file
+    ChunkReceived { id: TransferId, identity: &'a [u8], index: u64 },
+    ChunkRetry { id: TransferId, identity: &'a [u8], index: u64 },
+    TransferCompleted { id: TransferId, identity: &'a [u8], path: &'a Path, size: u64, crc: u64, content_type: Option<&'a str> },
+    TransferFailed { id: TransferId, identity: &'a [u8], path: &'a Path, reason: String },
+}
+
+impl<'a> Event<'a> {
+    fn to_json(&self, time: u64) -> Value {
+        let mut obj = Map::new();
+        obj.insert("time".to_string(), Value::from(time));
+
+        let (name, id, identity) = match *self {
+            Event::TransferStarted { id, identity, path, size } => {
+                obj.insert("path".to_string(), Value::String(path.to_string_lossy().into_owned()));
+                obj.insert("size".to_string(), Value::from(size));
+                ("transfer_started", id, identity)
+            },
+            Event::ChunkReceived { id, identity, index } => {
+                obj.insert("index".to_string(), Value::from(index));
+                ("chunk_received", id, identity)
+            },
+            Event::ChunkRetry { id, identity, index } => {
+                obj.insert("index".to_string(), Value::from(index));
+                ("chunk_retry", id, identity)
+            },
+            Event::TransferCompleted { id, identity, path, size, crc, content_type } => {
+                obj.insert("path".to_string(), Value::String(path.to_string_lossy().into_owned()));
+                obj.insert("size".to_string(), Value::from(size));
+                obj.insert("crc".to_string(), Value::from(crc));
+                if let Some(content_type) = content_type {
+                    obj.insert("content_type".to_string(), Value::String(content_type.to_string()));
+                }
+                ("transfer_completed", id, identity)
+            },
+            Event::TransferFailed { id, identity, path, ref reason } => {
+                obj.insert("path".to_string(), Value::String(path.to_string_lossy().into_owned()));
+                obj.insert("reason".to_string(), Value::String(reason.clone()));
+                ("transfer_failed", id, identity)
+            },
+        };
+
+        obj.insert("event".to_string(), Value::String(name.to_string()));
+        obj.insert("id".to_string(), Value::from(id));
+        obj.insert("identity".to_string(), Value::String(hex(identity)));
+        Value::Object(obj)
+    }
+}
+
+/// The outcome of a transfer, passed to the Server's completion
+/// observer
+#[derive(Debug)]
+pub struct Completion {
+    pub id: TransferId,
+    pub identity: Vec<u8>,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Time from the NEW request to completion or failure
+    pub duration: Duration,
+    pub result: Result<()>,
+    /// Chunks that had to be requested again
+    pub retransmits: u32,
+    /// MIME type guessed from the file's contents, if the server
+    /// sniffs uploads
+    pub content_type: Option<&'static str>,
+    /// Microseconds spent in each phase, to tell whether the transfer
+    /// was network, disk or arbitration bound
+    pub timings: Timings,
+}
+
+impl Completion {
+    /// Encode as a transfer_completed or transfer_failed event
+    pub fn to_json(&self) -> Value {
+        let mut obj = Map::new();
+        let name = match self.result {
+            Ok(_) => "transfer_completed",
+            Err(ref e) => {
+                obj.insert("reason".to_string(), Value::String(e.to_string()));
+                "transfer_failed"
+            },
+        };
+
+        let millis = self.duration.as_secs() * 1000 + self.duration.subsec_nanos() as u64 / 1_000_000;
+        obj.insert("event".to_string(), Value::String(name.to_string()));
+        obj.insert("id".to_string(), Value::from(self.id));
+        obj.insert("identity".to_string(), Value::String(hex(&self.identity)));
+        obj.insert("path".to_string(), Value::String(self.path.to_string_lossy().into_owned()));
+        obj.insert("size".to_string(), Value::from(self.size));
+        obj.insert("duration".to_string(), Value::from(millis));
+        obj.insert("retransmits".to_string(), Value::from(self.retransmits as u64));
+        let mut timings = Map::new();
+        timings.insert("hashing".to_string(), Value::from(self.timings.hashing));
+        timings.insert("slot_wait".to_string(), Value::from(self.timings.slot_wait));
+        timings.insert("network".to_string(), Value::from(self.timings.network));
+        timings.insert("disk".to_string(), Value::from(self.timings.disk));
+        timings.insert("finalize".to_string(), Value::from(self.timings.finalize));
+        obj.insert("timings".to_string(), Value::Object(timings));
+        if let Some(content_type) = self.content_type {
+            obj.insert("content_type".to_string(), Value::String(content_type.to_string()));
+        }
+        Value::Object(obj)
+    }
+}
+
+/// Writes events to a user-supplied writer as JSON lines, e.g. for
+/// shipping to a log pipeline. Events are discarded until a writer
+/// is set.
+pub struct EventLog {
+    writer: Option<Box<Write>>,
+    observer: Option<Box<FnMut(&Completion)>>,
+    listener: Option<Box<FnMut(&Event)>>,
+}
+
+impl EventLog {
+    pub fn new() -> EventLog {
+        EventLog {
+            writer: None,
+            observer: None,
+            listener: None,
+        }
+    }
+
+    pub fn set_writer(&mut self, writer: Box<Write>) {
+        self.writer = Some(writer);
+    }
+
+    pub fn set_observer(&mut self, observer: Box<FnMut(&Completion)>) {
+        self.observer = Some(observer);
+    }
+
+    pub fn set_listener(&mut self, listener: Box<FnMut(&Event)>) {
+        self.listener = Some(listener);
+    }
+
+    /// Pass a finished transfer to the observer, if any
+    pub fn complete(&mut self, completion: Completion) {
+        if let Some(ref mut observer) = self.observer {
+            observer(&completion);
+        }
+    }
+
+    /// Pass an event to the listener, log it and write it. Events are
+    /// best effort, so a failing writer never interrupts a transfer.
+    pub fn emit(&mut self, event: Event) {
+        log_event(&event);
+
+        if let Some(ref mut listener) = self.listener {
+            listener(&event);
+        }
+
+        // A line per chunk would swamp the log
+        if let Event::ChunkReceived { .. } = event {
+            return;
+        }
+
+        if let Some(ref mut writer) = self.writer {
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let _ = writeln!(writer, "{}", event.to_json(time));
+            let _ = writer.flush();
+        }
+    }
+}
+
+// Events are logged whether or not a writer is set, as key=value
+// pairs that log processors can pick apart
+fn log_event(event: &Event) {
+    match *event {
+        Event::TransferStarted { id, identity, path, size } =>
+            info!("transfer started id={} identity={} path={} size={}", id, hex(identity), path.display(), size),
+        Event::ChunkReceived { id, identity, index } =>
+            trace!("chunk received id={} identity={} index={}", id, hex(identity), index),
+        Event::ChunkRetry { id, identity, index } =>
+            debug!("chunk retry id={} identity={} index={}", id, hex(identity), index),
+        Event::TransferCompleted { id, identity, path, size, crc, .. } =>
+            info!("transfer completed id={} identity={} path={} size={} crc={}", id, hex(identity), path.display(), size, crc),
+        Event::TransferFailed { id, identity, path, ref reason } =>
+            warn!("transfer failed id={} identity={} path={} reason={:?}", id, hex(identity), path.display(), reason),
+    }
+}
+
+// Router identities are usually binary, so they are logged as hex
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use std::cell::RefCell;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use super::*;
+    use tempfile::tempfile;
+
+    #[test]
+    fn test_to_json() {
+        let event = Event::ChunkRetry { id: 3, identity: &[0, 171], index: 7 };
+        assert_eq!(event.to_json(10).to_string(), "{\"event\":\"chunk_retry\",\"id\":3,\"identity\":\"00ab\",\"index\":7,\"time\":10}");
+
+        let event = Event::TransferFailed { id: 3, identity: b"a", path: Path::new("/tmp/f"), reason: "Timed out".into() };
+        let json = event.to_json(10);
+        assert_eq!(json["event"].as_str(), Some("transfer_failed"));
+        assert_eq!(json["reason"].as_str(), Some("Timed out"));
+    }
+
+    #[test]
+    fn test_emit() {
+        let mut fh = tempfile().unwrap();
+        let mut log = EventLog::new();
+        log.emit(Event::TransferStarted { id: 0, identity: b"a", path: Path::new("/tmp/f"), size: 1 });
+        log.set_writer(Box::new(fh.try_clone().unwrap()));
+        log.emit(Event::TransferStarted { id: 0, identity: b"a", path: Path::new("/tmp/f"), size: 1 });
+        log.emit(Event::TransferCompleted { id: 0, identity: b"a", path: Path::new("/tmp/f"), size: 1, crc: 2, content_type: Some("text/plain") });
+
+        let mut content = String::new();
+        fh.seek(SeekFrom::Start(0)).unwrap();
+        fh.read_to_string(&mut content).unwrap();
+
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"event\":\"transfer_started\""));
+        assert!(lines[1].contains("\"event\":\"transfer_completed\""));
+        assert!(lines[1].contains("\"content_type\":\"text/plain\""));
+    }
+
+    #[test]
+    fn test_listen() {
+        let mut fh = tempfile().unwrap();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_clone = seen.clone();
+
+        let mut log = EventLog::new();
+        log.set_writer(Box::new(fh.try_clone().unwrap()));
+        log.set_listener(Box::new(move |e: &Event| seen_clone.borrow_mut().push(e.to_json(0)["event"].as_str().unwrap().to_string())));
+        log.emit(Event::TransferStarted { id: 0, identity: b"a", path: Path::new("/tmp/f"), size: 1 });
+        log.emit(Event::ChunkReceived { id: 0, identity: b"a", index: 0 });
+
+        assert_eq!(*seen.borrow(), vec!["transfer_started", "chunk_received"]);
+
+        // Only the listener hears about chunks
+        let mut content = String::new();
+        fh.seek(SeekFrom::Start(0)).unwrap();
+        fh.read_to_string(&mut content).unwrap();
+        assert_eq!(content.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_complete() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_clone = seen.clone();
+
+        let mut log = EventLog::new();
+        log.complete(completion(0, Ok(())));
+        log.set_observer(Box::new(move |c: &Completion| seen_clone.borrow_mut().push((c.id, c.result.is_ok()))));
+        log.complete(completion(1, Ok(())));
+        log.complete(completion(2, Err(Error::FileFail)));
+
+        assert_eq!(*seen.borrow(), vec![(1, true), (2, false)]);
+    }
+
+    #[test]
+    fn test_completion_to_json() {
+        assert_eq!(completion(1, Ok(())).to_json().to_string(),
+                   "{\"duration\":1000,\"event\":\"transfer_completed\",\"id\":1,\"identity\":\"61\",\"path\":\"/tmp/f\",\"retransmits\":0,\"size\":1,\"timings\":{\"disk\":4,\"finalize\":5,\"hashing\":1,\"network\":3,\"slot_wait\":2}}");
+
+        let json = completion(1, Err(Error::FileFail)).to_json();
+        assert_eq!(json["event"].as_str(), Some("transfer_failed"));
+        assert_eq!(json["reason"].as_str(), Some("Failed to upload file"));
+    }
+
+    fn completion(id: u64, result: Result<()>) -> Completion {
+        Completion {
+            id: id,
+            identity: b"a".to_vec(),
+            path: PathBuf::from("/tmp/f"),
+            size: 1,
+            duration: Duration::new(1, 0),
+            result: result,
+            retransmits: 0,
+            content_type: None,
+            timings: Timings { hashing: 1, slot_wait: 2, network: 3, disk: 4, finalize: 5 },
+        }
+    }
+}
@@ -7,31 +7,224 @@
 // modified, or distributed except according to those terms.
 
 use arbitrator::Arbitrator;
-use chunk::Chunk;
-use crc::{crc64, Hasher64};
+use attrs;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use chunk;
+use chunk::{Chunk, Chunks, ChunkSet, Layout};
+use codec::{BinaryCodec, Codec, JsonCodec, WireCodec};
+use compress::{self, Algorithm};
 use czmq::{ZMsg, ZSock};
+use disk;
 use error::{Error, Result};
-use rustc_serialize::json;
-use std::cell::{RefMut, RefCell};
-use std::collections::HashMap;
-use std::fs::{create_dir_all, rename, self};
-use std::io::{Read, Seek, SeekFrom};
+use event::hex;
+use getrandom;
+use handle::Handle;
+use hash::{self, HashAlgorithm};
+#[cfg(unix)]
+use libc;
+use manifest::{manifest_path, Manifest, STAGING_VERSION};
+use protocol::{self, Compat, PROTOCOL_VERSION};
+use retry::RetryPolicy;
+use serde_json;
+#[cfg(feature = "signing")]
+use signing;
+use staging::StagingCipher;
+use stripe::Stripe;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{create_dir_all, self};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{self as unix_fs, FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+#[cfg(feature = "signing")]
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use uring;
 
 const CHUNK_SIZE: u64 = 1024; // 1Kb
-const MAX_CHUNK_ERR: u8 = 5;
+/// Windows' error for a rename across volumes
+#[cfg(not(unix))]
+const ERROR_NOT_SAME_DEVICE: i32 = 17;
+/// Suffix of numbered backups, unless the client sets its own
+const BACKUP_SUFFIX: &'static str = ".bk";
+/// Most numbered backups a client may ask to keep, as each is renamed
+/// whenever the file is saved
+const MAX_BACKUP_VERSIONS: u32 = 100;
+/// Maximum number of a file's chunks queued with the Arbitrator at once
+const QUEUE_WINDOW: u64 = 256;
+/// Consecutive successful chunks before the chunk size is grown
+const ADAPT_AFTER: u32 = 16;
+/// Completed chunks between updates to a staging manifest, unless
+/// the server sets its own
+const MANIFEST_INTERVAL: u64 = 64;
+/// Version of the encoded FileOptions. Bump it when adding a field.
+const OPTIONS_VERSION: u32 = 6;
+/// Fields in the encoded FileOptions before the version was added.
+/// Each version since has one more.
+const UNVERSIONED_FIELDS: usize = 17;
 
 pub struct File {
-    fh: Rc<RefCell<fs::File>>,
+    fh: Arc<Mutex<Handle>>,
     path: Option<PathBuf>,
     upload_path: Option<PathBuf>,
     size: u64,
     crc: u64,
-    chunks: HashMap<u64, Chunk>,
-    chunk_error_cnt: u8,
+    chunks: ChunkSet,
+    queued: u64,
+    chunk_error_cnt: u32,
     chunk_size: u64,
+    layout: Layout,
+    adapt: Option<(u64, u64)>,
+    adapt_streak: u32,
+    resize: Option<(u64, u64)>,
     options: FileOptions,
+    compat: Compat,
+    codec: WireCodec,
+    protocol: Option<u32>,
+    started: Instant,
+    timings: PhaseTimes,
+    sink_sock: Option<ZSock>,
+    stats: TransferStats,
+    unsent: ChunkSet,
+    // Chunks the server hasn't yet said it has, and the bytes it has
+    unacked: ChunkSet,
+    bytes_acked: u64,
+    read_ahead: ReadAhead,
+    #[cfg_attr(not(feature = "signing"), allow(dead_code))]
+    signing_key: Option<Vec<u8>>,
+    staging: Option<StagingCipher>,
+    retry: RetryPolicy,
+    // Longest a send may take, and may wait for any one message
+    deadline: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    // How often a followed file is checked for appended data
+    follow: Option<Duration>,
+    // How often the server asked to be sent PING during this send
+    heartbeat: Option<Duration>,
+    // Extra connections for the next send to spread chunks across,
+    // and the threads answering on them once it has begun
+    stripes: Vec<ZSock>,
+    stripe_threads: Option<(Arc<AtomicBool>, Vec<JoinHandle<Result<(u64, u64)>>>)>,
+    output: Option<Box<Write + Send>>,
+    // Whether a FIFO at the destination is written into, rather than
+    // the upload refused
+    stream_fifos: bool,
+    // A copy of an upload staged on another filesystem, made beside
+    // the destination so that saving only has to rename it
+    copy_path: Option<PathBuf>,
+    // Whether the upload was taken to be streamed elsewhere
+    streamed: bool,
+    manifest: Option<Manifest>,
+    // Chunks completed since the manifest was last saved
+    manifest_lag: u64,
+    manifest_interval: u64,
+}
+
+// Chunks a sender has sent before the server asked for them
+#[derive(Default)]
+struct ReadAhead {
+    // Chunks that may be sent ahead, as granted by the server
+    window: u64,
+    // One past the last chunk the server has asked for
+    frontier: u64,
+}
+
+// Running totals for each phase of a transfer
+#[derive(Default)]
+struct PhaseTimes {
+    hashing: Duration,
+    slot_wait: Duration,
+    in_flight: Duration,
+    disk: Duration,
+    finalize: Duration,
+}
+
+/// Time spent in each phase of a transfer, in microseconds. Chunk
+/// phases are summed across chunks, so they can exceed the age of a
+/// transfer when several chunks are in flight at once.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Timings {
+    /// Calculating the file checksum
+    pub hashing: u64,
+    /// Chunks waiting for a free Arbitrator slot
+    pub slot_wait: u64,
+    /// Chunks travelling between peers, excluding disk writes
+    pub network: u64,
+    /// Writing chunks to disk
+    pub disk: u64,
+    /// Verifying and moving the finished file into place
+    pub finalize: u64,
+}
+
+/// Statistics for the most recent `File::send()`
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct TransferStats {
+    /// ID the server assigned to the transfer, if it sent one. This
+    /// matches the ID in the server's events.
+    pub transfer_id: Option<u64>,
+    /// Milliseconds from sending NEW to the server's final reply
+    pub elapsed: u64,
+    pub bytes_sent: u64,
+    pub chunks_sent: u64,
+    /// Chunks that the server requested more than once
+    pub retransmits: u64,
+    /// Chunks that the server already had, so weren't sent
+    pub chunks_unchanged: u64,
+    /// Bytes sent per second
+    pub throughput: u64,
+    /// The destination already existed, so the server skipped the
+    /// upload as asked
+    pub skipped: bool,
+    /// The server already had the same file at the destination, so
+    /// nothing was sent
+    pub unchanged: bool,
+}
+
+/// A completed upload to copy into an output or FIFO, as taken by
+/// `File::take_stream()`
+pub struct Stream {
+    crc: u64,
+    digest: Option<Vec<u8>>,
+    target: StreamTarget,
+}
+
+enum StreamTarget {
+    Output(Box<Write + Send>),
+    Fifo(PathBuf),
+}
+
+impl Stream {
+    /// Copy the upload at `path` into the stream, if it was
+    /// checksummed as the sender said. An upload that doesn't match
+    /// is left for saving to refuse.
+    pub fn copy(self, path: &Path, crc: u64, digest: Option<&[u8]>) -> Result<()> {
+        if crc != self.crc || (self.digest.is_some() && digest != self.digest.as_ref().map(|d| &d[..])) {
+            return Ok(());
+        }
+        self.target.copy_from(&mut try!(fs::File::open(path)))
+    }
+}
+
+impl StreamTarget {
+    fn copy_from<R: Read>(self, staged: &mut R) -> Result<()> {
+        match self {
+            StreamTarget::Output(mut output) => {
+                try!(io::copy(staged, &mut output));
+                try!(output.flush());
+            },
+            StreamTarget::Fifo(path) => {
+                let mut fifo = try!(open_fifo(&path));
+                try!(io::copy(staged, &mut fifo));
+            },
+        }
+        Ok(())
+    }
 }
 
 impl File {
@@ -40,7 +233,7 @@ impl File {
         let mut buf = path.as_ref().to_owned();
 
         loop {
-            buf.set_file_name(&format!(".{}{}", path.as_ref().file_name().unwrap().to_str().unwrap(), counter));
+            buf.set_file_name(&staging_name(path.as_ref().file_name().unwrap(), counter));
 
             if !buf.exists() {
                 return buf;
@@ -50,60 +243,235 @@ impl File {
         }
     }
 
-    fn calc_crc(mut fh: RefMut<fs::File>) -> Result<u64> {
-        let mut buf = [0; 1024];
-        let mut digest = crc64::Digest::new(crc64::ECMA);
+    // A temporary name for `path` in `dir`, or beside `path` if no
+    // directory is given
+    fn staging_filename(path: &Path, dir: Option<&Path>) -> PathBuf {
+        match dir {
+            Some(dir) => Self::temporary_filename(dir.join(path.file_name().unwrap())),
+            None => Self::temporary_filename(path),
+        }
+    }
+
+    // Create the temporary file for `path`, as `staging_filename()`
+    // places it. Names are created exclusively, so no two uploads
+    // ever share one.
+    fn create_staging_file(path: &Path, dir: Option<&Path>, names: StagingNames) -> Result<(PathBuf, fs::File)> {
+        let name = path.file_name().unwrap();
+        let mut upload_path = dir.map_or(path.to_owned(), |d| d.join(name));
+        try!(create_dir_all(upload_path.parent().unwrap()));
+
+        let mut counter: u16 = 0;
+        loop {
+            match names {
+                StagingNames::Hidden => upload_path.set_file_name(&staging_name(name, counter)),
+                StagingNames::Random => upload_path.set_file_name(&append_name(name, &format!(".{}.part", try!(random_suffix())))),
+            }
+            match fs::OpenOptions::new().create_new(true).read(true).write(true).open(&upload_path) {
+                Ok(fh) => return Ok((upload_path, fh)),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && counter < u16::max_value() => counter += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn calc_crc<R: Read + Seek>(fh: &mut R) -> Result<u64> {
+        Self::calc_crc_range(fh, 0, u64::max_value())
+    }
+
+    // A range hashes the same as a file holding only those bytes
+    fn calc_crc_range<R: Read + Seek>(fh: &mut R, offset: u64, len: u64) -> Result<u64> {
+        hash::hash_range(fh, offset, len, None).map(|(crc, _)| crc)
+    }
+
+    /// Calculate the CRC of the file at a path
+    pub fn checksum<P: AsRef<Path>>(path: P) -> Result<u64> {
+        let mut fh = try!(fs::File::open(path));
+        Self::calc_crc(&mut fh)
+    }
 
-        try!(fh.seek(SeekFrom::Start(0)));
-        while try!(fh.read(&mut buf)) > 0 {
-            digest.write(&buf);
+    /// Checksum each chunk of the file at `path` that a file of
+    /// `size` bytes would share with it, for a client to compare
+    /// against its own chunks. There are none if no regular file is
+    /// at `path`.
+    pub fn chunk_hashes<P: AsRef<Path>>(path: P, size: u64, chunk_size: u64) -> Result<Vec<u64>> {
+        let mut fh = match fs::File::open(path) {
+            Ok(fh) => fh,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let meta = try!(fh.metadata());
+        if !meta.is_file() || chunk_size == 0 {
+            return Ok(Vec::new());
         }
 
-        Ok(digest.sum64())
+        let layout = Layout::new(size, chunk_size);
+        let mut hashes = Vec::new();
+        for index in 0..layout.count() {
+            if layout.offset(index) + layout.len(index) > meta.len() {
+                break;
+            }
+            hashes.push(try!(Self::calc_crc_range(&mut fh, layout.offset(index), layout.len(index))));
+        }
+        Ok(hashes)
     }
 
     /// Open a local file for sending
     pub fn open<P: AsRef<Path>>(path: P, options: Option<&[Options]>) -> Result<File> {
+        Self::open_path(path.as_ref(), options, None)
+    }
+
+    /// As `open()`, but trusting `crc` as the checksum of the file
+    /// (or of its `Range`) rather than reading it all to find out.
+    /// A wrong CRC fails the transfer once the server has checked
+    /// it. With a `Hash` option or a signing key, the file is still
+    /// read in full for its digest; see `open_with_known_digest()`.
+    pub fn open_with_known_crc<P: AsRef<Path>>(path: P, crc: u64, options: Option<&[Options]>) -> Result<File> {
+        Self::open_path(path.as_ref(), options, Some((crc, None)))
+    }
+
+    /// As `open_with_known_crc()`, also trusting `digest` as the
+    /// digest for the `Hash` option or signing key, so the file is
+    /// only read for its chunks. The server is told both before the
+    /// first chunk, so neither can be worked out while they're sent.
+    pub fn open_with_known_digest<P: AsRef<Path>>(path: P, crc: u64, digest: Vec<u8>, options: Option<&[Options]>) -> Result<File> {
+        Self::open_path(path.as_ref(), options, Some((crc, Some(digest))))
+    }
+
+    fn open_path(path: &Path, options: Option<&[Options]>, known: Option<(u64, Option<Vec<u8>>)>) -> Result<File> {
+        // A symlink is sent as its target, with no contents
+        if options.map_or(false, |opts| opts.iter().any(|opt| match opt {
+            &Options::PreserveSymlinks => true,
+            _ => false,
+        })) {
+            let meta = try!(fs::symlink_metadata(&path).or(Err(Error::InvalidFilePath)));
+            if meta.file_type().is_symlink() {
+                let target = try!(fs::read_link(&path));
+                let target = try!(target.to_str().ok_or(Error::InvalidFilePath)).to_string();
+                let mut file = try!(Self::from_reader(io::Cursor::new(Vec::new()), options));
+                file.options.symlink = Some(target);
+                return Ok(file);
+            }
+        }
+
         // Check file exists
-        if !path.as_ref().exists() || !path.as_ref().is_file() {
+        let meta = try!(fs::metadata(&path).or(Err(Error::InvalidFilePath)));
+        if meta.is_dir() {
             return Err(Error::InvalidFilePath);
         }
+        if !meta.is_file() {
+            return Err(Error::SpecialFile);
+        }
 
         let fh = try!(fs::File::open(&path));
-        Self::open_file(fh, options)
+        Self::open_handle(Handle::File(fh), meta.len(), attrs::mtime(&meta), options, known)
     }
 
     /// Wrap a local file for sending
     pub fn open_file(fh: fs::File, options: Option<&[Options]>) -> Result<File> {
+        // FIFOs, devices and sockets can't be chunked or checksummed
+        // like a regular file
         let meta = try!(fh.metadata());
-        let fh = Rc::new(RefCell::new(fh));
-        let crc = try!(Self::calc_crc(fh.borrow_mut()));
+        if !meta.is_file() {
+            return Err(Error::SpecialFile);
+        }
+        Self::open_handle(Handle::File(fh), meta.len(), attrs::mtime(&meta), options, None)
+    }
+
+    /// Wrap any seekable reader for sending, e.g. a `Cursor` over data
+    /// generated in memory, without writing it to disk first. Its
+    /// length is found by seeking to the end.
+    pub fn from_reader<R: Read + Seek + Send + 'static>(mut reader: R, options: Option<&[Options]>) -> Result<File> {
+        let len = try!(reader.seek(SeekFrom::End(0)));
+        Self::open_handle(Handle::Reader(Box::new(reader)), len, None, options, None)
+    }
+
+    fn open_handle(fh: Handle, len: u64, mtime: Option<(u64, u32)>, options: Option<&[Options]>, known: Option<(u64, Option<Vec<u8>>)>) -> Result<File> {
+        let mut file_options = FileOptions::new(options);
+        let (offset, size) = match file_options.range {
+            Some((offset, range_len)) if offset.checked_add(range_len).map_or(true, |end| end > len) => return Err(Error::InvalidFileOpts),
+            Some(range) => range,
+            None => (0, len),
+        };
+
+        let fh = Arc::new(Mutex::new(fh));
+        let hash_start = Instant::now();
+        let algorithm = digest_algorithm(options);
+        let (crc, digest) = match known {
+            Some((crc, _)) if algorithm.is_none() => (crc, None),
+            Some((crc, Some(digest))) => (crc, Some(digest)),
+            _ => try!(hash::hash_range(&mut *fh.lock().unwrap(), offset, size, algorithm)),
+        };
+        file_options.hash = algorithm.and_then(|a| digest.map(|d| (a, d)));
+        let hashing = hash_start.elapsed();
 
         let mut file = File {
             fh: fh.clone(),
             path: None,
             upload_path: None,
-            size: meta.len(),
+            size: size,
             crc: crc,
-            chunks: HashMap::new(),
+            chunks: ChunkSet::new(0),
+            queued: 0,
             chunk_error_cnt: 0,
             chunk_size: CHUNK_SIZE,
-            options: FileOptions::new(options),
+            layout: Layout::window(offset, size, CHUNK_SIZE),
+            adapt: None,
+            adapt_streak: 0,
+            resize: None,
+            options: file_options,
+            compat: Compat::Auto,
+            codec: WireCodec::Json,
+            protocol: None,
+            started: Instant::now(),
+            timings: PhaseTimes { hashing: hashing, ..PhaseTimes::default() },
+            sink_sock: None,
+            stats: TransferStats::default(),
+            unsent: ChunkSet::new(0),
+            unacked: ChunkSet::new(0),
+            bytes_acked: 0,
+            read_ahead: ReadAhead::default(),
+            signing_key: None,
+            staging: None,
+            retry: RetryPolicy::default(),
+            deadline: None,
+            idle_timeout: None,
+            follow: None,
+            heartbeat: None,
+            stripes: Vec::new(),
+            stripe_threads: None,
+            output: None,
+            stream_fifos: false,
+            copy_path: None,
+            streamed: false,
+            manifest: None,
+            manifest_lag: 0,
+            manifest_interval: MANIFEST_INTERVAL,
         };
 
+        if let Some(options) = options {
+            for opt in options {
+                match opt {
+                    &Options::Codec(codec) => file.codec = codec,
+                    &Options::Compat(compat) => file.compat = compat,
+                    &Options::Deadline(millis) => file.deadline = Some(Duration::from_millis(millis as u64)),
+                    &Options::Follow(millis) => file.follow = Some(Duration::from_millis(millis as u64)),
+                    &Options::IdleTimeout(millis) => file.idle_timeout = Some(Duration::from_millis(millis as u64)),
+                    &Options::PreserveTimestamps => file.options.mtime = mtime,
+                    #[cfg(feature = "signing")]
+                    &Options::SigningKey(ref key) => file.signing_key = Some(key.clone()),
+                    _ => (),
+                }
+            }
+        }
+
         if let Some(size) = file.options.chunk_size {
             file.chunk_size = size;
+            file.layout = Layout::window(file.offset(), file.size, size);
         }
 
-        // Create chunks
-        let mut size_ctr = file.size as i64;
-        let mut index = 0;
-        while size_ctr > 0 {
-            let chunk = Chunk::new(fh.clone(), index);
-            file.chunks.insert(index, chunk);
-
-            index += 1;
-            size_ctr -= file.chunk_size as i64;
+        // Where an appended file ends on the server isn't known, so
+        // neither is where its growth belongs
+        if file.follow.is_some() && file.options.append == Some(true) {
+            return Err(Error::InvalidFileOpts);
         }
 
         Ok(file)
@@ -116,16 +484,221 @@ impl File {
                                   size: u64,
                                   crc: u64,
                                   chunk_size: u64,
-                                  options: &str) -> Result<File> {
+                                  options: &[u8]) -> Result<File> {
+        Self::create_delta(arbitrator, router_id, path, size, crc, chunk_size, options, &[])
+    }
+
+    /// Create a new file container from path for receiving. The
+    /// `unchanged` chunks are copied from the file already at `path`
+    /// rather than received.
+    pub fn create_delta<P: AsRef<Path>>(arbitrator: &mut Arbitrator,
+                                        router_id: &[u8],
+                                        path: P,
+                                        size: u64,
+                                        crc: u64,
+                                        chunk_size: u64,
+                                        options: &[u8],
+                                        unchanged: &[u64]) -> Result<File> {
+        Self::create_staged(arbitrator, router_id, path, size, crc, chunk_size, options, unchanged, None, StagingNames::Hidden)
+    }
 
-        let upload_path = Self::temporary_filename(path.as_ref());
+    /// As `create_delta()`, but with the temporary file in
+    /// `staging_dir` rather than beside `path`, if given, and named
+    /// as `names` says. The directory may be on another filesystem.
+    pub fn create_staged<P: AsRef<Path>>(arbitrator: &mut Arbitrator,
+                                         router_id: &[u8],
+                                         path: P,
+                                         size: u64,
+                                         crc: u64,
+                                         chunk_size: u64,
+                                         options: &[u8],
+                                         unchanged: &[u64],
+                                         staging_dir: Option<&Path>,
+                                         names: StagingNames) -> Result<File> {
+
+        // A patch is written into the existing file, so there must be
+        // one to write into. Appending creates it if need be.
+        let file_options = try!(FileOptions::decode(options));
+        if file_options.backup_versions.map_or(false, |v| v > MAX_BACKUP_VERSIONS) {
+            return Err(Error::InvalidFileOpts);
+        }
+        let patch_offset = if file_options.append == Some(true) { None } else { file_options.patch_offset() };
+        if let Some(offset) = patch_offset {
+            let meta = try!(fs::metadata(path.as_ref()).or(Err(Error::InvalidFilePath)));
+            if !meta.is_file() {
+                return Err(Error::InvalidFilePath);
+            }
+            if offset > meta.len() {
+                return Err(Error::InvalidFileOpts);
+            }
+        }
+
+        // Unchanged chunks must be whole within the existing file
+        let layout = Layout::new(size, chunk_size);
+        if !unchanged.is_empty() {
+            let existing_len = try!(fs::metadata(path.as_ref()).or(Err(Error::InvalidFilePath))).len();
+            if unchanged.iter().any(|&i| i >= layout.count() || layout.offset(i) + layout.len(i) > existing_len) {
+                return Err(Error::ChunkIndex);
+            }
+        }
 
         // Create file
         try!(create_dir_all(path.as_ref().parent().unwrap()));
-        let fh = try!(fs::OpenOptions::new().create(true).read(true).write(true).open(&upload_path));
+        let (upload_path, mut fh) = try!(Self::create_staging_file(path.as_ref(), staging_dir, names));
         try!(fh.set_len(size as u64));
 
-        Self::create_file(arbitrator, router_id, fh, &upload_path, path, size, crc, chunk_size, options)
+        let mut missing = ChunkSet::new(layout.count());
+        if !unchanged.is_empty() {
+            let mut existing = try!(fs::File::open(path.as_ref()));
+            let mut buf = Vec::new();
+
+            for &index in unchanged {
+                buf.resize(layout.len(index) as usize, 0);
+                try!(existing.seek(SeekFrom::Start(layout.offset(index))));
+                try!(existing.read_exact(&mut buf));
+                try!(fh.seek(SeekFrom::Start(layout.offset(index))));
+                try!(fh.write_all(&buf));
+                missing.remove(index);
+            }
+        }
+
+        let mut file = try!(Self::receive(arbitrator, router_id, fh, &upload_path, path.as_ref(), size, crc, chunk_size, options, missing));
+
+        // A restarted server uses the manifest to resume the upload
+        file.manifest = Some(Manifest {
+            version: STAGING_VERSION,
+            protocol: None,
+            path: path.as_ref().to_string_lossy().into_owned(),
+            size: size,
+            crc: crc,
+            chunk_size: chunk_size,
+            options: options.to_vec(),
+            missing: file.chunks.ranges().to_vec(),
+            router_id: Some(router_id.to_vec()),
+            transfer_id: None,
+            client: None,
+        });
+        try!(file.save_manifest());
+
+        Ok(file)
+    }
+
+    /// Resume receiving into a temporary file left by an earlier
+    /// server, as described by its manifest. Only the chunks that
+    /// the manifest lists as missing are requested.
+    pub fn resume<P: AsRef<Path>>(arbitrator: &mut Arbitrator, router_id: &[u8], upload_path: P, mut manifest: Manifest) -> Result<File> {
+        if manifest.chunk_size == 0 {
+            return Err(Error::ChunkSize);
+        }
+
+        let count = Layout::new(manifest.size, manifest.chunk_size).count();
+        let missing = try!(ChunkSet::from_ranges(&manifest.missing, count).ok_or(Error::InvalidFileOpts));
+
+        let fh = try!(fs::OpenOptions::new().read(true).write(true).open(upload_path.as_ref()));
+        if try!(fh.metadata()).len() != manifest.size {
+            return Err(Error::FileSize);
+        }
+
+        let mut file = try!(Self::receive(arbitrator, router_id, fh, upload_path, &manifest.path, manifest.size, manifest.crc, manifest.chunk_size, &manifest.options, missing));
+        file.protocol = manifest.protocol;
+        manifest.router_id = Some(router_id.to_vec());
+        file.manifest = Some(manifest);
+        Ok(file)
+    }
+
+    /// Serve a local file to a client that is downloading it. Its
+    /// chunks are queued with the Arbitrator like an upload's, and
+    /// `sink()` is called as the client acknowledges them. The caller
+    /// gives the file's CRC, as checksumming a large file takes a
+    /// while.
+    pub fn serve<P: AsRef<Path>>(arbitrator: &mut Arbitrator,
+                                 router_id: &[u8],
+                                 path: P,
+                                 chunk_size: u64,
+                                 crc: u64,
+                                 options: &[u8]) -> Result<File> {
+
+        let meta = try!(fs::metadata(&path).or(Err(Error::InvalidFilePath)));
+        if meta.is_dir() {
+            return Err(Error::InvalidFilePath);
+        }
+        if !meta.is_file() {
+            return Err(Error::SpecialFile);
+        }
+        if chunk_size == 0 {
+            return Err(Error::ChunkSize);
+        }
+
+        let fh = try!(fs::File::open(&path));
+        let count = Layout::new(meta.len(), chunk_size).count();
+        let mut file = try!(Self::receive(arbitrator, router_id, fh, &path, &path, meta.len(), crc, chunk_size, options, ChunkSet::new(count)));
+
+        // Nothing is staged, and the file must outlive the download
+        file.upload_path = None;
+        Ok(file)
+    }
+
+    /// Download `remote_path` from the server to `local_path`. Chunks
+    /// are written to a temporary file, which replaces `local_path`
+    /// once every chunk has arrived and the checksum matches.
+    pub fn fetch<P: AsRef<Path>, Q: AsRef<Path>>(sock: &mut ZSock, remote_path: P, local_path: Q) -> Result<()> {
+        let msg = ZMsg::new();
+        try!(msg.addstr("GET"));
+        try!(msg.addbytes(&try!(protocol::encode_path(remote_path.as_ref()))));
+        try!(msg.addbytes(&try!(FileOptions::new(None).encode(WireCodec::Json))));
+        try!(msg.send(sock));
+
+        let reply = try!(ZMsg::recv(sock));
+        match try!(reply.popstr().and_then(|s| s.ok()).ok_or(Error::InvalidReply)).as_ref() {
+            "FILE" => (),
+            "Err" => return Err(protocol::pop_err(&reply)),
+            "BUSY" => {
+                let secs = try!(reply.popstr().and_then(|s| s.ok()).ok_or(Error::InvalidReply));
+                return Err(Error::Busy(try!(secs.parse::<u32>().or(Err(Error::InvalidReply)))));
+            },
+            _ => return Err(Error::InvalidReply),
+        }
+
+        let size = try!(protocol::pop_u64(&reply, false).ok_or(Error::InvalidReply));
+        let crc = try!(protocol::pop_u64(&reply, false).ok_or(Error::InvalidReply));
+        let chunk_size = try!(protocol::pop_u64(&reply, false).ok_or(Error::InvalidReply));
+        let version = try!(protocol::pop_u64(&reply, false).ok_or(Error::InvalidReply));
+        if chunk_size == 0 {
+            return Err(Error::InvalidReply);
+        }
+
+        // The temporary file is created at the size the server gave, so
+        // one that won't fit is refused before it can fill the disk
+        if size > try!(disk::available(local_path.as_ref())) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Not enough free disk space for the download").into());
+        }
+
+        let (upload_path, mut fh) = try!(Self::create_staging_file(local_path.as_ref(), None, StagingNames::Hidden));
+        if let Err(e) = fh.set_len(size) {
+            let _ = fs::remove_file(&upload_path);
+            return Err(e.into());
+        }
+
+        let layout = Layout::new(size, chunk_size);
+        let version = Some(version as u32);
+        let result = recv_download(sock, &mut fh, &layout, protocol::binary_ints(version), protocol::sparse_chunks(version)).and_then(|_| {
+            if try!(Self::checksum(&upload_path)) == crc {
+                Ok(())
+            } else {
+                Err(Error::FailChecksum)
+            }
+        });
+
+        match result {
+            Ok(()) => {
+                try!(rename(&upload_path, local_path));
+                Ok(())
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&upload_path);
+                Err(e)
+            },
+        }
     }
 
     /// Create a new file container for receiving
@@ -137,245 +710,2704 @@ impl File {
                                                        size: u64,
                                                        crc: u64,
                                                        chunk_size: u64,
-                                                       options: &str) -> Result<File> {
+                                                       options: &[u8]) -> Result<File> {
 
-        let fh = Rc::new(RefCell::new(fh));
+        let count = Layout::new(size, chunk_size).count();
+        Self::receive(arbitrator, router_id, fh, fh_path, path, size, crc, chunk_size, options, ChunkSet::new(count))
+    }
 
-        // Split size into chunks and queue
-        let mut chunks = HashMap::new();
-        let mut size_ctr = size as i64;
-        let mut index = 0;
-        while size_ctr > 0 {
-            let chunk = Chunk::new(fh.clone(), index);
-            try!(arbitrator.queue(&chunk, router_id));
-            chunks.insert(index, chunk);
+    // Create a file container that receives the `missing` chunks
+    fn receive<P: AsRef<Path>, Q: AsRef<Path>>(arbitrator: &mut Arbitrator,
+                                               router_id: &[u8],
+                                               fh: fs::File,
+                                               fh_path: P,
+                                               path: Q,
+                                               size: u64,
+                                               crc: u64,
+                                               chunk_size: u64,
+                                               options: &[u8],
+                                               missing: ChunkSet) -> Result<File> {
 
-            index += 1;
-            size_ctr -= chunk_size as i64;
-        }
+        let fh = Arc::new(Mutex::new(Handle::File(fh)));
 
-        // Decode options
+        // Decoded first, so a bad request leaves nothing queued
+        let codec = WireCodec::detect(options);
         let options = try!(FileOptions::decode(options));
 
+        // Only a window of chunks is queued up front. The rest are
+        // queued as earlier chunks complete.
+        let layout = Layout::new(size, chunk_size);
+        let mut window = Vec::new();
+        for &(start, end) in missing.ranges() {
+            let take = cmp::min(end - start, QUEUE_WINDOW - window.len() as u64);
+            window.extend((start..start + take).map(|i| Chunk::new(fh.clone(), i)));
+        }
+        let queued = window.last().map_or(0, |c: &Chunk| c.get_index() + 1);
+        arbitrator.set_remaining(router_id, missing.len());
+        try!(arbitrator.queue_many(window.iter().map(|c| (c, layout.len(c.get_index()))), router_id));
+
         Ok(File {
             fh: fh,
             path: Some(path.as_ref().to_owned()),
             upload_path: Some(fh_path.as_ref().to_owned()),
             size: size,
             crc: crc,
-            chunks: chunks,
+            chunks: missing,
+            queued: queued,
             chunk_error_cnt: 0,
             chunk_size: chunk_size,
+            layout: layout,
+            adapt: None,
+            adapt_streak: 0,
+            resize: None,
             options: options,
+            compat: Compat::Auto,
+            codec: codec,
+            protocol: None,
+            started: Instant::now(),
+            timings: PhaseTimes::default(),
+            sink_sock: None,
+            stats: TransferStats::default(),
+            unsent: ChunkSet::new(0),
+            unacked: ChunkSet::new(0),
+            bytes_acked: 0,
+            read_ahead: ReadAhead::default(),
+            signing_key: None,
+            staging: None,
+            retry: RetryPolicy::default(),
+            deadline: None,
+            idle_timeout: None,
+            follow: None,
+            heartbeat: None,
+            stripes: Vec::new(),
+            stripe_threads: None,
+            output: None,
+            stream_fifos: false,
+            copy_path: None,
+            streamed: false,
+            manifest: None,
+            manifest_lag: 0,
+            manifest_interval: MANIFEST_INTERVAL,
         })
     }
 
     pub fn send<P: AsRef<Path>>(&mut self, sock: &mut ZSock, remote_path: P) -> Result<()> {
-        let msg = ZMsg::new();
-        try!(msg.addstr("NEW"));
-        try!(msg.addstr(remote_path.as_ref().to_str().unwrap()));
-        let meta = try!(self.fh.borrow().metadata());
-        try!(msg.addstr(&meta.len().to_string()));
-        try!(msg.addstr(&self.crc.to_string()));
-        try!(msg.addstr(&self.chunk_size.to_string()));
-        try!(msg.addstr(&try!(self.options.encode())));
-        try!(msg.send(sock));
+        self.send_with_progress(sock, remote_path, |_, _, _| ())
+    }
+
+    /// Send the file, calling `progress` with the chunks the server
+    /// has written so far, the total number of chunks and the bytes
+    /// written whenever it acknowledges chunks. Older servers don't
+    /// acknowledge them, so with those it's called as chunks are sent.
+    pub fn send_with_progress<P, F>(&mut self, sock: &mut ZSock, remote_path: P, mut progress: F) -> Result<()>
+        where P: AsRef<Path>,
+              F: FnMut(u64, u64, u64)
+    {
+        try!(self.send_once(sock, remote_path.as_ref(), &mut progress));
+
+        let interval = match self.follow {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
 
+        let start = Instant::now();
         loop {
-            let msg = try!(ZMsg::recv(sock));
+            if self.deadline.map_or(false, |d| start.elapsed() + interval >= d) {
+                return Ok(());
+            }
+            sleep(interval);
 
-            match try!(msg.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
-                "Ok" => return Ok(()),
-                "Err" => return Err(Error::UploadError(msg.popstr().unwrap().unwrap())),
-                "CHUNK" => {
-                    let index = msg.popstr().unwrap().unwrap().parse::<u64>().unwrap();
-                    match self.chunks.get_mut(&index) {
-                        Some(chunk) => try!(chunk.send(sock, self.chunk_size, self.size)),
-                        None => return Err(Error::ChunkIndex),
-                    }
-                },
-                _ => unreachable!(),
+            if try!(self.follow_growth()) {
+                try!(self.send_once(sock, remote_path.as_ref(), &mut progress));
             }
         }
     }
 
-    pub fn recv(&mut self, router_id: &[u8], index: u64, chunk_data: Vec<u8>) -> Result<()> {
-        let chunk = try!(self.chunks.get_mut(&index).ok_or(Error::ChunkIndex));
-        try!(chunk.recv(router_id, chunk_data, self.chunk_size));
-
-        Ok(())
-    }
-
-    pub fn sink(&mut self, arbitrator: &mut Arbitrator, router_id: &[u8], index: u64, success: bool) -> Result<()> {
-        if success {
-            {
-                let chunk = try!(self.chunks.get_mut(&index).ok_or(Error::ChunkIndex));
-                try!(arbitrator.release(chunk, router_id));
-            }
-            self.chunks.remove(&index);
-        } else if self.chunk_error_cnt < MAX_CHUNK_ERR {
-            let chunk = try!(self.chunks.get(&index).ok_or(Error::ChunkIndex));
-            try!(arbitrator.queue(chunk, router_id));
-            self.chunk_error_cnt += 1;
+    // Point the file at whatever was appended after the bytes last
+    // sent, to be written just past them in the server's copy. False
+    // if nothing was.
+    fn follow_growth(&mut self) -> Result<bool> {
+        let end = self.offset() + self.size;
+        let len = try!(self.fh.lock().unwrap().seek(SeekFrom::End(0)));
+        if len < end {
+            return Err(Error::Truncated);
+        }
+        if len == end {
+            return Ok(false);
         }
 
-        Ok(())
-    }
+        // Written at an offset rather than appended, so it lands
+        // where it belongs even if the server's copy has changed
+        // length, or fails if that copy has shrunk
+        let at = self.options.patch_offset().unwrap_or(0) + self.size;
 
-    pub fn is_complete(&self) -> bool {
-        self.chunks.len() == 0
+        let algorithm = self.hash_algorithm();
+        let (crc, digest) = try!(hash::hash_range(&mut *self.fh.lock().unwrap(), end, len - end, algorithm));
+        self.size = len - end;
+        self.crc = crc;
+        self.options.range = Some((end, len - end));
+        self.options.append = None;
+        self.options.write_at = Some(at);
+        self.options.hash = algorithm.and_then(|a| digest.map(|d| (a, d)));
+        Ok(true)
     }
 
-    pub fn is_error(&self) -> bool {
-        self.chunk_error_cnt >= MAX_CHUNK_ERR
-    }
+    fn send_once(&mut self, sock: &mut ZSock, remote_path: &Path, progress: &mut FnMut(u64, u64, u64)) -> Result<()> {
+        let path = try!(protocol::encode_path(remote_path));
 
-    pub fn save(&self) -> Result<()> {
-        if self.crc != try!(Self::calc_crc(self.fh.borrow_mut())) {
-            return Err(Error::FailChecksum);
+        // Signed as the server will see it
+        #[cfg(feature = "signing")]
+        {
+            if let Some(ref key) = self.signing_key {
+                let signed = try!(str::from_utf8(&path).or(Err(Error::InvalidFilePath)));
+                let hash = try!(self.options.hash.as_ref().ok_or(Error::SigningKey));
+                self.options.signature = Some(try!(signing::sign(key, signed, self.size, self.crc, hash)));
+            }
         }
 
-        let path = self.path.as_ref().unwrap();
-        let upload_path = self.upload_path.as_ref().unwrap();
+        let msg = ZMsg::new();
+        try!(msg.addstr("NEW"));
+        try!(msg.addbytes(&path));
+        try!(msg.addstr(&self.size.to_string()));
+        try!(msg.addstr(&self.crc.to_string()));
+        try!(msg.addstr(&self.chunk_size.to_string()));
+        try!(msg.addbytes(&try!(self.options.encode(self.codec))));
+        try!(msg.send(sock));
 
-        // Backup existing file
-        if self.options.backup_existing.is_some() && self.fh.borrow().metadata().is_ok() {
-            let suffix = self.options.backup_existing.as_ref().unwrap();
-            let file_name = path.file_name().unwrap().to_str().unwrap();
-            let mut backup_path = path.clone();
-            backup_path.set_file_name(&format!("{}{}", file_name, suffix));
-            try!(rename(path, backup_path));
+        self.protocol = None;
+        self.heartbeat = None;
+        self.layout = Layout::window(self.offset(), self.size, self.chunk_size);
+        self.stats = TransferStats::default();
+        self.unsent = ChunkSet::new(self.layout.count());
+        self.unacked = ChunkSet::new(self.layout.count());
+        self.bytes_acked = 0;
+        self.read_ahead = ReadAhead::default();
+
+        // A timeout is only set while sending, so the socket gets
+        // back whatever its owner had set afterwards
+        let rcvtimeo = sock.rcvtimeo();
+        let start = Instant::now();
+        let result = self.exchange(sock, start, progress);
+        self.stop_stripes();
+        sock.set_rcvtimeo(rcvtimeo);
+
+        let elapsed = start.elapsed();
+        self.stats.elapsed = micros(elapsed) / 1000;
+        if micros(elapsed) > 0 {
+            self.stats.throughput = self.stats.bytes_sent * 1_000_000 / micros(elapsed);
         }
 
-        try!(rename(upload_path, path));
-        Ok(())
+        result
     }
-}
 
-pub enum Options {
-    BackupExisting(String),
-    ChunkSize(u64),
-}
+    /// Send the file, retrying failures allowed by `policy`. A busy
+    /// server's requested delay is respected if it is longer than the
+    /// policy's.
+    pub fn send_retry<P: AsRef<Path>>(&mut self, sock: &mut ZSock, remote_path: P, policy: &RetryPolicy) -> Result<()> {
+        let mut retries = 0;
 
-#[derive(RustcDecodable, RustcEncodable)]
-struct FileOptions {
-    backup_existing: Option<String>,
-    chunk_size: Option<u64>,
-}
+        loop {
+            let err = match self.send(sock, remote_path.as_ref()) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
 
-impl FileOptions {
-    fn new(options: Option<&[Options]>) -> FileOptions {
-        let mut opts = FileOptions {
-            backup_existing: None,
-            chunk_size: None,
-        };
+            if !policy.should_retry(retries, &err) {
+                return Err(err);
+            }
 
-        if let Some(options) = options {
-            for opt in options {
-                match opt {
-                    &Options::BackupExisting(ref suffix) => opts.backup_existing = Some(suffix.to_string()),
-                    &Options::ChunkSize(size) => opts.chunk_size = Some(size),
-                }
+            let mut delay = policy.delay(retries);
+            if let Error::Busy(secs) = err {
+                delay = cmp::max(delay, Duration::new(secs as u64, 0));
             }
+            sleep(delay);
+            retries += 1;
         }
+    }
 
-        opts
+    /// Spread the next send's chunks across another connection to
+    /// the same server as well as the one it's sent on, if the server
+    /// supports it. The connection is closed once the send is over.
+    /// Progress only counts chunks sent on the first connection,
+    /// though the stats count them all.
+    pub fn add_stripe(&mut self, sock: ZSock) {
+        self.stripes.push(sock);
+        self.options.stripes = Some(self.stripes.len() as u32 + 1);
     }
 
-    fn decode(encoded: &str) -> Result<FileOptions> {
-        let options = try!(json::decode(encoded));
-        Ok(options)
+    // Have each extra connection join the transfer the server just
+    // ACKed and answer the chunk requests it is sent
+    fn start_stripes(&mut self, id: u64, token: String) {
+        if self.stripes.is_empty() || !protocol::join_tokens(self.protocol) {
+            return;
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let (compression, sparse) = (self.compression(), self.sparse());
+        let mut threads = Vec::with_capacity(self.stripes.len());
+        for sock in self.stripes.drain(..) {
+            let stripe = Stripe {
+                fh: self.fh.clone(),
+                layout: self.layout.clone(),
+                binary: protocol::binary_ints(self.protocol),
+                compression: compression,
+                sparse: sparse,
+            };
+            threads.push(stripe.spawn(sock, id, token.clone(), done.clone()));
+        }
+        self.stripe_threads = Some((done, threads));
     }
 
-    fn encode(&self) -> Result<String> {
-        Ok(try!(json::encode(&self)))
+    // Stop the threads answering on extra connections, counting what
+    // they sent. A stripe that failed only lost chunks that the
+    // server asked for again.
+    fn stop_stripes(&mut self) {
+        if let Some((done, threads)) = self.stripe_threads.take() {
+            done.store(true, Ordering::SeqCst);
+            for thread in threads {
+                if let Ok(Ok((chunks, bytes))) = thread.join() {
+                    self.stats.chunks_sent += chunks;
+                    self.stats.bytes_sent += bytes;
+                }
+            }
+        }
+
+        self.stripes.clear();
+        self.options.stripes = None;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use arbitrator::Arbitrator;
-    use czmq::{ZMsg, ZSock, SocketType, ZSys};
-    use std::cell::RefCell;
-    use std::fs;
-    use std::io::Write;
-    use std::path::Path;
-    use std::thread::spawn;
-    use super::*;
-    use super::FileOptions;
-    use tempdir::TempDir;
+    /// Cancel this file's upload, e.g. after `send()` gave up part
+    /// way. The server deletes what it has received so far. Chunk
+    /// requests it sent before seeing the cancellation are discarded.
+    pub fn cancel(&mut self, sock: &mut ZSock) -> Result<()> {
+        try!(sock.send_str("CANCEL"));
 
-    #[test]
-    fn test_temporary_filename() {
-        assert_eq!(File::temporary_filename("/path/to/file"), Path::new("/path/to/.file0"));
+        loop {
+            let msg = try!(ZMsg::recv(sock));
+            match try!(msg.popstr().and_then(|s| s.ok()).ok_or(Error::InvalidReply)).as_ref() {
+                "CANCELLED" => return Ok(()),
+                // A server that can't cancel rejects the request
+                "Err" => return Err(protocol::pop_err(&msg)),
+                _ => (),
+            }
+        }
+    }
 
-        let tempdir = TempDir::new("file_test_temporary_filename").unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        fs::File::create(&format!("{}/.file0", path)).unwrap();
+    /// Sync the file to disk before it's reported as saved, whether
+    /// or not the sender asked
+    pub fn set_durable(&mut self) {
+        self.options.durable = Some(true);
+    }
 
-        assert_eq!(File::temporary_filename(format!("{}/file", path)), Path::new(&format!("{}/.file1", path)));
+    /// Write the completed file into a FIFO at its destination,
+    /// rather than refusing the upload with `Error::SpecialFile`
+    pub fn set_stream_fifos(&mut self) {
+        self.stream_fifos = true;
     }
 
-    #[test]
-    fn test_calc_crc() {
-        let tempdir = TempDir::new("file_test_temporary_filename").unwrap();
-        let path = format!("{}/.file0", tempdir.path().to_str().unwrap());
-        let fh = RefCell::new(fs::OpenOptions::new().create(true).read(true).write(true).open(&path).unwrap());
-        let mut file = fh.borrow_mut();
-        file.write_all(b"12345").unwrap();
+    /// Save the staging manifest after every `chunks` chunks are
+    /// written, rather than every 64. The fewer, the less a restart
+    /// requests again.
+    pub fn set_manifest_interval(&mut self, chunks: u64) {
+        self.manifest_interval = cmp::max(chunks, 1);
+    }
 
-        assert_eq!(File::calc_crc(file).unwrap(), 16742651521893322043);
+    /// Record the ID the server gave this upload in its staging
+    /// manifest, so a restarted server resumes it under the same ID
+    pub fn set_transfer_id(&mut self, id: u64) -> Result<()> {
+        match self.manifest {
+            Some(ref mut manifest) => manifest.transfer_id = Some(id),
+            None => return Ok(()),
+        }
+        self.save_manifest()
     }
 
-    #[test]
-    fn test_create_recv() {
-        let tempdir = TempDir::new("file_test_new_recv").unwrap();
-        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
-        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1, 0, 1, "{}").unwrap();
-        assert!(file.recv(&Vec::new(), 0, Vec::new()).is_ok());
+    /// Record who is sending this upload in its staging manifest, so
+    /// a restarted server only lets the same client carry on
+    pub fn set_client(&mut self, client: &[u8]) -> Result<()> {
+        match self.manifest {
+            Some(ref mut manifest) if manifest.client.as_ref().map_or(true, |c| &c[..] != client) => manifest.client = Some(client.to_vec()),
+            _ => return Ok(()),
+        }
+        self.save_manifest()
     }
 
-    #[test]
-    fn test_open_send() {
-        ZSys::init();
+    /// How failed chunks are retried when receiving. A client that
+    /// asked for fewer retries gets them.
+    pub fn set_retry_policy(&mut self, mut policy: RetryPolicy) {
+        if let Some(max) = self.options.max_retries {
+            policy.max_retries = cmp::min(policy.max_retries, max as u32);
+        }
+        self.retry = policy;
+    }
 
-        let tempdir = TempDir::new("file_test_new_recv").unwrap();
-        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
-        let remote_path = format!("{}/remote_file.txt", tempdir.path().to_str().unwrap());
-        let remote_path_clone = remote_path.clone();
-        let mut fs_file = fs::File::create(&local_path).unwrap();
-        fs_file.write_all("abc".as_bytes()).unwrap();
+    // Answer the server's requests until it accepts or rejects the
+    // upload
+    fn exchange(&mut self, sock: &mut ZSock, start: Instant, progress: &mut FnMut(u64, u64, u64)) -> Result<()> {
+        loop {
+            let msg = try!(self.recv_reply(sock, start));
+
+            match try!(msg.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
+                "ACK" => {
+                    let version = try!(msg.popstr().unwrap().or(Err(Error::InvalidReply)));
+                    self.protocol = Some(try!(version.parse::<u32>().or(Err(Error::InvalidReply))));
+
+                    // Older servers don't send a transfer ID
+                    let id = match msg.popstr() {
+                        Some(Ok(id)) => Some(try!(id.parse::<u64>().or(Err(Error::InvalidReply)))),
+                        _ => None,
+                    };
+                    if id.is_some() {
+                        self.stats.transfer_id = id;
+                    }
+                    let window = msg.popstr();
+
+                    if let Some(Ok(millis)) = msg.popstr() {
+                        if protocol::heartbeats(self.protocol) {
+                            let millis = try!(millis.parse::<u64>().or(Err(Error::InvalidReply)));
+                            self.heartbeat = if millis > 0 { Some(Duration::from_millis(millis)) } else { None };
+                        }
+                    }
+
+                    // The token extra connections join the transfer
+                    // with, if the server will spread its chunks
+                    if let (Some(id), Some(Ok(token))) = (id, msg.popstr()) {
+                        self.start_stripes(id, token);
+                    }
+
+                    // The number of chunks the server lets us send
+                    // ahead of its first request and each one after.
+                    // Stripes only send what they're asked for.
+                    if let Some(Ok(window)) = window {
+                        if protocol::pipelined_chunks(self.protocol) && self.stripe_threads.is_none() {
+                            let granted = try!(window.parse::<u64>().or(Err(Error::InvalidReply)));
+                            self.read_ahead.window = cmp::min(granted, self.window() as u64);
+                        }
+                    }
+                },
+                "Ok" => {
+                    // A skipped or unchanged file is reported in place
+                    // of the ACK, by a server that understood
+                    if let Some(Ok(status)) = msg.popstr() {
+                        match status.as_ref() {
+                            "SKIPPED" => {
+                                self.stats.skipped = true;
+                                return Ok(());
+                            },
+                            "UNCHANGED" => {
+                                self.stats.unchanged = true;
+                                return Ok(());
+                            },
+                            _ => (),
+                        }
+                    }
+                    try!(self.check_peer());
+                    return Ok(());
+                },
+                "EXISTS" => return Err(Error::FileExists),
+                // The server's oldest and newest accepted versions
+                "UNSUPPORTED" => {
+                    let min = try!(msg.popstr().unwrap().or(Err(Error::InvalidReply)));
+                    return Err(Error::UnsupportedVersion(try!(min.parse::<u32>().or(Err(Error::InvalidReply)))));
+                },
+                // Sent before the ACK, in place of requesting chunks
+                // the server may already have
+                "HASHES" => {
+                    let frame = try!(try!(msg.popbytes()).ok_or(Error::InvalidReply));
+                    let hashes = try!(protocol::unpack_u64s(&frame).ok_or(Error::InvalidReply));
+
+                    let reply = ZMsg::new();
+                    try!(reply.addstr("UNCHANGED"));
+                    for (index, hash) in (0..self.layout.count()).zip(hashes) {
+                        let crc = try!(Self::calc_crc_range(&mut *self.fh.lock().unwrap(), self.layout.offset(index), self.layout.len(index)));
+                        if crc == hash {
+                            try!(protocol::add_u64(&reply, index, true));
+                            self.unsent.remove(index);
+                            self.unacked.remove(index);
+                            self.stats.chunks_unchanged += 1;
+                        }
+                    }
+                    try!(reply.send(sock));
+                    self.report_progress(progress);
+                },
+                "Err" => return Err(protocol::pop_err(&msg)),
+                "BUSY" => {
+                    let secs = try!(msg.popstr().unwrap().or(Err(Error::InvalidReply)));
+                    return Err(Error::Busy(try!(secs.parse::<u32>().or(Err(Error::InvalidReply)))));
+                },
+                "CHUNK" => {
+                    try!(self.check_peer());
+                    let binary = protocol::binary_ints(self.protocol);
+                    let index = try!(protocol::pop_u64(&msg, binary).ok_or(Error::InvalidReply));
+                    // A chunk sent ahead is only asked for if the
+                    // server dropped it or it failed, so is sent again
+                    for mut chunk in try!(self.chunk_range(index, index)) {
+                        try!(chunk.send(sock, &self.layout, binary, self.compression(), self.sparse()));
+                    }
+                    self.record_sent(index, index);
+                    try!(self.send_ahead(sock, index + 1));
+                    if !protocol::acked_chunks(self.protocol) {
+                        self.report_progress(progress);
+                    }
+                },
+                "CHUNKS" => {
+                    try!(self.check_peer());
+                    let binary = protocol::binary_ints(self.protocol);
+                    let (first, last) = try!(pop_range(&msg, binary));
+
+                    let reply = ZMsg::new();
+                    try!(reply.addstr("CHUNKS"));
+                    try!(self.add_chunks(&reply, first, last, binary));
+                    try!(reply.send(sock));
+                    self.record_sent(first, last);
+                    try!(self.send_ahead(sock, last + 1));
+                    if !protocol::acked_chunks(self.protocol) {
+                        self.report_progress(progress);
+                    }
+                },
+                // Chunks the server has written
+                "RECEIVED" => {
+                    if !protocol::acked_chunks(self.protocol) {
+                        return Err(Error::InvalidReply);
+                    }
+                    let binary = protocol::binary_ints(self.protocol);
+                    while let Some(index) = protocol::pop_u64(&msg, binary) {
+                        if index >= self.layout.count() {
+                            return Err(Error::InvalidReply);
+                        }
+                        if self.unacked.remove(index) {
+                            self.bytes_acked += self.layout.len(index);
+                        }
+                    }
+                    self.report_progress(progress);
+                },
+                "RESIZE" => {
+                    if !protocol::adaptive_chunks(self.protocol) {
+                        return Err(Error::InvalidReply);
+                    }
+                    let first = try!(protocol::pop_u64(&msg, true).ok_or(Error::InvalidReply));
+                    let chunk_size = try!(protocol::pop_u64(&msg, true).ok_or(Error::InvalidReply));
+                    try!(self.layout.resize(first, chunk_size));
+                    self.unsent.set_tail(first, self.layout.count());
+                    self.unacked.set_tail(first, self.layout.count());
+                    let unacked = &self.unacked;
+                    let layout = &self.layout;
+                    self.bytes_acked = (0..first).filter(|&i| !unacked.contains(i)).map(|i| layout.len(i)).sum();
+
+                    // Chunks sent ahead would no longer line up
+                    self.read_ahead.window = 0;
+                },
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    // Wait for the server's next message, for no longer than the idle
+    // timeout or what's left of the deadline. A server that asked for
+    // heartbeats is sent PING whenever it has been quiet for one.
+    fn recv_reply(&self, sock: &mut ZSock, start: Instant) -> Result<ZMsg> {
+        let waiting = Instant::now();
+        loop {
+            let idle = self.idle_timeout.map(|i| i.checked_sub(waiting.elapsed()).unwrap_or(Duration::new(0, 0)));
+            let remaining = self.deadline.map(|d| d.checked_sub(start.elapsed()).unwrap_or(Duration::new(0, 0)));
+            let wait = match (idle, remaining) {
+                (Some(idle), Some(remaining)) => Some(cmp::min(idle, remaining)),
+                (idle, remaining) => idle.or(remaining),
+            };
+            let (timeout, ping) = match (wait, self.heartbeat) {
+                (Some(wait), Some(heartbeat)) if heartbeat < wait => (Some(heartbeat), true),
+                (None, Some(heartbeat)) => (Some(heartbeat), true),
+                (wait, _) => (wait, false),
+            };
+
+            if let Some(timeout) = timeout {
+                let millis = micros(timeout) / 1000;
+                if millis == 0 {
+                    return Err(Error::Timeout);
+                }
+                sock.set_rcvtimeo(Some(cmp::min(millis, i32::max_value() as u64) as i32));
+            }
+
+            match ZMsg::recv(sock) {
+                Err(_) if ping => try!(sock.send_str("PING")),
+                Err(_) if timeout.is_some() => return Err(Error::Timeout),
+                result => return Ok(try!(result)),
+            }
+        }
+    }
+
+    fn record_sent(&mut self, first: u64, last: u64) {
+        for index in first..last + 1 {
+            if !self.unsent.remove(index) {
+                self.stats.retransmits += 1;
+            }
+            self.stats.bytes_sent += self.layout.len(index);
+            self.stats.chunks_sent += 1;
+        }
+    }
+
+    // Send the unsent chunks within the window past `requested`, the
+    // chunk after the last one the server asked for
+    fn send_ahead(&mut self, sock: &mut ZSock, requested: u64) -> Result<()> {
+        self.read_ahead.frontier = cmp::max(self.read_ahead.frontier, requested);
+        let end = cmp::min(self.read_ahead.frontier + self.read_ahead.window, self.layout.count());
+        let binary = protocol::binary_ints(self.protocol);
+
+        for index in self.read_ahead.frontier..end {
+            if self.unsent.contains(index) {
+                for mut chunk in try!(self.chunk_range(index, index)) {
+                    try!(chunk.send(sock, &self.layout, binary, self.compression(), self.sparse()));
+                }
+                self.record_sent(index, index);
+            }
+        }
+        Ok(())
+    }
+
+    // Chunks the server already has count as written, or as sent to
+    // a server that doesn't acknowledge them
+    fn report_progress(&self, progress: &mut FnMut(u64, u64, u64)) {
+        let total = self.layout.count();
+        if protocol::acked_chunks(self.protocol) {
+            progress(total - self.unacked.len(), total, self.bytes_acked);
+        } else {
+            progress(total - self.unsent.len(), total, self.stats.bytes_sent);
+        }
+    }
+
+    // Where the bytes being sent start in the local file
+    fn offset(&self) -> u64 {
+        self.options.range.map_or(0, |(offset, _)| offset)
+    }
+
+    /// Statistics for the most recent send
+    pub fn get_stats(&self) -> TransferStats {
+        self.stats.clone()
+    }
+
+    /// Decode the protocol version advertised in a client's encoded
+    /// options. Legacy clients do not advertise a version.
+    pub fn options_protocol(options: &[u8]) -> Result<Option<u32>> {
+        Ok(try!(FileOptions::decode(options)).protocol)
+    }
+
+    /// Decode the chunk size a client asked for in its encoded
+    /// options, or the default if it didn't ask
+    pub fn options_chunk_size(options: &[u8]) -> Result<u64> {
+        Ok(try!(FileOptions::decode(options)).chunk_size.unwrap_or(CHUNK_SIZE))
+    }
+
+    /// Whether a client's encoded options ask for the file to be
+    /// synced to disk before it's reported saved
+    pub fn options_durable(options: &[u8]) -> Result<bool> {
+        Ok(try!(FileOptions::decode(options)).durable == Some(true))
+    }
+
+    /// Whether a client's encoded options ask for unchanged chunks
+    /// to be skipped. Patches are always sent in full.
+    pub fn options_delta(options: &[u8]) -> Result<bool> {
+        let options = try!(FileOptions::decode(options));
+        Ok(options.delta == Some(true) && !options.is_patch())
+    }
+
+    /// Whether the file at `path` is already the one a client is
+    /// about to upload, by size, CRC and digest if it sent one.
+    /// Patches, symlinks, and uploads that ask for a backup,
+    /// attributes or metadata, never match, as they want more done
+    /// than writing the contents.
+    pub fn matches_existing<P: AsRef<Path>>(path: P, size: u64, crc: u64, options: &[u8]) -> Result<bool> {
+        if !try!(Self::could_match_existing(&path, size, options)) {
+            return Ok(false);
+        }
+
+        let hash = try!(Self::options_hash(options));
+        let (existing_crc, digest) = try!(hash::hash_file(&path, hash.as_ref().map(|&(algorithm, _)| algorithm)));
+        Ok(existing_crc == crc && digest == hash.map(|(_, expected)| expected))
+    }
+
+    /// As `matches_existing()`, but without reading the file, so
+    /// only whether it's worth hashing to find out
+    pub fn could_match_existing<P: AsRef<Path>>(path: P, size: u64, options: &[u8]) -> Result<bool> {
+        let options = try!(FileOptions::decode(options));
+        if options.is_patch() || options.backup_existing.is_some() || options.metadata.is_some() ||
+           options.mode.is_some() || options.owner.is_some() || options.group.is_some() || options.mtime.is_some() ||
+           options.symlink.is_some() {
+            return Ok(false);
+        }
+
+        match fs::metadata(&path) {
+            Ok(ref meta) if meta.is_file() && meta.len() == size => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    /// Decode the digest, if any, that a client expects its file to
+    /// have from its encoded options
+    pub fn options_hash(options: &[u8]) -> Result<Option<(HashAlgorithm, Vec<u8>)>> {
+        Ok(try!(FileOptions::decode(options)).hash)
+    }
+
+    /// Decode what a client wants done with an existing destination
+    /// from its encoded options
+    pub fn options_if_exists(options: &[u8]) -> Result<IfExists> {
+        Ok(try!(FileOptions::decode(options)).if_exists.unwrap_or(IfExists::Overwrite))
+    }
+
+    /// Decode the target of the symlink, if any, that a client wants
+    /// created in place of a file
+    pub fn options_symlink(options: &[u8]) -> Result<Option<String>> {
+        Ok(try!(FileOptions::decode(options)).symlink)
+    }
+
+    /// Whether a client's encoded options say it will spread chunks
+    /// across more than one connection
+    pub fn options_striped(options: &[u8]) -> Result<bool> {
+        Ok(try!(FileOptions::decode(options)).stripes.map_or(false, |n| n > 1))
+    }
+
+    /// Decode the signature, if any, in a client's encoded options,
+    /// along with the digest it covers
+    pub fn options_signature(options: &[u8]) -> Result<(Option<Vec<u8>>, Option<(HashAlgorithm, Vec<u8>)>)> {
+        let options = try!(FileOptions::decode(options));
+        Ok((options.signature, options.hash))
+    }
+
+    // Chunks are only compressed once both peers have agreed to it
+    fn compression(&self) -> Option<Algorithm> {
+        if protocol::compressed_chunks(self.protocol) {
+            self.options.compress
+        } else {
+            None
+        }
+    }
+
+    // Holes are only sent once both peers have agreed to them
+    fn sparse(&self) -> bool {
+        protocol::sparse_chunks(self.protocol)
+    }
+
+    /// Protocol version negotiated with the peer, or None if the
+    /// peer is legacy.
+    pub fn get_protocol(&self) -> Option<u32> {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Option<u32>) {
+        self.protocol = protocol;
+        if let Some(ref mut manifest) = self.manifest {
+            manifest.protocol = protocol;
+        }
+    }
+
+    // A legacy server never ACKs the NEW request, so reaching this
+    // point without a negotiated version means the peer is legacy.
+    fn check_peer(&self) -> Result<()> {
+        if self.protocol.is_none() && self.compat == Compat::Versioned {
+            Err(Error::LegacyPeer)
+        } else if self.options.hash.is_some() && !protocol::file_digests(self.protocol) {
+            Err(Error::HashUnsupported)
+        } else if self.options.if_exists.map_or(false, |p| p != IfExists::Overwrite) && !protocol::if_exists(self.protocol) {
+            Err(Error::IfExistsUnsupported)
+        } else if self.options.symlink.is_some() && !protocol::symlinks(self.protocol) {
+            Err(Error::SymlinkUnsupported)
+        } else if (self.options.append.is_some() || self.options.write_at.is_some()) && !protocol::offset_writes(self.protocol) {
+            Err(Error::OffsetWriteUnsupported)
+        } else if self.options.durable.is_some() && !protocol::durable_writes(self.protocol) {
+            Err(Error::DurableUnsupported)
+        } else {
+            Ok(())
+        }
+    }
+
+    // A sender doesn't track chunk state, so any index within the
+    // file is valid and chunks are generated from the index alone.
+    fn chunk_range(&self, first: u64, last: u64) -> Result<Chunks> {
+        if last < self.layout.count() {
+            Ok(Chunks::new(self.fh.clone(), first, last + 1))
+        } else {
+            Err(Error::ChunkIndex)
+        }
+    }
+
+    #[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+    fn add_chunks(&self, msg: &ZMsg, first: u64, last: u64, binary: bool) -> Result<()> {
+        for mut chunk in try!(self.chunk_range(first, last)) {
+            try!(chunk.add_to(msg, &self.layout, binary, self.compression(), self.sparse()));
+        }
+        Ok(())
+    }
+
+    // Read the whole batch in a single io_uring submission
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn add_chunks(&self, msg: &ZMsg, first: u64, last: u64, binary: bool) -> Result<()> {
+        let chunks = try!(self.chunk_range(first, last));
+
+        // A reader has no file descriptor to submit
+        if self.fh.lock().unwrap().file().is_none() {
+            for mut chunk in chunks {
+                try!(chunk.add_to(msg, &self.layout, binary, self.compression(), self.sparse()));
+            }
+            return Ok(());
+        }
+
+        let ranges: Vec<(u64, u64)> = (first..last + 1).map(|i| (self.layout.offset(i), self.layout.len(i))).collect();
+        let bufs = try!(uring::read_batch(self.fh.lock().unwrap().file().unwrap(), &ranges));
+
+        for (index, buf) in (first..last + 1).zip(bufs) {
+            try!(protocol::add_u64(msg, index, binary));
+            try!(chunk::add_frame(msg, &buf, self.compression(), self.sparse()));
+        }
+        Ok(())
+    }
+
+    /// Answer a chunk request from the Arbitrator for a file being
+    /// served, returning a CHUNKS message with the chunks' data
+    pub fn serve_chunks(&self, request: &ZMsg) -> Result<ZMsg> {
+        let binary = protocol::binary_ints(self.protocol);
+        let (first, last) = match request.popstr() {
+            Some(Ok(ref action)) if action == "CHUNK" => {
+                let index = try!(protocol::pop_u64(request, binary).ok_or(Error::InvalidRequest));
+                (index, index)
+            },
+            Some(Ok(ref action)) if action == "CHUNKS" => try!(pop_range(request, binary)),
+            _ => return Err(Error::InvalidRequest),
+        };
+
+        let reply = ZMsg::new();
+        try!(reply.addstr("CHUNKS"));
+        try!(self.add_chunks(&reply, first, last, binary));
+        Ok(reply)
+    }
+
+    // Chunks are cheap handles onto the file, so they are created on
+    // demand rather than stored for the life of the transfer.
+    fn chunk(&self, index: u64) -> Result<Chunk> {
+        if self.chunks.contains(index) {
+            Ok(Chunk::new(self.fh.clone(), index))
+        } else {
+            Err(Error::ChunkIndex)
+        }
+    }
+
+    /// Grant a chunk that arrived without being requested, e.g. one
+    /// sent ahead, a slot with the Arbitrator. One that isn't granted
+    /// a slot should be dropped, and is requested in its turn.
+    pub fn admit(&self, arbitrator: &mut Arbitrator, router_id: &[u8], index: u64) -> Result<bool> {
+        let chunk = try!(self.chunk(index));
+        arbitrator.admit(&chunk, self.layout.len(index), router_id)
+    }
+
+    /// Whether a chunk belongs to the file but has already been
+    /// received, e.g. a late copy of a chunk that was requested again
+    pub fn is_stale(&self, index: u64) -> bool {
+        index < self.layout.count() && !self.chunks.contains(index)
+    }
+
+    /// Byte offset of an outstanding chunk within the file
+    pub fn chunk_offset(&self, index: u64) -> Result<u64> {
+        try!(self.chunk(index));
+        Ok(self.layout.offset(index))
+    }
+
+    /// Encrypt chunks in the temporary file with a key held only in
+    /// memory. They are decrypted by `unseal()`.
+    pub fn encrypt_staging(&mut self) -> Result<()> {
+        self.staging = Some(try!(StagingCipher::new()));
+
+        // The key dies with the server, so the upload can't be resumed
+        self.remove_manifest();
+        Ok(())
+    }
+
+    /// Encrypt chunk data before it is written, if staging is
+    /// encrypted
+    pub fn seal(&self, index: u64, data: &mut Vec<u8>) -> Result<()> {
+        if let Some(ref cipher) = self.staging {
+            try!(cipher.seal(try!(self.chunk_offset(index)), data));
+        }
+        Ok(())
+    }
+
+    /// Decompress chunk data received from a client that compresses
+    /// its chunks. A hole is left empty, unless staging is encrypted,
+    /// when it has to be written out as zeros to decrypt as zeros.
+    pub fn decompress(&self, index: u64, data: &mut Vec<u8>) -> Result<()> {
+        if data.is_empty() && self.sparse() {
+            try!(self.chunk(index));
+            if self.staging.is_some() {
+                data.resize(self.layout.len(index) as usize, 0);
+            }
+        } else if let Some(algorithm) = self.compression() {
+            try!(self.chunk(index));
+            *data = try!(compress::decompress(algorithm, data, self.layout.len(index)));
+        }
+        Ok(())
+    }
+
+    /// Decrypt the completed temporary file so it can be checked and
+    /// saved
+    pub fn unseal(&mut self) -> Result<()> {
+        if let Some(cipher) = self.staging.take() {
+            try!(cipher.decrypt_file(self.upload_path.as_ref().unwrap()));
+        }
+        Ok(())
+    }
+
+    /// Take the cipher the temporary file is encrypted with, if any,
+    /// to decrypt it off-thread rather than with `unseal()`
+    pub fn take_cipher(&mut self) -> Option<StagingCipher> {
+        self.staging.take()
+    }
+
+    pub fn recv(&mut self, router_id: &[u8], index: u64, chunk_data: Vec<u8>) -> Result<()> {
+        let mut chunk = try!(self.chunk(index));
+        let start = Instant::now();
+        let sink = try!(connect_sink(&mut self.sink_sock));
+        try!(chunk.recv(router_id, chunk_data, &self.layout, sink));
+        self.timings.disk += start.elapsed();
+
+        Ok(())
+    }
+
+    /// Receive a batch of chunks
+    pub fn recv_many(&mut self, router_id: &[u8], chunks: Vec<(u64, Vec<u8>)>) -> Result<()> {
+        for &(index, _) in chunks.iter() {
+            try!(self.chunk(index));
+        }
+
+        let start = Instant::now();
+        try!(self.write_chunks(router_id, chunks));
+        self.timings.disk += start.elapsed();
+        Ok(())
+    }
+
+    #[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+    fn write_chunks(&mut self, router_id: &[u8], chunks: Vec<(u64, Vec<u8>)>) -> Result<()> {
+        let sink = try!(connect_sink(&mut self.sink_sock));
+        for (index, data) in chunks {
+            try!(Chunk::new(self.fh.clone(), index).recv(router_id, data, &self.layout, sink));
+        }
+        Ok(())
+    }
+
+    // Write the whole batch in a single io_uring submission
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn write_chunks(&mut self, router_id: &[u8], chunks: Vec<(u64, Vec<u8>)>) -> Result<()> {
+        let writes: Vec<(u64, &[u8])> = chunks.iter().map(|&(index, ref data)| (self.layout.offset(index), &data[..])).collect();
+        // Received chunks are always written to a file on disk
+        let success = try!(uring::write_batch(self.fh.lock().unwrap().file().unwrap(), &writes));
+
+        let sink = try!(connect_sink(&mut self.sink_sock));
+        for (&(index, _), ok) in chunks.iter().zip(success) {
+            try!(chunk::send_sink(sink, router_id, index, ok));
+        }
+        Ok(())
+    }
+
+    pub fn sink(&mut self, arbitrator: &mut Arbitrator, router_id: &[u8], index: u64, success: bool) -> Result<()> {
+        // A chunk that arrived twice is reported twice
+        if self.is_stale(index) {
+            return Ok(());
+        }
+
+        let chunk = try!(self.chunk(index));
+
+        // A chunk the sender sent ahead was admitted with a slot of
+        // its own, so doesn't make room in the queue
+        let ahead = index >= self.queued;
+
+        if success {
+            let (slot_wait, in_flight) = try!(arbitrator.release(&chunk, router_id));
+            self.timings.slot_wait += slot_wait;
+            self.timings.in_flight += in_flight;
+            self.chunks.remove(index);
+            arbitrator.set_remaining(router_id, self.chunks.len());
+            self.adapt_chunk_size(true);
+
+            self.manifest_lag += 1;
+            if self.manifest_lag >= self.manifest_interval {
+                try!(self.save_manifest());
+            }
+
+            // Keep the queue window full
+            match self.next_unqueued() {
+                Some(next) if !ahead => {
+                    let chunk = Chunk::new(self.fh.clone(), next);
+                    try!(arbitrator.queue(&chunk, self.layout.len(next), router_id));
+                    self.queued = next + 1;
+                },
+                _ => (),
+            }
+        } else if self.retry.should_retry(self.chunk_error_cnt, &Error::ChunkFail) {
+            self.adapt_chunk_size(false);
+            try!(arbitrator.requeue(&chunk, self.layout.len(index), router_id, &self.retry));
+            self.chunk_error_cnt += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Let the chunk size adapt between `min` and `max` bytes as the
+    /// transfer progresses. The peer must support RESIZE.
+    pub fn set_adaptive(&mut self, min: u64, max: u64) {
+        self.adapt = Some((min, max));
+    }
+
+    // Grow the chunk size after a run of successful chunks, or shrink
+    // it on failure. Only chunks that haven't been queued yet can be
+    // resized, as the peer may already have been asked for the rest.
+    fn adapt_chunk_size(&mut self, success: bool) {
+        let (min, max) = match self.adapt {
+            Some(limits) => limits,
+            None => return,
+        };
+
+        if success {
+            self.adapt_streak += 1;
+            if self.adapt_streak < ADAPT_AFTER {
+                return;
+            }
+        }
+        self.adapt_streak = 0;
+
+        let current = self.layout.chunk_size();
+        let chunk_size = if success {
+            cmp::max(current, cmp::min(current * 2, max))
+        } else {
+            cmp::min(current, cmp::max(current / 2, min))
+        };
+
+        if chunk_size == current || self.queued >= self.layout.count() {
+            return;
+        }
+
+        if self.layout.resize(self.queued, chunk_size).is_ok() {
+            self.chunks.set_tail(self.queued, self.layout.count());
+            self.resize = Some((self.queued, chunk_size));
+
+            // Manifests only describe a fixed chunk size
+            self.remove_manifest();
+        }
+    }
+
+    // The first outstanding chunk that hasn't been queued. Resumed
+    // uploads have gaps where chunks were written before a restart.
+    fn next_unqueued(&self) -> Option<u64> {
+        self.chunks.ranges().iter().find(|&&(_, end)| end > self.queued).map(|&(start, _)| cmp::max(start, self.queued))
+    }
+
+    fn save_manifest(&mut self) -> Result<()> {
+        self.manifest_lag = 0;
+        if let Some(ref mut manifest) = self.manifest {
+            manifest.missing = self.chunks.ranges().to_vec();
+            try!(manifest.save(self.upload_path.as_ref().unwrap()));
+        }
+        Ok(())
+    }
+
+    /// Delete the staging manifest, once the upload is finished or
+    /// can no longer be resumed
+    pub fn remove_manifest(&mut self) {
+        if self.manifest.take().is_some() {
+            let _ = fs::remove_file(manifest_path(self.upload_path.as_ref().unwrap()));
+        }
+    }
+
+    /// Take the most recent chunk size change, which the peer needs
+    /// to be told about
+    pub fn take_resize(&mut self) -> Option<(u64, u64)> {
+        self.resize.take()
+    }
+
+    /// Write the completed file to `output` instead of saving it at
+    /// its path, e.g. to feed it into a processing pipeline
+    pub fn set_output(&mut self, output: Box<Write + Send>) {
+        self.output = Some(output);
+    }
+
+    pub fn get_path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|p| p.as_path())
+    }
+
+    /// Path of the temporary file that chunks are written to
+    pub fn get_upload_path(&self) -> Option<&Path> {
+        self.upload_path.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn get_crc(&self) -> u64 {
+        self.crc
+    }
+
+    /// Digest of the file being sent, if it has a `Hash` option or
+    /// a signing key
+    pub fn get_digest(&self) -> Option<&[u8]> {
+        self.options.hash.as_ref().map(|&(_, ref digest)| &digest[..])
+    }
+
+    /// Key/value metadata supplied by the sender
+    pub fn get_metadata(&self) -> Option<&BTreeMap<String, String>> {
+        self.options.metadata.as_ref()
+    }
+
+    /// Write a `<name>.meta` JSON file next to the saved file,
+    /// recording where it came from
+    pub fn write_sidecar(&self, identity: &[u8], crc: u64) -> Result<()> {
+        let path = self.path.as_ref().unwrap();
+        let mut meta_path = path.clone();
+        meta_path.set_file_name(&append_name(path.file_name().unwrap(), ".meta"));
+
+        let sidecar = Sidecar {
+            identity: hex(identity),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            crc: crc,
+            size: self.size,
+            metadata: self.options.metadata.clone().unwrap_or_else(BTreeMap::new),
+        };
+
+        let mut fh = try!(fs::File::create(meta_path));
+        try!(fh.write_all(&try!(serde_json::to_vec(&sidecar))));
+        Ok(())
+    }
+
+    pub fn get_retries(&self) -> u32 {
+        self.chunk_error_cnt
+    }
+
+    /// Time elapsed since the file was opened or created
+    pub fn get_age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Record time spent checksumming the file elsewhere, e.g. on
+    /// the Server's Hasher threads
+    pub fn add_hashing(&mut self, elapsed: Duration) {
+        self.timings.hashing += elapsed;
+    }
+
+    /// Time spent in each phase of the transfer so far
+    pub fn get_timings(&self) -> Timings {
+        // Chunk writes happen while the chunk is in flight, so
+        // subtract them to leave the network time.
+        let network = if self.timings.in_flight > self.timings.disk {
+            self.timings.in_flight - self.timings.disk
+        } else {
+            Duration::new(0, 0)
+        };
+
+        Timings {
+            hashing: micros(self.timings.hashing),
+            slot_wait: micros(self.timings.slot_wait),
+            network: micros(network),
+            disk: micros(self.timings.disk),
+            finalize: micros(self.timings.finalize),
+        }
+    }
+
+    /// Number of bytes that no longer need transferring
+    pub fn bytes_done(&self) -> u64 {
+        let remaining = self.chunks.ranges().iter().fold(0, |acc, &(first, end)| {
+            acc + self.layout.offset(end - 1) + self.layout.len(end - 1) - self.layout.offset(first)
+        });
+
+        self.size - remaining
+    }
+
+    /// Number of chunks still to be received
+    pub fn chunks_outstanding(&self) -> u64 {
+        self.chunks.len()
+    }
+
+    /// Number of chunks the sender asked to send ahead of requests
+    pub fn window(&self) -> u32 {
+        self.options.window.unwrap_or(0)
+    }
+
+    /// Algorithm of the digest the sender asked to be checked
+    pub fn hash_algorithm(&self) -> Option<HashAlgorithm> {
+        self.options.hash.as_ref().map(|&(algorithm, _)| algorithm)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.chunk_error_cnt >= self.retry.max_retries
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        try!(self.unseal());
+        let start = Instant::now();
+        let (crc, digest) = try!(hash::hash_range(&mut *self.fh.lock().unwrap(), 0, u64::max_value(), self.hash_algorithm()));
+        self.timings.hashing += start.elapsed();
+        self.save_checked(crc, digest.as_ref().map(|d| &d[..]))
+    }
+
+    /// Save the file using a CRC, and a digest if the sender asked for
+    /// one, that have already been calculated
+    pub fn save_checked(&mut self, crc: u64, digest: Option<&[u8]>) -> Result<()> {
+        let start = Instant::now();
+        let result = self.finalize(crc, digest).and_then(|_| self.sync_placed());
+        self.timings.finalize += start.elapsed();
+
+        if result.is_err() {
+            self.discard_copy();
+        }
+        self.copy_path = None;
+
+        // Whether saved or corrupt, there's nothing left to resume
+        self.remove_manifest();
+        result
+    }
+
+    /// Reserve a file beside the destination to copy the upload into,
+    /// if it was staged on another filesystem, so that the copy can be
+    /// made off-thread with `copy_synced()`. Saving then moves the
+    /// copy into place instead of the upload.
+    pub fn stage_copy(&mut self) -> Result<Option<PathBuf>> {
+        if self.output.is_some() || self.options.is_patch() || self.options.symlink.is_some() {
+            return Ok(None);
+        }
+
+        let copy_path = {
+            let path = self.path.as_ref().unwrap();
+            if is_fifo(path) || same_device(self.upload_path.as_ref().unwrap(), path.parent().unwrap_or(Path::new(""))) {
+                return Ok(None);
+            }
+            try!(Self::create_staging_file(path, None, StagingNames::Hidden)).0
+        };
+
+        self.discard_copy();
+        self.copy_path = Some(copy_path.clone());
+        Ok(Some(copy_path))
+    }
+
+    /// Take the output, or FIFO at the destination, that the upload is
+    /// copied into rather than saved, so that the copy can be made
+    /// off-thread with `Stream::copy()`. Saving then only checks the
+    /// upload and removes it.
+    pub fn take_stream(&mut self) -> Option<Stream> {
+        let target = match self.output.take() {
+            Some(output) => StreamTarget::Output(output),
+            None => {
+                let path = self.path.as_ref().unwrap();
+                if !self.stream_fifos || !is_fifo(path) {
+                    return None;
+                }
+                StreamTarget::Fifo(path.clone())
+            },
+        };
+
+        self.streamed = true;
+        Some(Stream {
+            crc: self.crc,
+            digest: self.options.hash.as_ref().map(|&(_, ref digest)| digest.clone()),
+            target: target,
+        })
+    }
+
+    /// Remove the copy reserved by `stage_copy()`, if the upload won't
+    /// be saved after all
+    pub fn discard_copy(&mut self) {
+        if let Some(copy_path) = self.copy_path.take() {
+            let _ = fs::remove_file(copy_path);
+        }
+    }
+
+    fn finalize(&mut self, crc: u64, digest: Option<&[u8]>) -> Result<()> {
+        if self.crc != crc {
+            return Err(Error::FailChecksum);
+        }
+        if let Some((_, ref expected)) = self.options.hash {
+            if digest != Some(&expected[..]) {
+                return Err(Error::FailDigest);
+            }
+        }
+
+        if self.streamed {
+            try!(fs::remove_file(self.upload_path.as_ref().unwrap()));
+            return Ok(());
+        }
+        if is_fifo(self.path.as_ref().unwrap()) && self.output.is_none() {
+            if !self.stream_fifos {
+                return Err(Error::SpecialFile);
+            }
+            return self.stream();
+        }
+        if self.output.is_some() {
+            return self.stream();
+        }
+
+        let path = self.path.as_ref().unwrap();
+        let upload_path = self.upload_path.as_ref().unwrap();
+
+        // The destination may have been created since the upload
+        // started
+        if self.options.if_exists == Some(IfExists::Fail) && path.exists() {
+            return Err(Error::FileExists);
+        }
+
+        // Backup existing file
+        if let Some(versions) = self.options.backup_versions {
+            if versions > 0 && path.exists() {
+                let suffix = self.options.backup_existing.as_ref().map_or(BACKUP_SUFFIX, |s| &s[..]);
+                try!(rotate_backups(path, suffix, versions, self.options.is_patch()));
+            }
+        } else if self.options.backup_existing.is_some() && self.fh.lock().unwrap().file().map_or(false, |fh| fh.metadata().is_ok()) {
+            let suffix = self.options.backup_existing.as_ref().unwrap();
+            let mut backup_path = path.clone();
+            backup_path.set_file_name(&append_name(path.file_name().unwrap(), suffix));
+
+            // A patch leaves the file in place, so it needs a copy
+            if self.options.is_patch() {
+                try!(fs::copy(path, backup_path));
+            } else {
+                try!(rename(path, backup_path));
+            }
+        }
+
+        // The contents reach the disk before they're moved into
+        // place, so a crash can't leave the destination empty or
+        // partly written
+        let durable = self.options.durable == Some(true);
+        if durable {
+            if let Some(fh) = self.fh.lock().unwrap().file() {
+                try!(fh.sync_all());
+            }
+        }
+
+        if let Some(ref target) = self.options.symlink {
+            return place_symlink(target, upload_path, path);
+        }
+
+        // A patch was staged and checked on its own, so the
+        // destination is only touched once it is known to be good.
+        // The bytes written are checked again in place, and the
+        // region they replaced is put back if they don't match.
+        if self.options.is_patch() {
+            let append = self.options.append == Some(true);
+            let mut staged = self.fh.lock().unwrap();
+            try!(staged.seek(SeekFrom::Start(0)));
+            let mut dest = try!(fs::OpenOptions::new().read(true).write(true).create(append).open(path));
+            let old_len = try!(dest.seek(SeekFrom::End(0)));
+            let offset = match self.options.patch_offset() {
+                Some(offset) if !append => offset,
+                _ => old_len,
+            };
+
+            // Nothing before an append is touched, so only a write at
+            // an offset keeps a copy of what it overwrites
+            let saved_path = upload_path.with_file_name(append_name(upload_path.file_name().unwrap(), ".old"));
+            let mut saved = None;
+            if offset < old_len {
+                let mut old = try!(fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&saved_path));
+                try!(dest.seek(SeekFrom::Start(offset)));
+                try!(io::copy(&mut (&mut dest).take(self.size), &mut old));
+                saved = Some(old);
+            }
+
+            try!(dest.seek(SeekFrom::Start(offset)));
+            let result = match io::copy(&mut *staged, &mut dest) {
+                Ok(_) => match hash::hash_range(&mut dest, offset, self.size, None) {
+                    Ok((crc, _)) if crc == self.crc => Ok(()),
+                    Ok(_) => Err(Error::FailChecksum),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                try!(restore_region(&mut dest, offset, old_len, saved.as_mut()));
+                let _ = fs::remove_file(&saved_path);
+                return Err(e);
+            }
+            if saved.is_some() {
+                try!(fs::remove_file(&saved_path));
+            }
+            if durable {
+                try!(dest.sync_all());
+            }
+
+            try!(fs::remove_file(upload_path));
+            return Ok(());
+        }
+
+        // Applied before the rename, so the file never appears at its
+        // destination with the wrong owner or mode
+        let opts = &self.options;
+        let apply_attrs = |p: &Path| attrs::apply(p, opts.mode, opts.owner.as_ref().map(|o| &o[..]), opts.group.as_ref().map(|g| &g[..]), opts.mtime);
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        // A copy made off-thread was synced, and checked in place of
+        // the upload
+        if let Some(ref copy_path) = self.copy_path {
+            try!(apply_attrs(copy_path));
+            try!(rename(copy_path, path));
+            try!(sync_dir(dir));
+            try!(fs::remove_file(upload_path));
+            return Ok(());
+        }
+
+        try!(apply_attrs(upload_path));
+
+        // Synced before the rename, unless that was done above, and
+        // the directory after, so the destination is never left empty
+        if !durable {
+            if let Some(fh) = self.fh.lock().unwrap().file() {
+                try!(fh.sync_all());
+            }
+        }
+        match rename(upload_path, path) {
+            Err(ref e) if is_cross_device(e) => copy_into_place(upload_path, path, apply_attrs),
+            Err(e) => Err(e.into()),
+            Ok(()) => sync_dir(dir),
+        }
+    }
+
+    // Once a durable upload is in place, its directory is synced so
+    // that the rename survives a crash as well as the contents
+    fn sync_placed(&self) -> Result<()> {
+        match self.path {
+            Some(ref path) if self.options.durable == Some(true) => sync_dir(path.parent().unwrap_or(Path::new(""))),
+            _ => Ok(()),
+        }
+    }
+
+    // Copy the staged file into the output, or the FIFO at the
+    // destination path, rather than moving it into place. A FIFO
+    // without a reader fails rather than blocking, though a slow
+    // reader still holds up the copy.
+    fn stream(&mut self) -> Result<()> {
+        let target = match self.output.take() {
+            Some(output) => StreamTarget::Output(output),
+            None => StreamTarget::Fifo(self.path.clone().unwrap()),
+        };
+
+        {
+            let mut staged = self.fh.lock().unwrap();
+            try!(staged.seek(SeekFrom::Start(0)));
+            try!(target.copy_from(&mut *staged));
+        }
+
+        try!(fs::remove_file(self.upload_path.as_ref().unwrap()));
+        Ok(())
+    }
+}
+
+// Put back the region of a patched file that was overwritten from
+// `offset`, as saved beforehand, and its length before the patch
+fn restore_region(dest: &mut fs::File, offset: u64, len: u64, saved: Option<&mut fs::File>) -> Result<()> {
+    if let Some(saved) = saved {
+        try!(saved.seek(SeekFrom::Start(0)));
+        try!(dest.seek(SeekFrom::Start(offset)));
+        try!(io::copy(saved, dest));
+    }
+    try!(dest.set_len(len));
+    Ok(())
+}
+
+// A file name with `suffix` added, which needn't be UTF-8
+fn append_name(name: &OsStr, suffix: &str) -> OsString {
+    let mut appended = name.to_owned();
+    appended.push(suffix);
+    appended
+}
+
+// Dotfiles are hidden on Unix, so that's where uploads are staged
+#[cfg(unix)]
+fn staging_name(name: &OsStr, counter: u16) -> OsString {
+    let mut staged = OsString::from(".");
+    staged.push(name);
+    staged.push(counter.to_string());
+    staged
+}
+
+// Windows doesn't hide dotfiles, so the name is marked as partial
+// instead, like `StagingNames::Random`'s
+#[cfg(not(unix))]
+fn staging_name(name: &OsStr, counter: u16) -> OsString {
+    append_name(name, &format!(".{}.part", counter))
+}
+
+// Shift the numbered backups of `path` along by one, dropping any
+// past `versions`, then move the file itself to the first. A file
+// that is only being patched is copied instead.
+fn rotate_backups(path: &Path, suffix: &str, versions: u32, copy: bool) -> Result<()> {
+    let file_name = path.file_name().unwrap();
+    let backup = |n: u32| path.with_file_name(&append_name(file_name, &format!("{}.{}", suffix, n)));
+
+    // Fewer versions may be kept than last time
+    let mut n = versions.saturating_add(1);
+    while backup(n).exists() {
+        try!(fs::remove_file(backup(n)));
+        n += 1;
+    }
+
+    for n in (1..versions).rev() {
+        if backup(n).exists() {
+            try!(rename(backup(n), backup(n + 1)));
+        }
+    }
+
+    if copy {
+        try!(fs::copy(path, backup(1)));
+    } else {
+        try!(rename(path, backup(1)));
+    }
+    Ok(())
+}
+
+// Copy a staged file to its destination on another filesystem. The
+// copy is made beside the destination and renamed over it, so the
+// destination is still replaced in one step. The copy and its
+// directory are synced before the staged file is removed, so a crash
+// can't lose both.
+fn copy_into_place<F: Fn(&Path) -> Result<()>>(from: &Path, to: &Path, apply_attrs: F) -> Result<()> {
+    let tmp_path = File::temporary_filename(to);
+    let result = fs::copy(from, &tmp_path).map_err(Error::from)
+        .and_then(|_| Ok(try!(try!(fs::File::open(&tmp_path)).sync_all())))
+        .and_then(|_| apply_attrs(&tmp_path))
+        .and_then(|_| Ok(try!(rename(&tmp_path, to))));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+
+    try!(sync_dir(to.parent().unwrap_or(Path::new(""))));
+    try!(fs::remove_file(from));
+    Ok(())
+}
+
+/// Copy a staged file into one reserved by `File::stage_copy()`, and
+/// sync it. The copy must already exist, so that one discarded in the
+/// meantime isn't made again.
+pub fn copy_synced(from: &Path, to: &Path) -> Result<()> {
+    let mut copy = try!(fs::OpenOptions::new().write(true).truncate(true).open(to));
+    try!(io::copy(&mut try!(fs::File::open(from)), &mut copy));
+    try!(copy.sync_all());
+    Ok(())
+}
+
+// Create the link beside the destination and rename it over it, so
+// whatever was there is replaced in one step. The staged upload
+// holds no contents and is discarded.
+#[cfg(unix)]
+fn place_symlink(target: &str, upload_path: &Path, path: &Path) -> Result<()> {
+    let tmp_path = File::staging_filename(path, None);
+    try!(unix_fs::symlink(target, &tmp_path));
+    if let Err(e) = rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    try!(fs::remove_file(upload_path));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn place_symlink(_: &str, _: &Path, _: &Path) -> Result<()> {
+    Err(Error::SymlinkUnsupported)
+}
+
+#[cfg(unix)]
+fn is_cross_device(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(not(unix))]
+fn is_cross_device(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE)
+}
+
+#[cfg(unix)]
+fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    fs::rename(from, to)
+}
+
+// Windows won't rename over a file that is read-only or open, so it
+// is moved aside and only deleted once the new file is in place.
+// Unlike on Unix, `to` is briefly missing.
+#[cfg(not(unix))]
+fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    match fs::rename(from, to) {
+        Err(ref e) if !is_cross_device(e) && to.is_file() => (),
+        result => return result,
+    }
+
+    let aside = File::temporary_filename(to);
+    try!(fs::rename(to, &aside));
+    if let Err(e) = fs::rename(from, to) {
+        let _ = fs::rename(&aside, to);
+        return Err(e);
+    }
+
+    if let Ok(meta) = fs::metadata(&aside) {
+        let mut perms = meta.permissions();
+        perms.set_readonly(false);
+        let _ = fs::set_permissions(&aside, perms);
+    }
+    let _ = fs::remove_file(&aside);
+    Ok(())
+}
+
+/// Sync a directory, so that files renamed into it stay renamed
+/// after a crash
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    try!(try!(fs::File::open(dir)).sync_all());
+    Ok(())
+}
+
+// Directories can't be opened to sync on Windows
+#[cfg(not(unix))]
+pub fn sync_dir(_: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Whether a file is on the same filesystem as a directory, and so can
+// be renamed into it. Assumed to be if that can't be told.
+#[cfg(unix)]
+fn same_device(path: &Path, dir: &Path) -> bool {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    match (fs::metadata(path), fs::metadata(dir)) {
+        (Ok(file), Ok(dir)) => file.dev() == dir.dev(),
+        _ => true,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_device(_: &Path, _: &Path) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    fs::metadata(path).map(|m| m.file_type().is_fifo()).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_: &Path) -> bool {
+    false
+}
+
+// Open a FIFO for writing without waiting for a reader, which fails
+// with ENXIO if there is none. Writes then block as usual.
+#[cfg(unix)]
+fn open_fifo(path: &Path) -> Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let fifo = try!(fs::OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path));
+    let fd = fifo.as_raw_fd();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(fifo)
+}
+
+#[cfg(not(unix))]
+fn open_fifo(path: &Path) -> Result<fs::File> {
+    Ok(try!(fs::OpenOptions::new().write(true).open(path)))
+}
+
+// Connect to the Server's sink on first use, then keep the socket
+// for the rest of the transfer rather than opening one per chunk
+fn connect_sink(sock: &mut Option<ZSock>) -> Result<&mut ZSock> {
+    if sock.is_none() {
+        let sink = try!(ZSock::new_push(">inproc://zfilexfer_sink"));
+        sink.set_sndtimeo(Some(1000));
+        *sock = Some(sink);
+    }
+
+    Ok(sock.as_mut().unwrap())
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1000) as u64
+}
+
+// Write the chunks of a download as the server sends them,
+// acknowledging each batch once it is on disk
+fn recv_download(sock: &mut ZSock, fh: &mut fs::File, layout: &Layout, binary: bool, sparse: bool) -> Result<()> {
+    let mut missing = ChunkSet::new(layout.count());
+
+    while !missing.is_empty() {
+        let msg = try!(ZMsg::recv(sock));
+        match try!(msg.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
+            "CHUNKS" => (),
+            "Err" => return Err(protocol::pop_err(&msg)),
+            _ => return Err(Error::InvalidReply),
+        }
+
+        let ack = ZMsg::new();
+        try!(ack.addstr("RECEIVED"));
+        let mut acked = 0;
+
+        while let Some(frame) = try!(msg.popbytes()) {
+            let index = try!(protocol::decode_u64(&frame, binary).ok_or(Error::InvalidReply));
+            let data = try!(try!(msg.popbytes()).ok_or(Error::InvalidReply));
+            let hole = sparse && data.is_empty();
+            if index >= layout.count() || (data.len() as u64 != layout.len(index) && !hole) {
+                return Err(Error::InvalidReply);
+            }
+
+            // A chunk that timed out may be sent again. The file was
+            // created sparse, so a hole needs no write.
+            if missing.remove(index) && !hole {
+                try!(fh.seek(SeekFrom::Start(layout.offset(index))));
+                try!(fh.write_all(&data));
+            }
+            try!(protocol::add_u64(&ack, index, binary));
+            acked += 1;
+        }
+
+        if acked == 0 {
+            return Err(Error::InvalidReply);
+        }
+        try!(ack.send(sock));
+    }
+
+    Ok(())
+}
+
+/// Pop the first and last index of a CHUNKS request
+pub fn pop_range(msg: &ZMsg, binary: bool) -> Result<(u64, u64)> {
+    if binary {
+        let first = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidReply));
+        let last = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidReply));
+        Ok((first, last))
+    } else {
+        let range = try!(msg.popstr().unwrap_or(Err(Vec::new())).or(Err(Error::InvalidReply)));
+        parse_range(&range)
+    }
+}
+
+// Parse a "first-last" chunk range from a CHUNKS request
+fn parse_range(range: &str) -> Result<(u64, u64)> {
+    let mut parts = range.splitn(2, '-');
+    let first = try!(parts.next().unwrap().parse::<u64>().or(Err(Error::InvalidReply)));
+    let last = try!(parts.next().ok_or(Error::InvalidReply).and_then(|l| l.parse::<u64>().or(Err(Error::InvalidReply))));
+
+    if first > last {
+        return Err(Error::InvalidReply);
+    }
+
+    Ok((first, last))
+}
+
+#[derive(Clone)]
+pub enum Options {
+    /// Add the bytes being sent to the end of the destination file,
+    /// creating it if need be
+    Append,
+    BackupExisting(String),
+    /// Keep this many numbered backups of the file being replaced,
+    /// from `file.bk.1` (newest) up. The suffix before the number is
+    /// `.bk` unless set by `BackupExisting`. Servers refuse more than
+    /// 100.
+    BackupVersions(u32),
+    ChunkSize(u64),
+    Codec(WireCodec),
+    Compat(Compat),
+    /// Compress each chunk's data, if the server supports it
+    Compress(Algorithm),
+    /// Milliseconds `send()` may take in all before giving up with
+    /// `Error::Timeout`
+    Deadline(u32),
+    /// Let the server offer checksums of the file it already has at
+    /// the destination, so that unchanged chunks aren't sent
+    Delta,
+    /// Have the server sync the file and its directory to disk before
+    /// it replies that the file was saved
+    Durable,
+    /// After sending the file, keep adding whatever is appended to it
+    /// to the server's copy, checking every this many milliseconds,
+    /// like `tail -f`. Each addition is written just past the last,
+    /// so it fails if the server's copy has since shrunk. Following
+    /// ends once `Deadline` passes, or with `Error::Truncated` if the
+    /// file shrinks. It can't be combined with `Append`.
+    Follow(u32),
+    /// Give the file this group on the server, by name or ID
+    Group(String),
+    /// Check the file against a digest as well as its CRC, which
+    /// needs a server that supports digests
+    Hash(HashAlgorithm),
+    /// Milliseconds `send()` waits for each message from the server
+    /// before giving up with `Error::Timeout`
+    IdleTimeout(u32),
+    /// What the server does if the destination already exists.
+    /// Defaults to `IfExists::Overwrite`.
+    IfExists(IfExists),
+    /// A key/value pair for the server to keep with the file, e.g.
+    /// in a sidecar file
+    Metadata(String, String),
+    /// Ask the server to give up after this many chunk failures, if
+    /// that's fewer than it would allow
+    MaxRetries(u8),
+    /// Give the file these permission bits on the server
+    Mode(u32),
+    /// Give the file this owner on the server, by name or ID
+    Owner(String),
+    /// Send a symlink as a link to the same target, for the server to
+    /// recreate, rather than sending the file it points to
+    PreserveSymlinks,
+    /// Give the file the same modification time on the server as it
+    /// has locally
+    PreserveTimestamps,
+    /// Send only `length` bytes from `offset`, which the server
+    /// writes at the same offset in the existing destination file
+    Range(u64, u64),
+    /// Sign the transfer with this ed25519 key (32 byte seed followed
+    /// by the public key), for servers that only accept files from
+    /// trusted senders. The signature covers the file's digest, which
+    /// is SHA-256 unless `Hash` says otherwise, and expires after
+    /// five minutes.
+    #[cfg(feature = "signing")]
+    SigningKey(Vec<u8>),
+    /// Send up to this many chunks ahead of the server's requests,
+    /// rather than waiting for each one, if the server allows it. The
+    /// chunk size stays fixed for the transfer.
+    Window(u32),
+    /// Write the bytes being sent at this offset in the existing
+    /// destination file, rather than replacing it. With `Range`, the
+    /// bytes are written here rather than at the range's offset.
+    WriteAt(u64),
+}
+
+/// How a server names the temporary files it writes uploads to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StagingNames {
+    /// `.file0`, `.file1` and so on, or `file.0.part` on Windows,
+    /// which doesn't hide dotfiles. This is the default.
+    Hidden,
+    /// `file.<16 random hex digits>.part`, for directories watched
+    /// by tools that act on dotfiles
+    Random,
+}
+
+/// Algorithm of the digest sent with a file opened with `options`,
+/// if any
+pub fn digest_algorithm(options: Option<&[Options]>) -> Option<HashAlgorithm> {
+    let algorithm = options.and_then(|opts| opts.iter().filter_map(|opt| match opt {
+        &Options::Hash(algorithm) => Some(algorithm),
+        _ => None,
+    }).last());
+    // A signature covers the digest, as a CRC is easily forged
+    #[cfg(feature = "signing")]
+    let algorithm = algorithm.or_else(|| options.and_then(|opts| opts.iter().find(|opt| match opt {
+        &&Options::SigningKey(_) => true,
+        _ => false,
+    })).map(|_| HashAlgorithm::Sha256));
+    algorithm
+}
+
+// 16 hex digits from the system's random source
+fn random_suffix() -> Result<String> {
+    let mut random = [0; 8];
+    try!(getrandom::getrandom(&mut random).map_err(io::Error::from));
+    Ok(random.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// What to do with an upload whose destination already exists
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum IfExists {
+    /// Reject the upload with `Error::FileExists`
+    Fail,
+    /// Replace the existing file
+    Overwrite,
+    /// Leave the existing file be and report the upload as skipped
+    Skip,
+}
+
+// New fields go last and must be Options. The binary codec has no
+// field names, so `decode()` reads the fields an older peer left out
+// as None, going by the version it sent. JSON decoding ignores
+// fields it doesn't know, and reads missing ones as None.
+#[derive(Deserialize, Serialize)]
+struct FileOptions {
+    backup_existing: Option<String>,
+    chunk_size: Option<u64>,
+    protocol: Option<u32>,
+    metadata: Option<BTreeMap<String, String>>,
+    signature: Option<Vec<u8>>,
+    range: Option<(u64, u64)>,
+    delta: Option<bool>,
+    compress: Option<Algorithm>,
+    mode: Option<u32>,
+    owner: Option<String>,
+    group: Option<String>,
+    /// Seconds and nanoseconds since the Unix epoch
+    mtime: Option<(u64, u32)>,
+    max_retries: Option<u8>,
+    /// Algorithm and digest of the file being sent
+    hash: Option<(HashAlgorithm, Vec<u8>)>,
+    window: Option<u32>,
+    if_exists: Option<IfExists>,
+    backup_versions: Option<u32>,
+    /// OPTIONS_VERSION of the peer that encoded these. Older peers
+    /// didn't send one.
+    version: Option<u32>,
+    /// Target of a symlink to create in place of the file
+    symlink: Option<String>,
+    append: Option<bool>,
+    /// Offset in the destination to write at
+    write_at: Option<u64>,
+    durable: Option<bool>,
+    /// Number of connections the sender spreads chunks across
+    stripes: Option<u32>,
+}
+
+// Contents of a `<name>.meta` sidecar file
+#[derive(Serialize)]
+struct Sidecar {
+    identity: String,
+    timestamp: u64,
+    crc: u64,
+    size: u64,
+    metadata: BTreeMap<String, String>,
+}
+
+impl FileOptions {
+    fn new(options: Option<&[Options]>) -> FileOptions {
+        let mut opts = FileOptions {
+            backup_existing: None,
+            chunk_size: None,
+            protocol: Some(PROTOCOL_VERSION),
+            metadata: None,
+            signature: None,
+            range: None,
+            delta: None,
+            compress: None,
+            mode: None,
+            owner: None,
+            group: None,
+            mtime: None,
+            max_retries: None,
+            hash: None,
+            window: None,
+            if_exists: None,
+            backup_versions: None,
+            version: Some(OPTIONS_VERSION),
+            symlink: None,
+            append: None,
+            write_at: None,
+            durable: None,
+            stripes: None,
+        };
+
+        if let Some(options) = options {
+            for opt in options {
+                match opt {
+                    &Options::Append => opts.append = Some(true),
+                    &Options::BackupExisting(ref suffix) => opts.backup_existing = Some(suffix.to_string()),
+                    &Options::BackupVersions(versions) => opts.backup_versions = Some(versions),
+                    &Options::ChunkSize(size) => opts.chunk_size = Some(size),
+                    &Options::Compat(Compat::Legacy) => opts.protocol = None,
+                    &Options::Compat(_) => opts.protocol = Some(PROTOCOL_VERSION),
+                    &Options::Codec(_) => (),
+                    &Options::Compress(algorithm) => opts.compress = Some(algorithm),
+                    // Only used by the sender
+                    &Options::Deadline(_) => (),
+                    &Options::Delta => opts.delta = Some(true),
+                    &Options::Durable => opts.durable = Some(true),
+                    &Options::Follow(_) => (),
+                    &Options::Group(ref group) => opts.group = Some(group.clone()),
+                    // Needs the file's digest, so set when it's opened
+                    &Options::Hash(_) => (),
+                    &Options::IdleTimeout(_) => (),
+                    &Options::IfExists(policy) => opts.if_exists = Some(policy),
+                    &Options::Metadata(ref key, ref value) => {
+                        if opts.metadata.is_none() {
+                            opts.metadata = Some(BTreeMap::new());
+                        }
+                        opts.metadata.as_mut().unwrap().insert(key.clone(), value.clone());
+                    },
+                    &Options::MaxRetries(max) => opts.max_retries = Some(max),
+                    &Options::Mode(mode) => opts.mode = Some(mode),
+                    &Options::Owner(ref owner) => opts.owner = Some(owner.clone()),
+                    // Captured when the file is opened
+                    &Options::PreserveSymlinks => (),
+                    &Options::PreserveTimestamps => (),
+                    &Options::Range(offset, length) => opts.range = Some((offset, length)),
+                    #[cfg(feature = "signing")]
+                    &Options::SigningKey(_) => (),
+                    &Options::Window(chunks) => opts.window = Some(chunks),
+                    &Options::WriteAt(offset) => opts.write_at = Some(offset),
+                }
+            }
+        }
+
+        opts
+    }
+
+    // Where the bytes sent go in the existing destination, unless
+    // they're appended. None if they replace it.
+    fn patch_offset(&self) -> Option<u64> {
+        self.write_at.or(self.range.map(|(offset, _)| offset))
+    }
+
+    // Whether the bytes sent patch the existing destination rather
+    // than replacing it
+    fn is_patch(&self) -> bool {
+        self.append == Some(true) || self.patch_offset().is_some()
+    }
+
+    // Binary encoded options are checked against the version they
+    // claim, as the fields a peer leaves out are only known from the
+    // number of bytes it sent
+    fn decode(encoded: &[u8]) -> Result<FileOptions> {
+        match WireCodec::detect(encoded) {
+            WireCodec::Binary => {
+                let fields = UNVERSIONED_FIELDS + OPTIONS_VERSION as usize;
+                // Even the first peers sent the backup suffix and
+                // chunk size
+                let (options, missing): (FileOptions, usize) = try!(BinaryCodec.decode_partial(encoded, fields - 2));
+                let sent = fields - missing;
+                let expected = match options.version {
+                    Some(v) if v >= OPTIONS_VERSION => fields,
+                    Some(v) if v > 0 => UNVERSIONED_FIELDS + v as usize,
+                    Some(_) => return Err(Error::InvalidFileOpts),
+                    None if sent <= UNVERSIONED_FIELDS => sent,
+                    None => return Err(Error::InvalidFileOpts),
+                };
+                if sent != expected {
+                    return Err(Error::InvalidFileOpts);
+                }
+                Ok(options)
+            },
+            WireCodec::Json => JsonCodec.decode(encoded),
+        }
+    }
+
+    fn encode(&self, codec: WireCodec) -> Result<Vec<u8>> {
+        codec.encode(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arbitrator::Arbitrator;
+    use codec::WireCodec;
+    use compress::{self, Algorithm};
+    use czmq::{ZMsg, ZSock, SocketType, ZSys};
+    use error::Error;
+    use manifest::{manifest_path, Manifest, STAGING_VERSION};
+    use protocol::{self, Compat, PROTOCOL_VERSION};
+    use retry::RetryPolicy;
+    use std::fs;
+    use serde_json::{self, Value};
+    use std::io::{Cursor, Read, Write};
+    #[cfg(unix)]
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+    use super::*;
+    use super::FileOptions;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_temporary_filename() {
+        assert_eq!(File::temporary_filename("/path/to/file"), Path::new("/path/to/.file0"));
+
+        let tempdir = TempDir::new("file_test_temporary_filename").unwrap();
+        let path = tempdir.path().to_str().unwrap();
+        fs::File::create(&format!("{}/.file0", path)).unwrap();
+
+        assert_eq!(File::temporary_filename(format!("{}/file", path)), Path::new(&format!("{}/.file1", path)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_temporary_filename_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"/path/to/fil\xff"));
+        assert_eq!(File::temporary_filename(path), Path::new(OsStr::from_bytes(b"/path/to/.fil\xff0")));
+        assert_eq!(append_name(path.file_name().unwrap(), ".bk").as_bytes(), b"fil\xff.bk");
+    }
+
+    #[test]
+    fn test_file_is_send() {
+        let tempdir = TempDir::new("file_test_file_is_send").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        // An open file can be handed to another thread to send
+        let file = File::open(&path, None).unwrap();
+        let crc = spawn(move || file.get_crc()).join().unwrap();
+        assert_eq!(crc, File::checksum(&path).unwrap());
+    }
+
+    #[test]
+    fn test_calc_crc() {
+        let tempdir = TempDir::new("file_test_temporary_filename").unwrap();
+        let path = format!("{}/.file0", tempdir.path().to_str().unwrap());
+        let mut file = fs::OpenOptions::new().create(true).read(true).write(true).open(&path).unwrap();
+        file.write_all(b"12345").unwrap();
+
+        assert_eq!(File::calc_crc(&mut file).unwrap(), 16742651521893322043);
+    }
+
+    #[test]
+    fn test_create_recv() {
+        let tempdir = TempDir::new("file_test_new_recv").unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1, 0, 1, b"{}").unwrap();
+        assert!(file.recv(&Vec::new(), 0, Vec::new()).is_ok());
+        assert!(file.recv(&Vec::new(), 1, Vec::new()).is_err());
+        assert!(file.recv_many(&Vec::new(), vec![(0, Vec::new()), (1, Vec::new())]).is_err());
+    }
+
+    #[test]
+    fn test_decompress() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_decompress").unwrap();
+        let options = FileOptions::new(Some(&[Options::Compress(Algorithm::Gzip)])).encode(WireCodec::Json).unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), tempdir.path().join("testfile"), 3, 0, 2, &options).unwrap();
+
+        // Chunks aren't compressed until the protocol is negotiated
+        let mut data = b"ab".to_vec();
+        file.decompress(0, &mut data).unwrap();
+        assert_eq!(data, b"ab");
+
+        file.set_protocol(Some(protocol::COMPRESSED_CHUNKS));
+        let mut data = compress::compress(Algorithm::Gzip, b"c").unwrap();
+        file.decompress(1, &mut data).unwrap();
+        assert_eq!(data, b"c");
+
+        let mut data = compress::compress(Algorithm::Gzip, b"c").unwrap();
+        assert!(file.decompress(0, &mut data).is_err());
+        assert!(file.decompress(2, &mut data).is_err());
+
+        // A hole isn't compressed
+        let mut data = Vec::new();
+        assert!(file.decompress(0, &mut data).is_err());
+        file.set_protocol(Some(protocol::SPARSE_CHUNKS));
+        file.decompress(0, &mut data).unwrap();
+        assert!(data.is_empty());
+        file.encrypt_staging().unwrap();
+        file.decompress(0, &mut data).unwrap();
+        assert_eq!(data, vec![0, 0]);
+    }
+
+    #[test]
+    fn test_bytes_done() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_bytes_done").unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 3, 0, 2, b"{}").unwrap();
+        assert_eq!(file.bytes_done(), 0);
+
+        file.chunks.remove(1);
+        assert_eq!(file.bytes_done(), 1);
+        file.chunks.remove(0);
+        assert_eq!(file.bytes_done(), 3);
+        assert!(file.is_complete());
+    }
+
+    #[test]
+    fn test_open_send() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_new_recv").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        let remote_path = format!("{}/remote_file.txt", tempdir.path().to_str().unwrap());
+        let remote_path_clone = remote_path.clone();
+        let mut fs_file = fs::File::create(&local_path).unwrap();
+        fs_file.write_all("abc".as_bytes()).unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "NEW");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), &remote_path_clone);
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "3");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "5336943202215289992");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "2");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "{\"backup_existing\":null,\"chunk_size\":2,\"protocol\":18,\"metadata\":null,\"signature\":null,\"range\":null,\"delta\":null,\"compress\":null,\"mode\":null,\"owner\":null,\"group\":null,\"mtime\":null,\"max_retries\":null,\"hash\":null,\"window\":null,\"if_exists\":null,\"backup_versions\":null,\"version\":6,\"symlink\":null,\"append\":null,\"write_at\":null,\"durable\":null,\"stripes\":null}");
+
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr("4").unwrap();
+            msg.send(&mut server).unwrap();
+
+            let msg = ZMsg::new();
+            msg.addstr("CHUNK").unwrap();
+            msg.addstr("1").unwrap();
+            msg.send(&mut server).unwrap();
+
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "1");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "c");
+
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, Some(&[Options::ChunkSize(2)])).unwrap();
+        let mut progress = Vec::new();
+        file.send_with_progress(&mut client, &remote_path, |sent, total, bytes| progress.push((sent, total, bytes))).unwrap();
+        assert_eq!(file.get_protocol(), Some(1));
+        assert_eq!(progress, vec![(1, 2, 1)]);
+        assert_eq!(file.get_stats().transfer_id, Some(4));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_stats() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_stats").unwrap();
+        let local_path = tempdir.path().join("local_file.txt");
+        fs::File::create(&local_path).unwrap().write_all("abc".as_bytes()).unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "NEW");
+
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr("4").unwrap();
+            msg.send(&mut server).unwrap();
+
+            // Ask twice, as if the first reply was lost
+            for _ in 0..2 {
+                let msg = ZMsg::new();
+                msg.addstr("CHUNK").unwrap();
+                msg.addstr("1").unwrap();
+                msg.send(&mut server).unwrap();
+
+                let msg = ZMsg::recv(&mut server).unwrap();
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "1");
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "c");
+            }
+
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, Some(&[Options::ChunkSize(2)])).unwrap();
+        file.send(&mut client, "/remote_file.txt").unwrap();
+
+        let stats = file.get_stats();
+        assert_eq!(stats.bytes_sent, 2);
+        assert_eq!(stats.chunks_sent, 2);
+        assert_eq!(stats.retransmits, 1);
+        assert_eq!(stats.transfer_id, Some(4));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_acked() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_acked").unwrap();
+        let local_path = tempdir.path().join("local_file.txt");
+        fs::File::create(&local_path).unwrap().write_all(b"abc").unwrap();
 
         let (mut client, mut server) = ZSys::create_pipe().unwrap();
         client.set_rcvtimeo(Some(500));
         server.set_rcvtimeo(Some(500));
 
         let handle = spawn(move|| {
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr(&PROTOCOL_VERSION.to_string()).unwrap();
+            msg.send(&mut server).unwrap();
+
+            for index in 0..2 {
+                let msg = ZMsg::new();
+                msg.addstr("CHUNK").unwrap();
+                protocol::add_u64(&msg, index, true).unwrap();
+                msg.send(&mut server).unwrap();
+                ZMsg::recv(&mut server).unwrap();
+            }
+
+            // Both chunks are written, but the second is acknowledged
+            // first
+            for index in (0..2).rev() {
+                let msg = ZMsg::new();
+                msg.addstr("RECEIVED").unwrap();
+                protocol::add_u64(&msg, index, true).unwrap();
+                msg.send(&mut server).unwrap();
+            }
+
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, Some(&[Options::ChunkSize(2)])).unwrap();
+        let mut progress = Vec::new();
+        file.send_with_progress(&mut client, "/remote", |acked, total, bytes| progress.push((acked, total, bytes))).unwrap();
+        assert_eq!(progress, vec![(1, 2, 1), (2, 2, 3)]);
+        assert_eq!(file.get_stats().chunks_sent, 2);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_timeout() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_timeout").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&local_path).unwrap().write_all(b"abc").unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            // Goes quiet after the ACK
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr(&PROTOCOL_VERSION.to_string()).unwrap();
+            msg.send(&mut server).unwrap();
+
+            // Keeps asking for a chunk, but never finishes
+            ZMsg::recv(&mut server).unwrap();
+            for _ in 0..10 {
+                let msg = ZMsg::new();
+                msg.addstr("CHUNK").unwrap();
+                protocol::add_u64(&msg, 0, true).unwrap();
+                msg.send(&mut server).unwrap();
+                sleep(Duration::from_millis(20));
+            }
+        });
+
+        let mut file = File::open(&local_path, Some(&[Options::IdleTimeout(50)])).unwrap();
+        match file.send(&mut client, "/remote") {
+            Err(Error::Timeout) => (),
+            _ => panic!("Expected Timeout error"),
+        }
+
+        let mut file = File::open(&local_path, Some(&[Options::Deadline(100), Options::IdleTimeout(50)])).unwrap();
+        match file.send(&mut client, "/remote") {
+            Err(Error::Timeout) => (),
+            _ => panic!("Expected Timeout error"),
+        }
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_heartbeat() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_heartbeat").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&local_path).unwrap().write_all(b"abc").unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr(&PROTOCOL_VERSION.to_string()).unwrap();
+            msg.addstr("4").unwrap();
+            msg.addstr("0").unwrap();
+            msg.addstr("20").unwrap();
+            msg.send(&mut server).unwrap();
+
+            // Stays quiet until pinged
             let msg = ZMsg::recv(&mut server).unwrap();
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "NEW");
-            assert_eq!(&msg.popstr().unwrap().unwrap(), &remote_path_clone);
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "3");
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "5336943202215289992");
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "2");
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "{\"backup_existing\":null,\"chunk_size\":2}");
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "PING");
+
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        // The caller's own timeout is put back afterwards
+        client.set_rcvtimeo(Some(1000));
+        let mut file = File::open(&local_path, None).unwrap();
+        file.send(&mut client, "/remote").unwrap();
+        assert_eq!(client.rcvtimeo(), Some(1000));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_cancel() {
+        ZSys::init();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            for reply in [&b"CHUNK"[..], b"CANCELLED", b"\xff"].iter() {
+                if reply != &b"CHUNK" {
+                    assert_eq!(server.recv_str().unwrap().unwrap(), "CANCEL");
+                }
+                let msg = ZMsg::new();
+                msg.addbytes(reply).unwrap();
+                msg.send(&mut server).unwrap();
+            }
+        });
+
+        // Chunk requests still on their way are passed over
+        let mut file = File::from_reader(Cursor::new(b"abc".to_vec()), None).unwrap();
+        file.cancel(&mut client).unwrap();
+        match file.cancel(&mut client) {
+            Err(Error::InvalidReply) => (),
+            _ => panic!("Expected InvalidReply error"),
+        }
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_if_exists() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_if_exists").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&local_path).unwrap().write_all(b"abc").unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.addstr("SKIPPED").unwrap();
+            msg.send(&mut server).unwrap();
+
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("EXISTS").unwrap();
+            msg.send(&mut server).unwrap();
+
+            // A server that ignores the policy would overwrite
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr("7").unwrap();
+            msg.send(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, Some(&[Options::IfExists(IfExists::Skip)])).unwrap();
+        file.send(&mut client, "/remote").unwrap();
+        assert!(file.get_stats().skipped);
+        assert_eq!(file.get_stats().chunks_sent, 0);
+
+        let mut file = File::open(&local_path, Some(&[Options::IfExists(IfExists::Fail)])).unwrap();
+        match file.send(&mut client, "/remote") {
+            Err(Error::FileExists) => (),
+            _ => panic!("Expected FileExists error"),
+        }
+        match file.send(&mut client, "/remote") {
+            Err(Error::IfExistsUnsupported) => (),
+            _ => panic!("Expected IfExistsUnsupported error"),
+        }
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_unsupported() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_unsupported").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&local_path).unwrap().write_all(b"abc").unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("UNSUPPORTED").unwrap();
+            msg.addstr("12").unwrap();
+            msg.addstr("13").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, None).unwrap();
+        match file.send(&mut client, "/remote") {
+            Err(Error::UnsupportedVersion(12)) => (),
+            _ => panic!("Expected UnsupportedVersion error"),
+        }
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_unchanged() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_unchanged").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&local_path).unwrap().write_all(b"abc").unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            ZMsg::recv(&mut server).unwrap();
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.addstr("UNCHANGED").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, None).unwrap();
+        file.send(&mut client, "/remote").unwrap();
+        let stats = file.get_stats();
+        assert!(stats.unchanged);
+        assert!(!stats.skipped);
+        assert_eq!(stats.bytes_sent, 0);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_matches_existing() {
+        let tempdir = TempDir::new("file_test_matches_existing").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+        let (crc, _) = hash::hash_file(&path, None).unwrap();
+
+        let options = FileOptions::new(None).encode(WireCodec::Json).unwrap();
+        assert!(File::matches_existing(&path, 3, crc, &options).unwrap());
+        assert!(!File::matches_existing(&path, 3, crc + 1, &options).unwrap());
+        assert!(!File::matches_existing(&path, 4, crc, &options).unwrap());
+        assert!(!File::matches_existing(tempdir.path().join("missing"), 3, crc, &options).unwrap());
+
+        let options = FileOptions::new(Some(&[Options::Mode(0o644)])).encode(WireCodec::Json).unwrap();
+        assert!(!File::matches_existing(&path, 3, crc, &options).unwrap());
+        assert!(!File::could_match_existing(&path, 3, &options).unwrap());
+    }
+
+    #[test]
+    fn test_send_resize() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_resize").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&local_path).unwrap().write_all(b"abcde").unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            ZMsg::recv(&mut server).unwrap();
+
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr(&PROTOCOL_VERSION.to_string()).unwrap();
+            msg.send(&mut server).unwrap();
+
+            let msg = ZMsg::new();
+            msg.addstr("RESIZE").unwrap();
+            protocol::add_u64(&msg, 1, true).unwrap();
+            protocol::add_u64(&msg, 3, true).unwrap();
+            msg.send(&mut server).unwrap();
 
             let msg = ZMsg::new();
             msg.addstr("CHUNK").unwrap();
+            protocol::add_u64(&msg, 1, true).unwrap();
+            msg.send(&mut server).unwrap();
+
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(protocol::pop_u64(&msg, true), Some(1));
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "cde");
+
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
+            msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, Some(&[Options::ChunkSize(2)])).unwrap();
+        file.send(&mut client, "/remote").unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_ahead() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_ahead").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&local_path).unwrap().write_all(b"abcde").unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
+
+        let handle = spawn(move|| {
+            ZMsg::recv(&mut server).unwrap();
+
+            // Grant more than the client asked for
+            let msg = ZMsg::new();
+            msg.addstr("ACK").unwrap();
+            msg.addstr(&PROTOCOL_VERSION.to_string()).unwrap();
             msg.addstr("1").unwrap();
+            msg.addstr("4").unwrap();
+            msg.send(&mut server).unwrap();
+
+            // The window opens past the first request
+            let msg = ZMsg::new();
+            msg.addstr("CHUNK").unwrap();
+            protocol::add_u64(&msg, 0, true).unwrap();
+            msg.send(&mut server).unwrap();
+
+            for &(index, data) in [(0, "a"), (1, "b"), (2, "c")].iter() {
+                let msg = ZMsg::recv(&mut server).unwrap();
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+                assert_eq!(protocol::pop_u64(&msg, true), Some(index));
+                assert_eq!(&msg.popstr().unwrap().unwrap(), data);
+            }
+
+            // A chunk sent ahead that is asked for was dropped, so is
+            // sent again, and only chunk 3 is new to the window
+            let msg = ZMsg::new();
+            msg.addstr("CHUNK").unwrap();
+            protocol::add_u64(&msg, 1, true).unwrap();
+            msg.send(&mut server).unwrap();
+
+            for &(index, data) in [(1, "b"), (3, "d")].iter() {
+                let msg = ZMsg::recv(&mut server).unwrap();
+                assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+                assert_eq!(protocol::pop_u64(&msg, true), Some(index));
+                assert_eq!(&msg.popstr().unwrap().unwrap(), data);
+            }
+
+            let msg = ZMsg::new();
+            msg.addstr("CHUNK").unwrap();
+            protocol::add_u64(&msg, 4, true).unwrap();
+            msg.send(&mut server).unwrap();
+
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
+            assert_eq!(protocol::pop_u64(&msg, true), Some(4));
+
+            let msg = ZMsg::new();
+            msg.addstr("Ok").unwrap();
             msg.send(&mut server).unwrap();
+        });
+
+        let mut file = File::open(&local_path, Some(&[Options::ChunkSize(1), Options::Window(2)])).unwrap();
+        file.send(&mut client, "/remote").unwrap();
+
+        let stats = file.get_stats();
+        assert_eq!(stats.chunks_sent, 6);
+        assert_eq!(stats.retransmits, 1);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_adapt_chunk_size() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_adapt_chunk_size").unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1000, 0, 1, b"{}").unwrap();
+        file.set_adaptive(1, 4);
+
+        for index in 0..super::ADAPT_AFTER as u64 {
+            assert!(file.take_resize().is_none());
+            file.sink(&mut arbitrator, "abc".as_bytes(), index, true).unwrap();
+        }
+
+        // Grown from the first unqueued chunk onwards
+        let first = super::QUEUE_WINDOW + super::ADAPT_AFTER as u64 - 1;
+        assert_eq!(file.take_resize(), Some((first, 2)));
+        assert_eq!(file.layout.count(), first + 365);
+        assert_eq!(file.bytes_done(), super::ADAPT_AFTER as u64);
+
+        file.sink(&mut arbitrator, "abc".as_bytes(), 20, false).unwrap();
+        assert_eq!(file.take_resize(), Some((first + 1, 1)));
+    }
+
+    #[test]
+    fn test_chunk_range() {
+        let tempdir = TempDir::new("file_test_chunk_range").unwrap();
+        let path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        let file = File::open(&path, Some(&[Options::ChunkSize(2)])).unwrap();
+        assert_eq!(file.chunk_range(0, 1).unwrap().count(), 2);
+        assert!(file.chunk_range(1, 2).is_err());
+    }
+
+    #[test]
+    fn test_send_legacy_peer() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_send_legacy_peer").unwrap();
+        let local_path = format!("{}/local_file.txt", tempdir.path().to_str().unwrap());
+        let mut fs_file = fs::File::create(&local_path).unwrap();
+        fs_file.write_all("abc".as_bytes()).unwrap();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        client.set_rcvtimeo(Some(500));
+        server.set_rcvtimeo(Some(500));
 
-            let msg = ZMsg::recv(&mut server).unwrap();
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "CHUNK");
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "1");
-            assert_eq!(&msg.popstr().unwrap().unwrap(), "c");
+        let handle = spawn(move|| {
+            for _ in 0..2 {
+                ZMsg::recv(&mut server).unwrap();
 
-            let msg = ZMsg::new();
-            msg.addstr("Ok").unwrap();
-            msg.send(&mut server).unwrap();
+                let msg = ZMsg::new();
+                msg.addstr("Ok").unwrap();
+                msg.send(&mut server).unwrap();
+            }
         });
 
-        let mut file = File::open(&local_path, Some(&[Options::ChunkSize(2)])).unwrap();
-        file.send(&mut client, &remote_path).unwrap();
+        let mut file = File::open(&local_path, None).unwrap();
+        file.send(&mut client, "/remote").unwrap();
+        assert_eq!(file.get_protocol(), None);
+
+        let mut file = File::open(&local_path, Some(&[Options::Compat(Compat::Versioned)])).unwrap();
+        match file.send(&mut client, "/remote") {
+            Err(Error::LegacyPeer) => (),
+            _ => panic!("Expected LegacyPeer error"),
+        }
 
         handle.join().unwrap();
     }
@@ -386,15 +3418,83 @@ mod tests {
 
         let tempdir = TempDir::new("file_test_recv").unwrap();
         let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
-        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1, 0, 1, "{}").unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1, 0, 1, b"{}").unwrap();
 
         for _ in 0..6 {
             file.sink(&mut arbitrator, "abc".as_bytes(), 0, false).unwrap();
         }
 
         assert!(file.is_error());
+        assert_eq!(file.get_retries(), 5);
+
+        let options = FileOptions::new(Some(&[Options::MaxRetries(2)])).encode(WireCodec::Json).unwrap();
+        let mut file = File::create(&mut arbitrator, "def".as_bytes(), tempdir.path().join("testfile2"), 1, 0, 1, &options).unwrap();
+        file.set_retry_policy(RetryPolicy::default());
+        for _ in 0..3 {
+            file.sink(&mut arbitrator, "def".as_bytes(), 0, false).unwrap();
+        }
+        assert!(file.is_error());
+        assert_eq!(file.get_retries(), 2);
+
+        // The server may allow more retries than fit in a byte
+        let mut many = File::create(&mut arbitrator, "ghi".as_bytes(), tempdir.path().join("testfile3"), 1, 0, 1, b"{}").unwrap();
+        let mut policy = RetryPolicy::default();
+        policy.max_retries = 300;
+        many.set_retry_policy(policy);
+        for _ in 0..301 {
+            many.sink(&mut arbitrator, "ghi".as_bytes(), 0, false).unwrap();
+        }
+        assert!(many.is_error());
+        assert_eq!(many.get_retries(), 300);
+
+        assert_eq!(file.bytes_done(), 0);
         assert!(file.sink(&mut arbitrator, "abc".as_bytes(), 0, true).is_ok());
         assert!(file.is_complete());
+        assert_eq!(file.bytes_done(), 1);
+        assert_eq!(file.get_timings().network, 0);
+
+        // A duplicate report for a received chunk is ignored
+        assert!(file.is_stale(0));
+        assert!(!file.is_stale(1));
+        assert!(file.sink(&mut arbitrator, "abc".as_bytes(), 0, true).is_ok());
+
+        // A chunk sent ahead of the queue needs a slot of its own,
+        // which is released like any other
+        let ahead = super::QUEUE_WINDOW + 1;
+        let mut file = File::create(&mut arbitrator, "ghi".as_bytes(), tempdir.path().join("testfile3"), ahead + 1, 0, 1, b"{}").unwrap();
+        assert!(!file.admit(&mut arbitrator, "ghi".as_bytes(), ahead).unwrap());
+
+        let mut other = Arbitrator::new(ZSock::new(SocketType::ROUTER), 1).unwrap();
+        assert!(file.admit(&mut other, "ghi".as_bytes(), ahead).unwrap());
+        file.sink(&mut other, "ghi".as_bytes(), ahead, true).unwrap();
+        assert!(file.is_stale(ahead));
+        assert_eq!(file.bytes_done(), 1);
+
+        // Nothing else was queued in its place
+        assert_eq!(other.queued(), 0);
+    }
+
+    #[test]
+    fn test_timings() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_timings").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 1, 0, 1, b"{}").unwrap();
+        assert_eq!(file.get_timings(), Timings::default());
+
+        file.add_hashing(Duration::from_millis(3));
+        file.timings.in_flight = Duration::from_millis(5);
+        file.timings.disk = Duration::from_millis(2);
+
+        let timings = file.get_timings();
+        assert_eq!(timings.hashing, 3000);
+        assert_eq!(timings.network, 3000);
+        assert_eq!(timings.disk, 2000);
+
+        file.timings.disk = Duration::from_millis(8);
+        assert_eq!(file.get_timings().network, 0);
     }
 
     #[test]
@@ -408,21 +3508,712 @@ mod tests {
         path.push("file");
 
         let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
-        let file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, "{}").unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, b"{}").unwrap();
 
         assert!(tmp_path.exists());
+        assert!(manifest_path(&tmp_path).exists());
+        file.set_transfer_id(3).unwrap();
+        let manifest = Manifest::load(&manifest_path(&tmp_path)).unwrap();
+        assert_eq!((manifest.router_id, manifest.transfer_id), (Some(b"abc".to_vec()), Some(3)));
         assert!(!path.exists());
         assert!(file.save().is_ok());
         assert!(!tmp_path.exists());
+        assert!(!manifest_path(&tmp_path).exists());
         assert!(path.exists());
     }
 
+    #[test]
+    fn test_save_staging_dir() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_staging_dir").unwrap();
+        let staging_dir = tempdir.path().join("staging");
+        let path = tempdir.path().join("dest/file");
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create_staged(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, b"{}", &[], Some(&staging_dir), StagingNames::Hidden).unwrap();
+        assert_eq!(file.get_upload_path().unwrap(), &staging_dir.join(".file0"));
+        file.save().unwrap();
+        assert!(path.exists());
+        assert!(!staging_dir.join(".file0").exists());
+    }
+
+    #[test]
+    fn test_save_copy() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_copy").unwrap();
+        let staging_dir = tempdir.path().join("staging");
+        let path = tempdir.path().join("dest/file");
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create_staged(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, b"{}", &[], Some(&staging_dir), StagingNames::Hidden).unwrap();
+        // Both are on the same filesystem, so there's nothing to copy
+        assert_eq!(file.stage_copy().unwrap(), None);
+
+        // As though they weren't, and the copy had been made
+        let copy_path = tempdir.path().join("dest/.file0");
+        fs::File::create(&copy_path).unwrap();
+        super::copy_synced(&staging_dir.join(".file0"), &copy_path).unwrap();
+        file.copy_path = Some(copy_path.clone());
+        file.save().unwrap();
+        assert!(path.exists());
+        assert!(!copy_path.exists());
+        assert!(!staging_dir.join(".file0").exists());
+
+        let mut file = File::create_staged(&mut arbitrator, "abc".as_bytes(), &path, 0, 1, 1, b"{}", &[], Some(&staging_dir), StagingNames::Hidden).unwrap();
+        fs::File::create(&copy_path).unwrap();
+        file.copy_path = Some(copy_path.clone());
+        assert!(file.save().is_err());
+        assert!(!copy_path.exists());
+    }
+
+    #[test]
+    fn test_create_staging_file() {
+        let tempdir = TempDir::new("file_test_create_staging_file").unwrap();
+        let staging_dir = tempdir.path().join("staging");
+        let path = tempdir.path().join("file");
+
+        let (first, _) = File::create_staging_file(&path, Some(&staging_dir), StagingNames::Random).unwrap();
+        let (second, _) = File::create_staging_file(&path, Some(&staging_dir), StagingNames::Random).unwrap();
+        assert!(first != second);
+        assert_eq!(first.parent(), Some(staging_dir.as_path()));
+
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("file.") && name.ends_with(".part"));
+        assert_eq!(name.len(), "file..part".len() + 16);
+        assert!(first.exists() && second.exists());
+
+        let (hidden, _) = File::create_staging_file(&path, None, StagingNames::Hidden).unwrap();
+        assert_eq!(hidden, tempdir.path().join(".file0"));
+
+        // A name taken since is never shared
+        let (next, _) = File::create_staging_file(&path, None, StagingNames::Hidden).unwrap();
+        assert_eq!(next, tempdir.path().join(".file1"));
+    }
+
+    #[test]
+    fn test_open_with_known_crc() {
+        let tempdir = TempDir::new("file_test_open_with_known_crc").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        assert_eq!(File::open_with_known_crc(&path, 1, None).unwrap().get_crc(), 1);
+        assert!(File::open_with_known_crc(tempdir.path().join("moo"), 1, None).is_err());
+
+        // A digest needs the file read anyway, and so does its CRC
+        let file = File::open_with_known_crc(&path, 1, Some(&[Options::Hash(HashAlgorithm::Sha256)])).unwrap();
+        assert_eq!(file.get_crc(), File::checksum(&path).unwrap());
+        let digest = file.get_digest().unwrap().to_vec();
+
+        // Unless the digest is known too
+        let file = File::open_with_known_digest(&path, 1, vec![2], Some(&[Options::Hash(HashAlgorithm::Sha256)])).unwrap();
+        assert_eq!(file.get_crc(), 1);
+        assert_eq!(file.get_digest(), Some(&[2][..]));
+        assert!(digest != vec![2]);
+        assert_eq!(File::open_with_known_digest(&path, 1, vec![2], None).unwrap().get_digest(), None);
+    }
+
+    #[test]
+    fn test_copy_into_place() {
+        let tempdir = TempDir::new("file_test_copy_into_place").unwrap();
+        let from = tempdir.path().join("from");
+        let to = tempdir.path().join("to");
+        fs::File::create(&from).unwrap().write_all(b"new").unwrap();
+        fs::File::create(&to).unwrap().write_all(b"old").unwrap();
+
+        super::copy_into_place(&from, &to, |p| {
+            assert!(p != to.as_path());
+            Ok(())
+        }).unwrap();
+
+        let mut content = String::new();
+        fs::File::open(&to).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "new");
+        assert!(!from.exists());
+        assert!(!tempdir.path().join(".to0").exists());
+    }
+
+    #[test]
+    fn test_save_backup_versions() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_backup_versions").unwrap();
+        let path = tempdir.path().join("file");
+        let backup = |n: u32| tempdir.path().join(format!("file.bk.{}", n));
+        let options = FileOptions::new(Some(&[Options::BackupVersions(2)])).encode(WireCodec::Json).unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+
+        for version in ["v0", "v1", "v2"].iter() {
+            fs::File::create(&path).unwrap().write_all(version.as_bytes()).unwrap();
+            let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, &options).unwrap();
+            file.save().unwrap();
+        }
+
+        let mut content = String::new();
+        fs::File::open(backup(1)).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "v2");
+        content.clear();
+        fs::File::open(backup(2)).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "v1");
+        assert!(!backup(3).exists());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        let options = FileOptions::new(Some(&[Options::BackupVersions(super::MAX_BACKUP_VERSIONS + 1)])).encode(WireCodec::Json).unwrap();
+        match File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, &options) {
+            Err(Error::InvalidFileOpts) => (),
+            _ => panic!("Expected InvalidFileOpts error"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_symlink() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_symlink").unwrap();
+        let target = tempdir.path().join("target");
+        fs::File::create(&target).unwrap().write_all(b"abc").unwrap();
+        let link = tempdir.path().join("link");
+        symlink("target", &link).unwrap();
+
+        // Without the option, the link is followed
+        assert_eq!(File::open(&link, None).unwrap().size, 3);
+
+        let mut file = File::open(&link, Some(&[Options::PreserveSymlinks])).unwrap();
+        assert_eq!(file.size, 0);
+        assert_eq!(file.options.symlink, Some("target".to_string()));
+
+        file.set_protocol(Some(protocol::UNSUPPORTED_REPLY));
+        match file.check_peer() {
+            Err(Error::SymlinkUnsupported) => (),
+            _ => panic!("Expected SymlinkUnsupported error"),
+        }
+        file.set_protocol(Some(protocol::SYMLINKS));
+        assert!(file.check_peer().is_ok());
+
+        // Replaces the file at the destination
+        let dest = tempdir.path().join("dest");
+        fs::File::create(&dest).unwrap();
+        let options = file.options.encode(WireCodec::Json).unwrap();
+        assert!(!File::matches_existing(&dest, 0, file.crc, &options).unwrap());
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut upload = File::create(&mut arbitrator, "abc".as_bytes(), &dest, 0, file.crc, 1, &options).unwrap();
+        upload.save().unwrap();
+        assert_eq!(fs::read_link(&dest).unwrap(), Path::new("target"));
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_save_digest() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_digest").unwrap();
+        let empty = tempdir.path().join("empty");
+        fs::File::create(&empty).unwrap();
+        let abc = tempdir.path().join("abc");
+        fs::File::create(&abc).unwrap().write_all(b"abc").unwrap();
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+
+        let file = File::open(&empty, Some(&[Options::Hash(HashAlgorithm::Sha256)])).unwrap();
+        assert_eq!(file.hash_algorithm(), Some(HashAlgorithm::Sha256));
+        let options = file.options.encode(WireCodec::Json).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), tempdir.path().join("a"), 0, 0, 1, &options).unwrap();
+        assert!(file.save().is_ok());
+
+        // The staged file is empty, so only its CRC matches
+        let file = File::open(&abc, Some(&[Options::Hash(HashAlgorithm::Blake2b)])).unwrap();
+        let options = file.options.encode(WireCodec::Json).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), tempdir.path().join("b"), 0, 0, 1, &options).unwrap();
+        match file.save() {
+            Err(Error::FailDigest) => (),
+            _ => panic!("Digest should not match"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_attrs() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_attrs").unwrap();
+        let local = tempdir.path().join("local");
+        fs::File::create(&local).unwrap();
+
+        let file = File::open(&local, Some(&[Options::Mode(0o600), Options::PreserveTimestamps])).unwrap();
+        let mtime = file.options.mtime;
+        assert_eq!(mtime, ::attrs::mtime(&fs::metadata(&local).unwrap()));
+
+        let path = tempdir.path().join("file");
+        let options = file.options.encode(WireCodec::Json).unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, &options).unwrap();
+        file.save().unwrap();
+
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(::attrs::mtime(&meta), mtime.map(|(secs, nanos)| (secs, nanos / 1000 * 1000)));
+    }
+
+    #[test]
+    fn test_resume() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_resume").unwrap();
+        let tmp_path = tempdir.path().join(".file0");
+        let path = tempdir.path().join("file");
+        fs::File::create(&tmp_path).unwrap().write_all(b"ab").unwrap();
+
+        let manifest = Manifest {
+            version: STAGING_VERSION,
+            protocol: Some(PROTOCOL_VERSION),
+            path: path.to_str().unwrap().into(),
+            size: 3,
+            crc: 0,
+            chunk_size: 1,
+            options: b"{}".to_vec(),
+            missing: vec![(1, 2)],
+            router_id: None,
+            transfer_id: Some(4),
+            client: None,
+        };
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        assert!(File::resume(&mut arbitrator, "abc".as_bytes(), &tmp_path, manifest.clone()).is_err());
+
+        fs::OpenOptions::new().write(true).open(&tmp_path).unwrap().set_len(3).unwrap();
+        let file = File::resume(&mut arbitrator, "abc".as_bytes(), &tmp_path, manifest.clone()).unwrap();
+        assert_eq!(file.get_path(), Some(path.as_path()));
+        assert_eq!(file.get_protocol(), Some(PROTOCOL_VERSION));
+        assert_eq!(file.chunks_outstanding(), 1);
+        assert!(file.is_stale(0));
+        assert!(!file.is_stale(1));
+        assert!(file.is_stale(2));
+        assert_eq!(file.next_unqueued(), None);
+        assert_eq!(file.manifest.as_ref().unwrap().router_id, Some(b"abc".to_vec()));
+
+        let bad = Manifest { missing: vec![(1, 4)], ..manifest };
+        assert!(File::resume(&mut arbitrator, "abc".as_bytes(), &tmp_path, bad).is_err());
+    }
+
+    #[test]
+    fn test_create_delta() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_create_delta").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abcde").unwrap();
+
+        // A chunk running past the end of the existing file isn't
+        // shared with it
+        assert_eq!(File::chunk_hashes(&path, 5, 2).unwrap().len(), 3);
+        assert_eq!(File::chunk_hashes(&path, 8, 2).unwrap().len(), 2);
+        assert!(File::chunk_hashes(tempdir.path().join("missing"), 5, 2).unwrap().is_empty());
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        assert!(File::create_delta(&mut arbitrator, "abc".as_bytes(), &path, 8, 0, 2, b"{}", &[3]).is_err());
+
+        let file = File::create_delta(&mut arbitrator, "abc".as_bytes(), &path, 8, 0, 2, b"{}", &[1]).unwrap();
+        assert_eq!(file.chunks_outstanding(), 3);
+        assert!(file.is_stale(1));
+
+        let mut content = Vec::new();
+        fs::File::open(file.upload_path.as_ref().unwrap()).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(&content[2..4], b"cd");
+    }
+
+    #[test]
+    fn test_save_output() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_output").unwrap();
+        let path = tempdir.path().join("file");
+        let out_path = tempdir.path().join("out");
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, b"{}").unwrap();
+        file.set_output(Box::new(fs::File::create(&out_path).unwrap()));
+
+        assert!(file.save().is_ok());
+        assert!(!tempdir.path().join(".file0").exists());
+        assert!(!path.exists());
+        assert!(out_path.exists());
+    }
+
+    #[test]
+    fn test_take_stream() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_take_stream").unwrap();
+        let path = tempdir.path().join("file");
+        let out_path = tempdir.path().join("out");
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, b"{}").unwrap();
+        assert!(file.take_stream().is_none());
+
+        file.set_output(Box::new(fs::File::create(&out_path).unwrap()));
+        let stream = file.take_stream().unwrap();
+        let upload_path = file.get_upload_path().unwrap().to_owned();
+        fs::File::create(&upload_path).unwrap().write_all(b"12345").unwrap();
+
+        stream.copy(&upload_path, 0, None).unwrap();
+        let mut content = Vec::new();
+        fs::File::open(&out_path).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"12345");
+
+        // Saving only removes the upload
+        assert!(file.save_checked(0, None).is_ok());
+        assert!(!upload_path.exists());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_save_fifo() {
+        use libc;
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_fifo").unwrap();
+        let path = tempdir.path().join("fifo");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, b"{}").unwrap();
+        match file.save() {
+            Err(Error::SpecialFile) => (),
+            _ => panic!("FIFO should be refused"),
+        }
+
+        // Without a reader, opening fails rather than blocking
+        let mut file = File::create(&mut arbitrator, "def".as_bytes(), &path, 0, 0, 1, b"{}").unwrap();
+        file.set_stream_fifos();
+        assert!(file.save().is_err());
+    }
+
+    #[test]
+    fn test_open_special() {
+        match File::open("/dev/null", None) {
+            Err(Error::SpecialFile) => (),
+            _ => panic!("Device should be rejected"),
+        }
+
+        match File::open_file(fs::File::open("/dev/null").unwrap(), None) {
+            Err(Error::SpecialFile) => (),
+            _ => panic!("Device should be rejected"),
+        }
+
+        match File::open("/nonexistent", None) {
+            Err(Error::InvalidFilePath) => (),
+            _ => panic!("Missing file should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_open_range() {
+        let tempdir = TempDir::new("file_test_open_range").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abcdef").unwrap();
+        let part_path = tempdir.path().join("part");
+        fs::File::create(&part_path).unwrap().write_all(b"cde").unwrap();
+
+        let file = File::open(&path, Some(&[Options::Range(2, 3)])).unwrap();
+        assert_eq!(file.get_size(), 3);
+        assert_eq!(file.crc, File::checksum(&part_path).unwrap());
+        assert_eq!(file.layout.offset(0), 2);
+
+        assert!(File::open(&path, Some(&[Options::Range(4, 3)])).is_err());
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let tempdir = TempDir::new("file_test_from_reader").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abcdef").unwrap();
+
+        let file = File::from_reader(Cursor::new(b"abcdef".to_vec()), None).unwrap();
+        assert_eq!(file.get_size(), 6);
+        assert_eq!(file.crc, File::checksum(&path).unwrap());
+
+        let mut chunks = file.chunk_range(0, 0).unwrap();
+        let msg = ZMsg::new();
+        chunks.next().unwrap().add_to(&msg, &Layout::new(6, 4), false, None, false).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "abcd");
+
+        let file = File::from_reader(Cursor::new(b"abcdef".to_vec()), Some(&[Options::Range(2, 3)])).unwrap();
+        assert_eq!(file.get_size(), 3);
+        assert!(File::from_reader(Cursor::new(b"abcdef".to_vec()), Some(&[Options::Range(4, 3)])).is_err());
+    }
+
+    #[test]
+    fn test_save_range() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_range").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abcdef").unwrap();
+        let patch_path = tempdir.path().join("patch");
+        fs::File::create(&patch_path).unwrap().write_all(b"XYZ").unwrap();
+        let crc = File::checksum(&patch_path).unwrap();
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 3, crc, 3, b"{\"range\":[2,3]}").unwrap();
+        let tmp_path = tempdir.path().join(".file0");
+        fs::OpenOptions::new().write(true).open(&tmp_path).unwrap().write_all(b"XYZ").unwrap();
+
+        file.save().unwrap();
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abXYZf");
+        assert!(!tmp_path.exists());
+
+        // The range must start within the existing file
+        assert!(File::create(&mut arbitrator, "abc".as_bytes(), &path, 3, crc, 3, b"{\"range\":[7,3]}").is_err());
+        assert!(File::create(&mut arbitrator, "abc".as_bytes(), &tempdir.path().join("none"), 3, crc, 3, b"{\"range\":[0,3]}").is_err());
+    }
+
+    #[test]
+    fn test_save_append_write_at() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_append_write_at").unwrap();
+        let path = tempdir.path().join("file");
+        let tmp_path = tempdir.path().join(".file0");
+        let patch_path = tempdir.path().join("patch");
+        fs::File::create(&patch_path).unwrap().write_all(b"XYZ").unwrap();
+        let crc = File::checksum(&patch_path).unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+
+        // Appending creates the file if it's missing
+        for &expected in ["XYZ", "XYZXYZ"].iter() {
+            let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 3, crc, 3, b"{\"append\":true}").unwrap();
+            fs::OpenOptions::new().write(true).open(&tmp_path).unwrap().write_all(b"XYZ").unwrap();
+            file.save().unwrap();
+
+            let mut content = String::new();
+            fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+            assert_eq!(content, expected);
+            assert!(!tmp_path.exists());
+        }
+
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 3, crc, 3, b"{\"write_at\":1}").unwrap();
+        fs::OpenOptions::new().write(true).open(&tmp_path).unwrap().write_all(b"XYZ").unwrap();
+        file.save().unwrap();
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "XXYZYZ");
+
+        // The offset must be within the existing file
+        assert!(File::create(&mut arbitrator, "abc".as_bytes(), &path, 3, crc, 3, b"{\"write_at\":7}").is_err());
+        assert!(File::create(&mut arbitrator, "abc".as_bytes(), &tempdir.path().join("none"), 3, crc, 3, b"{\"write_at\":0}").is_err());
+
+        // Appending a range doesn't need the range's offset to exist
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 3, crc, 3, b"{\"append\":true,\"range\":[9,3]}").unwrap();
+        fs::OpenOptions::new().write(true).open(&tmp_path).unwrap().write_all(b"XYZ").unwrap();
+        file.save().unwrap();
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "XXYZYZXYZ");
+
+        // An older server would replace the file instead
+        let mut file = File::open(&patch_path, Some(&[Options::Append])).unwrap();
+        file.set_protocol(Some(protocol::SPARSE_CHUNKS));
+        match file.check_peer() {
+            Err(Error::OffsetWriteUnsupported) => (),
+            _ => panic!("Expected OffsetWriteUnsupported error"),
+        }
+    }
+
+    #[test]
+    fn test_restore_region() {
+        let tempdir = TempDir::new("file_test_restore_region").unwrap();
+        let path = tempdir.path().join("file");
+        let saved_path = tempdir.path().join("saved");
+        fs::File::create(&path).unwrap().write_all(b"abcXYZW").unwrap();
+        fs::File::create(&saved_path).unwrap().write_all(b"cde").unwrap();
+
+        // A patch that overwrote "cde" at 2 and grew the file
+        let mut dest = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut saved = fs::File::open(&saved_path).unwrap();
+        super::restore_region(&mut dest, 2, 5, Some(&mut saved)).unwrap();
+
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abcde");
+
+        // Nothing was overwritten by an append
+        super::restore_region(&mut dest, 5, 3, None).unwrap();
+        content.clear();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abc");
+    }
+
+    #[test]
+    fn test_follow_growth() {
+        let tempdir = TempDir::new("file_test_follow_growth").unwrap();
+        let path = tempdir.path().join("log");
+        let part_path = tempdir.path().join("part");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+        fs::File::create(&part_path).unwrap().write_all(b"de").unwrap();
+
+        let mut file = File::open(&path, Some(&[Options::Follow(10)])).unwrap();
+        assert_eq!(file.follow, Some(Duration::from_millis(10)));
+        assert!(!file.follow_growth().unwrap());
+
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"de").unwrap();
+        assert!(file.follow_growth().unwrap());
+        assert_eq!(file.get_size(), 2);
+        assert_eq!(file.get_crc(), File::checksum(&part_path).unwrap());
+        assert_eq!(file.options.range, Some((3, 2)));
+        assert_eq!(file.options.write_at, Some(3));
+        assert!(!file.follow_growth().unwrap());
+
+        // Each addition goes just past the last
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"f").unwrap();
+        assert!(file.follow_growth().unwrap());
+        assert_eq!(file.options.range, Some((5, 1)));
+        assert_eq!(file.options.write_at, Some(5));
+
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(1).unwrap();
+        match file.follow_growth() {
+            Err(Error::Truncated) => (),
+            _ => panic!("Expected Truncated error"),
+        }
+
+        match File::open(&path, Some(&[Options::Follow(10), Options::Append])) {
+            Err(Error::InvalidFileOpts) => (),
+            _ => panic!("Expected InvalidFileOpts error"),
+        }
+    }
+
+    #[test]
+    fn test_save_durable() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_save_durable").unwrap();
+        let path = tempdir.path().join("file");
+        let tmp_path = tempdir.path().join(".file0");
+        let data_path = tempdir.path().join("data");
+        fs::File::create(&data_path).unwrap().write_all(b"abc").unwrap();
+        let crc = File::checksum(&data_path).unwrap();
+
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 3, crc, 3, b"{}").unwrap();
+        file.set_durable();
+        fs::OpenOptions::new().write(true).open(&tmp_path).unwrap().write_all(b"abc").unwrap();
+        file.save().unwrap();
+        assert_eq!(File::checksum(&path).unwrap(), crc);
+        assert!(!tmp_path.exists());
+
+        let mut file = File::open(&data_path, Some(&[Options::Durable])).unwrap();
+        file.set_protocol(Some(protocol::OFFSET_WRITES));
+        match file.check_peer() {
+            Err(Error::DurableUnsupported) => (),
+            _ => panic!("Expected DurableUnsupported error"),
+        }
+    }
+
+    #[test]
+    fn test_write_sidecar() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("file_test_write_sidecar").unwrap();
+        let path = tempdir.path().join("file");
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let file = File::create(&mut arbitrator, "abc".as_bytes(), &path, 0, 0, 1, b"{\"metadata\":{\"owner\":\"ops\"}}").unwrap();
+        assert_eq!(file.get_metadata().unwrap().get("owner").unwrap(), "ops");
+
+        file.write_sidecar(b"abc", 7).unwrap();
+
+        let mut content = String::new();
+        fs::File::open(tempdir.path().join("file.meta")).unwrap().read_to_string(&mut content).unwrap();
+        let json: Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(json["identity"].as_str(), Some("616263"));
+        assert_eq!(json["crc"].as_u64(), Some(7));
+        assert_eq!(json["size"].as_u64(), Some(0));
+        assert_eq!(json["metadata"]["owner"].as_str(), Some("ops"));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(super::parse_range("5-12").unwrap(), (5, 12));
+        assert!(super::parse_range("5").is_err());
+        assert!(super::parse_range("12-5").is_err());
+        assert!(super::parse_range("a-b").is_err());
+    }
+
     #[test]
     fn test_file_options() {
-        let options = FileOptions::new(Some(&[Options::BackupExisting("_moo".into()), Options::ChunkSize(123)]));
-        let encoded = options.encode().unwrap();
-        let decoded = FileOptions::decode(&encoded).unwrap();
-        assert_eq!(&decoded.backup_existing.unwrap(), "_moo");
-        assert_eq!(decoded.chunk_size.unwrap(), 123);
+        let options = FileOptions::new(Some(&[Options::BackupExisting("_moo".into()), Options::ChunkSize(123), Options::Metadata("owner".into(), "ops".into())]));
+
+        for codec in WireCodec::supported() {
+            let encoded = options.encode(codec).unwrap();
+            let decoded = FileOptions::decode(&encoded).unwrap();
+            assert_eq!(&decoded.backup_existing.unwrap(), "_moo");
+            assert_eq!(decoded.chunk_size.unwrap(), 123);
+            assert_eq!(decoded.protocol, Some(PROTOCOL_VERSION));
+            assert_eq!(decoded.metadata.unwrap().get("owner").unwrap(), "ops");
+        }
+
+        assert_eq!(File::options_protocol(b"{}").unwrap(), None);
+
+        let options = FileOptions::new(Some(&[Options::Compat(Compat::Legacy)]));
+        assert!(options.protocol.is_none());
+    }
+
+    #[test]
+    fn test_file_options_versions() {
+        // A peer that knows about fewer fields sends fewer. The first
+        // peers sent only the backup suffix and chunk size.
+        let chunk_size = [0, 0, 0, 0, 0, 0, 0, 123];
+        let mut legacy = vec![0, 0, 1];
+        legacy.extend_from_slice(&chunk_size);
+        let decoded = FileOptions::decode(&legacy).unwrap();
+        assert_eq!(decoded.chunk_size, Some(123));
+        assert_eq!(decoded.version, None);
+
+        // Version 1 added the version after the first 17 fields, and
+        // version 5 is 4 fields on with `durable` last
+        let mut v1 = legacy.clone();
+        v1.extend_from_slice(&[0; 15]);
+        v1.extend_from_slice(&[1, 0, 0, 0, 1]);
+        let decoded = FileOptions::decode(&v1).unwrap();
+        assert_eq!(decoded.chunk_size, Some(123));
+        assert_eq!(decoded.version, Some(1));
+        assert_eq!(decoded.durable, None);
+
+        let mut v5 = legacy.clone();
+        v5.extend_from_slice(&[0; 15]);
+        v5.extend_from_slice(&[1, 0, 0, 0, 5, 0, 0, 0, 1, 1]);
+        let decoded = FileOptions::decode(&v5).unwrap();
+        assert_eq!(decoded.version, Some(5));
+        assert_eq!(decoded.durable, Some(true));
+        assert_eq!(decoded.stripes, None);
+
+        // Options with fields missing for the version they claim, or
+        // with fields after the version but none claimed, are refused
+        let mut short = v5.clone();
+        short.truncate(v5.len() - 2);
+        assert!(FileOptions::decode(&short).is_err());
+        let mut unclaimed = legacy.clone();
+        unclaimed.extend_from_slice(&[0; 17]);
+        assert!(FileOptions::decode(&unclaimed).is_err());
+
+        let options = FileOptions::new(Some(&[Options::ChunkSize(123)]));
+        let decoded = FileOptions::decode(&options.encode(WireCodec::Binary).unwrap()).unwrap();
+        assert_eq!(decoded.chunk_size, Some(123));
+        assert_eq!(decoded.version, Some(OPTIONS_VERSION));
+
+        let decoded = FileOptions::decode(b"{\"chunk_size\":123,\"version\":99,\"new_option\":[1,2]}").unwrap();
+        assert_eq!(decoded.chunk_size, Some(123));
+        assert_eq!(decoded.version, Some(99));
+        assert_eq!(FileOptions::decode(b"{}").unwrap().version, None);
+        assert_eq!(FileOptions::new(None).version, Some(OPTIONS_VERSION));
     }
 }
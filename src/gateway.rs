@@ -0,0 +1,198 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use czmq::ZSock;
+use error::{Error, Result};
+use file::{File, Options};
+use std::io::{self, Seek, SeekFrom};
+use tempfile::tempfile;
+use tiny_http::{Header, Method, Request, Response, Server as HttpServer, StatusCode};
+
+/// Accepts uploads via HTTP PUT and forwards them to a zfilexfer
+/// server using the normal NEW/CHUNK flow.
+///
+/// The request path, percent-decoded, is used as the remote path.
+/// File options can be passed as query parameters, e.g.
+/// `PUT /etc/app.conf?chunk_size=4096&backup=.bk`.
+pub struct HttpGateway {
+    http: HttpServer,
+    endpoint: String,
+    timeout: u32,
+}
+
+/// Milliseconds the gateway waits on the server by default
+const DEFAULT_TIMEOUT: u32 = 60_000;
+
+impl HttpGateway {
+    /// Listen for HTTP requests on `addr` and forward uploads to the
+    /// zfilexfer server at `endpoint`.
+    pub fn new(addr: &str, endpoint: &str) -> Result<HttpGateway> {
+        let http = try!(HttpServer::http(addr).map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+        Ok(HttpGateway {
+            http: http,
+            endpoint: endpoint.into(),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Milliseconds to wait for each message to or from the server
+    /// before answering 504 Gateway Timeout. Defaults to 60 seconds.
+    pub fn set_timeout(&mut self, millis: u32) {
+        self.timeout = millis;
+    }
+
+    /// Serve requests until the HTTP server shuts down
+    pub fn run(&self) -> Result<()> {
+        for request in self.http.incoming_requests() {
+            // A client that goes away mustn't stop the others
+            if let Err(e) = self.handle(request) {
+                warn!("gateway request failed error={:?}", e.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, mut request: Request) -> Result<()> {
+        if *request.method() != Method::Put {
+            try!(request.respond(Response::from_string("Method not allowed").with_status_code(StatusCode(405))));
+            return Ok(());
+        }
+
+        let result = match parse_url(request.url()) {
+            Ok((path, options)) => self.forward(&mut request, &path, &options),
+            Err(e) => Err(e),
+        };
+
+        let response = match result {
+            Ok(_) => Response::from_string("Created").with_status_code(StatusCode(201)),
+            Err(Error::InvalidFileOpts) => Response::from_string(Error::InvalidFileOpts.to_string()).with_status_code(StatusCode(400)),
+            Err(Error::InvalidFilePath) => Response::from_string(Error::InvalidFilePath.to_string()).with_status_code(StatusCode(400)),
+            Err(Error::Busy(secs)) => Response::from_string(Error::Busy(secs).to_string())
+                                              .with_status_code(StatusCode(503))
+                                              .with_header(Header::from_bytes(&b"Retry-After"[..], secs.to_string()).unwrap()),
+            Err(Error::Timeout) => Response::from_string(Error::Timeout.to_string()).with_status_code(StatusCode(504)),
+            Err(e) => Response::from_string(e.to_string()).with_status_code(StatusCode(502)),
+        };
+        try!(request.respond(response));
+
+        Ok(())
+    }
+
+    fn forward(&self, request: &mut Request, path: &str, options: &[Options]) -> Result<()> {
+        // Spool the body to disk, as the chunking protocol needs to
+        // seek within the file.
+        let mut fh = try!(tempfile());
+        try!(io::copy(request.as_reader(), &mut fh));
+        try!(fh.seek(SeekFrom::Start(0)));
+
+        // A server that stops answering mustn't hold the request open
+        // forever
+        let mut sock = try!(ZSock::new_dealer(&self.endpoint));
+        sock.set_sndtimeo(Some(self.timeout as i32));
+        let mut options = options.to_vec();
+        options.push(Options::IdleTimeout(self.timeout));
+
+        let mut file = try!(File::open_file(fh, Some(&options)));
+        file.send(&mut sock, path)
+    }
+}
+
+fn parse_url(url: &str) -> Result<(String, Vec<Options>)> {
+    let mut parts = url.splitn(2, '?');
+    let path = try!(percent_decode(parts.next().unwrap()).ok_or(Error::InvalidFilePath));
+    let mut options = Vec::new();
+
+    if let Some(query) = parts.next() {
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let mut pair = param.splitn(2, '=');
+            match (pair.next().unwrap(), pair.next()) {
+                ("backup", Some(suffix)) => options.push(Options::BackupExisting(try!(percent_decode(suffix).ok_or(Error::InvalidFileOpts)))),
+                ("chunk_size", Some(size)) => options.push(Options::ChunkSize(try!(size.parse().or(Err(Error::InvalidFileOpts))))),
+                _ => return Err(Error::InvalidFileOpts),
+            }
+        }
+    }
+
+    Ok((path, options))
+}
+
+// Decode `%XX` escapes, or None if one is malformed or the result
+// isn't UTF-8
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = match s.get(i + 1..i + 3) {
+                Some(hex) if hex.chars().all(|c| c.is_digit(16)) => hex,
+                _ => return None,
+            };
+            decoded.push(match u8::from_str_radix(hex, 16) {
+                Ok(b) => b,
+                Err(_) => return None,
+            });
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use file::Options;
+    use super::{parse_url, percent_decode};
+
+    #[test]
+    fn test_parse_url() {
+        let (path, options) = parse_url("/path/to/file").unwrap();
+        assert_eq!(path, "/path/to/file");
+        assert!(options.is_empty());
+
+        let (path, options) = parse_url("/file?backup=.bk&chunk_size=10").unwrap();
+        assert_eq!(path, "/file");
+        match options[0] {
+            Options::BackupExisting(ref s) => assert_eq!(s, ".bk"),
+            _ => panic!("Expected BackupExisting"),
+        }
+        match options[1] {
+            Options::ChunkSize(s) => assert_eq!(s, 10),
+            _ => panic!("Expected ChunkSize"),
+        }
+
+        assert!(parse_url("/file?chunk_size=moo").is_err());
+        assert!(parse_url("/file?moo=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_url_escaped() {
+        let (path, options) = parse_url("/my%20file?backup=%2Ebk").unwrap();
+        assert_eq!(path, "/my file");
+        match options[0] {
+            Options::BackupExisting(ref s) => assert_eq!(s, ".bk"),
+            _ => panic!("Expected BackupExisting"),
+        }
+
+        assert!(parse_url("/file%2").is_err());
+        assert!(parse_url("/file%zz").is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+        assert_eq!(percent_decode("100%25").unwrap(), "100%");
+        assert!(percent_decode("%ff").is_none());
+    }
+}
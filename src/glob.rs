@@ -0,0 +1,349 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Files picked by wildcard patterns, e.g. `conf.d/*.toml`, and sent
+//! under a remote directory. Patterns are expanded on the client.
+//!
+//! Within a path component, `*` matches any run of characters, `?`
+//! any one character and `[a-z]` or `[!a-z]` any one character in or
+//! not in a set. A component that is only `**` matches any number of
+//! directories. As in a shell, names starting with '.' are only
+//! matched by a pattern that starts with '.' too.
+
+use batch::Batch;
+use czmq::ZSock;
+use error::{Error, Result};
+use file::{File, Options};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub struct Glob {
+    /// Local files matched, and where they're sent relative to the
+    /// remote directory
+    files: Vec<(PathBuf, PathBuf)>,
+    excludes: Vec<Vec<String>>,
+    options: Vec<Options>,
+    results: Vec<(PathBuf, Result<()>)>,
+}
+
+impl Glob {
+    /// Start with no files. `options` apply to each file sent.
+    pub fn new(options: Option<&[Options]>) -> Glob {
+        Glob {
+            files: Vec::new(),
+            excludes: Vec::new(),
+            options: options.map_or(Vec::new(), |o| o.to_vec()),
+            results: Vec::new(),
+        }
+    }
+
+    /// Add the files matching `pattern`. Each is sent to its path
+    /// below the pattern's last directory without wildcards, so
+    /// `conf.d/**/*.toml` sends `conf.d/a/b.toml` to `a/b.toml` in
+    /// the remote directory. Directories aren't sent, and symlinks
+    /// aren't followed into them.
+    pub fn include(&mut self, pattern: &str) -> Result<()> {
+        let components = split(pattern);
+        if components.is_empty() {
+            return Err(Error::InvalidFilePath);
+        }
+
+        let literal = components[..components.len() - 1].iter().take_while(|c| !is_wild(c)).count();
+        let mut base = PathBuf::new();
+        for component in components[..literal].iter() {
+            base.push(component);
+        }
+        if pattern.starts_with('/') {
+            base = Path::new("/").join(base);
+        }
+
+        let mut matches = Vec::new();
+        try!(expand(&base, Path::new(""), &components[literal..], &mut matches));
+        for relative in matches {
+            self.files.push((base.join(&relative), relative));
+        }
+        self.files.sort();
+        self.files.dedup();
+        Ok(())
+    }
+
+    /// Leave out files whose remote path, relative to the remote
+    /// directory, matches `pattern`. A pattern without a '/' is
+    /// matched against file names alone, so `*.bak` leaves out
+    /// backups in every directory.
+    pub fn exclude(&mut self, pattern: &str) {
+        let mut components = split(pattern);
+        if !pattern.contains('/') {
+            components.insert(0, "**".into());
+        }
+        self.excludes.push(components);
+    }
+
+    /// Local files that will be sent, and where, after excludes
+    pub fn files(&self) -> Vec<(&Path, &Path)> {
+        self.files.iter()
+            .filter(|&&(_, ref relative)| !self.is_excluded(relative))
+            .map(|&(ref path, ref relative)| (path.as_path(), relative.as_path()))
+            .collect()
+    }
+
+    fn is_excluded(&self, relative: &Path) -> bool {
+        let names: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+        self.excludes.iter().any(|pattern| matches_path(pattern, &names))
+    }
+
+    /// Send each file to its path under `remote_dir`, one after the
+    /// other. Files that fail don't stop the rest, but this returns
+    /// `Error::FileFail` if any did. See `get_results()` for which.
+    pub fn send<P: AsRef<Path>>(&mut self, sock: &mut ZSock, remote_dir: P) -> Result<()> {
+        let files: Vec<(PathBuf, PathBuf)> = self.files().into_iter().map(|(p, r)| (p.to_owned(), r.to_owned())).collect();
+        self.results.clear();
+
+        for (path, relative) in files {
+            let result = File::open(&path, Some(&self.options))
+                              .and_then(|mut file| file.send(sock, remote_dir.as_ref().join(relative)));
+            self.results.push((path, result));
+        }
+
+        if self.results.iter().all(|&(_, ref result)| result.is_ok()) {
+            Ok(())
+        } else {
+            Err(Error::FileFail)
+        }
+    }
+
+    /// Send the files as a `Batch`, so the server replaces their
+    /// destinations only if every one of them arrives
+    pub fn send_batch<P: AsRef<Path>>(&mut self, sock: &mut ZSock, remote_dir: P) -> Result<()> {
+        let files: Vec<PathBuf> = self.files().into_iter().map(|(p, _)| p.to_owned()).collect();
+        let mut batch = Batch::new(Some(&self.options));
+        for (path, relative) in self.files() {
+            batch.add(path, remote_dir.as_ref().join(relative));
+        }
+
+        let sent = batch.send(sock);
+        self.results.clear();
+        for (path, &(_, ref result)) in files.into_iter().zip(batch.get_results().iter()) {
+            self.results.push((path, match *result {
+                Ok(()) => Ok(()),
+                Err(_) => Err(Error::FileFail),
+            }));
+        }
+        sent
+    }
+
+    /// Result of sending each file in the most recent send, by local
+    /// path. A batch only reports which files failed, as
+    /// `Error::FileFail`.
+    pub fn get_results(&self) -> &[(PathBuf, Result<()>)] {
+        &self.results
+    }
+}
+
+fn split(pattern: &str) -> Vec<String> {
+    pattern.split('/').filter(|c| !c.is_empty() && *c != ".").map(|c| c.to_string()).collect()
+}
+
+fn is_wild(component: &str) -> bool {
+    component.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+// Walk `dir` for the files below it that match `pattern`, pushing
+// their paths relative to where the walk started
+fn expand(dir: &Path, relative: &Path, pattern: &[String], matches: &mut Vec<PathBuf>) -> Result<()> {
+    let (first, rest) = match pattern.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+
+    if first == "**" {
+        try!(expand(dir, relative, rest, matches));
+        for (name, is_dir) in try!(entries(dir)) {
+            if is_dir && !name.starts_with('.') {
+                try!(expand(&dir.join(&name), &relative.join(&name), pattern, matches));
+            }
+        }
+        return Ok(());
+    }
+
+    // A literal name needn't be listed, so it may be in a directory
+    // that can't be read
+    let names = if is_wild(first) {
+        try!(entries(dir)).into_iter().filter(|&(ref name, _)| matches_name(first, name)).collect()
+    } else {
+        match fs::symlink_metadata(dir.join(first)) {
+            Ok(meta) => vec![(first.clone(), meta.is_dir())],
+            Err(_) => Vec::new(),
+        }
+    };
+
+    for (name, is_dir) in names {
+        let path = dir.join(&name);
+        if rest.is_empty() {
+            // A link to a file is sent as that file
+            if !is_dir && fs::metadata(&path).map(|m| m.is_file()).unwrap_or(false) {
+                matches.push(relative.join(&name));
+            }
+        } else if is_dir {
+            try!(expand(&path, &relative.join(&name), rest, matches));
+        }
+    }
+    Ok(())
+}
+
+// Names in a directory, and whether each is a directory. Symlinks
+// aren't, so a link back up the tree can't loop. Names that aren't
+// UTF-8 can't be matched, so they're left out.
+fn entries(dir: &Path) -> Result<Vec<(String, bool)>> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut entries = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        if let Ok(name) = entry.file_name().into_string() {
+            entries.push((name, try!(entry.file_type()).is_dir()));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+fn matches_path(pattern: &[String], names: &[String]) -> bool {
+    match pattern.split_first() {
+        Some((first, rest)) if first == "**" => {
+            (0..names.len() + 1).any(|skip| matches_path(rest, &names[skip..]))
+        },
+        Some((first, rest)) => match names.split_first() {
+            Some((name, names)) => matches_name(first, name) && matches_path(rest, names),
+            None => false,
+        },
+        None => names.is_empty(),
+    }
+}
+
+fn matches_name(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_chars(&pattern, &name)
+}
+
+fn matches_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&'*') => (0..name.len() + 1).any(|skip| matches_chars(&pattern[1..], &name[skip..])),
+        Some(&'?') => !name.is_empty() && matches_chars(&pattern[1..], &name[1..]),
+        Some(&'[') => match (pattern.iter().skip(2).position(|&c| c == ']'), name.first()) {
+            // The first character of a set may be ']'
+            (Some(end), Some(&c)) => {
+                let set = &pattern[1..end + 2];
+                let (negated, set) = match set.first() {
+                    Some(&'!') => (true, &set[1..]),
+                    _ => (false, set),
+                };
+                in_set(set, c) != negated && matches_chars(&pattern[end + 3..], &name[1..])
+            },
+            (Some(_), None) => false,
+            // An unclosed '[' is just a character
+            (None, _) => name.first() == Some(&'[') && matches_chars(&pattern[1..], &name[1..]),
+        },
+        Some(&c) => name.first() == Some(&c) && matches_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+fn in_set(set: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if set[i] <= c && c <= set[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_matches_name() {
+        assert!(matches_name("*.toml", "a.toml"));
+        assert!(!matches_name("*.toml", "a.toml.bak"));
+        assert!(!matches_name("*.toml", ".hidden.toml"));
+        assert!(matches_name(".*.toml", ".hidden.toml"));
+        assert!(matches_name("a?c", "abc"));
+        assert!(!matches_name("a?c", "ac"));
+        assert!(matches_name("[a-c]x", "bx"));
+        assert!(!matches_name("[!a-c]x", "bx"));
+        assert!(matches_name("[]]", "]"));
+        assert!(matches_name("a[b", "a[b"));
+    }
+
+    #[test]
+    fn test_matches_path() {
+        let names = |p: &str| split(p);
+        assert!(matches_path(&names("**/*.bak"), &names("a.bak")));
+        assert!(matches_path(&names("**/*.bak"), &names("a/b/c.bak")));
+        assert!(matches_path(&names("a/**"), &names("a/b/c")));
+        assert!(!matches_path(&names("a/*"), &names("a/b/c")));
+    }
+
+    #[test]
+    fn test_include() {
+        let tempdir = TempDir::new("glob_test_include").unwrap();
+        let conf = tempdir.path().join("conf.d");
+        fs::create_dir_all(conf.join("sub/deeper")).unwrap();
+        fs::create_dir_all(conf.join(".git")).unwrap();
+        for name in ["a.toml", "b.toml", "c.json", ".d.toml", "sub/e.toml", "sub/deeper/f.toml", ".git/g.toml"].iter() {
+            fs::File::create(conf.join(name)).unwrap();
+        }
+        fs::create_dir(conf.join("dir.toml")).unwrap();
+
+        let mut glob = Glob::new(None);
+        glob.include(&format!("{}/*.toml", conf.to_str().unwrap())).unwrap();
+        assert_eq!(glob.files(), vec![
+            (conf.join("a.toml").as_path(), Path::new("a.toml")),
+            (conf.join("b.toml").as_path(), Path::new("b.toml")),
+        ]);
+
+        let mut glob = Glob::new(None);
+        glob.include(&format!("{}/**/*.toml", conf.to_str().unwrap())).unwrap();
+        let relative: Vec<PathBuf> = glob.files().into_iter().map(|(_, r)| r.to_owned()).collect();
+        assert_eq!(relative, vec![
+            PathBuf::from("a.toml"),
+            PathBuf::from("b.toml"),
+            PathBuf::from("sub/deeper/f.toml"),
+            PathBuf::from("sub/e.toml"),
+        ]);
+
+        glob.exclude("b.toml");
+        glob.exclude("sub/*/*");
+        let relative: Vec<PathBuf> = glob.files().into_iter().map(|(_, r)| r.to_owned()).collect();
+        assert_eq!(relative, vec![PathBuf::from("a.toml"), PathBuf::from("sub/e.toml")]);
+
+        // Wildcards in directories, and nothing matched
+        let mut glob = Glob::new(None);
+        glob.include(&format!("{}/s*/e.*", conf.to_str().unwrap())).unwrap();
+        glob.include(&format!("{}/*.yaml", conf.to_str().unwrap())).unwrap();
+        assert_eq!(glob.files(), vec![(conf.join("sub/e.toml").as_path(), Path::new("sub/e.toml"))]);
+
+        assert!(Glob::new(None).include("").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! What a file's chunks are read from and written to. That is a file
+//! on disk, except when sending from a reader, which can only be
+//! read.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Anything that can be sent like a file
+pub trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+pub enum Handle {
+    File(fs::File),
+    Reader(Box<Source>),
+}
+
+impl Handle {
+    /// The file on disk, for reads and writes that need one (memory
+    /// maps, io_uring), if there is one
+    pub fn file(&self) -> Option<&fs::File> {
+        match *self {
+            Handle::File(ref fh) => Some(fh),
+            Handle::Reader(_) => None,
+        }
+    }
+}
+
+impl Read for Handle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Handle::File(ref mut fh) => fh.read(buf),
+            Handle::Reader(ref mut reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for Handle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            Handle::File(ref mut fh) => fh.seek(pos),
+            Handle::Reader(ref mut reader) => reader.seek(pos),
+        }
+    }
+}
+
+impl Write for Handle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Handle::File(ref mut fh) => fh.write(buf),
+            Handle::Reader(_) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Reader cannot be written to")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Handle::File(ref mut fh) => fh.flush(),
+            Handle::Reader(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use super::*;
+
+    #[test]
+    fn test_reader() {
+        let mut handle = Handle::Reader(Box::new(Cursor::new(b"abcdef".to_vec())));
+        assert!(handle.file().is_none());
+
+        let mut buf = [0; 3];
+        handle.seek(SeekFrom::Start(2)).unwrap();
+        handle.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"cde");
+        assert!(handle.write_all(b"x").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Checksums of files and ranges of files. Every transfer is checked
+//! with a CRC64, which catches accidental corruption. A client can
+//! also ask for a cryptographic digest to be checked, which catches
+//! tampering as well.
+
+use blake2_rfc::blake2b::Blake2b;
+use crc::{crc64, Hasher64};
+use error::Result;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const BLAKE2B_LEN: usize = 64;
+
+/// Digests that can be checked as well as the CRC
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum HashAlgorithm {
+    Blake2b,
+    Sha256,
+}
+
+enum Digester {
+    Blake2b(Blake2b),
+    Sha256(Sha256),
+}
+
+impl Digester {
+    fn new(algorithm: HashAlgorithm) -> Digester {
+        match algorithm {
+            HashAlgorithm::Blake2b => Digester::Blake2b(Blake2b::new(BLAKE2B_LEN)),
+            HashAlgorithm::Sha256 => Digester::Sha256(Sha256::default()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            Digester::Blake2b(ref mut d) => d.update(data),
+            Digester::Sha256(ref mut d) => d.input(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Digester::Blake2b(d) => d.finalize().as_bytes().to_vec(),
+            Digester::Sha256(d) => d.result().to_vec(),
+        }
+    }
+}
+
+/// CRC of `len` bytes of a file from `offset`, and their digest if
+/// an algorithm is given. A range hashes the same as a file holding
+/// only those bytes.
+pub fn hash_range<R: Read + Seek>(fh: &mut R, offset: u64, len: u64, algorithm: Option<HashAlgorithm>) -> Result<(u64, Option<Vec<u8>>)> {
+    let mut buf = [0; 1024];
+    let mut crc = crc64::Digest::new(crc64::ECMA);
+    let mut digester = algorithm.map(Digester::new);
+
+    try!(fh.seek(SeekFrom::Start(offset)));
+    let mut reader = fh.take(len);
+    loop {
+        let read = try!(reader.read(&mut buf));
+        if read == 0 {
+            break;
+        }
+
+        // The CRC has always covered the whole buffer, and peers must
+        // agree on it
+        crc.write(&buf);
+        if let Some(ref mut digester) = digester {
+            digester.update(&buf[..read]);
+        }
+    }
+
+    Ok((crc.sum64(), digester.map(Digester::finish)))
+}
+
+/// CRC of the file at a path, and its digest if an algorithm is
+/// given
+pub fn hash_file<P: AsRef<Path>>(path: P, algorithm: Option<HashAlgorithm>) -> Result<(u64, Option<Vec<u8>>)> {
+    let mut fh = try!(fs::File::open(path));
+    hash_range(&mut fh, 0, u64::max_value(), algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use super::*;
+    use tempdir::TempDir;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let tempdir = TempDir::new("hash_test_hash_file").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        let (crc, digest) = hash_file(&path, None).unwrap();
+        assert!(digest.is_none());
+
+        let (sha_crc, digest) = hash_file(&path, Some(HashAlgorithm::Sha256)).unwrap();
+        assert_eq!(sha_crc, crc);
+        assert_eq!(hex(&digest.unwrap()), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let (_, digest) = hash_file(&path, Some(HashAlgorithm::Blake2b)).unwrap();
+        assert_eq!(hex(&digest.unwrap()), "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+                                            7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923");
+    }
+
+    #[test]
+    fn test_hash_range() {
+        let tempdir = TempDir::new("hash_test_hash_range").unwrap();
+        let whole = tempdir.path().join("whole");
+        let part = tempdir.path().join("part");
+        fs::File::create(&whole).unwrap().write_all(b"xxabcxx").unwrap();
+        fs::File::create(&part).unwrap().write_all(b"abc").unwrap();
+
+        let mut fh = fs::File::open(&whole).unwrap();
+        assert_eq!(hash_range(&mut fh, 2, 3, Some(HashAlgorithm::Sha256)).unwrap(),
+                   hash_file(&part, Some(HashAlgorithm::Sha256)).unwrap());
+    }
+}
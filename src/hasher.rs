@@ -0,0 +1,281 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use file::{self, File, Stream};
+use hash::{self, HashAlgorithm};
+use protocol;
+use staging::StagingCipher;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{JoinHandle, spawn};
+use std::time::Instant;
+use transfer::TransferId;
+
+/// A small pool of threads that checksum uploaded files, so that
+/// verifying a large file doesn't block the Server loop. Results
+/// are sent to the given endpoint as (router_id, transfer ID,
+/// success, CRC, microseconds spent hashing, digest) messages. The
+/// digest frame is empty unless one was asked for, and holds the
+/// packed chunk CRCs for `submit_chunks()`. An upload queued with
+/// `submit_upload()` is decrypted and copied first, if need be, and
+/// hashed as copied, then streamed once its checksums are known.
+pub struct Hasher {
+    jobs: Option<Sender<Job>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+struct Job {
+    id: TransferId,
+    router_id: Vec<u8>,
+    path: PathBuf,
+    work: Work,
+}
+
+enum Work {
+    // The file's CRC, and digest if an algorithm is given
+    File(Option<HashAlgorithm>),
+    // The same for an upload, once it is decrypted if its staging was
+    // encrypted, and copied to the path if one is given. It's then
+    // copied into the stream, if given and the checksums match.
+    Upload(Option<HashAlgorithm>, Option<StagingCipher>, Option<PathBuf>, Option<Stream>),
+    // The CRC of each chunk the file has, for an upload of a size and
+    // chunk size
+    Chunks(u64, u64),
+}
+
+impl Drop for Hasher {
+    fn drop(&mut self) {
+        // Closing the channel terminates the threads once they have
+        // finished their current job.
+        self.jobs.take();
+        for handle in self.handles.drain(..) {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Hasher {
+    pub fn new(threads: u32, endpoint: &str) -> Result<Hasher> {
+        let (tx, rx) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let mut handles = Vec::new();
+
+        for _ in 0..threads {
+            let sock = try!(ZSock::new_push(endpoint));
+            sock.set_sndtimeo(Some(1000));
+            let rx = rx.clone();
+            handles.push(spawn(move|| run(sock, rx)));
+        }
+
+        Ok(Hasher {
+            jobs: Some(tx),
+            handles: handles,
+        })
+    }
+
+    /// Queue a file to be checksummed, and digested with `algorithm`
+    /// if given
+    pub fn submit(&self, id: TransferId, router_id: &[u8], path: &Path, algorithm: Option<HashAlgorithm>) -> Result<()> {
+        self.send(id, router_id, path, Work::File(algorithm))
+    }
+
+    /// Queue a completed upload to be decrypted with `cipher`, as taken
+    /// by `File::take_cipher()`, and copied into the file at
+    /// `copy_path`, as reserved by `File::stage_copy()`, then
+    /// checksummed as `submit()` does and copied into `stream`, as
+    /// taken by `File::take_stream()`
+    pub fn submit_upload(&self, id: TransferId, router_id: &[u8], path: &Path, algorithm: Option<HashAlgorithm>, cipher: Option<StagingCipher>, copy_path: Option<&Path>, stream: Option<Stream>) -> Result<()> {
+        self.send(id, router_id, path, Work::Upload(algorithm, cipher, copy_path.map(|p| p.to_owned()), stream))
+    }
+
+    /// Queue a file to have the CRC of each chunk it shares with an
+    /// upload of `size` bytes taken, as `File::chunk_hashes()` does
+    pub fn submit_chunks(&self, id: TransferId, router_id: &[u8], path: &Path, size: u64, chunk_size: u64) -> Result<()> {
+        self.send(id, router_id, path, Work::Chunks(size, chunk_size))
+    }
+
+    fn send(&self, id: TransferId, router_id: &[u8], path: &Path, work: Work) -> Result<()> {
+        let job = Job {
+            id: id,
+            router_id: router_id.to_vec(),
+            path: path.to_owned(),
+            work: work,
+        };
+
+        self.jobs.as_ref().unwrap().send(job).or(Err(Error::FileFail))
+    }
+}
+
+fn run(mut sock: ZSock, jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // Only hold the lock while waiting for a job, not while
+        // hashing it.
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => break,
+        };
+
+        let start = Instant::now();
+        let result = match job.work {
+            Work::File(algorithm) => hash::hash_file(&job.path, algorithm),
+            Work::Upload(algorithm, cipher, copy_path, stream) => unseal(&job.path, cipher.as_ref(), copy_path.as_ref().map(|p| p.as_path()))
+                .and_then(|path| hash::hash_file(path, algorithm).and_then(|(crc, digest)| {
+                    match stream {
+                        Some(stream) => stream.copy(path, crc, digest.as_ref().map(|d| &d[..])).map(|_| (crc, digest)),
+                        None => Ok((crc, digest)),
+                    }
+                })),
+            Work::Chunks(size, chunk_size) => File::chunk_hashes(&job.path, size, chunk_size).map(|h| (0, Some(protocol::pack_u64s(&h)))),
+        };
+        let elapsed = start.elapsed();
+        let micros = elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1000) as u64;
+
+        let msg = ZMsg::new();
+        msg.addbytes(&job.router_id).unwrap();
+        protocol::add_u64(&msg, job.id, true).unwrap();
+        msg.addbytes(if result.is_ok() { &[1] } else { &[0] }).unwrap();
+        let (crc, digest) = result.unwrap_or((0, None));
+        protocol::add_u64(&msg, crc, true).unwrap();
+        protocol::add_u64(&msg, micros, true).unwrap();
+        msg.addbytes(&digest.unwrap_or(Vec::new())).unwrap();
+
+        // Failing to send means the Server has gone away
+        let _ = msg.send(&mut sock);
+    }
+}
+
+// Decrypt and copy an upload as asked, returning the path of the file
+// to hash
+fn unseal<'a>(path: &'a Path, cipher: Option<&StagingCipher>, copy_path: Option<&'a Path>) -> Result<&'a Path> {
+    if let Some(cipher) = cipher {
+        try!(cipher.decrypt_file(path));
+    }
+
+    match copy_path {
+        Some(copy_path) => {
+            try!(file::copy_synced(path, copy_path));
+            Ok(copy_path)
+        },
+        None => Ok(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZMsg, ZSock, ZSys};
+    use file::File;
+    use hash::{hash_file, HashAlgorithm};
+    use protocol;
+    use staging::StagingCipher;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use super::Hasher;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_hasher() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("hasher_test_hasher").unwrap();
+        let path = format!("{}/test", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().write_all(b"12345").unwrap();
+
+        let mut results = ZSock::new_pull("inproc://hasher_test_hasher").unwrap();
+        results.set_rcvtimeo(Some(500));
+
+        let hasher = Hasher::new(1, ">inproc://hasher_test_hasher").unwrap();
+        hasher.submit(7, b"abc", Path::new(&path), None).unwrap();
+        hasher.submit(8, b"abc", Path::new("/nonexistent"), None).unwrap();
+        hasher.submit(9, b"abc", Path::new(&path), Some(HashAlgorithm::Sha256)).unwrap();
+
+        let msg = ZMsg::recv(&mut results).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "abc");
+        assert_eq!(protocol::pop_u64(&msg, true), Some(7));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![1]);
+        assert_eq!(protocol::pop_u64(&msg, true), Some(16742651521893322043));
+        assert!(protocol::pop_u64(&msg, true).is_some());
+        assert!(msg.popbytes().unwrap().unwrap().is_empty());
+
+        let msg = ZMsg::recv(&mut results).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "abc");
+        assert_eq!(protocol::pop_u64(&msg, true), Some(8));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![0]);
+
+        let msg = ZMsg::recv(&mut results).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(protocol::pop_u64(&msg, true), Some(9));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![1]);
+        protocol::pop_u64(&msg, true).unwrap();
+        protocol::pop_u64(&msg, true).unwrap();
+        assert_eq!(msg.popbytes().unwrap(), hash_file(&path, Some(HashAlgorithm::Sha256)).unwrap().1);
+    }
+
+    #[test]
+    fn test_hasher_chunks() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("hasher_test_hasher_chunks").unwrap();
+        let path = format!("{}/test", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().write_all(b"12345").unwrap();
+
+        let mut results = ZSock::new_pull("inproc://hasher_test_hasher_chunks").unwrap();
+        results.set_rcvtimeo(Some(500));
+
+        let hasher = Hasher::new(1, ">inproc://hasher_test_hasher_chunks").unwrap();
+        hasher.submit_chunks(7, b"abc", Path::new(&path), 6, 2).unwrap();
+
+        let msg = ZMsg::recv(&mut results).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(protocol::pop_u64(&msg, true), Some(7));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![1]);
+        protocol::pop_u64(&msg, true).unwrap();
+        protocol::pop_u64(&msg, true).unwrap();
+        let hashes = File::chunk_hashes(&path, 6, 2).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(protocol::unpack_u64s(&msg.popbytes().unwrap().unwrap()), Some(hashes));
+    }
+
+    #[test]
+    fn test_hasher_upload() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("hasher_test_hasher_upload").unwrap();
+        let path = tempdir.path().join("test");
+        let copy_path = tempdir.path().join("copy");
+        let cipher = StagingCipher::new().unwrap();
+        let mut data = b"12345".to_vec();
+        cipher.seal(0, &mut data).unwrap();
+        fs::File::create(&path).unwrap().write_all(&data).unwrap();
+        fs::File::create(&copy_path).unwrap();
+
+        let mut results = ZSock::new_pull("inproc://hasher_test_hasher_upload").unwrap();
+        results.set_rcvtimeo(Some(500));
+
+        let hasher = Hasher::new(1, ">inproc://hasher_test_hasher_upload").unwrap();
+        hasher.submit_upload(7, b"abc", &path, None, Some(cipher), Some(&copy_path), None).unwrap();
+        // A copy that was discarded isn't made
+        hasher.submit_upload(8, b"abc", &path, None, None, Some(&tempdir.path().join("discarded")), None).unwrap();
+
+        let msg = ZMsg::recv(&mut results).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(protocol::pop_u64(&msg, true), Some(7));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![1]);
+        assert_eq!(protocol::pop_u64(&msg, true), Some(16742651521893322043));
+        assert_eq!(File::checksum(&copy_path).unwrap(), 16742651521893322043);
+
+        let msg = ZMsg::recv(&mut results).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(protocol::pop_u64(&msg, true), Some(8));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![0]);
+        assert!(!tempdir.path().join("discarded").exists());
+    }
+}
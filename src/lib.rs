@@ -6,21 +6,105 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+extern crate bincode;
+extern crate blake2_rfc;
 extern crate crc;
 extern crate czmq;
-extern crate rustc_serialize;
+extern crate flate2;
+extern crate getrandom;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+extern crate io_uring;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate memmap;
+#[cfg(feature = "webhook")]
+extern crate reqwest;
+extern crate ring;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
 #[cfg(test)]
 extern crate tempdir;
-#[cfg(test)]
+#[cfg(any(test, feature = "http"))]
 extern crate tempfile;
+#[cfg(feature = "http")]
+extern crate tiny_http;
 extern crate zdaemon;
 
 mod arbitrator;
+mod attrs;
+mod auth;
+mod bandwidth;
+mod batch;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod channel;
+mod checksum;
 mod chunk;
+mod clock;
+mod client;
+mod codec;
+mod compress;
+mod dir;
+mod disk;
 mod error;
+mod event;
 mod file;
+#[cfg(feature = "http")]
+mod gateway;
+mod glob;
+mod handle;
+mod hash;
+mod hasher;
+mod manifest;
+mod protocol;
+mod record;
+mod remote;
+mod request;
+mod retry;
+mod sanitize;
 mod server;
+#[cfg(feature = "signing")]
+mod signing;
+mod sniff;
+mod staging;
+mod stripe;
+mod transfer;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring;
+#[cfg(feature = "webhook")]
+mod webhook;
+mod worker;
 
+pub use arbitrator::Schedule;
+pub use auth::ServerAuth;
+pub use bandwidth::BandwidthSchedule;
+pub use batch::Batch;
+#[cfg(feature = "chaos")]
+pub use chaos::{ChaosConfig, ChaosProxy};
+pub use channel::Multiplexer;
+pub use checksum::ChecksumCache;
+pub use client::{connect, Client, Options as ClientOptions};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use codec::{BinaryCodec, Codec, JsonCodec, WireCodec};
+pub use compress::Algorithm;
+pub use dir::Dir;
 pub use error::Error;
-pub use file::{File, Options as FileOptions};
-pub use server::Server;
+pub use event::{Completion, Event};
+pub use file::{File, IfExists, Options as FileOptions, StagingNames, Timings, TransferStats};
+#[cfg(feature = "http")]
+pub use gateway::HttpGateway;
+pub use glob::Glob;
+pub use hash::HashAlgorithm;
+pub use protocol::{Compat, PROTOCOL_VERSION};
+pub use record::{Recorder, Replayer};
+pub use remote::{delete, Entry, Stat};
+pub use request::{parse_sink, Request};
+pub use retry::{ErrorClass, RetryPolicy};
+pub use sanitize::NamePolicy;
+pub use server::{Description, Options as ServerOptions, Progress, Quota, Server, ServerStats, Shutdown, TransferState};
+#[cfg(feature = "webhook")]
+pub use webhook::Webhook;
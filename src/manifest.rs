@@ -0,0 +1,336 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Manifests kept beside temporary upload files, so that a restarted
+//! server can tell partial uploads it can resume from leftovers it
+//! should delete.
+//!
+//! A manifest lags behind the chunks actually written, so resuming
+//! may request some chunks again but never skips a missing one.
+
+use error::Result;
+use serde_json;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever temporary files or manifests change in a way that
+/// older partial uploads can't be resumed
+pub const STAGING_VERSION: u32 = 1;
+
+const SUFFIX: &'static str = ".manifest";
+const TMP_SUFFIX: &'static str = ".manifest.tmp";
+const PART_SUFFIX: &'static str = ".part";
+const RANDOM_LEN: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Protocol version negotiated with the client that started the
+    /// upload
+    pub protocol: Option<u32>,
+    /// Destination path
+    pub path: String,
+    pub size: u64,
+    pub crc: u64,
+    pub chunk_size: u64,
+    /// Encoded FileOptions, as sent by the client
+    pub options: Vec<u8>,
+    /// Half-open ranges of chunks not yet written
+    pub missing: Vec<(u64, u64)>,
+    /// Router ID of the client sending the upload, so that its chunks
+    /// can be taken after a restart without a new request
+    pub router_id: Option<Vec<u8>>,
+    /// ID the upload was given, which it keeps when resumed
+    pub transfer_id: Option<u64>,
+    /// Who sent the upload: its User-Id if it authenticated, or else
+    /// its router identity. Only the same client takes its chunks
+    /// after a restart.
+    pub client: Option<Vec<u8>>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let mut encoded = Vec::new();
+        try!(try!(fs::File::open(path)).read_to_end(&mut encoded));
+        Ok(try!(serde_json::from_slice(&encoded)))
+    }
+
+    /// Write the manifest for the temporary file at `upload_path`.
+    /// It is written beside the manifest and renamed over it, so a
+    /// crash never leaves half a manifest.
+    pub fn save(&self, upload_path: &Path) -> Result<()> {
+        let path = manifest_path(upload_path);
+        let mut tmp_path = upload_path.as_os_str().to_owned();
+        tmp_path.push(TMP_SUFFIX);
+
+        {
+            let mut fh = try!(fs::File::create(&tmp_path));
+            try!(fh.write_all(&try!(serde_json::to_vec(self))));
+        }
+        try!(fs::rename(&tmp_path, path));
+        Ok(())
+    }
+
+    /// Whether a NEW request is for the same upload
+    pub fn matches(&self, size: u64, crc: u64, chunk_size: u64, options: &[u8]) -> bool {
+        self.size == size && self.crc == crc && self.chunk_size == chunk_size && self.options == options
+    }
+}
+
+/// Path of the manifest for a temporary upload file
+pub fn manifest_path(upload_path: &Path) -> PathBuf {
+    let mut path = upload_path.as_os_str().to_owned();
+    path.push(SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Search `dir` and its subdirectories for manifests. Uploads that
+/// can be resumed are returned with the path of their temporary
+/// file. Manifests from other staging versions, unreadable ones and
+/// those whose temporary file is missing or the wrong size are
+/// deleted along with the file. Only files named like the server's
+/// temporary files are considered, so others are left alone however
+/// their neighbours are named.
+pub fn recover(dir: &Path) -> Result<Vec<(PathBuf, Manifest)>> {
+    let mut resumable = Vec::new();
+
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        let file_type = try!(entry.file_type());
+
+        if file_type.is_dir() {
+            resumable.extend(try!(recover(&path)));
+            continue;
+        }
+
+        let upload_path = match path.to_str() {
+            Some(p) if file_type.is_file() && p.ends_with(SUFFIX) => PathBuf::from(&p[..p.len() - SUFFIX.len()]),
+            // Left by a crash part way through saving
+            Some(p) if file_type.is_file() && p.ends_with(TMP_SUFFIX) => {
+                if is_staging_name(Path::new(&p[..p.len() - TMP_SUFFIX.len()])) {
+                    try!(fs::remove_file(&path));
+                }
+                continue;
+            },
+            _ => continue,
+        };
+        if !is_staging_name(&upload_path) {
+            continue;
+        }
+
+        let manifest = Manifest::load(&path).ok().and_then(|m| {
+            let size = fs::metadata(&upload_path).map(|meta| meta.len()).ok();
+            if m.version == STAGING_VERSION && size == Some(m.size) {
+                Some(m)
+            } else {
+                None
+            }
+        });
+
+        match manifest {
+            Some(m) => resumable.push((upload_path, m)),
+            None => {
+                let _ = fs::remove_file(&upload_path);
+                try!(fs::remove_file(&path));
+            },
+        }
+    }
+
+    Ok(resumable)
+}
+
+/// Search `dir` and its subdirectories for temporary upload files
+/// with a manifest of this staging version, whether or not they can
+/// be resumed. Files not named as the server names its temporary
+/// files are never included, whatever is beside them.
+pub fn scan(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut uploads = Vec::new();
+
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        let file_type = try!(entry.file_type());
+
+        if file_type.is_dir() {
+            uploads.extend(try!(scan(&path)));
+        } else if let Some(p) = path.to_str() {
+            if !file_type.is_file() || !p.ends_with(SUFFIX) {
+                continue;
+            }
+
+            let upload_path = PathBuf::from(&p[..p.len() - SUFFIX.len()]);
+            if is_staging_name(&upload_path) && Manifest::load(&path).map_or(false, |m| m.version == STAGING_VERSION) {
+                uploads.push(upload_path);
+            }
+        }
+    }
+
+    Ok(uploads)
+}
+
+/// Whether a path is named like the server's temporary files:
+/// `.name<N>`, `name.<N>.part` or `name.<16 hex digits>.part`
+pub fn is_staging_name(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return false,
+    };
+
+    if name.ends_with(PART_SUFFIX) {
+        let stem = &name[..name.len() - PART_SUFFIX.len()];
+        return match stem.rfind('.') {
+            Some(dot) if dot > 0 => {
+                let suffix = &stem[dot + 1..];
+                (!suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit())) ||
+                (suffix.len() == RANDOM_LEN && suffix.bytes().all(|b| b.is_ascii_hexdigit()))
+            },
+            _ => false,
+        };
+    }
+
+    if name.starts_with('.') {
+        let digits = name.bytes().rev().take_while(|b| b.is_ascii_digit()).count();
+        return digits > 0 && name.len() > digits + 1;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use super::*;
+    use tempdir::TempDir;
+
+    fn manifest(path: &Path, size: u64) -> Manifest {
+        Manifest {
+            version: STAGING_VERSION,
+            protocol: Some(1),
+            path: path.to_str().unwrap().into(),
+            size: size,
+            crc: 0,
+            chunk_size: 1,
+            options: b"{}".to_vec(),
+            missing: vec![(1, 3)],
+            router_id: Some(b"abc".to_vec()),
+            transfer_id: Some(7),
+            client: Some(b"abc".to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let tempdir = TempDir::new("manifest_test_save_load").unwrap();
+        let upload_path = tempdir.path().join(".file0");
+        let m = manifest(&tempdir.path().join("file"), 3);
+
+        m.save(&upload_path).unwrap();
+        assert_eq!(manifest_path(&upload_path), tempdir.path().join(".file0.manifest"));
+        assert_eq!(Manifest::load(&manifest_path(&upload_path)).unwrap(), m);
+        assert!(m.matches(3, 0, 1, b"{}"));
+        assert!(!m.matches(3, 0, 1, b"{\"range\":null}"));
+
+        // Manifests from before uploads were resumed by router ID
+        fs::File::create(manifest_path(&upload_path)).unwrap().write_all(
+            b"{\"version\":1,\"protocol\":null,\"path\":\"file\",\"size\":3,\"crc\":0,\"chunk_size\":1,\"options\":[],\"missing\":[]}").unwrap();
+        let old = Manifest::load(&manifest_path(&upload_path)).unwrap();
+        assert_eq!((old.router_id, old.transfer_id, old.client), (None, None, None));
+    }
+
+    #[test]
+    fn test_recover() {
+        let tempdir = TempDir::new("manifest_test_recover").unwrap();
+        let subdir = tempdir.path().join("sub");
+        fs::create_dir(&subdir).unwrap();
+
+        // Resumable
+        let good = subdir.join(".good0");
+        fs::File::create(&good).unwrap().set_len(3).unwrap();
+        manifest(&subdir.join("good"), 3).save(&good).unwrap();
+
+        // From an incompatible version
+        let old = tempdir.path().join(".old0");
+        fs::File::create(&old).unwrap().set_len(3).unwrap();
+        Manifest { version: STAGING_VERSION + 1, ..manifest(&tempdir.path().join("old"), 3) }.save(&old).unwrap();
+
+        // Truncated temporary file
+        let short = tempdir.path().join(".short0");
+        fs::File::create(&short).unwrap().set_len(2).unwrap();
+        manifest(&tempdir.path().join("short"), 3).save(&short).unwrap();
+
+        // Unreadable manifest
+        let garbage = tempdir.path().join("garbage.1.part");
+        fs::File::create(&garbage).unwrap();
+        fs::File::create(manifest_path(&garbage)).unwrap().write_all(b"moo").unwrap();
+
+        // Someone else's files, which only look like a manifest
+        let notes = tempdir.path().join("notes");
+        fs::File::create(&notes).unwrap();
+        fs::File::create(manifest_path(&notes)).unwrap().write_all(b"moo").unwrap();
+        let other = tempdir.path().join("other");
+        fs::File::create(&other).unwrap();
+        fs::File::create(tempdir.path().join("other.manifest.tmp")).unwrap();
+
+        let resumable = recover(tempdir.path()).unwrap();
+        assert_eq!(resumable, vec![(good.clone(), manifest(&subdir.join("good"), 3))]);
+
+        assert!(good.exists());
+        for path in [old, short, garbage].iter() {
+            assert!(!path.exists());
+            assert!(!manifest_path(path).exists());
+        }
+        assert!(notes.exists() && manifest_path(&notes).exists());
+        assert!(other.exists() && tempdir.path().join("other.manifest.tmp").exists());
+    }
+
+    #[test]
+    fn test_scan() {
+        let tempdir = TempDir::new("manifest_test_scan").unwrap();
+        let subdir = tempdir.path().join("sub");
+        fs::create_dir(&subdir).unwrap();
+
+        let upload = subdir.join(".file0");
+        fs::File::create(&upload).unwrap();
+        manifest(&subdir.join("file"), 3).save(&upload).unwrap();
+        fs::File::create(tempdir.path().join(".other0")).unwrap();
+
+        // Not the server's, whatever the manifest says
+        let notes = tempdir.path().join("notes");
+        fs::File::create(&notes).unwrap();
+        manifest(&tempdir.path().join("file"), 3).save(&notes).unwrap();
+
+        // Manifests that don't parse, or are from another version
+        let garbage = tempdir.path().join(".garbage0");
+        fs::File::create(&garbage).unwrap();
+        fs::File::create(manifest_path(&garbage)).unwrap().write_all(b"moo").unwrap();
+        let old = tempdir.path().join(".old0");
+        fs::File::create(&old).unwrap();
+        Manifest { version: STAGING_VERSION + 1, ..manifest(&tempdir.path().join("old"), 3) }.save(&old).unwrap();
+
+        assert_eq!(scan(tempdir.path()).unwrap(), vec![upload]);
+    }
+
+    #[test]
+    fn test_is_staging_name() {
+        assert!(is_staging_name(Path::new("/tmp/.file0")));
+        assert!(is_staging_name(Path::new("/tmp/.file.txt12")));
+        assert!(is_staging_name(Path::new("/tmp/file.3.part")));
+        assert!(is_staging_name(Path::new("/tmp/file.0123456789abcdef.part")));
+
+        assert!(!is_staging_name(Path::new("/tmp/file")));
+        assert!(!is_staging_name(Path::new("/tmp/.file")));
+        assert!(!is_staging_name(Path::new("/tmp/.0")));
+        assert!(!is_staging_name(Path::new("/tmp/file.part")));
+        assert!(!is_staging_name(Path::new("/tmp/file.abc.part")));
+        assert!(!is_staging_name(Path::new("/tmp/.3.part")));
+    }
+}
@@ -0,0 +1,558 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use czmq::{self, ZMsg};
+use error::{Error, ErrorCode, Result};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::result::Result as StdResult;
+use std::str;
+
+pub const PROTOCOL_VERSION: u32 = 18;
+
+/// First protocol version to carry integers on the hot path as
+/// fixed-width binary frames rather than decimal strings
+pub const BINARY_INTS: u32 = 2;
+
+/// First protocol version that lets the server renegotiate the chunk
+/// size part way through a transfer
+pub const ADAPTIVE_CHUNKS: u32 = 3;
+
+/// First protocol version in which the server can offer checksums of
+/// an existing destination file, so unchanged chunks aren't sent
+pub const DELTA_CHUNKS: u32 = 4;
+
+/// First protocol version in which chunk data can be compressed
+pub const COMPRESSED_CHUNKS: u32 = 5;
+
+/// First protocol version in which the server checks a digest of the
+/// whole file as well as its CRC
+pub const FILE_DIGESTS: u32 = 6;
+
+/// First protocol version in which a client may send chunks ahead of
+/// the server's requests, up to a window the server grants in its ACK
+pub const PIPELINED_CHUNKS: u32 = 7;
+
+/// First protocol version in which the server honours a client's
+/// policy for a destination that already exists
+pub const IF_EXISTS: u32 = 8;
+
+/// First protocol version in which the server can skip an upload
+/// whose destination already holds the same file
+pub const UNCHANGED_FILES: u32 = 9;
+
+/// First protocol version in which a server that won't accept the
+/// client's version replies with the versions it does accept
+pub const UNSUPPORTED_REPLY: u32 = 10;
+
+/// First protocol version in which the server recreates a symlink
+/// from the target a client sends
+pub const SYMLINKS: u32 = 11;
+
+/// First protocol version to send a chunk of zeros as an empty frame,
+/// leaving a hole in the file it's written to
+pub const SPARSE_CHUNKS: u32 = 12;
+
+/// First protocol version in which the server appends to or writes
+/// at an offset in an existing file
+pub const OFFSET_WRITES: u32 = 13;
+
+/// First protocol version in which the server syncs an upload to
+/// disk before replying, if asked
+pub const DURABLE_WRITES: u32 = 14;
+
+/// First protocol version in which other connections can join an
+/// upload and be sent some of its chunk requests
+pub const STRIPED_CHUNKS: u32 = 15;
+
+/// First protocol version in which the server tells clients how often
+/// to PING while they wait, and expires uploads whose client stops
+pub const HEARTBEATS: u32 = 16;
+
+/// First protocol version in which connections JOIN an upload with a
+/// token that the server only gives the upload's owner
+pub const JOIN_TOKENS: u32 = 17;
+
+/// First protocol version in which the server tells an upload's owner
+/// about each chunk it has written
+pub const ACKED_CHUNKS: u32 = 18;
+
+/// Compatibility mode for talking to peers that predate protocol
+/// versioning.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compat {
+    /// Use versioning if the peer supports it, otherwise fall back
+    /// to the legacy NEW/CHUNK flow
+    Auto,
+    /// Always use the legacy flow
+    Legacy,
+    /// Refuse to talk to legacy peers
+    Versioned,
+}
+
+/// Whether a negotiated protocol version uses binary integers
+pub fn binary_ints(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= BINARY_INTS)
+}
+
+/// Whether a negotiated protocol version understands RESIZE
+pub fn adaptive_chunks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= ADAPTIVE_CHUNKS)
+}
+
+/// Whether a negotiated protocol version understands HASHES
+pub fn delta_chunks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= DELTA_CHUNKS)
+}
+
+/// Whether a negotiated protocol version allows compressed chunks
+pub fn compressed_chunks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= COMPRESSED_CHUNKS)
+}
+
+/// Whether a negotiated protocol version checks file digests
+pub fn file_digests(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= FILE_DIGESTS)
+}
+
+/// Whether a negotiated protocol version allows sending chunks ahead
+pub fn pipelined_chunks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= PIPELINED_CHUNKS)
+}
+
+/// Whether a negotiated protocol version honours `IfExists`
+pub fn if_exists(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= IF_EXISTS)
+}
+
+/// Whether a negotiated protocol version may report a whole file as
+/// unchanged in place of an ACK
+pub fn unchanged_files(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= UNCHANGED_FILES)
+}
+
+/// Whether a client speaking a protocol version understands an
+/// UNSUPPORTED reply to its NEW request
+pub fn unsupported_reply(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= UNSUPPORTED_REPLY)
+}
+
+/// Whether a negotiated protocol version lets a client send a symlink
+/// in place of a file
+pub fn symlinks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= SYMLINKS)
+}
+
+/// Whether a negotiated protocol version may send chunks as holes
+pub fn sparse_chunks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= SPARSE_CHUNKS)
+}
+
+/// Whether a negotiated protocol version supports appending and
+/// writing at an offset
+pub fn offset_writes(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= OFFSET_WRITES)
+}
+
+/// Whether a negotiated protocol version supports durable writes
+pub fn durable_writes(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= DURABLE_WRITES)
+}
+
+/// Whether a negotiated protocol version lets connections JOIN an
+/// upload
+pub fn striped_chunks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= STRIPED_CHUNKS)
+}
+
+/// Whether a negotiated protocol version sends heartbeats
+pub fn heartbeats(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= HEARTBEATS)
+}
+
+/// Whether a negotiated protocol version gives out join tokens
+pub fn join_tokens(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= JOIN_TOKENS)
+}
+
+/// Whether a negotiated protocol version acknowledges written chunks
+pub fn acked_chunks(protocol: Option<u32>) -> bool {
+    protocol.map_or(false, |v| v >= ACKED_CHUNKS)
+}
+
+/// An Err reply holding the error's description, then its code if it
+/// has one. Older clients only read the description.
+pub fn new_err(err: &Error) -> StdResult<ZMsg, czmq::Error> {
+    let msg = ZMsg::new();
+    try!(msg.addstr("Err"));
+    try!(msg.addstr(&err.to_string()));
+    if let Some(code) = err.code() {
+        try!(msg.addstr(code.as_str()));
+        if let Some(value) = code.value() {
+            try!(msg.addstr(&value.to_string()));
+        }
+    }
+    Ok(msg)
+}
+
+/// Read the error from the rest of an Err reply. Without a code this
+/// client knows, it's an `UploadError` with the server's description.
+pub fn pop_err(msg: &ZMsg) -> Error {
+    let description = match msg.popstr() {
+        Some(Ok(s)) => s,
+        _ => return Error::InvalidReply,
+    };
+
+    match msg.popstr() {
+        Some(Ok(ref code)) => {
+            let value = match msg.popstr() {
+                Some(Ok(v)) => v.parse::<u32>().ok(),
+                _ => None,
+            };
+            ErrorCode::parse(code, value).map_or(Error::UploadError(description), Error::from)
+        },
+        _ => Error::UploadError(description),
+    }
+}
+
+/// Append an integer frame, either as 8 big-endian bytes or as a
+/// decimal string for older peers.
+pub fn add_u64(msg: &ZMsg, value: u64, binary: bool) -> Result<()> {
+    if binary {
+        let mut buf = [0; 8];
+        for i in 0..8 {
+            buf[i] = (value >> (56 - i * 8)) as u8;
+        }
+        try!(msg.addbytes(&buf));
+    } else {
+        let mut buf = [0; 20];
+        try!(msg.addbytes(decimal(value, &mut buf)));
+    }
+
+    Ok(())
+}
+
+/// Append a "first-last" range frame for peers without binary
+/// integers
+pub fn add_range(msg: &ZMsg, first: u64, last: u64) -> Result<()> {
+    let mut buf = [0; 41];
+    // Digits are written backwards from the end of the buffer
+    let start = {
+        let dash = buf.len() - decimal(last, &mut buf).len() - 1;
+        buf[dash] = b'-';
+        dash - decimal(first, &mut buf[..dash]).len()
+    };
+    try!(msg.addbytes(&buf[start..]));
+    Ok(())
+}
+
+// Write an integer's decimal digits to the end of `buf`, which must
+// have room for 20 digits, without allocating a String
+fn decimal(value: u64, buf: &mut [u8]) -> &[u8] {
+    let mut i = buf.len();
+    let mut value = value;
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    &buf[i..]
+}
+
+pub fn decode_u64(frame: &[u8], binary: bool) -> Option<u64> {
+    if binary {
+        if frame.len() == 8 {
+            Some(frame.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+        } else {
+            None
+        }
+    } else {
+        match ::std::str::from_utf8(frame) {
+            Ok(s) => s.parse().ok(),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Pack integers into a single frame of consecutive 8 byte
+/// big-endian values, for lists too long to send a frame each
+pub fn pack_u64s(values: &[u64]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(values.len() * 8);
+    for value in values {
+        for i in 0..8 {
+            frame.push((value >> (56 - i * 8)) as u8);
+        }
+    }
+    frame
+}
+
+/// Unpack a frame written by `pack_u64s()`
+pub fn unpack_u64s(frame: &[u8]) -> Option<Vec<u64>> {
+    if frame.len() % 8 != 0 {
+        return None;
+    }
+    Some(frame.chunks(8).map(|b| decode_u64(b, true).unwrap()).collect())
+}
+
+/// Pop an integer frame. Returns None if the frame is missing or
+/// malformed.
+pub fn pop_u64(msg: &ZMsg, binary: bool) -> Option<u64> {
+    match msg.popbytes() {
+        Ok(Some(frame)) => decode_u64(&frame, binary),
+        _ => None,
+    }
+}
+
+/// A path as it is sent, with '/' between its components. Unix paths
+/// are sent as their bytes, but servers refuse any that aren't UTF-8
+/// with `Error::InvalidFilePath` (see `decode_path()`).
+#[cfg(unix)]
+pub fn encode_path(path: &Path) -> Result<Vec<u8>> {
+    Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+/// A path as it is sent, with '/' between its components. Windows
+/// paths must be valid Unicode.
+#[cfg(not(unix))]
+pub fn encode_path(path: &Path) -> Result<Vec<u8>> {
+    let path = try!(path.to_str().ok_or(Error::InvalidFilePath));
+    Ok(path.replace('\\', "/").into_bytes())
+}
+
+/// A path frame as the server handles it. Paths are checked against
+/// `allowed_paths`, sanitized and logged as UTF-8, so a frame that
+/// isn't is refused with `Error::InvalidFilePath` rather than passed
+/// on as an `OsString`.
+#[cfg(unix)]
+pub fn decode_path(frame: &[u8]) -> Result<String> {
+    str::from_utf8(frame).map(|p| p.to_string()).or(Err(Error::InvalidFilePath))
+}
+
+/// A path frame as the server handles it. Clients that predate
+/// `encode_path()` send Windows' own separators, which are '/' once
+/// received like everyone else's.
+#[cfg(not(unix))]
+pub fn decode_path(frame: &[u8]) -> Result<String> {
+    str::from_utf8(frame).map(|p| p.replace('\\', "/")).or(Err(Error::InvalidFilePath))
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::ZMsg;
+    use super::*;
+
+    #[test]
+    fn test_binary_ints() {
+        assert!(!binary_ints(None));
+        assert!(!binary_ints(Some(1)));
+        assert!(binary_ints(Some(BINARY_INTS)));
+    }
+
+    #[test]
+    fn test_adaptive_chunks() {
+        assert!(!adaptive_chunks(None));
+        assert!(!adaptive_chunks(Some(BINARY_INTS)));
+        assert!(adaptive_chunks(Some(ADAPTIVE_CHUNKS)));
+    }
+
+    #[test]
+    fn test_delta_chunks() {
+        assert!(!delta_chunks(None));
+        assert!(!delta_chunks(Some(ADAPTIVE_CHUNKS)));
+        assert!(delta_chunks(Some(DELTA_CHUNKS)));
+    }
+
+    #[test]
+    fn test_compressed_chunks() {
+        assert!(!compressed_chunks(None));
+        assert!(!compressed_chunks(Some(DELTA_CHUNKS)));
+        assert!(compressed_chunks(Some(COMPRESSED_CHUNKS)));
+    }
+
+    #[test]
+    fn test_file_digests() {
+        assert!(!file_digests(None));
+        assert!(!file_digests(Some(COMPRESSED_CHUNKS)));
+        assert!(file_digests(Some(FILE_DIGESTS)));
+    }
+
+    #[test]
+    fn test_pipelined_chunks() {
+        assert!(!pipelined_chunks(None));
+        assert!(!pipelined_chunks(Some(FILE_DIGESTS)));
+        assert!(pipelined_chunks(Some(PIPELINED_CHUNKS)));
+    }
+
+    #[test]
+    fn test_if_exists() {
+        assert!(!if_exists(None));
+        assert!(!if_exists(Some(PIPELINED_CHUNKS)));
+        assert!(if_exists(Some(IF_EXISTS)));
+    }
+
+    #[test]
+    fn test_unchanged_files() {
+        assert!(!unchanged_files(None));
+        assert!(!unchanged_files(Some(IF_EXISTS)));
+        assert!(unchanged_files(Some(UNCHANGED_FILES)));
+    }
+
+    #[test]
+    fn test_unsupported_reply() {
+        assert!(!unsupported_reply(None));
+        assert!(!unsupported_reply(Some(UNCHANGED_FILES)));
+        assert!(unsupported_reply(Some(UNSUPPORTED_REPLY)));
+    }
+
+    #[test]
+    fn test_symlinks() {
+        assert!(!symlinks(None));
+        assert!(!symlinks(Some(UNSUPPORTED_REPLY)));
+        assert!(symlinks(Some(SYMLINKS)));
+    }
+
+    #[test]
+    fn test_sparse_chunks() {
+        assert!(!sparse_chunks(None));
+        assert!(!sparse_chunks(Some(SYMLINKS)));
+        assert!(sparse_chunks(Some(SPARSE_CHUNKS)));
+    }
+
+    #[test]
+    fn test_offset_writes() {
+        assert!(!offset_writes(None));
+        assert!(!offset_writes(Some(SPARSE_CHUNKS)));
+        assert!(offset_writes(Some(OFFSET_WRITES)));
+    }
+
+    #[test]
+    fn test_durable_writes() {
+        assert!(!durable_writes(None));
+        assert!(!durable_writes(Some(OFFSET_WRITES)));
+        assert!(durable_writes(Some(DURABLE_WRITES)));
+    }
+
+    #[test]
+    fn test_striped_chunks() {
+        assert!(!striped_chunks(None));
+        assert!(!striped_chunks(Some(DURABLE_WRITES)));
+        assert!(striped_chunks(Some(STRIPED_CHUNKS)));
+    }
+
+    #[test]
+    fn test_heartbeats() {
+        assert!(!heartbeats(None));
+        assert!(!heartbeats(Some(STRIPED_CHUNKS)));
+        assert!(heartbeats(Some(HEARTBEATS)));
+    }
+
+    #[test]
+    fn test_join_tokens() {
+        assert!(!join_tokens(None));
+        assert!(!join_tokens(Some(HEARTBEATS)));
+        assert!(join_tokens(Some(JOIN_TOKENS)));
+    }
+
+    #[test]
+    fn test_new_pop_err() {
+        let msg = new_err(&Error::QuotaExceeded).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        match pop_err(&msg) {
+            Error::QuotaExceeded => (),
+            _ => panic!("Expected QuotaExceeded error"),
+        }
+
+        // Errors without a code, and replies from older servers, keep
+        // only their description
+        // A code's value follows it
+        let msg = new_err(&Error::UnsupportedVersion(12)).unwrap();
+        msg.popstr().unwrap().unwrap();
+        match pop_err(&msg) {
+            Error::UnsupportedVersion(12) => (),
+            _ => panic!("Expected UnsupportedVersion error"),
+        }
+
+        let msg = new_err(&Error::Timeout).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(pop_err(&msg).to_string(), Error::UploadError(Error::Timeout.to_string()).to_string());
+
+        let msg = ZMsg::new();
+        msg.addstr("Quota exceeded").unwrap();
+        msg.addstr("NEW_CODE").unwrap();
+        match pop_err(&msg) {
+            Error::UploadError(ref s) if s == "Quota exceeded" => (),
+            _ => panic!("Expected UploadError"),
+        }
+    }
+
+    #[test]
+    fn test_add_pop_u64() {
+        let msg = ZMsg::new();
+        add_u64(&msg, 258, true).unwrap();
+        add_u64(&msg, 258, false).unwrap();
+        msg.addstr("moo").unwrap();
+
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(pop_u64(&msg, false), Some(258));
+        assert_eq!(pop_u64(&msg, false), None);
+        assert_eq!(pop_u64(&msg, true), None);
+    }
+
+    #[test]
+    fn test_add_range() {
+        let msg = ZMsg::new();
+        add_range(&msg, 0, 18446744073709551615).unwrap();
+        add_range(&msg, 5, 12).unwrap();
+
+        assert_eq!(msg.popstr().unwrap().unwrap(), "0-18446744073709551615");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "5-12");
+    }
+
+    #[test]
+    fn test_decode_u64() {
+        assert_eq!(decode_u64(&[0, 0, 0, 0, 0, 0, 1, 2], true), Some(258));
+        assert_eq!(decode_u64(&[1, 2], true), None);
+        assert_eq!(decode_u64(b"258", false), Some(258));
+        assert_eq!(decode_u64(b"moo", false), None);
+    }
+
+    #[test]
+    fn test_pack_u64s() {
+        let frame = pack_u64s(&[258, 0]);
+        assert_eq!(frame, vec![0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(unpack_u64s(&frame), Some(vec![258, 0]));
+        assert_eq!(unpack_u64s(&[]), Some(vec![]));
+        assert_eq!(unpack_u64s(&[1, 2]), None);
+    }
+
+    #[test]
+    fn test_encode_decode_path() {
+        assert_eq!(encode_path(Path::new("/tmp/a")).unwrap(), b"/tmp/a");
+        assert_eq!(decode_path(b"/tmp/a").unwrap(), "/tmp/a");
+        assert!(decode_path(b"/tmp/\xff").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_encode_path_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Sent as it is, for the server to refuse
+        let encoded = encode_path(Path::new(OsStr::from_bytes(b"/tmp/\xff"))).unwrap();
+        assert_eq!(encoded, b"/tmp/\xff");
+        match decode_path(&encoded) {
+            Err(Error::InvalidFilePath) => (),
+            _ => panic!("Expected InvalidFilePath error"),
+        }
+    }
+}
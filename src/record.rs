@@ -0,0 +1,222 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Session recordings, so that a problem seen in the field can be
+//! reproduced against a local server and kept as a regression test.
+//!
+//! A recording starts with a magic line, followed by one record per
+//! client message: the number of frames, then each frame's length
+//! and bytes, all lengths being big-endian u64s. The first frame of
+//! each record is the client's router ID.
+//!
+//! Chunk payloads are recorded as zeros of the same length, so a
+//! recording never holds file contents. Replayed uploads keep their
+//! shape but fail verification.
+
+use channel::CHANNEL;
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+const MAGIC: &'static [u8] = b"ZFXREC1\n";
+
+/// Writes every message a Server receives from its clients
+pub struct Recorder {
+    writer: Box<Write>,
+}
+
+impl Recorder {
+    pub fn new(mut writer: Box<Write>) -> Result<Recorder> {
+        try!(writer.write_all(MAGIC));
+        Ok(Recorder {
+            writer: writer,
+        })
+    }
+
+    pub fn record(&mut self, router_id: &[u8], frames: &[Vec<u8>]) -> Result<()> {
+        try!(write_u64(&mut self.writer, frames.len() as u64 + 1));
+        try!(write_frame(&mut self.writer, router_id));
+
+        let start = if frames.first().map_or(false, |f| f == CHANNEL) { 2 } else { 0 };
+        let payloads = match frames.get(start).map(|f| &f[..]) {
+            Some(b"CHUNK") | Some(b"CHUNKS") => true,
+            _ => false,
+        };
+        for (i, frame) in frames.iter().enumerate() {
+            // Offsets and payloads alternate after the action
+            if payloads && i > start && (i - start) % 2 == 0 {
+                try!(write_frame(&mut self.writer, &vec![0; frame.len()]));
+            } else {
+                try!(write_frame(&mut self.writer, frame));
+            }
+        }
+
+        // A crashing server should still leave a usable recording
+        try!(self.writer.flush());
+        Ok(())
+    }
+}
+
+/// Sends a recording's messages to a server.
+///
+/// Each recorded client gets its own DEALER socket, so the server
+/// sees the same clients as when the session was recorded, albeit
+/// with new router IDs. Messages are sent in their recorded order
+/// without waiting for replies, which stay queued on the client
+/// sockets for tests to check.
+pub struct Replayer {
+    reader: Box<Read>,
+    clients: HashMap<Vec<u8>, ZSock>,
+}
+
+impl Replayer {
+    pub fn new(mut reader: Box<Read>) -> Result<Replayer> {
+        let mut magic = [0; 8];
+        if reader.read_exact(&mut magic).is_err() || magic != MAGIC {
+            return Err(Error::InvalidRecording);
+        }
+
+        Ok(Replayer {
+            reader: reader,
+            clients: HashMap::new(),
+        })
+    }
+
+    /// Read the next record, returning the router ID and frames
+    pub fn next_record(&mut self) -> Result<Option<(Vec<u8>, Vec<Vec<u8>>)>> {
+        let count = match read_u64(&mut self.reader) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        if count < 2 {
+            return Err(Error::InvalidRecording);
+        }
+
+        let router_id = try!(read_frame(&mut self.reader));
+        let mut frames = Vec::new();
+        for _ in 1..count {
+            frames.push(try!(read_frame(&mut self.reader)));
+        }
+
+        Ok(Some((router_id, frames)))
+    }
+
+    /// Send the remaining messages to the server at `endpoint`,
+    /// returning how many were sent
+    pub fn replay(&mut self, endpoint: &str) -> Result<u64> {
+        let mut sent = 0;
+        while let Some((router_id, frames)) = try!(self.next_record()) {
+            if !self.clients.contains_key(&router_id) {
+                let sock = try!(ZSock::new_dealer(&format!(">{}", endpoint)));
+                self.clients.insert(router_id.clone(), sock);
+            }
+
+            let msg = ZMsg::new();
+            for frame in frames {
+                try!(msg.addbytes(&frame));
+            }
+            try!(msg.send(self.clients.get_mut(&router_id).unwrap()));
+            sent += 1;
+        }
+
+        Ok(sent)
+    }
+
+    /// The socket standing in for a recorded client
+    pub fn client(&mut self, router_id: &[u8]) -> Option<&mut ZSock> {
+        self.clients.get_mut(router_id)
+    }
+}
+
+fn write_u64(writer: &mut Write, value: u64) -> Result<()> {
+    let mut buf = [0; 8];
+    for i in 0..8 {
+        buf[i] = (value >> (56 - i * 8)) as u8;
+    }
+    try!(writer.write_all(&buf));
+    Ok(())
+}
+
+fn write_frame(writer: &mut Write, frame: &[u8]) -> Result<()> {
+    try!(write_u64(writer, frame.len() as u64));
+    try!(writer.write_all(frame));
+    Ok(())
+}
+
+fn read_u64(reader: &mut Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    try!(reader.read_exact(&mut buf));
+    Ok(buf.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+}
+
+fn read_frame(reader: &mut Read) -> Result<Vec<u8>> {
+    let len = try!(read_u64(reader).or(Err(Error::InvalidRecording)));
+
+    // Don't trust the length enough to allocate it up front
+    let mut frame = Vec::new();
+    try!(reader.take(len).read_to_end(&mut frame));
+    if frame.len() as u64 != len {
+        return Err(Error::InvalidRecording);
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_record_replay() {
+        let tempdir = TempDir::new("record_test_record_replay").unwrap();
+        let path = tempdir.path().join("session");
+
+        {
+            let mut recorder = Recorder::new(Box::new(fs::File::create(&path).unwrap())).unwrap();
+            recorder.record(b"abc", &[b"NEW".to_vec(), b"/tmp/a".to_vec()]).unwrap();
+            recorder.record(b"def", &[b"CHUNK".to_vec(), vec![0; 8], Vec::new()]).unwrap();
+        }
+
+        let mut replayer = Replayer::new(Box::new(fs::File::open(&path).unwrap())).unwrap();
+        assert_eq!(replayer.next_record().unwrap(), Some((b"abc".to_vec(), vec![b"NEW".to_vec(), b"/tmp/a".to_vec()])));
+        assert_eq!(replayer.next_record().unwrap(), Some((b"def".to_vec(), vec![b"CHUNK".to_vec(), vec![0; 8], Vec::new()])));
+        assert_eq!(replayer.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn test_record_payloads() {
+        let tempdir = TempDir::new("record_test_record_payloads").unwrap();
+        let path = tempdir.path().join("session");
+
+        {
+            let mut recorder = Recorder::new(Box::new(fs::File::create(&path).unwrap())).unwrap();
+            recorder.record(b"abc", &[b"CHUNK".to_vec(), b"1".to_vec(), b"secret".to_vec()]).unwrap();
+            recorder.record(b"abc", &[b"CHANNEL".to_vec(), b"t".to_vec(), b"CHUNKS".to_vec(), vec![0; 8], b"ab".to_vec(), vec![1; 8], b"cd".to_vec()]).unwrap();
+        }
+
+        let mut replayer = Replayer::new(Box::new(fs::File::open(&path).unwrap())).unwrap();
+        assert_eq!(replayer.next_record().unwrap(), Some((b"abc".to_vec(), vec![b"CHUNK".to_vec(), b"1".to_vec(), vec![0; 6]])));
+        assert_eq!(replayer.next_record().unwrap(), Some((b"abc".to_vec(), vec![b"CHANNEL".to_vec(), b"t".to_vec(), b"CHUNKS".to_vec(), vec![0; 8], vec![0; 2], vec![1; 8], vec![0; 2]])));
+    }
+
+    #[test]
+    fn test_invalid_recording() {
+        assert!(Replayer::new(Box::new(Cursor::new(b"moo".to_vec()))).is_err());
+
+        // A frame claiming more bytes than the recording holds
+        let mut data = b"ZFXREC1\n".to_vec();
+        data.extend(&[0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, b'a']);
+        let mut replayer = Replayer::new(Box::new(Cursor::new(data))).unwrap();
+        assert!(replayer.next_record().is_err());
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Management of files already on a server: listing directories,
+//! checking files and deleting them. The server checks each path
+//! the same way it checks an upload's before anything here sees it.
+
+use attrs;
+use codec::{Codec, JsonCodec};
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use protocol;
+use std::fs;
+use std::path::Path;
+
+/// A directory entry, as returned by the LIST action
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// Seconds and nanoseconds since the Unix epoch
+    pub mtime: Option<(u64, u32)>,
+}
+
+/// A file's details, as returned by the STAT action
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Stat {
+    pub is_dir: bool,
+    pub size: u64,
+    /// Seconds and nanoseconds since the Unix epoch
+    pub mtime: Option<(u64, u32)>,
+    /// Checksum of the file's contents, as uploads are checked
+    /// against. None for a directory.
+    pub crc: Option<u64>,
+}
+
+impl Entry {
+    /// List a directory on a remote server
+    pub fn list(sock: &mut ZSock, path: &str) -> Result<Vec<Entry>> {
+        request(sock, "LIST", path).and_then(|encoded| JsonCodec.decode(&encoded))
+    }
+}
+
+impl Stat {
+    /// Request the details of a file on a remote server
+    pub fn request(sock: &mut ZSock, path: &str) -> Result<Stat> {
+        request(sock, "STAT", path).and_then(|encoded| JsonCodec.decode(&encoded))
+    }
+}
+
+/// Delete a file on a remote server. Directories aren't deleted.
+pub fn delete(sock: &mut ZSock, path: &str) -> Result<()> {
+    request(sock, "DELETE", path).map(|_| ())
+}
+
+// Send an action with a path, returning the encoded reply if there
+// is one
+fn request(sock: &mut ZSock, action: &str, path: &str) -> Result<Vec<u8>> {
+    let msg = ZMsg::new();
+    try!(msg.addstr(action));
+    try!(msg.addstr(path));
+    try!(msg.send(sock));
+
+    let msg = try!(ZMsg::recv(sock));
+    match try!(msg.popstr().and_then(|s| s.ok()).ok_or(Error::InvalidReply)).as_ref() {
+        "Ok" => Ok(try!(msg.popbytes()).unwrap_or(Vec::new())),
+        "Err" => Err(protocol::pop_err(&msg)),
+        _ => Err(Error::InvalidReply),
+    }
+}
+
+/// Entries of the directory at `path`, sorted by name. Entries whose
+/// names aren't UTF-8 are left out, as no path sent to the server
+/// could name them.
+pub fn list_dir<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    let meta = try!(fs::metadata(&path).or(Err(Error::InvalidFilePath)));
+    if !meta.is_dir() {
+        return Err(Error::InvalidFilePath);
+    }
+
+    let mut entries = Vec::new();
+    for entry in try!(fs::read_dir(&path)) {
+        let entry = try!(entry);
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let meta = try!(entry.metadata());
+
+        entries.push(Entry {
+            name: name,
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            mtime: attrs::mtime(&meta),
+        });
+    }
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Details of the file or directory at `path`. The CRC is left for
+/// the caller to fill in, as reading a large file takes a while.
+pub fn stat_path<P: AsRef<Path>>(path: P) -> Result<Stat> {
+    let meta = try!(fs::metadata(&path).or(Err(Error::InvalidFilePath)));
+    if !meta.is_dir() && !meta.is_file() {
+        return Err(Error::SpecialFile);
+    }
+
+    Ok(Stat {
+        is_dir: meta.is_dir(),
+        size: meta.len(),
+        mtime: attrs::mtime(&meta),
+        crc: None,
+    })
+}
+
+/// Delete the file or symlink at `path`. A symlink is deleted
+/// rather than what it points to.
+pub fn delete_path<P: AsRef<Path>>(path: P) -> Result<()> {
+    let meta = try!(fs::symlink_metadata(&path).or(Err(Error::InvalidFilePath)));
+    if meta.is_dir() {
+        return Err(Error::InvalidFilePath);
+    }
+
+    try!(fs::remove_file(&path));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_list_dir() {
+        let tempdir = TempDir::new("remote_test_list_dir").unwrap();
+        fs::File::create(tempdir.path().join("b")).unwrap().write_all(b"abc").unwrap();
+        fs::create_dir(tempdir.path().join("a")).unwrap();
+
+        let entries = list_dir(tempdir.path()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((&entries[0].name[..], entries[0].is_dir), ("a", true));
+        assert_eq!((&entries[1].name[..], entries[1].is_dir, entries[1].size), ("b", false, 3));
+
+        assert!(list_dir(tempdir.path().join("b")).is_err());
+        assert!(list_dir(tempdir.path().join("c")).is_err());
+    }
+
+    #[test]
+    fn test_stat_path() {
+        let tempdir = TempDir::new("remote_test_stat_path").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        let stat = stat_path(&path).unwrap();
+        assert_eq!(stat.size, 3);
+        assert!(!stat.is_dir);
+        assert!(stat.mtime.is_some());
+        assert_eq!(stat.crc, None);
+
+        let stat = stat_path(tempdir.path()).unwrap();
+        assert!(stat.is_dir);
+        assert_eq!(stat.crc, None);
+
+        assert!(stat_path(tempdir.path().join("moo")).is_err());
+    }
+
+    #[test]
+    fn test_delete_path() {
+        let tempdir = TempDir::new("remote_test_delete_path").unwrap();
+        let path = tempdir.path().join("file");
+        fs::File::create(&path).unwrap();
+
+        delete_path(&path).unwrap();
+        assert!(!path.exists());
+        assert!(delete_path(&path).is_err());
+        assert!(delete_path(tempdir.path()).is_err());
+        assert!(tempdir.path().exists());
+    }
+}
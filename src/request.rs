@@ -0,0 +1,291 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Parsing of the frames a server receives. Nothing here touches a
+//! socket or the filesystem, so any input can be thrown at it (see
+//! the targets in `fuzz/`, e.g. `cargo fuzz run parse_request`).
+
+use error::{Error, Result};
+use protocol;
+use std::str;
+
+/// A client request, without its router ID
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// Start a batch upload of the files in an encoded manifest,
+    /// staging them until it is committed
+    Batch(Vec<u8>),
+    BatchAbort,
+    BatchCommit,
+    /// Abandon the client's upload or download in progress
+    Cancel,
+    /// Delete a file on the server
+    Delete(String),
+    Describe,
+    /// Add this connection to the upload with a transfer ID, started
+    /// on another connection, to be sent some of its chunk requests.
+    /// The token is the one the server ACKed the upload with.
+    Join(u64, Vec<u8>),
+    /// List a directory on the server
+    List(String),
+    ListTransfers,
+    /// Tells the server that a client waiting on its upload is still
+    /// there. Not replied to.
+    Ping,
+    Progress(u64),
+    Quota,
+    /// Details of a file on the server
+    Stat(String),
+    New {
+        path: String,
+        size: u64,
+        crc: u64,
+        chunk_size: u64,
+        /// Encoded FileOptions, which are decoded once the codec is
+        /// known
+        options: Vec<u8>,
+    },
+    Chunk(u64, Vec<u8>),
+    Chunks(Vec<(u64, Vec<u8>)>),
+    Get {
+        path: String,
+        /// Encoded FileOptions
+        options: Vec<u8>,
+    },
+    /// Chunks of a download that the client has written
+    Received(Vec<u64>),
+    /// Start a directory upload, staging files sent to paths within
+    /// it until it is committed
+    Dir(String),
+    DirAbort,
+    DirCommit,
+    /// Create an empty directory within a directory upload
+    Mkdir(String),
+    /// Chunks that the server's existing copy of a file already has
+    Unchanged(Vec<u64>),
+}
+
+impl Request {
+    /// Parse the frames that follow the router ID. `binary` is
+    /// whether the client's protocol sends chunk indexes as binary
+    /// integers.
+    pub fn parse(frames: &[Vec<u8>], binary: bool) -> Result<Request> {
+        let (action, args) = match frames.split_first() {
+            Some((action, args)) => (try!(str::from_utf8(action).or(Err(Error::InvalidRequest))), args),
+            None => return Err(Error::InvalidRequest),
+        };
+
+        match action {
+            "BATCH" => {
+                try!(expect(args, 1));
+                Ok(Request::Batch(args[0].clone()))
+            },
+            "BATCH-ABORT" => expect(args, 0).map(|_| Request::BatchAbort),
+            "BATCH-COMMIT" => expect(args, 0).map(|_| Request::BatchCommit),
+            "CANCEL" => expect(args, 0).map(|_| Request::Cancel),
+            "DELETE" => {
+                try!(expect(args, 1));
+                Ok(Request::Delete(try!(protocol::decode_path(&args[0]))))
+            },
+            "DESCRIBE" => expect(args, 0).map(|_| Request::Describe),
+            "DIR" => {
+                try!(expect(args, 1));
+                Ok(Request::Dir(try!(protocol::decode_path(&args[0]))))
+            },
+            "DIR-ABORT" => expect(args, 0).map(|_| Request::DirAbort),
+            "DIR-COMMIT" => expect(args, 0).map(|_| Request::DirCommit),
+            "JOIN" => {
+                try!(expect(args, 2));
+                Ok(Request::Join(try!(decode_u64(&args[0], false)), args[1].clone()))
+            },
+            "LIST" => {
+                try!(expect(args, 1));
+                Ok(Request::List(try!(protocol::decode_path(&args[0]))))
+            },
+            "LIST-TRANSFERS" => expect(args, 0).map(|_| Request::ListTransfers),
+            "MKDIR" => {
+                try!(expect(args, 1));
+                Ok(Request::Mkdir(try!(protocol::decode_path(&args[0]))))
+            },
+            "PING" => expect(args, 0).map(|_| Request::Ping),
+            "PROGRESS" => {
+                try!(expect(args, 1));
+                Ok(Request::Progress(try!(decode_u64(&args[0], false))))
+            },
+            "QUOTA" => expect(args, 0).map(|_| Request::Quota),
+            "STAT" => {
+                try!(expect(args, 1));
+                Ok(Request::Stat(try!(protocol::decode_path(&args[0]))))
+            },
+            "NEW" => {
+                try!(expect(args, 5));
+                Ok(Request::New {
+                    path: try!(protocol::decode_path(&args[0])),
+                    size: try!(decode_u64(&args[1], false)),
+                    crc: try!(decode_u64(&args[2], false)),
+                    chunk_size: try!(decode_u64(&args[3], false)),
+                    options: args[4].clone(),
+                })
+            },
+            "CHUNK" => {
+                try!(expect(args, 2));
+                Ok(Request::Chunk(try!(decode_u64(&args[0], binary)), args[1].clone()))
+            },
+            // Frames alternate between chunk index and data
+            "CHUNKS" => {
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return Err(Error::InvalidRequest);
+                }
+
+                let mut chunks = Vec::with_capacity(args.len() / 2);
+                for pair in args.chunks(2) {
+                    chunks.push((try!(decode_u64(&pair[0], binary)), pair[1].clone()));
+                }
+                Ok(Request::Chunks(chunks))
+            },
+            "GET" => {
+                try!(expect(args, 2));
+                Ok(Request::Get {
+                    path: try!(protocol::decode_path(&args[0])),
+                    options: args[1].clone(),
+                })
+            },
+            "RECEIVED" => {
+                if args.is_empty() {
+                    return Err(Error::InvalidRequest);
+                }
+
+                let mut indexes = Vec::with_capacity(args.len());
+                for frame in args {
+                    indexes.push(try!(decode_u64(frame, binary)));
+                }
+                Ok(Request::Received(indexes))
+            },
+            // Only clients that negotiated binary integers are sent
+            // HASHES, and it may be that no chunk is unchanged
+            "UNCHANGED" => {
+                let mut indexes = Vec::with_capacity(args.len());
+                for frame in args {
+                    indexes.push(try!(decode_u64(frame, true)));
+                }
+                Ok(Request::Unchanged(indexes))
+            },
+            _ => Err(Error::InvalidRequest),
+        }
+    }
+}
+
+/// Parse a chunk result from the sink, returning the chunk index and
+/// whether it was written successfully
+pub fn parse_sink(frames: &[Vec<u8>]) -> Result<(u64, bool)> {
+    try!(expect(frames, 2));
+    Ok((try!(decode_u64(&frames[0], true)), frames[1] == [1]))
+}
+
+fn expect(frames: &[Vec<u8>], count: usize) -> Result<()> {
+    if frames.len() == count {
+        Ok(())
+    } else {
+        Err(Error::InvalidRequest)
+    }
+}
+
+fn decode_u64(frame: &[u8], binary: bool) -> Result<u64> {
+    protocol::decode_u64(frame, binary).ok_or(Error::InvalidRequest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(strs: &[&str]) -> Vec<Vec<u8>> {
+        strs.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Request::parse(&frames(&["CANCEL"]), false).unwrap(), Request::Cancel);
+        assert_eq!(Request::parse(&frames(&["BATCH", "[]"]), false).unwrap(), Request::Batch(b"[]".to_vec()));
+        assert_eq!(Request::parse(&frames(&["BATCH-COMMIT"]), false).unwrap(), Request::BatchCommit);
+        assert_eq!(Request::parse(&frames(&["DESCRIBE"]), false).unwrap(), Request::Describe);
+        assert_eq!(Request::parse(&frames(&["PING"]), false).unwrap(), Request::Ping);
+        assert_eq!(Request::parse(&frames(&["PROGRESS", "3"]), false).unwrap(), Request::Progress(3));
+        assert_eq!(Request::parse(&frames(&["JOIN", "3", "4"]), true).unwrap(), Request::Join(3, b"4".to_vec()));
+        assert_eq!(Request::parse(&frames(&["NEW", "/tmp/a", "1", "2", "3", "{}"]), false).unwrap(), Request::New {
+            path: "/tmp/a".into(),
+            size: 1,
+            crc: 2,
+            chunk_size: 3,
+            options: b"{}".to_vec(),
+        });
+        assert_eq!(Request::parse(&frames(&["CHUNK", "1", "a"]), false).unwrap(), Request::Chunk(1, b"a".to_vec()));
+
+        let chunks = vec![b"CHUNKS".to_vec(), vec![0, 0, 0, 0, 0, 0, 0, 1], b"a".to_vec(), vec![0; 8], b"b".to_vec()];
+        assert_eq!(Request::parse(&chunks, true).unwrap(), Request::Chunks(vec![(1, b"a".to_vec()), (0, b"b".to_vec())]));
+
+        assert_eq!(Request::parse(&frames(&["GET", "/tmp/a", "{}"]), false).unwrap(), Request::Get {
+            path: "/tmp/a".into(),
+            options: b"{}".to_vec(),
+        });
+        assert_eq!(Request::parse(&frames(&["RECEIVED", "2", "0"]), false).unwrap(), Request::Received(vec![2, 0]));
+        assert_eq!(Request::parse(&frames(&["DIR", "/tmp/d"]), false).unwrap(), Request::Dir("/tmp/d".into()));
+        assert_eq!(Request::parse(&frames(&["MKDIR", "/tmp/d/e"]), false).unwrap(), Request::Mkdir("/tmp/d/e".into()));
+        assert_eq!(Request::parse(&frames(&["DIR-COMMIT"]), false).unwrap(), Request::DirCommit);
+        assert_eq!(Request::parse(&frames(&["LIST", "/tmp/d"]), false).unwrap(), Request::List("/tmp/d".into()));
+        assert_eq!(Request::parse(&frames(&["STAT", "/tmp/a"]), false).unwrap(), Request::Stat("/tmp/a".into()));
+        assert_eq!(Request::parse(&frames(&["DELETE", "/tmp/a"]), false).unwrap(), Request::Delete("/tmp/a".into()));
+        assert_eq!(Request::parse(&[b"UNCHANGED".to_vec(), vec![0, 0, 0, 0, 0, 0, 0, 3]], false).unwrap(), Request::Unchanged(vec![3]));
+        assert_eq!(Request::parse(&frames(&["UNCHANGED"]), false).unwrap(), Request::Unchanged(vec![]));
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let bad = [
+            vec![],
+            vec![vec![0xff]],
+            frames(&["MOO"]),
+            frames(&["CANCEL", "1"]),
+            frames(&["BATCH"]),
+            frames(&["BATCH-ABORT", "[]"]),
+            frames(&["DESCRIBE", "extra"]),
+            frames(&["PING", "1"]),
+            frames(&["PROGRESS"]),
+            frames(&["JOIN", "3"]),
+            frames(&["NEW", "/tmp/a", "-1", "2", "3", "{}"]),
+            frames(&["NEW", "/tmp/a", "1", "2", "3"]),
+            vec![b"NEW".to_vec(), vec![0xff], b"1".to_vec(), b"2".to_vec(), b"3".to_vec(), b"{}".to_vec()],
+            frames(&["CHUNK", "1"]),
+            frames(&["CHUNKS"]),
+            frames(&["CHUNKS", "1", "a", "2"]),
+            frames(&["GET", "/tmp/a"]),
+            frames(&["RECEIVED"]),
+            frames(&["RECEIVED", "1", "moo"]),
+            frames(&["DIR"]),
+            frames(&["DIR-ABORT", "/tmp/d"]),
+            frames(&["LIST"]),
+            frames(&["STAT", "/tmp/a", "/tmp/b"]),
+            vec![b"DELETE".to_vec(), vec![0xff]],
+            frames(&["UNCHANGED", "3"]),
+        ];
+
+        for frames in bad.iter() {
+            assert!(Request::parse(frames, false).is_err());
+        }
+
+        // Decimal indexes aren't accepted once binary is negotiated
+        assert!(Request::parse(&frames(&["CHUNK", "1", "a"]), true).is_err());
+    }
+
+    #[test]
+    fn test_parse_sink() {
+        assert_eq!(parse_sink(&[vec![0, 0, 0, 0, 0, 0, 0, 2], vec![1]]).unwrap(), (2, true));
+        assert_eq!(parse_sink(&[vec![0; 8], vec![0]]).unwrap(), (0, false));
+        assert!(parse_sink(&[vec![0; 8]]).is_err());
+        assert!(parse_sink(&[vec![1], vec![1]]).is_err());
+    }
+}
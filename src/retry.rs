@@ -0,0 +1,147 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use error::Error;
+use std::cmp;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Kinds of error that a RetryPolicy can choose to retry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+    /// The server is overloaded, or shutting down
+    Busy,
+    /// A socket error, e.g. a receive timeout
+    Socket,
+    /// A chunk or file failed to upload
+    Transfer,
+    /// A local IO error
+    Io,
+}
+
+impl ErrorClass {
+    /// Classify an error, or None if it can never succeed on retry
+    pub fn of(err: &Error) -> Option<ErrorClass> {
+        match *err {
+            Error::Busy(_) | Error::ShuttingDown => Some(ErrorClass::Busy),
+            Error::Czmq(_) | Error::Timeout => Some(ErrorClass::Socket),
+            Error::ChunkFail | Error::FailChecksum | Error::FileFail | Error::UploadError(_) => Some(ErrorClass::Transfer),
+            Error::Io(_) => Some(ErrorClass::Io),
+            _ => None,
+        }
+    }
+}
+
+/// How many times to retry a failed operation and how long to wait
+/// between attempts. Used for client uploads and server-side chunk
+/// retransmission.
+///
+/// Delays stop growing at a minute, unless `with_max_delay()` sets
+/// another limit.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Retries allowed before giving up
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Each delay is this many times the previous one
+    pub backoff: u32,
+    max_delay: Duration,
+    /// Fraction (0 to 1) of each delay that is randomised, so that
+    /// many clients don't retry in lockstep
+    pub jitter: f64,
+    /// Errors that are worth retrying
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::new(1, 0),
+            backoff: 2,
+            max_delay: Duration::new(60, 0),
+            jitter: 0.0,
+            retry_on: vec![ErrorClass::Busy, ErrorClass::Transfer],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Stop delays growing once they reach `max_delay`
+    pub fn with_max_delay(mut self, max_delay: Duration) -> RetryPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Whether to retry after `retries` earlier retries failed with
+    /// `err`
+    pub fn should_retry(&self, retries: u32, err: &Error) -> bool {
+        retries < self.max_retries && ErrorClass::of(err).map_or(false, |c| self.retry_on.contains(&c))
+    }
+
+    /// Delay before retry number `retries` (counting from 0)
+    pub fn delay(&self, retries: u32) -> Duration {
+        let mut delay = cmp::min(self.base_delay, self.max_delay);
+        for _ in 0..retries {
+            if delay >= self.max_delay {
+                break;
+            }
+            delay = cmp::min(delay.checked_mul(self.backoff).unwrap_or(self.max_delay), self.max_delay);
+        }
+
+        if self.jitter > 0.0 {
+            let millis = delay.as_secs() * 1000 + (delay.subsec_nanos() / 1_000_000) as u64;
+            let cut = (millis as f64 * self.jitter.min(1.0) * random_fraction()) as u64;
+            delay = Duration::from_millis(millis - cut);
+        }
+
+        delay
+    }
+}
+
+// Jitter doesn't need to be unpredictable, only to differ between
+// clients
+fn random_fraction() -> f64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    (nanos % 1000) as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(0, &Error::Busy(1)));
+        assert!(policy.should_retry(4, &Error::ChunkFail));
+        assert!(!policy.should_retry(5, &Error::ChunkFail));
+        assert!(!policy.should_retry(0, &Error::InvalidFilePath));
+
+        let policy = RetryPolicy { retry_on: vec![ErrorClass::Io], ..RetryPolicy::default() };
+        assert!(!policy.should_retry(0, &Error::Busy(1)));
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::new(1, 0));
+        assert_eq!(policy.delay(3), Duration::new(8, 0));
+        assert_eq!(policy.delay(200), Duration::new(60, 0));
+
+        let policy = RetryPolicy::default().with_max_delay(Duration::new(5, 0));
+        assert_eq!(policy.delay(2), Duration::new(4, 0));
+        assert_eq!(policy.delay(3), Duration::new(5, 0));
+
+        let policy = RetryPolicy { jitter: 0.5, ..RetryPolicy::default() };
+        let delay = policy.delay(1);
+        assert!(delay <= Duration::new(2, 0) && delay >= Duration::new(1, 0));
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use error::{Error, Result};
+
+/// Longest file name most filesystems accept (NAME_MAX)
+const MAX_COMPONENT: usize = 255;
+
+// Device names that Windows reserves, with or without an extension
+const RESERVED: [&'static str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// What the server does with destination paths containing control
+/// characters, reserved device names or components longer than 255
+/// bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NamePolicy {
+    /// Accept paths as they are sent
+    Allow,
+    /// Reject the upload. This is the default.
+    Reject,
+    /// Replace control characters with '_', prefix reserved names
+    /// with '_' and truncate long components
+    Replace,
+}
+
+/// Check a destination path against a policy, returning the path to
+/// use instead
+pub fn sanitize(path: &str, policy: NamePolicy) -> Result<String> {
+    if policy == NamePolicy::Allow {
+        return Ok(path.into());
+    }
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        if is_safe(component) {
+            components.push(component.to_string());
+        } else if policy == NamePolicy::Reject {
+            return Err(Error::UnsafeFileName);
+        } else {
+            components.push(replace(component));
+        }
+    }
+
+    Ok(components.join("/"))
+}
+
+fn is_safe(component: &str) -> bool {
+    component.len() <= MAX_COMPONENT &&
+    !component.chars().any(|c| c.is_control()) &&
+    !is_reserved(component)
+}
+
+fn is_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap().trim_right_matches(' ').to_uppercase();
+    RESERVED.contains(&&stem[..])
+}
+
+fn replace(component: &str) -> String {
+    let mut name: String = component.chars().map(|c| if c.is_control() { '_' } else { c }).collect();
+    if is_reserved(&name) {
+        name.insert(0, '_');
+    }
+
+    // Truncate on a character boundary
+    while name.len() > MAX_COMPONENT {
+        name.pop();
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let long: String = iter::repeat('a').take(300).collect();
+        let bad = ["/tmp/a\nb", "/tmp/CON", "/tmp/nul.txt", &format!("/tmp/{}", long)];
+
+        for path in bad.iter() {
+            assert_eq!(sanitize(path, NamePolicy::Allow).unwrap(), *path);
+            assert!(sanitize(path, NamePolicy::Reject).is_err());
+        }
+
+        assert_eq!(sanitize("/tmp/console.txt", NamePolicy::Reject).unwrap(), "/tmp/console.txt");
+        assert_eq!(sanitize("/tmp/a\nb", NamePolicy::Replace).unwrap(), "/tmp/a_b");
+        assert_eq!(sanitize("/tmp/nul.txt", NamePolicy::Replace).unwrap(), "/tmp/_nul.txt");
+        assert_eq!(sanitize(&format!("/tmp/{}", long), NamePolicy::Replace).unwrap().len(), 5 + 255);
+    }
+}
@@ -6,275 +6,4232 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
-use arbitrator::Arbitrator;
-use czmq::{ZFrame, ZMsg, ZSock, ZSys};
+use arbitrator::{Arbitrator, Schedule};
+use auth::ServerAuth;
+use bandwidth::BandwidthSchedule;
+use batch::{self, BatchFile};
+use channel::Channels;
+use clock::{Clock, SystemClock};
+use codec::{Codec, JsonCodec, WireCodec};
+use czmq::{SocketType, ZFrame, ZMsg, ZSock, ZSys};
+use dir;
+use disk;
 use error::{Error, Result};
-use file::File;
-use std::collections::HashMap;
+use event::{hex, Completion, Event, EventLog};
+use file::{File, IfExists, StagingNames, Timings};
+use hash::HashAlgorithm;
+use hasher::Hasher;
+use manifest::{self, Manifest};
+use protocol::{self, Compat, PROTOCOL_VERSION};
+use record::Recorder;
+use remote;
+use request::{parse_sink, Request};
+use retry::RetryPolicy;
+use sanitize::{sanitize, NamePolicy};
+#[cfg(feature = "signing")]
+use signing;
+use sniff::sniff;
+use std::{cmp, fs};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
 use std::result::Result as StdResult;
+use std::time::{Duration, Instant};
+use stripe::Stripes;
+use transfer::{TransferId, Transfers};
+use worker::WorkerPool;
 use zdaemon::{Endpoint, Error as DError, ZMsgExtended};
 
+const ACTIONS: [&'static str; 23] = ["BATCH", "BATCH-ABORT", "BATCH-COMMIT", "CANCEL", "CHUNK", "CHUNKS", "DELETE", "DESCRIBE", "DIR",
+                                     "DIR-ABORT", "DIR-COMMIT", "GET", "JOIN", "LIST", "LIST-TRANSFERS", "MKDIR", "NEW", "PING",
+                                     "PROGRESS", "QUOTA", "RECEIVED", "STAT", "UNCHANGED"];
+const CAPABILITIES: [&'static str; 10] = ["adaptive_chunk_size", "backup_existing", "backup_versions", "batching", "channels",
+                                           "chunk_size", "durable", "heartbeats", "stripes", "symlinks"];
+/// Heartbeats a client may miss before its upload is expired
+const MISSED_HEARTBEATS: u32 = 3;
+/// Largest chunk size that adaptive sizing grows to, unless the
+/// server sets its own maximum
+const ADAPT_MAX_CHUNK_SIZE: u64 = 1024 * 1024; // 1Mb
+const HASH_THREADS: u32 = 2;
+/// Chunks a client may send ahead of requests, unless the server
+/// sets its own maximum
+const MAX_WINDOW: u32 = 16;
+/// Length of the window for `HourlyQuota`
+const HOUR_SECS: u64 = 3600;
+/// Seconds an overloaded server asks clients to wait before retrying
+const RETRY_AFTER: u32 = 5;
+
 pub struct Server {
     router: ZSock,
     sink: ZSock,
-    files: HashMap<Vec<u8>, File>,
+    hashed: ZSock,
+    hasher: Hasher,
+    files: Transfers,
     arbitrator: Arbitrator,
     arbitrator_sock: ZSock,
+    options: ServerOptions,
+    workers: Option<WorkerPool>,
+    events: EventLog,
+    admin: Option<ZSock>,
+    auth: Option<ServerAuth>,
+    /// Decides whether a client's CURVE public key may make requests
+    authorizer: Option<Box<Fn(&str) -> bool>>,
+    /// Bytes uploaded by each client, for quotas
+    usage: HashMap<Vec<u8>, u64>,
+    /// Start of each client's current hour, and the bytes it has
+    /// uploaded since
+    hourly: HashMap<Vec<u8>, (Instant, u64)>,
+    sanitizer: Option<Box<Fn(&str) -> Result<String>>>,
+    output: Option<Box<FnMut(&Path) -> Option<Box<Write + Send>>>>,
+    recorder: Option<Recorder>,
+    /// Partial uploads from before a restart, by destination path
+    staged: HashMap<String, (PathBuf, Manifest)>,
+    last_sweep: Instant,
+    /// Partial uploads found by a sweep still walking the `Recover`
+    /// directories
+    sweeping: Option<Receiver<Vec<PathBuf>>>,
+    /// When each client was last heard from, if heartbeats are on
+    last_seen: HashMap<Vec<u8>, Instant>,
+    last_expire: Instant,
+    /// Files being sent to downloading clients, by router ID
+    downloads: HashMap<Vec<u8>, File>,
+    /// Directory uploads by router ID, with their destination and
+    /// staging directory
+    dirs: HashMap<Vec<u8>, (PathBuf, PathBuf)>,
+    /// Batch uploads by router ID, with each declared file's
+    /// staging path
+    batches: HashMap<Vec<u8>, Vec<(BatchFile, PathBuf)>>,
+    /// Router IDs of batch uploads with a file that asked to be
+    /// durable, whose commit is then synced too
+    durable_batches: HashSet<Vec<u8>>,
+    /// Uploads waiting for the client to say which chunks are
+    /// unchanged, by router ID
+    deltas: HashMap<Vec<u8>, Upload>,
+    /// Replies waiting for the Hasher to read a file, by router ID
+    lookups: HashMap<Vec<u8>, (TransferId, Lookup)>,
+    /// Clients running transfers on more than one channel have a
+    /// router ID for each
+    channels: Channels,
+    /// Extra connections that joined uploads
+    stripes: Stripes,
+    shutting_down: Option<Shutdown>,
+    /// Outcomes of finished uploads, for `stats()`
+    totals: Totals,
+}
+
+// Counts of finished uploads since the server started
+#[derive(Default)]
+struct Totals {
+    completed: u64,
+    failed: u64,
+    bytes_received: u64,
+    // Time the completed uploads took, end to end
+    elapsed: Duration,
+}
+
+impl Totals {
+    fn record(&mut self, completion: &Completion) {
+        if completion.result.is_ok() {
+            self.completed += 1;
+            self.bytes_received += completion.size;
+            self.elapsed += completion.duration;
+        } else {
+            self.failed += 1;
+        }
+    }
+}
+
+// What to reply once a file that a client is waiting on has been
+// hashed
+enum Lookup {
+    // Checksums of the chunks an upload shares with the file at its
+    // destination, which the client is sent before it starts
+    Delta(Upload),
+    Get { path: String, chunk_size: u64, options: Vec<u8>, protocol: u32 },
+    Stat(remote::Stat),
+    // Whether the file at an upload's destination is already the one
+    // being sent
+    Unchanged(Upload),
+}
+
+// An upload request that has passed the server's checks
+struct Upload {
+    path: String,
+    size: u64,
+    crc: u64,
+    chunk_size: u64,
+    options: Vec<u8>,
+    protocol: Option<u32>,
 }
 
 impl Server {
-    pub fn new(router: ZSock, upload_slots: u32) -> Result<Server> {
+    pub fn new(router: ZSock, upload_slots: u32, options: Option<&[Options]>) -> Result<Server> {
         // Would use RC instead of pipe, however RC !Send and Arc
         // +Sync & ZSock !Sync.
         let (s_sock, a_sock) = try!(ZSys::create_pipe());
-        let arbitrator = try!(Arbitrator::new(a_sock, upload_slots));
+        let options = ServerOptions::new(options);
+        let mut arbitrator = try!(Arbitrator::with_clock(a_sock, upload_slots, options.clock.clone()));
+        let sink = try!(ZSock::new_pull("inproc://zfilexfer_sink"));
+
+        if let Some(millis) = options.timer_interval {
+            try!(arbitrator.set_timer_interval(millis));
+        }
+
+        if let Some(millis) = options.chunk_timeout {
+            try!(arbitrator.set_chunk_timeout(millis));
+        }
+
+        if let Some(bytes) = options.max_buffered {
+            arbitrator.set_budget(bytes);
+        }
+
+        arbitrator.set_schedule(options.schedule);
+
+        if let Some(slots) = options.upload_slots_per_client {
+            arbitrator.set_client_slots(slots);
+        }
+
+        if let Some(bytes) = options.max_buffered_per_client {
+            arbitrator.set_client_budget(bytes);
+        }
+
+        if let Some(ref schedule) = options.bandwidth {
+            arbitrator.set_bandwidth(schedule.clone());
+        }
+
+        // Workers connect to the sink, so it must be bound first
+        let workers = match options.workers {
+            Some(n) if n > 0 => Some(try!(WorkerPool::new(n))),
+            _ => None,
+        };
+
+        // New transfers mustn't take the IDs of those that may resume
+        let mut files = Transfers::new();
+        let mut staged = HashMap::new();
+        for dir in options.recover.iter() {
+            try!(batch::remove_staged(Path::new(dir)));
+            for (upload_path, manifest) in try!(manifest::recover(Path::new(dir))) {
+                if let Some(id) = manifest.transfer_id {
+                    files.reserve(id);
+                }
+                staged.insert(manifest.path.clone(), (upload_path, manifest));
+            }
+        }
+
+        let now = options.clock.now();
+        let hashed = try!(ZSock::new_pull("inproc://zfilexfer_hashed"));
+        let hasher = try!(Hasher::new(HASH_THREADS, ">inproc://zfilexfer_hashed"));
 
         Ok(Server {
             router: router,
-            sink: try!(ZSock::new_pull("inproc://zfilexfer_sink")),
-            files: HashMap::new(),
+            sink: sink,
+            hashed: hashed,
+            hasher: hasher,
+            files: files,
             arbitrator: arbitrator,
             arbitrator_sock: s_sock,
+            options: options,
+            workers: workers,
+            events: EventLog::new(),
+            admin: None,
+            auth: None,
+            authorizer: None,
+            usage: HashMap::new(),
+            hourly: HashMap::new(),
+            sanitizer: None,
+            output: None,
+            recorder: None,
+            staged: staged,
+            last_sweep: now,
+            sweeping: None,
+            last_seen: HashMap::new(),
+            last_expire: now,
+            downloads: HashMap::new(),
+            dirs: HashMap::new(),
+            batches: HashMap::new(),
+            durable_batches: HashSet::new(),
+            deltas: HashMap::new(),
+            lookups: HashMap::new(),
+            channels: Channels::new(),
+            stripes: Stripes::new(),
+            totals: Totals::default(),
+            shutting_down: None,
+        })
+    }
+
+    /// Write transfer events (transfer_started, chunk_retry,
+    /// transfer_completed and transfer_failed) to `writer` as JSON
+    /// lines
+    pub fn set_event_writer(&mut self, writer: Box<Write>) {
+        self.events.set_writer(writer);
+    }
+
+    /// Call `listener` with each transfer event as it happens, e.g. to
+    /// reload a service once its config arrives. This includes a
+    /// chunk_received event for every chunk written, which the event
+    /// writer leaves out.
+    pub fn set_event_listener<F>(&mut self, listener: F) where F: FnMut(&Event) + 'static {
+        self.events.set_listener(Box::new(listener));
+    }
+
+    /// Require clients to authenticate with CURVE. Only clients in
+    /// the allowlist can connect from now on. ZMQ ignores CURVE
+    /// options set once a socket is bound, so the router passed to
+    /// `new()` should come from `ServerAuth::bind()`. The admin
+    /// endpoint, if any, is bound here.
+    pub fn set_auth(&mut self, auth: ServerAuth) -> Result<()> {
+        auth.apply(&mut self.router);
+
+        if let Some(ref endpoint) = self.options.admin_endpoint {
+            let mut admin = ZSock::new(SocketType::REP);
+            auth.apply(&mut admin);
+            try!(admin.bind(endpoint));
+            self.admin = Some(admin);
+        }
+
+        self.auth = Some(auth);
+        Ok(())
+    }
+
+    /// Check each request with `authorizer`, which is passed the
+    /// client's CURVE public key as Z85 text. Requests it returns
+    /// false for, and any from clients without a key, get
+    /// `Error::Unauthorized`. It is called for every chunk, so should
+    /// be cheap.
+    pub fn set_authorizer<F>(&mut self, authorizer: F) where F: Fn(&str) -> bool + 'static {
+        self.authorizer = Some(Box::new(authorizer));
+    }
+
+    /// Call `observer` whenever a transfer completes or fails, e.g. to
+    /// keep an inventory of delivered files
+    pub fn set_completion_observer<F>(&mut self, observer: F) where F: FnMut(&Completion) + 'static {
+        self.events.set_observer(Box::new(observer));
+    }
+
+    /// Check destination paths with `sanitizer` instead of the
+    /// NamePolicy option. It returns the path to upload to, or an
+    /// error to reject the upload.
+    pub fn set_name_sanitizer<F>(&mut self, sanitizer: F) where F: Fn(&str) -> Result<String> + 'static {
+        self.sanitizer = Some(Box::new(sanitizer));
+    }
+
+    /// Call `output` with the destination path of each new transfer.
+    /// If it returns a writer, the completed file is written to it
+    /// instead of being saved. Uploads to an existing FIFO are
+    /// written into it only with `StreamFifos`.
+    pub fn set_output<F>(&mut self, output: F) where F: FnMut(&Path) -> Option<Box<Write + Send>> + 'static {
+        self.output = Some(Box::new(output));
+    }
+
+    /// Record every message received from clients to `writer`, so
+    /// the session can be replayed later with a `Replayer`. Chunk
+    /// payloads are left out, and recording stops if `writer` fails.
+    pub fn set_recorder(&mut self, writer: Box<Write>) -> Result<()> {
+        self.recorder = Some(try!(Recorder::new(writer)));
+        Ok(())
+    }
+
+    /// Describe the protocol version, actions and limits of this
+    /// server
+    pub fn describe(&self) -> Description {
+        Description {
+            version: PROTOCOL_VERSION,
+            actions: ACTIONS.iter().map(|a| a.to_string()).collect(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            codecs: WireCodec::supported().iter().map(|c| c.name().to_string()).collect(),
+            max_file_size: self.options.max_file_size,
+            min_chunk_size: self.options.min_chunk_size,
+            max_chunk_size: self.options.max_chunk_size,
+        }
+    }
+
+    /// Take a snapshot of all active transfers
+    pub fn snapshot(&self) -> Vec<TransferState> {
+        self.files.iter().map(|(id, &(ref router_id, ref file))| {
+            TransferState {
+                id: *id,
+                identity: self.channels.identity(router_id).to_vec(),
+                path: file.get_path().map_or(String::new(), |p| p.to_string_lossy().into_owned()),
+                size: file.get_size(),
+                bytes_done: file.bytes_done(),
+                retries: file.get_retries(),
+                age: file.get_age().as_secs(),
+                timings: file.get_timings(),
+            }
+        }).collect()
+    }
+
+    /// Counters for monitoring the server since it started
+    pub fn stats(&self) -> ServerStats {
+        let millis = self.totals.elapsed.as_secs() * 1000 + (self.totals.elapsed.subsec_nanos() / 1_000_000) as u64;
+
+        ServerStats {
+            active_transfers: self.files.len() as u64,
+            completed: self.totals.completed,
+            failed: self.totals.failed,
+            bytes_received: self.totals.bytes_received,
+            throughput: if millis == 0 { 0 } else { self.totals.bytes_received * 1000 / millis },
+            queued: self.arbitrator.queued() as u64,
+        }
+    }
+
+    /// Limits that apply to a client's uploads
+    pub fn quota(&self, router_id: &[u8]) -> Quota {
+        let total = self.options.quota.map(|q| q.saturating_sub(*self.usage.get(self.channels.client(router_id)).unwrap_or(&0)));
+        let hourly = self.options.hourly_quota.map(|q| q.saturating_sub(self.hourly_usage(self.channels.client(router_id))));
+
+        Quota {
+            remaining: match (total, hourly) {
+                (Some(total), Some(hourly)) => Some(cmp::min(total, hourly)),
+                (total, hourly) => total.or(hourly),
+            },
+            max_file_size: self.options.max_file_size,
+            allowed_paths: self.options.allowed_paths.clone(),
+        }
+    }
+
+    /// Progress of an active transfer
+    pub fn progress(&self, id: TransferId) -> Option<Progress> {
+        self.files.get_by_id(id).map(|file| {
+            Progress {
+                id: id,
+                bytes_done: file.bytes_done(),
+                chunks_outstanding: file.chunks_outstanding(),
+                retries: file.get_retries(),
+            }
         })
     }
 
-    fn reply_err(&mut self, router_id: &[u8], err: Error) -> StdResult<(), DError> {
-        let msg = try!(ZMsg::new_err(&err.into()));
-        try!(msg.pushbytes(router_id));
-        try!(msg.send(&mut self.router));
-        Ok(())
-    }
-}
+    // Bytes a client has uploaded in its current hour, which starts
+    // with its first upload after the last hour ended
+    fn hourly_usage(&self, client: &[u8]) -> u64 {
+        match self.hourly.get(client) {
+            Some(&(start, bytes)) if self.options.clock.now().duration_since(start).as_secs() < HOUR_SECS => bytes,
+            _ => 0,
+        }
+    }
+
+    // Whether accepting another transfer would overload the server,
+    // or give a client more than its share. A client's new request
+    // replaces its current transfer, so that doesn't count towards
+    // the limit.
+    fn is_overloaded(&self, router_id: &[u8]) -> bool {
+        let transfers = self.files.len() - if self.files.contains_key(router_id) { 1 } else { 0 };
+
+        self.options.max_transfers.map_or(false, |max| transfers >= max as usize) ||
+        self.options.max_queued.map_or(false, |max| self.arbitrator.queued() >= max as usize) ||
+        self.options.max_transfers_per_client.map_or(false, |max| self.client_transfers(router_id) >= max as usize)
+    }
+
+    // Admins are known by their CURVE public key, as a router
+    // identity can be chosen by any peer
+    fn is_admin(&self, router_id: &[u8]) -> bool {
+        self.channels.user_id(router_id).map_or(false, |key| self.is_admin_key(key))
+    }
+
+    fn is_admin_key(&self, key: &[u8]) -> bool {
+        self.options.admins.iter().any(|a| &a[..] == key)
+    }
+
+    // Transfers in progress on a client's other channels
+    fn client_transfers(&self, router_id: &[u8]) -> usize {
+        let client = self.channels.client(router_id);
+        self.files.iter()
+            .filter(|&(_, &(ref key, _))| &key[..] != router_id && self.channels.client(key) == client)
+            .count()
+    }
+
+    fn check_limits(&self, router_id: &[u8], path: &str, size: u64, chunk_size: u64) -> Result<()> {
+        if let Some(max) = self.options.max_file_size {
+            if size > max {
+                return Err(Error::FileSize);
+            }
+        }
+
+        if let Some(remaining) = self.quota(router_id).remaining {
+            if size > remaining {
+                return Err(Error::QuotaExceeded);
+            }
+        }
+
+        try!(self.check_path(path));
+        try!(self.check_space(path, size));
+        self.check_chunk_size(chunk_size)
+    }
+
+    // Temporary files are created at full size, so an upload that
+    // won't fit is turned away before it can fill the disk
+    fn check_space(&self, path: &str, size: u64) -> Result<()> {
+        let staging = self.options.staging_dir.as_ref().map_or(Path::new(path), |d| d.as_path());
+
+        // Uploads in progress on the same filesystem have yet to fill
+        // their sparse staging files, so that space isn't really free
+        let device = disk::device(staging);
+        let reserved = self.files.iter()
+            .filter(|&(_, &(_, ref file))| device.is_some() && file.get_upload_path().map_or(false, |p| disk::device(p) == device))
+            .fold(0u64, |acc, (_, &(_, ref file))| acc.saturating_add(file.get_size().saturating_sub(file.bytes_done())));
+
+        if size.saturating_add(self.options.reserve_space).saturating_add(reserved) > try!(disk::available(staging)) {
+            return Err(Error::NoSpace);
+        }
+        Ok(())
+    }
+
+    // Symlinks are resolved before comparing, so a link inside an
+    // allowed directory can't lead out of it
+    fn check_path(&self, path: &str) -> Result<()> {
+        if !self.options.allowed_paths.is_empty() {
+            let path = Path::new(path);
+            if path.components().any(|c| c == Component::ParentDir) {
+                return Err(Error::PathNotAllowed);
+            }
+
+            let path = resolve(path);
+            if !self.options.allowed_paths.iter().any(|p| path.starts_with(resolve(Path::new(p)))) {
+                return Err(Error::PathNotAllowed);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Targets must be relative and stay below the link's directory,
+    // as must wherever they lead once resolved
+    fn check_symlink(&self, router_id: &[u8], path: &str, options: &[u8]) -> Result<()> {
+        match try!(File::options_symlink(options)) {
+            Some(target) => {
+                let target = Path::new(&target);
+                if target.components().any(|c| match c { Component::Normal(_) | Component::CurDir => false, _ => true }) {
+                    return Err(Error::PathNotAllowed);
+                }
+
+                let linked = Path::new(path).parent().unwrap_or(Path::new("")).join(target);
+                try!(self.check_tenant_root(router_id, &linked));
+                self.check_path(&linked.to_string_lossy())
+            },
+            None => Ok(()),
+        }
+    }
+
+    // GET, LIST, STAT and DELETE act on files already on the server, so
+    // must be turned on, and only reach the allowed paths or the
+    // client's tenant root
+    fn remote_action_path(&self, router_id: &[u8], path: &str) -> Result<String> {
+        if !self.options.remote_files || (self.options.allowed_paths.is_empty() && self.options.tenants.is_empty()) {
+            return Err(Error::Unauthorized);
+        }
+        self.remote_path(router_id, path)
+    }
+
+    // Paths for LIST, STAT, DELETE and batches go through the same
+    // checks as an upload's
+    fn remote_path(&self, router_id: &[u8], path: &str) -> Result<String> {
+        let path = try!(self.sanitize_path(path).and_then(|p| self.tenant_path(router_id, &p)));
+        try!(self.check_path(&path));
+        Ok(path)
+    }
+
+    fn check_chunk_size(&self, chunk_size: u64) -> Result<()> {
+        if chunk_size == 0 ||
+           self.options.min_chunk_size.map_or(false, |min| chunk_size < min) ||
+           self.options.max_chunk_size.map_or(false, |max| chunk_size > max) {
+            return Err(Error::ChunkSize);
+        }
+
+        Ok(())
+    }
+
+    fn sanitize_path(&self, path: &str) -> Result<String> {
+        match self.sanitizer {
+            Some(ref sanitizer) => sanitizer(path),
+            None => sanitize(path, self.options.name_policy),
+        }
+    }
+
+    // Move a path under the client's tenant root. Once any tenants
+    // are configured, clients without a root can't upload at all.
+    fn tenant_path(&self, router_id: &[u8], path: &str) -> Result<String> {
+        if self.options.tenants.is_empty() {
+            return Ok(path.into());
+        }
+
+        let root = match self.tenant_root(router_id) {
+            Some(r) => r,
+            None => return Err(Error::PathNotAllowed),
+        };
+
+        let relative = Path::new(path.trim_left_matches('/'));
+        if relative.components().any(|c| match c { Component::Normal(_) | Component::CurDir => false, _ => true }) {
+            return Err(Error::PathNotAllowed);
+        }
+
+        let path = Path::new(root).join(relative);
+        try!(self.check_tenant_root(router_id, &path));
+        match path.to_str() {
+            Some(p) => Ok(p.into()),
+            None => Err(Error::InvalidFilePath),
+        }
+    }
+
+    // Symlinks are resolved before comparing, so a link that a tenant
+    // created can't lead out of its root
+    fn check_tenant_root(&self, router_id: &[u8], path: &Path) -> Result<()> {
+        if self.options.tenants.is_empty() {
+            return Ok(());
+        }
+
+        match self.tenant_root(router_id) {
+            Some(root) if resolve(path).starts_with(resolve(Path::new(root))) => Ok(()),
+            _ => Err(Error::PathNotAllowed),
+        }
+    }
+
+    // Tenants are known by their CURVE public key, so a client can't
+    // pick another tenant's root by choosing its router identity
+    fn tenant_root(&self, router_id: &[u8]) -> Option<&String> {
+        self.channels.user_id(router_id).and_then(|key| self.options.tenants.get(key))
+    }
+
+    // Paths within a directory upload are staged until it is
+    // committed, and may not climb out of the staging directory.
+    // Other paths are left alone.
+    fn dir_path(&self, router_id: &[u8], path: &str) -> Result<Option<String>> {
+        let (relative, staging) = match self.dirs.get(router_id) {
+            Some(&(ref dest, ref staging)) => match Path::new(path).strip_prefix(dest) {
+                Ok(relative) => (relative, staging),
+                Err(_) => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        if relative.components().any(|c| match c { Component::Normal(_) => false, _ => true }) {
+            return Err(Error::InvalidFilePath);
+        }
+        staging.join(relative).to_str().map(|p| Some(p.into())).ok_or(Error::InvalidFilePath)
+    }
+
+    // Files declared in a batch upload are staged until it is
+    // committed, and must be sent as declared. Other paths are left
+    // alone.
+    fn batch_path(&self, router_id: &[u8], path: &str, size: u64, crc: u64) -> Result<Option<String>> {
+        let &(ref declared, ref staging) = match self.batches.get(router_id).and_then(|files| files.iter().find(|&&(ref f, _)| f.path == path)) {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+
+        if declared.size != size || declared.crc != crc {
+            return Err(Error::FailChecksum);
+        }
+        staging.to_str().map(|p| Some(p.into())).ok_or(Error::InvalidFilePath)
+    }
+
+    // Discard a batch upload's staged files
+    fn abort_batch(&mut self, router_id: &[u8]) {
+        self.durable_batches.remove(router_id);
+        if let Some(files) = self.batches.remove(router_id) {
+            for (_, staging) in files {
+                let _ = fs::remove_file(staging);
+            }
+        }
+    }
+
+    // Discard a directory upload's staged files
+    fn abort_dir(&mut self, router_id: &[u8]) {
+        if let Some((_, staging)) = self.dirs.remove(router_id) {
+            let _ = dir::remove_tree(&staging);
+        }
+    }
+
+    // Only accept transfers signed by a trusted key, if the server
+    // has any
+    #[cfg(feature = "signing")]
+    fn check_signature(&self, path: &str, size: u64, crc: u64, options: &[u8]) -> Result<()> {
+        if self.options.trusted_keys.is_empty() {
+            return Ok(());
+        }
+
+        // The digest signed is the one checked against the file
+        // once it has arrived
+        match try!(File::options_signature(options)) {
+            (Some(ref sig), ref hash) if signing::verify(&self.options.trusted_keys, path, size, crc, hash.as_ref(), sig) => Ok(()),
+            _ => Err(Error::BadSignature),
+        }
+    }
+
+    #[cfg(not(feature = "signing"))]
+    fn check_signature(&self, _: &str, _: u64, _: u64, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    // Write a chunk in this thread, or hand it to a worker if the
+    // server has a worker pool.
+    fn recv_chunk(&mut self, router_id: &[u8], index: u64, mut data: Vec<u8>) -> Result<()> {
+        let file = self.files.get_mut(router_id).unwrap();
+        try!(file.decompress(index, &mut data));
+        try!(file.seal(index, &mut data));
+
+        match self.workers {
+            Some(ref mut workers) => {
+                let offset = try!(file.chunk_offset(index));
+                workers.write(router_id, file.get_upload_path().unwrap(), offset, index, data)
+            },
+            None => file.recv(router_id, index, data),
+        }
+    }
+
+    fn recv_chunks(&mut self, router_id: &[u8], mut chunks: Vec<(u64, Vec<u8>)>) -> Result<()> {
+        if self.workers.is_some() {
+            for (index, data) in chunks {
+                try!(self.recv_chunk(router_id, index, data));
+            }
+            Ok(())
+        } else {
+            let file = self.files.get_mut(router_id).unwrap();
+            for &mut (index, ref mut data) in chunks.iter_mut() {
+                try!(file.decompress(index, data));
+                try!(file.seal(index, data));
+            }
+            file.recv_many(router_id, chunks)
+        }
+    }
+
+    // Handle a request on the admin socket, which is a REP socket so
+    // has no router ID. Only admins may send commands.
+    fn recv_admin(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let msg = try!(ZMsg::recv(sock));
+
+        let result = match msg.pop() {
+            Some(frame) => {
+                // ZAP sets the User-Id of a CURVE client to its public key
+                let public_key = frame.meta("User-Id").and_then(|k| k.ok());
+                if !public_key.map_or(false, |k| self.is_admin_key(k.as_bytes())) {
+                    Err(Error::Unauthorized)
+                } else {
+                    match try!(frame.data()) {
+                        Ok(command) => self.admin_command(&command, &msg),
+                        Err(_) => Err(Error::InvalidRequest),
+                    }
+                }
+            },
+            None => Err(Error::InvalidRequest),
+        };
+
+        let reply = match result {
+            Ok(_) => try!(ZMsg::new_ok()),
+            Err(e) => try!(protocol::new_err(&e)),
+        };
+        try!(reply.send(sock));
+        Ok(())
+    }
+
+    fn admin_command(&mut self, command: &str, msg: &ZMsg) -> Result<()> {
+        match command {
+            "PAUSE" => self.arbitrator.set_paused(true),
+            "RESUME" => self.arbitrator.set_paused(false),
+            "CANCEL" => {
+                let id = try!(protocol::pop_u64(msg, false).ok_or(Error::InvalidRequest));
+                self.abandon(id, Some(Error::Cancelled))
+            },
+            "SWEEP" => self.sweep(),
+            "DRAIN" => self.shutdown(Shutdown::Drain),
+            "ABORT" => self.shutdown(Shutdown::Abort),
+            "SET-SLOTS" => {
+                let slots = try!(protocol::pop_u64(msg, false).ok_or(Error::InvalidRequest));
+                if slots == 0 || slots > u32::max_value() as u64 {
+                    return Err(Error::InvalidRequest);
+                }
+                self.arbitrator.set_slots(slots as u32)
+            },
+            // Pick up allowlist changes without a restart
+            "RELOAD-AUTH" => match self.auth {
+                Some(ref mut auth) => auth.reload(),
+                None => Err(Error::InvalidRequest),
+            },
+            // The router is already bound, so keeps its key until
+            // the server is restarted
+            "ROTATE-CERT" => match self.auth {
+                Some(ref mut auth) => auth.rotate(),
+                None => Err(Error::InvalidRequest),
+            },
+            _ => Err(Error::InvalidRequest),
+        }
+    }
+
+    // Abandon an upload that can't go on, telling the client why
+    fn fail_upload(&mut self, id: TransferId, e: Error) -> StdResult<(), DError> {
+        error!("upload failed id={} error={:?}", id, e.to_string());
+        if let Err(e) = self.abandon(id, Some(e)) {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    // Send a chunk request, or a download's chunks, unless the client's
+    // connection is full. A ROUTER would otherwise drop it, or block
+    // if told to fail instead.
+    fn forward(&mut self, msg: ZMsg) -> bool {
+        // Whatever timeout the router's owner set applies again
+        // afterwards
+        let sndtimeo = self.router.sndtimeo();
+        self.router.set_router_mandatory(true);
+        self.router.set_sndtimeo(Some(0));
+        let sent = self.channels.send(msg, &mut self.router).is_ok();
+        self.router.set_router_mandatory(false);
+        self.router.set_sndtimeo(sndtimeo);
+        sent
+    }
+
+    // Refuse an upload whose chunks were queued before it could start.
+    // Like one that starts, it replaces any earlier transfer.
+    fn refuse_upload(&mut self, router_id: &[u8], e: Error) -> StdResult<(), DError> {
+        self.files.remove_identity(router_id);
+        self.stripes.remove(router_id);
+        if let Err(e) = self.arbitrator.cancel(router_id) {
+            return Err(e.into());
+        }
+        self.reply_err(router_id, e)
+    }
+
+    // Remove a transfer and its temporary file, optionally telling
+    // the client why
+    fn abandon(&mut self, id: TransferId, reason: Option<Error>) -> Result<()> {
+        let router_id = try!(self.files.identity(id).ok_or(Error::InvalidRequest)).to_vec();
+
+        // Only the active transfer has chunks queued
+        if self.files.active(&router_id) == Some(id) {
+            try!(self.arbitrator.cancel(&router_id));
+        }
+
+        let mut file = self.files.remove(id).unwrap();
+        self.stripes.remove(&router_id);
+        let upload_path = file.get_upload_path().unwrap().to_owned();
+        info!("transfer abandoned id={} router_id={} path={}", id, hex(&router_id), file.get_path().unwrap().display());
+        if let Some(ref mut workers) = self.workers {
+            try!(workers.close(&router_id, &upload_path));
+        }
+        let _ = fs::remove_file(&upload_path);
+        file.discard_copy();
+        file.remove_manifest();
+
+        if !self.files.contains_key(&router_id) && !self.downloads.contains_key(&router_id) {
+            self.arbitrator.set_protocol(&router_id, None);
+        }
+
+        if let Some(e) = reason {
+            self.events.emit(Event::TransferFailed {
+                id: id,
+                identity: self.channels.identity(&router_id),
+                path: file.get_path().unwrap(),
+                reason: e.to_string(),
+            });
+
+            let msg = try!(protocol::new_err(&e));
+            try!(msg.pushbytes(&router_id));
+            try!(self.channels.send(msg, &mut self.router));
+
+            let c = completion(id, self.channels.identity(&router_id), &file, Err(e));
+            self.totals.record(&c);
+            self.events.complete(c);
+        }
+
+        self.close_idle(&router_id);
+        Ok(())
+    }
+
+    /// Stop accepting new transfers, which clients are refused with
+    /// `Error::ShuttingDown`. With `Shutdown::Abort`, transfers in
+    /// progress are cancelled and their temporary files deleted too.
+    /// The Arbitrator's timer thread stops once no transfer is left,
+    /// after which the server can be dropped without leaving any
+    /// client waiting.
+    pub fn shutdown(&mut self, mode: Shutdown) -> Result<()> {
+        info!("server shutting down mode={:?} transfers={} downloads={}", mode, self.files.len(), self.downloads.len());
+        self.shutting_down = Some(mode);
+
+        if mode == Shutdown::Abort {
+            let ids: Vec<TransferId> = self.files.iter().map(|(id, _)| *id).collect();
+            for id in ids {
+                try!(self.abandon(id, Some(Error::ShuttingDown)));
+            }
+
+            let downloads: Vec<Vec<u8>> = self.downloads.keys().cloned().collect();
+            for router_id in downloads {
+                self.downloads.remove(&router_id);
+                try!(self.arbitrator.cancel(&router_id));
+                self.arbitrator.set_protocol(&router_id, None);
+
+                let msg = try!(protocol::new_err(&Error::ShuttingDown));
+                try!(msg.pushbytes(&router_id));
+                try!(self.channels.send(msg, &mut self.router));
+                self.close_idle(&router_id);
+            }
+
+            let batches: Vec<Vec<u8>> = self.batches.keys().cloned().collect();
+            for router_id in batches {
+                self.abort_batch(&router_id);
+            }
+            let dirs: Vec<Vec<u8>> = self.dirs.keys().cloned().collect();
+            for router_id in dirs {
+                self.abort_dir(&router_id);
+            }
+            self.deltas.clear();
+        }
+
+        if self.is_drained() {
+            self.arbitrator.stop();
+        }
+        Ok(())
+    }
+
+    // Abandon uploads whose client has missed several heartbeats,
+    // releasing their upload slots and deleting their temporary
+    // files. Clients that predate heartbeats are left to their chunk
+    // timeouts.
+    fn expire(&mut self) -> Result<()> {
+        let interval = match self.options.heartbeat {
+            Some(millis) => Duration::from_millis(millis as u64),
+            None => return Ok(()),
+        };
+        let now = self.options.clock.now();
+        if now.duration_since(self.last_expire) < interval {
+            return Ok(());
+        }
+        self.last_expire = now;
+
+        let expired: Vec<TransferId> = {
+            let last_seen = &self.last_seen;
+            self.files.iter()
+                .filter(|&(_, &(ref router_id, ref file))| {
+                    protocol::heartbeats(file.get_protocol()) &&
+                    last_seen.get(router_id).map_or(false, |&seen| now.duration_since(seen) >= interval * MISSED_HEARTBEATS)
+                })
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        let mut dead = Vec::new();
+        for id in expired {
+            let router_id = self.files.identity(id).unwrap().to_vec();
+            warn!("transfer expired id={} router_id={}", id, hex(&router_id));
+            try!(self.abandon(id, Some(Error::Expired)));
+            dead.push(router_id);
+        }
+
+        // A batch or directory upload waits on its client between
+        // files, so one whose client has gone quiet with no file in
+        // progress is dropped
+        {
+            let last_seen = &self.last_seen;
+            let files = &self.files;
+            dead.extend(self.batches.keys().chain(self.dirs.keys())
+                .filter(|router_id| {
+                    !files.contains_key(*router_id) &&
+                    last_seen.get(*router_id).map_or(false, |&seen| now.duration_since(seen) >= interval * MISSED_HEARTBEATS)
+                })
+                .cloned());
+        }
+        dead.sort();
+        dead.dedup();
+        for router_id in dead {
+            try!(self.expire_client(&router_id));
+        }
+
+        let (files, batches, dirs) = (&self.files, &self.batches, &self.dirs);
+        self.last_seen.retain(|router_id, _| files.contains_key(router_id) || batches.contains_key(router_id) || dirs.contains_key(router_id));
+        Ok(())
+    }
+
+    // Drop whatever else a client that has stopped responding left in
+    // progress
+    fn expire_client(&mut self, router_id: &[u8]) -> Result<()> {
+        if self.batches.contains_key(router_id) {
+            warn!("batch expired router_id={}", hex(router_id));
+            self.abort_batch(router_id);
+        }
+        if self.dirs.contains_key(router_id) {
+            warn!("directory upload expired router_id={}", hex(router_id));
+            self.abort_dir(router_id);
+        }
+        self.deltas.remove(router_id);
+
+        if self.downloads.remove(router_id).is_some() {
+            warn!("download expired router_id={}", hex(router_id));
+            try!(self.arbitrator.cancel(router_id));
+            if !self.files.contains_key(router_id) {
+                self.arbitrator.set_protocol(router_id, None);
+            }
+
+            let msg = try!(protocol::new_err(&Error::Expired));
+            try!(msg.pushbytes(router_id));
+            try!(self.channels.send(msg, &mut self.router));
+        }
+
+        self.close_idle(router_id);
+        Ok(())
+    }
+
+    /// Whether no upload, download, batch or directory upload is in
+    /// progress, and no reply is waiting on the Hasher
+    pub fn is_drained(&self) -> bool {
+        self.files.len() == 0 && self.downloads.is_empty() && self.batches.is_empty() && self.dirs.is_empty() &&
+            self.deltas.is_empty() && self.lookups.is_empty()
+    }
+
+    /// Delete partial uploads under the `Recover` directories that no
+    /// transfer is using, including any found at startup that
+    /// haven't been resumed. The directories are walked on another
+    /// thread, and what it finds is deleted once the server next
+    /// handles a message after it's done.
+    pub fn sweep(&mut self) -> Result<()> {
+        self.staged.clear();
+        self.last_sweep = self.options.clock.now();
+        if self.sweeping.is_some() {
+            return Ok(());
+        }
+
+        let dirs = self.options.recover.clone();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut found = Vec::new();
+            for dir in dirs {
+                match manifest::scan(Path::new(&dir)) {
+                    Ok(uploads) => found.extend(uploads),
+                    Err(e) => warn!("sweep failed dir={} error={:?}", dir, e.to_string()),
+                }
+            }
+            let _ = tx.send(found);
+        });
+        self.sweeping = Some(rx);
+        Ok(())
+    }
+
+    // Delete what a finished sweep found, unless a transfer has
+    // started using it since
+    fn swept(&mut self) {
+        let found = match self.sweeping.as_ref().map(|rx| rx.try_recv()) {
+            Some(Ok(found)) => found,
+            Some(Err(TryRecvError::Empty)) | None => return,
+            Some(Err(TryRecvError::Disconnected)) => Vec::new(),
+        };
+        self.sweeping = None;
+
+        // Directories may be named differently from the paths that
+        // clients sent
+        let in_use: HashSet<PathBuf> = self.files.iter()
+            .filter_map(|(_, &(_, ref file))| file.get_upload_path().and_then(|p| fs::canonicalize(p).ok()))
+            .collect();
+
+        for upload_path in found {
+            if fs::canonicalize(&upload_path).map_or(true, |p| !in_use.contains(&p)) {
+                let _ = fs::remove_file(&upload_path);
+                let _ = fs::remove_file(manifest::manifest_path(&upload_path));
+            }
+        }
+    }
+
+    // Pick up a partial upload from before a restart, if the client
+    // is sending the same file again. Anything else staged for the
+    // path is stale.
+    fn resume(&mut self, router_id: &[u8], path: &str, size: u64, crc: u64, chunk_size: u64, options: &[u8]) -> Option<File> {
+        let (upload_path, manifest) = match self.staged.remove(path) {
+            Some(staged) => staged,
+            None => return None,
+        };
+
+        // Encrypting the rest of a plaintext file would corrupt it
+        let file = if !self.options.encrypt_staging && manifest.matches(size, crc, chunk_size, options) {
+            File::resume(&mut self.arbitrator, router_id, &upload_path, manifest).ok()
+        } else {
+            None
+        };
+
+        if file.is_none() {
+            let _ = fs::remove_file(manifest::manifest_path(&upload_path));
+            let _ = fs::remove_file(&upload_path);
+        }
+        file
+    }
+
+    // Pick up a partial upload from before a restart whose client is
+    // still sending its chunks, as it kept its router ID. The upload
+    // keeps its transfer ID, and is otherwise set up as when it
+    // started.
+    fn restore(&mut self, router_id: &[u8]) {
+        // Router identities are chosen by clients, so the one that
+        // authenticated must match too
+        let path = {
+            let client = self.channels.client(router_id);
+            match self.staged.iter().find(|&(_, &(_, ref m))| {
+                m.router_id.as_ref().map_or(false, |r| &r[..] == router_id) && m.client.as_ref().map_or(false, |c| &c[..] == client)
+            }) {
+                Some((path, _)) => path.clone(),
+                None => return,
+            }
+        };
+        let (upload_path, manifest) = self.staged.remove(&path).unwrap();
+        let (protocol, transfer_id) = (manifest.protocol, manifest.transfer_id);
+
+        // The path was checked when the upload began, but the
+        // server's settings may have changed since
+        let allowed = match self.sanitize_path(&path) {
+            Ok(ref sanitized) if *sanitized == path => {
+                self.check_path(&path).and_then(|_| self.check_tenant_root(router_id, Path::new(&path)))
+                    .and_then(|_| self.check_symlink(router_id, &path, &manifest.options))
+            },
+            Ok(_) => Err(Error::PathNotAllowed),
+            Err(e) => Err(e),
+        };
+        if let Err(ref e) = allowed {
+            warn!("transfer not restored router_id={} path={} error={:?}", hex(router_id), path, e.to_string());
+        }
+
+        // Encrypting the rest of a plaintext file would corrupt it
+        let file = if self.options.encrypt_staging || allowed.is_err() {
+            None
+        } else {
+            File::resume(&mut self.arbitrator, router_id, &upload_path, manifest).ok()
+        };
+
+        let mut file = match file {
+            Some(f) => f,
+            None => {
+                let _ = fs::remove_file(manifest::manifest_path(&upload_path));
+                let _ = fs::remove_file(&upload_path);
+                return;
+            },
+        };
+
+        self.arbitrator.set_protocol(router_id, protocol);
+        self.arbitrator.set_identity(router_id, Some(self.channels.client(router_id)));
+        self.prepare_upload(&mut file);
+        if let Some(ref mut output) = self.output {
+            if let Some(writer) = output(Path::new(&path)) {
+                file.set_output(writer);
+            }
+        }
+
+        let id = match transfer_id {
+            Some(id) => self.files.insert_with_id(id, router_id.to_vec(), file),
+            None => self.files.insert(router_id.to_vec(), file),
+        };
+        if transfer_id != Some(id) {
+            self.record_transfer_id(router_id, id);
+        }
+        info!("transfer restored id={} router_id={} path={}", id, hex(router_id), path);
+
+        // Every chunk may have been written before the restart
+        let submitted = {
+            let file = self.files.get_mut(router_id).unwrap();
+            if file.is_complete() { submit_hash(&self.hasher, id, router_id, file) } else { Ok(()) }
+        };
+        if let Err(e) = submitted {
+            warn!("transfer not verified id={} error={:?}", id, e.to_string());
+            let _ = self.abandon(id, None);
+        }
+    }
+
+    // Server settings that apply to every upload, however it began
+    fn prepare_upload(&self, file: &mut File) {
+        file.set_retry_policy(self.options.retry.clone());
+        if self.options.durable {
+            file.set_durable();
+        }
+        if let Some(chunks) = self.options.manifest_interval {
+            file.set_manifest_interval(chunks as u64);
+        }
+        if self.options.stream_fifos {
+            file.set_stream_fifos();
+        }
+    }
+
+    // Note an upload's transfer ID in its staging manifest. Failing
+    // to only costs resuming under the same ID.
+    fn record_transfer_id(&mut self, router_id: &[u8], id: TransferId) {
+        if let Err(e) = self.files.get_mut(router_id).unwrap().set_transfer_id(id) {
+            warn!("manifest not saved id={} error={:?}", id, e.to_string());
+        }
+    }
+
+    // A chunk sent to a downloading client was acknowledged or timed
+    // out. The download ends once every chunk is acknowledged, or
+    // when a chunk has failed too often.
+    fn sink_download(&mut self, router_id: &[u8], index: u64, success: bool) -> Result<()> {
+        let (complete, failed) = match self.downloads.get_mut(router_id) {
+            Some(file) => {
+                try!(file.sink(&mut self.arbitrator, router_id, index, success));
+                (file.is_complete(), file.is_error())
+            },
+            None => return Err(Error::InvalidRequest),
+        };
+
+        if complete || failed {
+            self.downloads.remove(router_id);
+            try!(self.arbitrator.cancel(router_id));
+            if !self.files.contains_key(router_id) {
+                self.arbitrator.set_protocol(router_id, None);
+            }
+        }
+
+        if failed {
+            let msg = try!(protocol::new_err(&Error::FileFail));
+            try!(msg.pushbytes(router_id));
+            try!(self.channels.send(msg, &mut self.router));
+        }
+
+        self.close_idle(router_id);
+        Ok(())
+    }
+
+    // Whether the client wants to skip the chunks that an upload
+    // shares with the file already at its destination
+    fn wants_delta(&self, upload: &Upload) -> Result<bool> {
+        Ok(protocol::delta_chunks(upload.protocol) && try!(File::options_delta(&upload.options)) &&
+           !self.options.encrypt_staging && !self.staged.contains_key(&upload.path) && Path::new(&upload.path).is_file())
+    }
+
+    // Offer the client the checksums of its destination's chunks if
+    // it wants to skip unchanged ones, or else start the upload
+    fn begin_upload(&mut self, router_id: &[u8], upload: Upload) -> StdResult<(), DError> {
+        match self.wants_delta(&upload) {
+            Ok(true) => {
+                let path = upload.path.clone();
+                if let Err(e) = self.look_up(router_id, Path::new(&path), None, Lookup::Delta(upload)) {
+                    return self.reply_err(router_id, e);
+                }
+                Ok(())
+            },
+            Ok(false) => self.start_upload(router_id, upload, &[]),
+            Err(e) => self.reply_err(router_id, e),
+        }
+    }
+
+    // Create the file for an upload and tell the client it has
+    // started. The `unchanged` chunks are copied from the existing
+    // destination rather than requested.
+    fn start_upload(&mut self, router_id: &[u8], upload: Upload, unchanged: &[u64]) -> StdResult<(), DError> {
+        let Upload { path, size, crc, chunk_size, options, protocol } = upload;
+
+        // A new request abandons any download
+        self.deltas.remove(router_id);
+        if self.downloads.remove(router_id).is_some() {
+            if let Err(e) = self.arbitrator.cancel(router_id) {
+                return Err(e.into());
+            }
+        }
+
+        self.arbitrator.set_protocol(router_id, protocol);
+        self.arbitrator.set_identity(router_id, Some(self.channels.client(router_id)));
+
+        // A partial upload from before a restart beats a delta
+        let resumed = if unchanged.is_empty() {
+            self.resume(router_id, &path, size, crc, chunk_size, &options)
+        } else {
+            None
+        };
+
+        let mut file = match resumed {
+            Some(f) => f,
+            None => match File::create_staged(&mut self.arbitrator, router_id, &path, size, crc, chunk_size, &options, unchanged,
+                                              self.options.staging_dir.as_ref().map(|d| d.as_path()), self.options.staging_names) {
+                Ok(f) => f,
+                Err(e) => return self.reply_err(router_id, e),
+            },
+        };
+        file.set_protocol(protocol);
+        self.prepare_upload(&mut file);
+
+        // Only the same client can carry on after a restart
+        if let Err(e) = file.set_client(self.channels.client(router_id)) {
+            return self.refuse_upload(router_id, e);
+        }
+
+        if let Some(ref mut output) = self.output {
+            if let Some(writer) = output(Path::new(&path)) {
+                file.set_output(writer);
+            }
+        }
+
+        if self.options.encrypt_staging {
+            if let Err(e) = file.encrypt_staging() {
+                return self.refuse_upload(router_id, e);
+            }
+        }
+
+        // Chunks sent ahead must keep the size they were sent with
+        let window = if protocol::pipelined_chunks(protocol) {
+            cmp::min(file.window(), self.options.max_window)
+        } else {
+            0
+        };
+
+        // As must chunks sent on connections that joined
+        let striped = protocol::striped_chunks(protocol) && File::options_striped(&options).unwrap_or(false);
+
+        if self.options.adaptive_chunk_size && protocol::adaptive_chunks(protocol) && window == 0 && !striped {
+            let min = self.options.min_chunk_size.unwrap_or(1);
+            let max = self.options.max_chunk_size.unwrap_or(ADAPT_MAX_CHUNK_SIZE);
+            file.set_adaptive(min, max);
+        }
+
+        // A client only uploads one file at a time, so a new request
+        // abandons any earlier transfer.
+        self.files.remove_identity(router_id);
+        self.stripes.remove(router_id);
+        let id = self.files.insert(router_id.to_vec(), file);
+        self.record_transfer_id(router_id, id);
+
+        // The transfer ID lets both peers refer to the same transfer
+        // in their logs.
+        if let Some(version) = protocol {
+            let msg = ZMsg::new();
+            try!(msg.addbytes(router_id));
+            try!(msg.addstr("ACK"));
+            try!(msg.addstr(&version.to_string()));
+            try!(msg.addstr(&id.to_string()));
+            if protocol::pipelined_chunks(protocol) {
+                try!(msg.addstr(&window.to_string()));
+            }
+            // How often the client should PING while it waits. Zero
+            // holds the place of the token that follows.
+            if let (true, Some(millis)) = (protocol::heartbeats(protocol), self.options.heartbeat) {
+                try!(msg.addstr(&millis.to_string()));
+            } else if protocol::join_tokens(protocol) {
+                try!(msg.addstr("0"));
+            }
+            // Only the client sending the file is told how to join it
+            if striped && protocol::join_tokens(protocol) {
+                match self.stripes.offer(router_id, id) {
+                    Ok(token) => try!(msg.addstr(&token)),
+                    Err(e) => return self.fail_upload(id, e),
+                }
+            }
+            try!(self.channels.send(msg, &mut self.router));
+        }
+
+        self.events.emit(Event::TransferStarted {
+            id: id,
+            identity: self.channels.identity(router_id),
+            path: Path::new(&path),
+            size: size,
+        });
+
+        // An upload resumed, or with every chunk unchanged, may have
+        // no chunk left to arrive and finish it
+        let submitted = {
+            let file = self.files.get_mut(router_id).unwrap();
+            if file.is_complete() { submit_hash(&self.hasher, id, router_id, file) } else { Ok(()) }
+        };
+        if let Err(e) = submitted {
+            return self.fail_upload(id, e);
+        }
+
+        Ok(())
+    }
+
+    // Hash a file that a client is waiting on off this thread. The
+    // reply is sent by `looked_up()` once it's done.
+    fn look_up(&mut self, router_id: &[u8], path: &Path, algorithm: Option<HashAlgorithm>, lookup: Lookup) -> Result<()> {
+        let id = self.files.take_id();
+        match lookup {
+            Lookup::Delta(ref upload) => try!(self.hasher.submit_chunks(id, router_id, path, upload.size, upload.chunk_size)),
+            _ => try!(self.hasher.submit(id, router_id, path, algorithm)),
+        }
+        self.lookups.insert(router_id.to_vec(), (id, lookup));
+        Ok(())
+    }
+
+    // Reply to a client whose file has been hashed, or couldn't be
+    fn looked_up(&mut self, router_id: &[u8], lookup: Lookup, hashed: Option<(u64, Vec<u8>)>, hashing: Duration) -> StdResult<(), DError> {
+        match lookup {
+            Lookup::Delta(upload) => {
+                if self.shutting_down.is_some() {
+                    return self.reply_err(router_id, Error::ShuttingDown);
+                }
+
+                // A destination that couldn't be read is replaced in
+                // full
+                let hashes = hashed.and_then(|(_, packed)| protocol::unpack_u64s(&packed)).unwrap_or(Vec::new());
+                if hashes.is_empty() {
+                    return self.start_upload(router_id, upload, &[]);
+                }
+
+                let msg = ZMsg::new();
+                try!(msg.addbytes(router_id));
+                try!(msg.addstr("HASHES"));
+                try!(msg.addbytes(&protocol::pack_u64s(&hashes)));
+                try!(self.channels.send(msg, &mut self.router));
+
+                self.deltas.insert(router_id.to_vec(), upload);
+                return Ok(());
+            },
+            Lookup::Get { path, chunk_size, options, protocol } => {
+                let crc = match hashed {
+                    Some((crc, _)) => crc,
+                    None => return self.reply_err(router_id, Error::FileFail),
+                };
+
+                self.arbitrator.set_protocol(router_id, Some(protocol));
+
+                let mut file = match File::serve(&mut self.arbitrator, router_id, &path, chunk_size, crc, &options) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        if !self.files.contains_key(router_id) {
+                            self.arbitrator.set_protocol(router_id, None);
+                        }
+                        return self.reply_err(router_id, e);
+                    },
+                };
+                file.set_protocol(Some(protocol));
+                file.set_retry_policy(self.options.retry.clone());
+                file.add_hashing(hashing);
+
+                let msg = ZMsg::new();
+                try!(msg.addbytes(router_id));
+                try!(msg.addstr("FILE"));
+                try!(msg.addstr(&file.get_size().to_string()));
+                try!(msg.addstr(&file.get_crc().to_string()));
+                try!(msg.addstr(&chunk_size.to_string()));
+                try!(msg.addstr(&protocol.to_string()));
+                try!(self.channels.send(msg, &mut self.router));
+
+                // An empty file has no chunks to send
+                if file.is_complete() {
+                    if !self.files.contains_key(router_id) {
+                        self.arbitrator.set_protocol(router_id, None);
+                    }
+                } else {
+                    self.downloads.insert(router_id.to_vec(), file);
+                }
+            },
+            Lookup::Unchanged(upload) => {
+                if self.shutting_down.is_some() {
+                    return self.reply_err(router_id, Error::ShuttingDown);
+                }
+
+                let expected = match File::options_hash(&upload.options) {
+                    Ok(hash) => hash.map_or(Vec::new(), |(_, digest)| digest),
+                    Err(e) => return self.reply_err(router_id, e),
+                };
+                if !hashed.map_or(false, |(crc, digest)| crc == upload.crc && digest == expected) {
+                    return self.begin_upload(router_id, upload);
+                }
+
+                let msg = ZMsg::new();
+                try!(msg.addbytes(router_id));
+                try!(msg.addstr("Ok"));
+                try!(msg.addstr("UNCHANGED"));
+                try!(self.channels.send(msg, &mut self.router));
+            },
+            Lookup::Stat(mut stat) => {
+                stat.crc = match hashed {
+                    Some((crc, _)) => Some(crc),
+                    None => return self.reply_err(router_id, Error::FileFail),
+                };
+                let encoded = match JsonCodec.encode(&stat) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
+
+                let msg = try!(ZMsg::new_ok());
+                try!(msg.addbytes(&encoded));
+                try!(msg.pushbytes(router_id));
+                try!(self.channels.send(msg, &mut self.router));
+            },
+        }
+
+        self.close_idle(router_id);
+        Ok(())
+    }
+
+    fn reply_err(&mut self, router_id: &[u8], err: Error) -> StdResult<(), DError> {
+        debug!("request rejected router_id={} error={:?}", hex(router_id), err.to_string());
+        let msg = try!(protocol::new_err(&err));
+        try!(msg.pushbytes(router_id));
+        try!(self.channels.send(msg, &mut self.router));
+        self.close_idle(router_id);
+        Ok(())
+    }
+
+    // Forget a channel with nothing left in progress, which the
+    // client opens again with its next message
+    fn close_idle(&mut self, router_id: &[u8]) {
+        if !self.files.contains_key(router_id) && !self.downloads.contains_key(router_id) && !self.lookups.contains_key(router_id) {
+            self.channels.close(router_id);
+        }
+    }
+}
+
+impl Endpoint for Server {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        let mut socks = vec![&mut self.router, &mut self.sink, &mut self.hashed, &mut self.arbitrator_sock];
+        if let Some(ref mut admin) = self.admin {
+            socks.push(admin);
+        }
+        socks
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let result = self.handle(sock);
+
+        // Once a shutdown has drained, nothing is left to time out
+        if self.shutting_down.is_some() && self.is_drained() {
+            self.arbitrator.stop();
+        }
+        result
+    }
+}
+
+impl Server {
+    fn handle(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        if let Some(secs) = self.options.sweep_interval {
+            if self.options.clock.now().duration_since(self.last_sweep) >= Duration::new(secs as u64, 0) {
+                if let Err(e) = self.sweep() {
+                    return Err(e.into());
+                }
+            }
+        }
+        self.swept();
+        if let Err(e) = self.expire() {
+            return Err(e.into());
+        }
+
+        if self.admin.as_ref().map_or(false, |admin| *sock == *admin) {
+            return self.recv_admin(sock);
+        }
+
+        // We always expect a router ID as it ties a request to a
+        // file. Its presence is not dependent on the socket type.
+        let frame = try!(ZFrame::recv(sock));
+        // ZAP sets the User-Id of a CURVE client to its public key
+        let public_key = frame.meta("User-Id").and_then(|k| k.ok());
+        let router_id = match try!(frame.data()) {
+            Ok(s) => s.into_bytes(),
+            Err(b) => b,
+        };
+
+        if *sock == self.router {
+            let frames = try!(recv_frames(sock));
+            self.channels.set_user(&router_id, public_key.clone());
+
+            // Recording is best-effort, so a failing writer ends the
+            // recording rather than the service
+            let failed = match self.recorder {
+                Some(ref mut recorder) => recorder.record(&router_id, &frames).err(),
+                None => None,
+            };
+            if let Some(e) = failed {
+                warn!("recording stopped error={:?}", e.to_string());
+                self.recorder = None;
+            }
+
+            // Each channel stands in for a client of its own. Bad
+            // channel frames are dropped, as there's no channel to
+            // reply on.
+            let (router_id, frames) = match self.channels.open(router_id, frames) {
+                Ok(r) => r,
+                Err(_) => return Ok(()),
+            };
+
+            let authorized = match self.authorizer {
+                Some(ref authorizer) => public_key.as_ref().map_or(false, |k| authorizer(&k[..])),
+                None => true,
+            };
+            if !authorized {
+                warn!("client not authorized router_id={} key={}", hex(&router_id), public_key.as_ref().map_or("none", |k| &k[..]));
+                return self.reply_err(&router_id, Error::Unauthorized);
+            }
+
+            // A connection that joined an upload stands in for the
+            // one that started it, but only for the chunks it sends
+            let chunk = frames.first().map_or(false, |a| &a[..] == b"CHUNK" || &a[..] == b"CHUNKS");
+            let router_id = match self.stripes.owner(&router_id) {
+                Some(owner) if chunk => owner.to_vec(),
+                _ => router_id,
+            };
+            if self.options.heartbeat.is_some() {
+                self.last_seen.insert(router_id.clone(), self.options.clock.now());
+            }
+
+            // Chunks from a client unknown since a restart may be for
+            // an upload it started before
+            if chunk && !self.staged.is_empty() && self.shutting_down.is_none() && !self.files.contains_key(&router_id) {
+                self.restore(&router_id);
+            }
+
+            let protocol = self.files.get(&router_id).or_else(|| self.downloads.get(&router_id)).and_then(|f| f.get_protocol());
+            let binary = protocol::binary_ints(protocol);
+            let request = match Request::parse(&frames, binary) {
+                Ok(r) => r,
+                Err(e) => return self.reply_err(&router_id, e),
+            };
+
+            // A server shutting down finishes what it has, but starts
+            // nothing new. Batch and directory uploads send each of
+            // their files with NEW.
+            let starts_transfer = match request {
+                Request::Batch(_) | Request::Dir(_) | Request::Get { .. } => true,
+                Request::New { .. } => !self.batches.contains_key(&router_id) && !self.dirs.contains_key(&router_id),
+                _ => false,
+            };
+            if starts_transfer && self.shutting_down.is_some() {
+                return self.reply_err(&router_id, Error::ShuttingDown);
+            }
+
+            match request {
+                Request::Cancel => {
+                    // Replied to first, as a channel is closed along
+                    // with its last transfer. Nothing is sent to the
+                    // client after this.
+                    let msg = ZMsg::new();
+                    try!(msg.addbytes(&router_id));
+                    try!(msg.addstr("CANCELLED"));
+                    try!(self.channels.send(msg, &mut self.router));
+
+                    self.lookups.remove(&router_id);
+                    if let Some(id) = self.files.active(&router_id) {
+                        if let Err(e) = self.abandon(id, None) {
+                            return Err(e.into());
+                        }
+                    }
+                    if self.downloads.remove(&router_id).is_some() {
+                        if let Err(e) = self.arbitrator.cancel(&router_id) {
+                            return Err(e.into());
+                        }
+                        if !self.files.contains_key(&router_id) {
+                            self.arbitrator.set_protocol(&router_id, None);
+                        }
+                    }
+                    self.close_idle(&router_id);
+                },
+                Request::Join(id, token) => {
+                    let owner = match self.files.identity(id) {
+                        Some(owner) if owner != &router_id[..] => owner.to_vec(),
+                        _ => return self.reply_err(&router_id, Error::InvalidRequest),
+                    };
+
+                    // Only the client sending the file was given its
+                    // token. A joiner must also have the same CURVE
+                    // key, so it was authorized, and has the tenant
+                    // root, the owner has.
+                    let joinable = {
+                        let file = self.files.get_by_id(id).unwrap();
+                        protocol::join_tokens(file.get_protocol()) && self.stripes.admits(&owner, id, &token) &&
+                        self.channels.user_id(&router_id) == self.channels.user_id(&owner)
+                    };
+                    if !joinable {
+                        return self.reply_err(&router_id, Error::InvalidRequest);
+                    }
+
+                    self.stripes.join(&owner, &router_id);
+                    debug!("stripe joined id={} router_id={} owner={}", id, hex(&router_id), hex(&owner));
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::Describe => {
+                    let encoded = match JsonCodec.encode(&self.describe()) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.addbytes(&encoded));
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::ListTransfers => {
+                    if !self.is_admin(&router_id) {
+                        return self.reply_err(&router_id, Error::Unauthorized);
+                    }
+
+                    let encoded = match JsonCodec.encode(&self.snapshot()) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.addbytes(&encoded));
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                // Heard from, which is all a PING is for
+                Request::Ping => (),
+                Request::Progress(id) => {
+                    let progress = match self.progress(id) {
+                        Some(p) => p,
+                        None => return self.reply_err(&router_id, Error::InvalidRequest),
+                    };
+
+                    // Only the client sending the file, or an admin,
+                    // may follow it
+                    let owner = self.files.identity(id).map_or(false, |owner| self.channels.client(owner) == self.channels.client(&router_id));
+                    if !owner && !self.is_admin(&router_id) {
+                        return self.reply_err(&router_id, Error::Unauthorized);
+                    }
+
+                    let encoded = match JsonCodec.encode(&progress) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.addbytes(&encoded));
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::List(path) => {
+                    let entries = match self.remote_action_path(&router_id, &path).and_then(remote::list_dir) {
+                        Ok(e) => e,
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    let encoded = match JsonCodec.encode(&entries) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.addbytes(&encoded));
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                    self.close_idle(&router_id);
+                },
+                Request::Stat(path) => {
+                    let (path, stat) = match self.remote_action_path(&router_id, &path).and_then(|p| remote::stat_path(&p).map(|s| (p, s))) {
+                        Ok(s) => s,
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    // A file's CRC is left to the Hasher, as reading
+                    // it all would hold up other clients
+                    if !stat.is_dir {
+                        if let Err(e) = self.look_up(&router_id, Path::new(&path), None, Lookup::Stat(stat)) {
+                            return self.reply_err(&router_id, e);
+                        }
+                        return Ok(());
+                    }
+
+                    let encoded = match JsonCodec.encode(&stat) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.addbytes(&encoded));
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                    self.close_idle(&router_id);
+                },
+                Request::Delete(path) => {
+                    if let Err(e) = self.remote_action_path(&router_id, &path).and_then(remote::delete_path) {
+                        return self.reply_err(&router_id, e);
+                    }
+                    info!("file deleted router_id={} path={:?}", hex(&router_id), path);
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                    self.close_idle(&router_id);
+                },
+                Request::Quota => {
+                    let encoded = match JsonCodec.encode(&self.quota(&router_id)) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.addbytes(&encoded));
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::New { path, size, crc, chunk_size, options } => {
+                    // Signatures cover the path the client sent, but
+                    // everything else uses the sanitized tenant path.
+                    if let Err(e) = self.check_signature(&path, size, crc, &options) {
+                        return self.reply_err(&router_id, e);
+                    }
+
+                    let path = match self.sanitize_path(&path).and_then(|p| self.tenant_path(&router_id, &p)) {
+                        Ok(p) => p,
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    if let Err(e) = self.check_limits(&router_id, &path, size, chunk_size) {
+                        return self.reply_err(&router_id, e);
+                    }
+
+                    let path = match self.batch_path(&router_id, &path, size, crc) {
+                        Ok(Some(p)) => {
+                            if File::options_durable(&options).unwrap_or(false) {
+                                self.durable_batches.insert(router_id.clone());
+                            }
+                            p
+                        },
+                        Ok(None) => match self.dir_path(&router_id, &path) {
+                            Ok(p) => p.unwrap_or(path),
+                            Err(e) => return self.reply_err(&router_id, e),
+                        },
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    // A link may only point where the client could
+                    // have uploaded to
+                    if let Err(e) = self.check_symlink(&router_id, &path, &options) {
+                        return self.reply_err(&router_id, e);
+                    }
+
+                    // Legacy clients don't advertise a protocol
+                    // version and don't expect an ACK.
+                    let protocol = match self.options.compat {
+                        Compat::Legacy => None,
+                        _ => match File::options_protocol(&options) {
+                            Ok(p) => p.map(|v| cmp::min(v, PROTOCOL_VERSION)),
+                            Err(e) => return self.reply_err(&router_id, e),
+                        },
+                    };
+
+                    if protocol.is_none() && self.options.compat == Compat::Versioned {
+                        return self.reply_err(&router_id, Error::LegacyPeer);
+                    }
+
+                    // Clients that understand it are told which
+                    // versions would be accepted
+                    if let Some(min) = self.options.min_protocol {
+                        if protocol.map_or(true, |v| v < min) {
+                            if !protocol::unsupported_reply(protocol) {
+                                return self.reply_err(&router_id, Error::UnsupportedVersion(min));
+                            }
+
+                            let msg = ZMsg::new();
+                            try!(msg.addbytes(&router_id));
+                            try!(msg.addstr("UNSUPPORTED"));
+                            try!(msg.addstr(&min.to_string()));
+                            try!(msg.addstr(&PROTOCOL_VERSION.to_string()));
+                            try!(self.channels.send(msg, &mut self.router));
+                            self.close_idle(&router_id);
+                            return Ok(());
+                        }
+                    }
+
+                    // An existing destination the client won't replace
+                    // is dealt with before anything is sent
+                    if Path::new(&path).exists() {
+                        match File::options_if_exists(&options) {
+                            Ok(IfExists::Overwrite) => (),
+                            Ok(policy) => {
+                                let msg = ZMsg::new();
+                                try!(msg.addbytes(&router_id));
+                                if policy == IfExists::Skip {
+                                    try!(msg.addstr("Ok"));
+                                    try!(msg.addstr("SKIPPED"));
+                                } else {
+                                    try!(msg.addstr("EXISTS"));
+                                }
+                                try!(self.channels.send(msg, &mut self.router));
+                                self.close_idle(&router_id);
+                                return Ok(());
+                            },
+                            Err(e) => return self.reply_err(&router_id, e),
+                        }
+                    }
+
+                    // Legacy clients don't understand BUSY, so they
+                    // get a plain error instead.
+                    if self.is_overloaded(&router_id) {
+                        let retry_after = self.options.retry_after.unwrap_or(RETRY_AFTER);
+                        if protocol.is_none() {
+                            return self.reply_err(&router_id, Error::Busy(retry_after));
+                        }
+
+                        let msg = ZMsg::new();
+                        try!(msg.addbytes(&router_id));
+                        try!(msg.addstr("BUSY"));
+                        try!(msg.addstr(&retry_after.to_string()));
+                        try!(self.channels.send(msg, &mut self.router));
+                        return Ok(());
+                    }
+
+                    let upload = Upload {
+                        path: path,
+                        size: size,
+                        crc: crc,
+                        chunk_size: chunk_size,
+                        options: options,
+                        protocol: protocol,
+                    };
+
+                    // Pushing the same file again is a no-op. A file
+                    // bound for an output writer is always sent. The
+                    // file already there is hashed off this thread.
+                    if protocol::unchanged_files(protocol) && self.output.is_none() {
+                        let hash = match File::could_match_existing(&upload.path, upload.size, &upload.options) {
+                            Ok(true) => File::options_hash(&upload.options).map(Some),
+                            Ok(false) => Ok(None),
+                            Err(e) => Err(e),
+                        };
+                        match hash {
+                            Ok(Some(hash)) => {
+                                let path = upload.path.clone();
+                                if let Err(e) = self.look_up(&router_id, Path::new(&path), hash.map(|(algorithm, _)| algorithm), Lookup::Unchanged(upload)) {
+                                    return self.reply_err(&router_id, e);
+                                }
+                                return Ok(());
+                            },
+                            Ok(None) => (),
+                            Err(e) => return self.reply_err(&router_id, e),
+                        }
+                    }
+
+                    // The client says which chunks the file already at
+                    // the destination has, before the transfer starts
+                    try!(self.begin_upload(&router_id, upload));
+                },
+                Request::Unchanged(indexes) => {
+                    let upload = match self.deltas.remove(&router_id) {
+                        Some(u) => u,
+                        None => return self.reply_err(&router_id, Error::InvalidRequest),
+                    };
+
+                    try!(self.start_upload(&router_id, upload, &indexes));
+                },
+                Request::Chunk(index, chunk) => {
+                    if !self.files.contains_key(&router_id) {
+                        return self.reply_err(&router_id, Error::InvalidRequest);
+                    }
+
+                    // Duplicates are harmless, so are dropped quietly
+                    if self.files.get(&router_id).unwrap().is_stale(index) {
+                        return Ok(());
+                    }
+
+                    // So are chunks sent ahead that can't have a slot
+                    match self.files.get(&router_id).unwrap().admit(&mut self.arbitrator, &router_id, index) {
+                        Ok(true) => (),
+                        Ok(false) => return Ok(()),
+                        Err(e) => return self.reply_err(&router_id, e),
+                    }
+
+                    if let Err(e) = self.recv_chunk(&router_id, index, chunk) {
+                        return self.reply_err(&router_id, e);
+                    }
+                },
+                Request::Chunks(chunks) => {
+                    if !self.files.contains_key(&router_id) {
+                        return self.reply_err(&router_id, Error::InvalidRequest);
+                    }
+
+                    let mut admitted = Vec::with_capacity(chunks.len());
+                    {
+                        let file = self.files.get(&router_id).unwrap();
+                        for (index, data) in chunks {
+                            if file.is_stale(index) {
+                                continue;
+                            }
+                            match file.admit(&mut self.arbitrator, &router_id, index) {
+                                Ok(true) => admitted.push((index, data)),
+                                Ok(false) => (),
+                                Err(e) => return self.reply_err(&router_id, e),
+                            }
+                        }
+                    }
+                    let chunks = admitted;
+
+                    if let Err(e) = self.recv_chunks(&router_id, chunks) {
+                        return self.reply_err(&router_id, e);
+                    }
+                },
+                Request::Get { path, options } => {
+                    let path = match self.remote_action_path(&router_id, &path) {
+                        Ok(p) => p,
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    // Directories and special files can't be served
+                    match remote::stat_path(&path) {
+                        Ok(ref stat) if stat.is_dir => return self.reply_err(&router_id, Error::InvalidFilePath),
+                        Ok(_) => (),
+                        Err(e) => return self.reply_err(&router_id, e),
+                    }
+
+                    let chunk_size = match File::options_chunk_size(&options) {
+                        Ok(size) => size,
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    if let Err(e) = self.check_chunk_size(chunk_size) {
+                        return self.reply_err(&router_id, e);
+                    }
+
+                    // Downloads postdate versioning, so every client
+                    // that can ask for one advertises a version.
+                    let protocol = match File::options_protocol(&options) {
+                        Ok(Some(v)) => cmp::min(v, PROTOCOL_VERSION),
+                        Ok(None) => return self.reply_err(&router_id, Error::LegacyPeer),
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    if self.is_overloaded(&router_id) {
+                        let msg = ZMsg::new();
+                        try!(msg.addbytes(&router_id));
+                        try!(msg.addstr("BUSY"));
+                        try!(msg.addstr(&self.options.retry_after.unwrap_or(RETRY_AFTER).to_string()));
+                        try!(self.channels.send(msg, &mut self.router));
+                        return Ok(());
+                    }
+
+                    // A client transfers one file at a time, so an
+                    // unfinished upload or download is abandoned.
+                    if let Some(id) = self.files.active(&router_id) {
+                        if !self.files.get_by_id(id).unwrap().is_complete() {
+                            if let Err(e) = self.abandon(id, None) {
+                                return Err(e.into());
+                            }
+                        }
+                    }
+                    if self.downloads.remove(&router_id).is_some() {
+                        if let Err(e) = self.arbitrator.cancel(&router_id) {
+                            return Err(e.into());
+                        }
+                    }
+
+                    // The file is checksummed off this thread, then
+                    // served once that's done
+                    let lookup = Lookup::Get { path: path.clone(), chunk_size: chunk_size, options: options, protocol: protocol };
+                    if let Err(e) = self.look_up(&router_id, Path::new(&path), None, lookup) {
+                        return self.reply_err(&router_id, e);
+                    }
+                },
+                Request::Dir(path) => {
+                    let path = match self.sanitize_path(&path).and_then(|p| self.tenant_path(&router_id, &p)) {
+                        Ok(p) => p,
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    if let Err(e) = self.check_path(&path) {
+                        return self.reply_err(&router_id, e);
+                    }
+
+                    self.abort_dir(&router_id);
+
+                    let dest = PathBuf::from(path);
+                    let staging = dir::staging_dir(&dest);
+                    if let Err(e) = fs::create_dir_all(&staging) {
+                        return self.reply_err(&router_id, e.into());
+                    }
+                    self.dirs.insert(router_id.clone(), (dest, staging));
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::DirAbort => {
+                    self.abort_dir(&router_id);
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::DirCommit => {
+                    // Files still arriving or being verified would be
+                    // missing from the tree
+                    if !self.dirs.contains_key(&router_id) || self.files.contains_key(&router_id) {
+                        return self.reply_err(&router_id, Error::InvalidRequest);
+                    }
+
+                    let (dest, staging) = self.dirs.remove(&router_id).unwrap();
+                    if let Err(e) = dir::commit(&staging, &dest) {
+                        let _ = dir::remove_tree(&staging);
+                        return self.reply_err(&router_id, e);
+                    }
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::Batch(manifest) => {
+                    let declared: Vec<BatchFile> = match JsonCodec.decode(&manifest) {
+                        Ok(d) => d,
+                        Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
+                    };
+                    if declared.len() > batch::MAX_FILES {
+                        return self.reply_err(&router_id, Error::InvalidRequest);
+                    }
+
+                    self.abort_batch(&router_id);
+
+                    let mut files: Vec<(BatchFile, PathBuf)> = Vec::with_capacity(declared.len());
+                    for mut file in declared {
+                        file.path = match self.remote_path(&router_id, &file.path) {
+                            Ok(p) => p,
+                            Err(e) => return self.reply_err(&router_id, e),
+                        };
+                        if files.iter().any(|&(ref f, _)| f.path == file.path) {
+                            return self.reply_err(&router_id, Error::InvalidRequest);
+                        }
+
+                        let staging = batch::staging_path(Path::new(&file.path));
+                        files.push((file, staging));
+                    }
+                    self.batches.insert(router_id.clone(), files);
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::BatchAbort => {
+                    self.abort_batch(&router_id);
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::BatchCommit => {
+                    if !self.batches.contains_key(&router_id) || self.files.contains_key(&router_id) {
+                        return self.reply_err(&router_id, Error::InvalidRequest);
+                    }
+
+                    // A file is only staged once it has verified, so
+                    // one that's missing failed or was never sent
+                    let staged: Vec<(PathBuf, PathBuf)> = self.batches[&router_id].iter()
+                                                                      .map(|&(ref f, ref staging)| (staging.clone(), PathBuf::from(&f.path)))
+                                                                      .collect();
+                    let durable = self.options.durable || self.durable_batches.contains(&router_id);
+                    let result = if staged.iter().all(|&(ref staging, _)| staging.exists()) {
+                        batch::commit(&staged, durable)
+                    } else {
+                        Err(Error::FileFail)
+                    };
+                    if let Err(e) = result {
+                        self.abort_batch(&router_id);
+                        return self.reply_err(&router_id, e);
+                    }
+                    self.batches.remove(&router_id);
+                    self.durable_batches.remove(&router_id);
+                    info!("batch committed router_id={} files={}", hex(&router_id), staged.len());
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::Mkdir(path) => {
+                    let path = match self.sanitize_path(&path).and_then(|p| self.tenant_path(&router_id, &p)) {
+                        Ok(p) => p,
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    let path = match self.dir_path(&router_id, &path) {
+                        Ok(Some(p)) => p,
+                        Ok(None) => return self.reply_err(&router_id, Error::InvalidRequest),
+                        Err(e) => return self.reply_err(&router_id, e),
+                    };
+
+                    if let Err(e) = fs::create_dir_all(&path) {
+                        return self.reply_err(&router_id, e.into());
+                    }
+
+                    let msg = try!(ZMsg::new_ok());
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                },
+                Request::Received(indexes) => {
+                    for index in indexes {
+                        if let Err(e) = self.sink_download(&router_id, index, true) {
+                            return self.reply_err(&router_id, e);
+                        }
+
+                        // The last chunk ends the download
+                        if !self.downloads.contains_key(&router_id) {
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+        else if *sock == self.sink {
+            // The Timer waking the Arbitrator after throttling
+            if router_id.is_empty() {
+                if let Err(e) = self.arbitrator.resume() {
+                    return Err(e.into());
+                }
+                return Ok(());
+            }
+
+            let (index, success) = match parse_sink(&try!(recv_frames(sock))) {
+                Ok(r) => r,
+                Err(e) => return Err(e.into()),
+            };
+
+            // A download's chunk timed out
+            if self.downloads.contains_key(&router_id) {
+                if let Err(e) = self.sink_download(&router_id, index, success) {
+                    return Err(e.into());
+                }
+                return Ok(());
+            }
+
+            if !self.files.contains_key(&router_id) {
+                return Err(Error::InvalidRequest.into());
+            }
+
+            let id = self.files.active(&router_id).unwrap();
+            let mut submitted = Ok(());
+            let failed = {
+                let file = self.files.get_mut(&router_id).unwrap();
+
+                // A chunk that arrived twice is only reported once
+                let stale = file.is_stale(index);
+                if let Err(e) = file.sink(&mut self.arbitrator, &router_id, index, success) {
+                    return Err(e.into());
+                }
+
+                if success && !stale {
+                    self.events.emit(Event::ChunkReceived { id: id, identity: self.channels.identity(&router_id), index: index });
+
+                    // So the client reports progress as chunks are
+                    // written, rather than sent
+                    if protocol::acked_chunks(file.get_protocol()) {
+                        if let Err(e) = send_received(&self.channels, &mut self.router, &router_id, index) {
+                            return Err(e.into());
+                        }
+                    }
+                } else if !success {
+                    let event = if file.is_error() {
+                        Event::TransferFailed {
+                            id: id,
+                            identity: self.channels.identity(&router_id),
+                            path: file.get_path().unwrap(),
+                            reason: "Too many chunk errors".into(),
+                        }
+                    } else {
+                        Event::ChunkRetry { id: id, identity: self.channels.identity(&router_id), index: index }
+                    };
+                    self.events.emit(event);
+                }
+
+                if let Some((first, chunk_size)) = file.take_resize() {
+                    if let Err(e) = send_resize(&self.channels, &mut self.router, &router_id, first, chunk_size) {
+                        return Err(e.into());
+                    }
+                }
+
+                if file.is_complete() {
+                    if let Some(ref mut workers) = self.workers {
+                        if let Err(e) = workers.close(&router_id, file.get_upload_path().unwrap()) {
+                            return Err(e.into());
+                        }
+                    }
+                }
+
+                if file.is_error() {
+                    let c = completion(id, self.channels.identity(&router_id), file, Err(Error::FileFail));
+                    self.totals.record(&c);
+                    self.events.complete(c);
+                    let msg = try!(protocol::new_err(&Error::FileFail));
+                    try!(msg.pushbytes(&router_id));
+                    try!(self.channels.send(msg, &mut self.router));
+                }
+                else if file.is_complete() {
+                    submitted = submit_hash(&self.hasher, id, &router_id, file);
+                }
+                file.is_error()
+            };
+
+            // The client has been told, so nothing is left to retry
+            if failed {
+                if let Err(e) = self.abandon(id, None) {
+                    return Err(e.into());
+                }
+            }
+            if let Err(e) = submitted {
+                return self.fail_upload(id, e);
+            }
+        }
+        else if *sock == self.hashed {
+            let msg = try!(ZMsg::expect_recv(sock, 5, Some(5), false));
+
+            let id = protocol::pop_u64(&msg, true).unwrap();
+            let success = try!(msg.popbytes()).unwrap() == [1];
+            let crc = protocol::pop_u64(&msg, true).unwrap();
+            let hashing = protocol::pop_u64(&msg, true).unwrap();
+            let digest = try!(msg.popbytes()).unwrap_or(Vec::new());
+            let hashing = Duration::new(hashing / 1_000_000, (hashing % 1_000_000) as u32 * 1000);
+
+            // Results for a lookup the client has since given up on
+            // are dropped, as are those for abandoned transfers
+            if self.lookups.get(&router_id).map_or(false, |&(lookup_id, _)| lookup_id == id) {
+                let (_, lookup) = self.lookups.remove(&router_id).unwrap();
+                return self.looked_up(&router_id, lookup, if success { Some((crc, digest)) } else { None }, hashing);
+            }
+
+            let mut failed = false;
+            let reply = match self.files.get_by_id_mut(id) {
+                Some(ref mut file) => {
+                    file.add_hashing(hashing);
+
+                    // Sniff the temporary file, as saving moves it
+                    let content_type = if success && self.options.sniff_content {
+                        sniff(file.get_upload_path().unwrap())
+                    } else {
+                        None
+                    };
+
+                    let result = if success {
+                        let saved = file.save_checked(crc, if digest.is_empty() { None } else { Some(&digest) });
+
+                        // The file is already in place, so a missing
+                        // sidecar doesn't fail the transfer
+                        if saved.is_ok() && self.options.sidecar {
+                            if let Err(e) = file.write_sidecar(self.channels.identity(&router_id), crc) {
+                                warn!("sidecar not written id={} path={} error={:?}", id, file.get_path().unwrap().display(), e.to_string());
+                            }
+                        }
+                        saved
+                    } else {
+                        file.discard_copy();
+                        Err(Error::FileFail)
+                    };
+
+                    match result {
+                        Ok(_) => {
+                            self.events.emit(Event::TransferCompleted {
+                                id: id,
+                                identity: self.channels.identity(&router_id),
+                                path: file.get_path().unwrap(),
+                                size: file.get_size(),
+                                crc: crc,
+                                content_type: content_type,
+                            });
+                            let mut c = completion(id, self.channels.identity(&router_id), file, Ok(()));
+                            c.content_type = content_type;
+                            self.totals.record(&c);
+                            self.events.complete(c);
+                            *self.usage.entry(self.channels.client(&router_id).to_vec()).or_insert(0) += file.get_size();
+                            if self.options.hourly_quota.is_some() {
+                                let now = self.options.clock.now();
+                                let hour = self.hourly.entry(self.channels.client(&router_id).to_vec()).or_insert((now, 0));
+                                if now.duration_since(hour.0).as_secs() >= HOUR_SECS {
+                                    *hour = (now, 0);
+                                }
+                                hour.1 += file.get_size();
+                            }
+                            try!(ZMsg::new_ok())
+                        },
+                        Err(e) => {
+                            error!("save failed id={} router_id={} path={} error={:?}", id, hex(&router_id), file.get_path().unwrap().display(), e.to_string());
+                            self.events.emit(Event::TransferFailed {
+                                id: id,
+                                identity: self.channels.identity(&router_id),
+                                path: file.get_path().unwrap(),
+                                reason: e.to_string(),
+                            });
+                            let reply = try!(protocol::new_err(&e));
+                            let c = completion(id, self.channels.identity(&router_id), file, Err(e));
+                            self.totals.record(&c);
+                            self.events.complete(c);
+                            failed = true;
+                            reply
+                        },
+                    }
+                },
+                // The client has since abandoned this transfer
+                None => return Ok(()),
+            };
+
+            // All chunks have been released, so nothing else refers to
+            // this transfer. It's removed before replying so that it
+            // goes even if the reply can't be sent, and a failed
+            // upload's temporary file goes with it.
+            if let Some(file) = self.files.remove(id) {
+                if failed {
+                    let _ = fs::remove_file(file.get_upload_path().unwrap());
+                }
+            }
+            self.stripes.remove(&router_id);
+            if !self.files.contains_key(&router_id) && !self.downloads.contains_key(&router_id) {
+                self.arbitrator.set_protocol(&router_id, None);
+                self.arbitrator.set_identity(&router_id, None);
+            }
+
+            try!(reply.pushbytes(&router_id));
+            try!(self.channels.send(reply, &mut self.router));
+            self.close_idle(&router_id);
+        }
+        else if *sock == self.arbitrator_sock {
+            // Which chunks the message requests, in case the client
+            // can't take it
+            let requested = self.arbitrator.take_request();
+            let msg = try!(ZMsg::recv(sock));
+
+            // A download's chunks are sent rather than requested
+            let msg = match self.downloads.get(&router_id) {
+                Some(file) => match file.serve_chunks(&msg) {
+                    Ok(reply) => reply,
+                    Err(e) => return Err(e.into()),
+                },
+                None => msg,
+            };
+
+            // Forward messages from Arbitrator to Router sock. An
+            // upload's chunk requests take turns between the
+            // connections that joined it.
+            try!(msg.pushbytes(self.stripes.next(&router_id)));
+            if self.forward(msg) {
+                return Ok(());
+            }
+
+            // Rather than dropped, so the chunks don't time out
+            if let Some((id, first, last)) = requested {
+                if let Err(e) = self.arbitrator.defer(&id, first, last) {
+                    return Err(e.into());
+                }
+            }
+        } else {
+            unreachable!();
+        }
+
+        Ok(())
+    }
+}
+
+// Receive the rest of a message as raw frames
+fn recv_frames(sock: &mut ZSock) -> StdResult<Vec<Vec<u8>>, DError> {
+    let msg = try!(ZMsg::recv(sock));
+    let mut frames = Vec::new();
+    while let Some(frame) = try!(msg.popbytes()) {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+fn completion(id: TransferId, router_id: &[u8], file: &File, result: Result<()>) -> Completion {
+    Completion {
+        id: id,
+        identity: router_id.to_vec(),
+        path: file.get_path().unwrap().to_owned(),
+        size: file.get_size(),
+        duration: file.get_age(),
+        result: result,
+        retransmits: file.get_retries(),
+        content_type: None,
+        timings: file.get_timings(),
+    }
+}
+
+// Canonicalize as much of a path as exists, leaving the rest as it
+// is. The parts that don't exist yet can't be symlinks.
+fn resolve(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut missing = Vec::new();
+
+    loop {
+        if let Ok(canonical) = fs::canonicalize(existing) {
+            return missing.iter().rev().fold(canonical, |p, name| p.join(name));
+        }
+
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            },
+            _ => return path.to_owned(),
+        }
+    }
+}
+
+// Checksumming a large file takes a while, so it is done off-thread
+// and the file saved once it's ready. An upload staged encrypted is
+// decrypted there too, one staged on another filesystem copied beside
+// its destination, and one bound for an output or FIFO streamed into
+// it, rather than when it is saved.
+fn submit_hash(hasher: &Hasher, id: TransferId, router_id: &[u8], file: &mut File) -> Result<()> {
+    let copy_path = try!(file.stage_copy());
+    let cipher = file.take_cipher();
+    let stream = file.take_stream();
+    hasher.submit_upload(id, router_id, file.get_upload_path().unwrap(), file.hash_algorithm(), cipher, copy_path.as_ref().map(|p| p.as_path()), stream)
+}
+
+// Tell a client that chunks from `first` onwards have a new size
+fn send_resize(channels: &Channels, router: &mut ZSock, router_id: &[u8], first: u64, chunk_size: u64) -> Result<()> {
+    let msg = ZMsg::new();
+    try!(msg.addbytes(router_id));
+    try!(msg.addstr("RESIZE"));
+    try!(protocol::add_u64(&msg, first, true));
+    try!(protocol::add_u64(&msg, chunk_size, true));
+    try!(channels.send(msg, router));
+    Ok(())
+}
+
+fn send_received(channels: &Channels, router: &mut ZSock, router_id: &[u8], index: u64) -> Result<()> {
+    let msg = ZMsg::new();
+    try!(msg.addbytes(router_id));
+    try!(msg.addstr("RECEIVED"));
+    try!(protocol::add_u64(&msg, index, true));
+    try!(channels.send(msg, router));
+    Ok(())
+}
+
+pub enum Options {
+    /// Let the chunk size of an upload grow while its chunks arrive
+    /// and shrink when they fail, within `MinChunkSize` and
+    /// `MaxChunkSize` (1Mb unless set). Only clients that support it
+    /// are asked to resize.
+    AdaptiveChunkSize,
+    /// Allow the client with this CURVE public key, Z85 encoded as
+    /// ZAP gives it in the User-Id, to use admin actions such as
+    /// LIST-TRANSFERS and the admin endpoint. Clients not using CURVE
+    /// are never admins.
+    Admin(Vec<u8>),
+    /// Only accept uploads to paths under this directory, after
+    /// resolving any symlinks. Can be given more than once.
+    AllowedPath(String),
+    /// Serve admin commands (PAUSE, RESUME, CANCEL <id>,
+    /// SET-SLOTS <n>, RELOAD-AUTH and ROTATE-CERT) on a REP
+    /// socket at this endpoint. SWEEP deletes unused partial uploads
+    /// as `Server::sweep()` does, and DRAIN and ABORT shut the server
+    /// down as `Server::shutdown()` does with `Shutdown::Drain` and
+    /// `Shutdown::Abort`. It's bound by `set_auth()`, as only CURVE
+    /// clients given with `Admin` may use it.
+    AdminEndpoint(String),
+    /// Limit the combined upload rate of all clients, which may vary
+    /// with the time of day
+    Bandwidth(BandwidthSchedule),
+    /// How long, in milliseconds, a requested chunk may take to
+    /// arrive before it is requested again. Defaults to 60 seconds.
+    ChunkTimeout(u32),
+    /// Time source for chunk timeouts. Defaults to the system clock;
+    /// tests can pass a `MockClock` to expire chunks without waiting.
+    Clock(Arc<Clock>),
+    Compat(Compat),
+    /// Sync every upload and its directory to disk before replying
+    /// that it was saved, whether or not the client asked
+    Durable,
+    /// Encrypt partial uploads on disk with ChaCha20-Poly1305, under
+    /// a per-transfer key that is only held in memory. Files are
+    /// decrypted, and refused if they were altered, once complete.
+    EncryptStaging,
+    /// How often, in milliseconds, clients should PING while they
+    /// wait on an upload. An upload whose client misses three is
+    /// abandoned, releasing its upload slots, as is a batch upload
+    /// that hears nothing from its client for as long between files.
+    /// Checked whenever the server handles a message.
+    Heartbeat(u32),
+    /// Bytes each client may upload in an hour, which starts with its
+    /// first upload after the last hour ended. Counted as uploads
+    /// complete, and clients told apart, like `Quota`.
+    HourlyQuota(u64),
+    /// Save each partial upload's manifest after this many chunks
+    /// are written, rather than every 64. With 1, a restarted server
+    /// requests no chunk twice. An upload resumes after a restart
+    /// when its client sends the same file again, or simply carries
+    /// on sending chunks if it connects with a fixed socket identity.
+    ManifestInterval(u32),
+    /// Maximum bytes of chunk data that each upload may have
+    /// requested but not yet written to disk
+    MaxBuffered(u64),
+    /// Maximum bytes of chunk data that all of a client's uploads
+    /// together may have requested but not yet written. Clients are
+    /// told apart like `Quota`.
+    MaxBufferedPerClient(u64),
+    /// Largest chunk size, in bytes, that a client may upload or
+    /// download with. A request asking for larger chunks is refused
+    /// with `Error::ChunkSize`, rather than resized. It also caps
+    /// `AdaptiveChunkSize`.
+    MaxChunkSize(u64),
+    /// Largest upload, in bytes, to accept. Larger ones are refused
+    /// with `Error::FileSize` before anything is sent. Clients can
+    /// see the limit with DESCRIBE and QUOTA.
+    MaxFileSize(u64),
+    /// Reject new uploads while this many chunks are queued
+    MaxQueued(u32),
+    /// Reject new uploads while this many transfers are in progress
+    MaxTransfers(u32),
+    /// Reject new uploads from a client while it has this many in
+    /// progress, on any of its channels or connections. Clients are
+    /// told apart like `Quota`.
+    MaxTransfersPerClient(u32),
+    /// Most chunks a client may send ahead of the server's requests.
+    /// Zero makes every client wait to be asked. Chunks sent ahead
+    /// take upload slots and count against budgets and the bandwidth
+    /// limit like requested ones, and any that would exceed them are
+    /// dropped and requested later.
+    MaxWindow(u32),
+    /// Smallest chunk size, in bytes, that a client may upload or
+    /// download with. A request asking for smaller chunks is refused
+    /// with `Error::ChunkSize`, rather than resized. It also bounds
+    /// `AdaptiveChunkSize`.
+    MinChunkSize(u64),
+    /// Oldest protocol version to accept uploads from. Clients that
+    /// predate versioning count as older than any version.
+    MinProtocol(u32),
+    /// How to treat destination paths with unsafe file names.
+    /// Defaults to `NamePolicy::Reject`.
+    NamePolicy(NamePolicy),
+    /// Bytes each client may upload over the life of the server.
+    /// Clients are told apart by their CURVE public key, or by their
+    /// router identity if they aren't using CURVE.
+    Quota(u64),
+    /// Look in this directory, and those below it, for partial
+    /// uploads when the server starts. A client sending the same file
+    /// again resumes where the upload left off. Partial uploads that
+    /// can't be resumed, e.g. from an incompatible version, are
+    /// deleted, as are files staged for batch uploads that were never
+    /// committed. Can be given more than once.
+    ///
+    /// These directories are also swept (see `SweepInterval`), so no
+    /// other server may stage uploads in them.
+    Recover(String),
+    /// Let clients GET, LIST, STAT and DELETE files already on the
+    /// server. Off by default, and refused unless uploads are confined
+    /// with `AllowedPath` or `Tenant`, which these actions are
+    /// confined to as well.
+    RemoteFiles,
+    /// Bytes of disk to keep free for other uses. Uploads that would
+    /// leave less are rejected, while without it they only need to
+    /// fit.
+    ReserveSpace(u64),
+    /// Seconds that rejected clients are asked to wait before retrying
+    RetryAfter(u32),
+    /// How many times a chunk that fails to upload is requested
+    /// again before the transfer fails
+    RetryPolicy(RetryPolicy),
+    /// Order in which queued chunks are requested. Defaults to FIFO.
+    Schedule(Schedule),
+    /// Write a `<name>.meta` JSON file next to each saved file with
+    /// the sender's identity, the time, CRC, size and any metadata
+    /// supplied by the client
+    Sidecar,
+    /// Guess the MIME type of each completed upload from its first
+    /// bytes and include it in completion events
+    SniffContent,
+    /// Write partial uploads to this directory rather than beside
+    /// their destinations, e.g. on a scratch disk. If it is on
+    /// another filesystem, complete files are copied into place.
+    /// Give it as a `Recover` directory too for uploads to resume
+    /// after a restart.
+    StagingDir(String),
+    /// How partial uploads are named. Defaults to
+    /// `StagingNames::Hidden`.
+    StagingNames(StagingNames),
+    /// Write uploads to an existing FIFO into it, rather than
+    /// refusing them. The FIFO must already have a reader, and the
+    /// server waits while it drains each upload.
+    StreamFifos,
+    /// Every this many seconds, delete partial uploads under the
+    /// `Recover` directories that no transfer is using. Those found at
+    /// startup are kept until the first sweep, so their clients have
+    /// this long to resume them. The server checks when it next gets
+    /// a message.
+    SweepInterval(u32),
+    /// Store uploads from the client with this CURVE public key, Z85
+    /// encoded as ZAP gives it in the User-Id, under a root
+    /// directory. Its paths are taken as relative to the root, so
+    /// `/app/config` from tenant A might land in
+    /// `/srv/tenants/A/app/config`. Once any tenant is set, clients
+    /// without one, including any not using CURVE, are rejected.
+    Tenant(Vec<u8>, String),
+    /// How often, in milliseconds, to check for timed out chunks
+    TimerInterval(u32),
+    /// Only accept uploads signed by this ed25519 public key, or any
+    /// other trusted key
+    #[cfg(feature = "signing")]
+    TrustedKey(Vec<u8>),
+    /// Most chunks that any one client may have in flight, out of the
+    /// `upload_slots` given to `Server::new`. Combine with
+    /// `Schedule::Fair` to share slots evenly between clients.
+    UploadSlotsPerClient(u32),
+    /// Write chunks to disk from this many worker threads
+    Workers(u32),
+}
+
+/// How `Server::shutdown()` treats transfers in progress
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shutdown {
+    /// Cancel them, telling their clients why
+    Abort,
+    /// Let them finish
+    Drain,
+}
+
+struct ServerOptions {
+    adaptive_chunk_size: bool,
+    admin_endpoint: Option<String>,
+    admins: Vec<Vec<u8>>,
+    allowed_paths: Vec<String>,
+    bandwidth: Option<BandwidthSchedule>,
+    chunk_timeout: Option<u32>,
+    clock: Arc<Clock>,
+    compat: Compat,
+    durable: bool,
+    encrypt_staging: bool,
+    heartbeat: Option<u32>,
+    hourly_quota: Option<u64>,
+    manifest_interval: Option<u32>,
+    max_buffered: Option<u64>,
+    max_buffered_per_client: Option<u64>,
+    max_chunk_size: Option<u64>,
+    max_file_size: Option<u64>,
+    max_queued: Option<u32>,
+    max_transfers: Option<u32>,
+    max_transfers_per_client: Option<u32>,
+    max_window: u32,
+    min_chunk_size: Option<u64>,
+    min_protocol: Option<u32>,
+    name_policy: NamePolicy,
+    quota: Option<u64>,
+    recover: Vec<String>,
+    remote_files: bool,
+    reserve_space: u64,
+    retry: RetryPolicy,
+    retry_after: Option<u32>,
+    schedule: Schedule,
+    sidecar: bool,
+    sniff_content: bool,
+    staging_dir: Option<PathBuf>,
+    staging_names: StagingNames,
+    stream_fifos: bool,
+    sweep_interval: Option<u32>,
+    tenants: HashMap<Vec<u8>, String>,
+    timer_interval: Option<u32>,
+    #[cfg_attr(not(feature = "signing"), allow(dead_code))]
+    trusted_keys: Vec<Vec<u8>>,
+    upload_slots_per_client: Option<u32>,
+    workers: Option<u32>,
+}
+
+impl ServerOptions {
+    fn new(options: Option<&[Options]>) -> ServerOptions {
+        let mut opts = ServerOptions {
+            adaptive_chunk_size: false,
+            admin_endpoint: None,
+            admins: Vec::new(),
+            allowed_paths: Vec::new(),
+            bandwidth: None,
+            chunk_timeout: None,
+            clock: Arc::new(SystemClock),
+            compat: Compat::Auto,
+            durable: false,
+            encrypt_staging: false,
+            heartbeat: None,
+            hourly_quota: None,
+            manifest_interval: None,
+            max_buffered: None,
+            max_buffered_per_client: None,
+            max_chunk_size: None,
+            max_file_size: None,
+            max_queued: None,
+            max_transfers: None,
+            max_transfers_per_client: None,
+            max_window: MAX_WINDOW,
+            min_chunk_size: None,
+            min_protocol: None,
+            name_policy: NamePolicy::Reject,
+            quota: None,
+            recover: Vec::new(),
+            remote_files: false,
+            reserve_space: 0,
+            retry: RetryPolicy::default(),
+            retry_after: None,
+            schedule: Schedule::Fifo,
+            sidecar: false,
+            sniff_content: false,
+            staging_dir: None,
+            staging_names: StagingNames::Hidden,
+            stream_fifos: false,
+            sweep_interval: None,
+            tenants: HashMap::new(),
+            timer_interval: None,
+            trusted_keys: Vec::new(),
+            upload_slots_per_client: None,
+            workers: None,
+        };
+
+        if let Some(options) = options {
+            for opt in options {
+                match opt {
+                    &Options::AdaptiveChunkSize => opts.adaptive_chunk_size = true,
+                    &Options::Admin(ref identity) => opts.admins.push(identity.clone()),
+                    &Options::AdminEndpoint(ref endpoint) => opts.admin_endpoint = Some(endpoint.clone()),
+                    &Options::AllowedPath(ref path) => opts.allowed_paths.push(path.clone()),
+                    &Options::Bandwidth(ref schedule) => opts.bandwidth = Some(schedule.clone()),
+                    &Options::ChunkTimeout(millis) => opts.chunk_timeout = Some(millis),
+                    &Options::Clock(ref clock) => opts.clock = clock.clone(),
+                    &Options::Compat(compat) => opts.compat = compat,
+                    &Options::Durable => opts.durable = true,
+                    &Options::EncryptStaging => opts.encrypt_staging = true,
+                    &Options::Heartbeat(millis) => opts.heartbeat = Some(millis),
+                    &Options::HourlyQuota(bytes) => opts.hourly_quota = Some(bytes),
+                    &Options::ManifestInterval(chunks) => opts.manifest_interval = Some(chunks),
+                    &Options::MaxBuffered(bytes) => opts.max_buffered = Some(bytes),
+                    &Options::MaxBufferedPerClient(bytes) => opts.max_buffered_per_client = Some(bytes),
+                    &Options::MaxChunkSize(size) => opts.max_chunk_size = Some(size),
+                    &Options::MaxFileSize(size) => opts.max_file_size = Some(size),
+                    &Options::MaxQueued(n) => opts.max_queued = Some(n),
+                    &Options::MaxTransfers(n) => opts.max_transfers = Some(n),
+                    &Options::MaxTransfersPerClient(n) => opts.max_transfers_per_client = Some(n),
+                    &Options::MaxWindow(n) => opts.max_window = n,
+                    &Options::MinChunkSize(size) => opts.min_chunk_size = Some(size),
+                    &Options::MinProtocol(version) => opts.min_protocol = Some(version),
+                    &Options::NamePolicy(policy) => opts.name_policy = policy,
+                    &Options::Quota(bytes) => opts.quota = Some(bytes),
+                    &Options::Recover(ref dir) => opts.recover.push(dir.clone()),
+                    &Options::RemoteFiles => opts.remote_files = true,
+                    &Options::ReserveSpace(bytes) => opts.reserve_space = bytes,
+                    &Options::RetryAfter(secs) => opts.retry_after = Some(secs),
+                    &Options::RetryPolicy(ref policy) => opts.retry = policy.clone(),
+                    &Options::Schedule(schedule) => opts.schedule = schedule,
+                    &Options::Sidecar => opts.sidecar = true,
+                    &Options::SniffContent => opts.sniff_content = true,
+                    &Options::StagingDir(ref dir) => opts.staging_dir = Some(PathBuf::from(dir)),
+                    &Options::StagingNames(names) => opts.staging_names = names,
+                    &Options::StreamFifos => opts.stream_fifos = true,
+                    &Options::SweepInterval(secs) => opts.sweep_interval = Some(secs),
+                    &Options::Tenant(ref identity, ref root) => { opts.tenants.insert(identity.clone(), root.clone()); },
+                    &Options::TimerInterval(millis) => opts.timer_interval = Some(millis),
+                    #[cfg(feature = "signing")]
+                    &Options::TrustedKey(ref key) => opts.trusted_keys.push(key.clone()),
+                    &Options::UploadSlotsPerClient(n) => opts.upload_slots_per_client = Some(n),
+                    &Options::Workers(n) => opts.workers = Some(n),
+                }
+            }
+        }
+
+        opts
+    }
+}
+
+/// Machine-readable description of a server, as returned by the
+/// DESCRIBE action
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Description {
+    pub version: u32,
+    pub actions: Vec<String>,
+    pub capabilities: Vec<String>,
+    pub codecs: Vec<String>,
+    pub max_file_size: Option<u64>,
+    pub min_chunk_size: Option<u64>,
+    pub max_chunk_size: Option<u64>,
+}
+
+/// Counters for monitoring a server, as returned by `Server::stats()`
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ServerStats {
+    /// Uploads in progress
+    pub active_transfers: u64,
+    /// Uploads saved since the server started
+    pub completed: u64,
+    /// Uploads that failed or were abandoned since the server started
+    pub failed: u64,
+    /// Bytes of the uploads that were saved
+    pub bytes_received: u64,
+    /// Bytes per second, averaged over the uploads that were saved
+    pub throughput: u64,
+    /// Chunks waiting for a slot or in flight
+    pub queued: u64,
+}
+
+/// A client's upload limits, as returned by the QUOTA action
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Quota {
+    /// Bytes the client may still upload, if it has a quota. With
+    /// an hourly quota as well, this is the smaller of the two.
+    pub remaining: Option<u64>,
+    pub max_file_size: Option<u64>,
+    /// Directories that uploads must go under. Empty if any path is
+    /// allowed.
+    pub allowed_paths: Vec<String>,
+}
+
+impl Quota {
+    /// Request this client's limits from a remote server
+    pub fn request(sock: &mut ZSock) -> Result<Quota> {
+        try!(sock.send_str("QUOTA"));
+
+        let msg = try!(ZMsg::recv(sock));
+        match try!(msg.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
+            "Ok" => {
+                let encoded = try!(try!(msg.popbytes()).ok_or(Error::InvalidReply));
+                JsonCodec.decode(&encoded)
+            },
+            "Err" => Err(protocol::pop_err(&msg)),
+            _ => Err(Error::InvalidReply),
+        }
+    }
+}
+
+/// State of an active transfer, as returned by `Server::snapshot()`
+/// and the LIST-TRANSFERS action
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TransferState {
+    pub id: TransferId,
+    pub identity: Vec<u8>,
+    pub path: String,
+    pub size: u64,
+    pub bytes_done: u64,
+    pub retries: u32,
+    /// Seconds since the transfer started
+    pub age: u64,
+    pub timings: Timings,
+}
+
+/// Remote-side progress of an upload, as returned by the PROGRESS
+/// action
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Progress {
+    pub id: TransferId,
+    pub bytes_done: u64,
+    pub chunks_outstanding: u64,
+    /// Number of chunks that had to be requested again
+    pub retries: u32,
+}
+
+impl Progress {
+    /// Request the progress of a transfer from a remote server. Only
+    /// the client sending the file, or an admin, may ask.
+    pub fn request(sock: &mut ZSock, id: TransferId) -> Result<Progress> {
+        let msg = ZMsg::new();
+        try!(msg.addstr("PROGRESS"));
+        try!(msg.addstr(&id.to_string()));
+        try!(msg.send(sock));
+
+        let msg = try!(ZMsg::recv(sock));
+        match try!(msg.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
+            "Ok" => {
+                let encoded = try!(try!(msg.popbytes()).ok_or(Error::InvalidReply));
+                JsonCodec.decode(&encoded)
+            },
+            "Err" => Err(protocol::pop_err(&msg)),
+            _ => Err(Error::InvalidReply),
+        }
+    }
+}
+
+impl TransferState {
+    /// List the active transfers on a remote server. The socket's
+    /// identity must be one of the server's admins.
+    pub fn list(sock: &mut ZSock) -> Result<Vec<TransferState>> {
+        try!(sock.send_str("LIST-TRANSFERS"));
+
+        let msg = try!(ZMsg::recv(sock));
+        match try!(msg.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
+            "Ok" => {
+                let encoded = try!(try!(msg.popbytes()).ok_or(Error::InvalidReply));
+                JsonCodec.decode(&encoded)
+            },
+            "Err" => Err(protocol::pop_err(&msg)),
+            _ => Err(Error::InvalidReply),
+        }
+    }
+}
+
+impl Description {
+    /// Request a description from a remote server
+    pub fn request(sock: &mut ZSock) -> Result<Description> {
+        try!(sock.send_str("DESCRIBE"));
+
+        let msg = try!(ZMsg::recv(sock));
+        match try!(msg.popstr().unwrap().or(Err(Error::InvalidReply))).as_ref() {
+            "Ok" => {
+                let encoded = try!(try!(msg.popbytes()).ok_or(Error::InvalidReply));
+                JsonCodec.decode(&encoded)
+            },
+            "Err" => Err(protocol::pop_err(&msg)),
+            _ => Err(Error::InvalidReply),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arbitrator::Arbitrator;
+    use clock::MockClock;
+    use codec::{Codec, JsonCodec};
+    use czmq::{RawInterface, ZFrame, ZMsg, ZSock, SocketType, ZSys};
+    use error::Error;
+    use event::{Completion, Event, EventLog};
+    use file::{File, Timings};
+    use hash;
+    use hasher::Hasher;
+    use protocol::{self, Compat, PROTOCOL_VERSION};
+    use sanitize::NamePolicy;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::rc::Rc;
+    use super::*;
+    use super::ServerOptions;
+    use tempdir::TempDir;
+    use zdaemon::Endpoint;
+
+    #[test]
+    fn test_new() {
+        ZSys::init();
+
+        let router = ZSock::new(SocketType::ROUTER);
+        assert!(Server::new(router, 0, None).is_ok());
+    }
+
+    #[test]
+    fn test_reply_err() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_reply_err").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_reply_err").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+
+        dealer.send_str("moo").unwrap();
+        let router_id = match ZFrame::recv(&mut router).unwrap().data().unwrap() {
+            Ok(s) => s.into_bytes(),
+            Err(b) => b,
+        };
+        router.flush();
+
+        let mut server = new_server(router, true);
+        assert!(server.reply_err(&router_id, Error::InvalidRequest).is_ok());
+
+        let reply = ZFrame::recv(&mut dealer).unwrap().data().unwrap().unwrap();
+        assert_eq!(&reply, "Err");
+    }
+
+    #[test]
+    fn test_admin_command() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        let completed = Rc::new(RefCell::new(Vec::new()));
+        let completed_clone = completed.clone();
+        server.set_completion_observer(move |c| completed_clone.borrow_mut().push((c.id, c.result.is_err())));
+        let tempdir = TempDir::new("server_test_admin_command").unwrap();
+        let create = |server: &mut Server, name: &str| {
+            let path = format!("{}/{}", tempdir.path().to_str().unwrap(), name);
+            let file = File::create(&mut server.arbitrator, name.as_bytes(), &path, 1, 0, 1, b"{}").unwrap();
+            server.files.insert(name.as_bytes().into(), file)
+        };
+
+        let msg = ZMsg::new();
+        msg.addstr("4").unwrap();
+        assert!(server.admin_command("SET-SLOTS", &msg).is_ok());
+        assert!(server.admin_command("SET-SLOTS", &msg).is_err());
+        for slots in &["0", "4294967296"] {
+            let msg = ZMsg::new();
+            msg.addstr(slots).unwrap();
+            assert!(server.admin_command("SET-SLOTS", &msg).is_err());
+        }
+        assert!(server.admin_command("PAUSE", &msg).is_ok());
+        assert!(server.admin_command("RESUME", &msg).is_ok());
+        assert!(server.admin_command("MOO", &msg).is_err());
+        assert!(server.admin_command("RELOAD-AUTH", &msg).is_err());
+
+        let id = create(&mut server, "abc");
+        let upload_path = server.files.get_by_id(id).unwrap().get_upload_path().unwrap().to_owned();
+        assert!(upload_path.exists());
+
+        let msg = ZMsg::new();
+        msg.addstr(&(id + 1).to_string()).unwrap();
+        assert!(server.admin_command("CANCEL", &msg).is_err());
+        let msg = ZMsg::new();
+        msg.addstr(&id.to_string()).unwrap();
+        assert!(server.admin_command("CANCEL", &msg).is_ok());
+        assert_eq!(server.files.len(), 0);
+        assert!(!upload_path.exists());
+        assert_eq!(*completed.borrow(), vec![(id, true)]);
+
+        // Failed transfers are abandoned as they fail, so there's
+        // nothing left to collect
+        assert!(server.admin_command("GC", &ZMsg::new()).is_err());
+    }
+
+    #[test]
+    fn test_recv_admin() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::Admin(b"key".to_vec())]));
+        let mut rep = ZSock::new_rep("inproc://server_test_recv_admin").unwrap();
+        rep.set_rcvtimeo(Some(500));
+        let mut req = ZSock::new_req(">inproc://server_test_recv_admin").unwrap();
+        req.set_rcvtimeo(Some(500));
+
+        // Inproc peers have no CURVE key, so can't be admins
+        req.send_str("PAUSE").unwrap();
+        server.recv_admin(&mut rep).unwrap();
+
+        let msg = ZMsg::recv(&mut req).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Identity is not authorized for this action");
+    }
+
+    #[test]
+    fn test_sweep() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        let tempdir = TempDir::new("server_test_sweep").unwrap();
+        server.options.recover.push(tempdir.path().to_str().unwrap().into());
+
+        let file = File::create(&mut server.arbitrator, b"abc", tempdir.path().join("active"), 1, 0, 1, b"{}").unwrap();
+        let active = file.get_upload_path().unwrap().to_owned();
+        server.files.insert(b"abc".to_vec(), file);
+
+        // Left behind by a transfer the server no longer knows of
+        let file = File::create(&mut server.arbitrator, b"def", tempdir.path().join("orphan"), 1, 0, 1, b"{}").unwrap();
+        let orphan = file.get_upload_path().unwrap().to_owned();
+
+        server.sweep().unwrap();
+        while server.sweeping.is_some() {
+            thread::sleep(Duration::from_millis(10));
+            server.swept();
+        }
+        assert!(active.exists());
+        assert!(manifest::manifest_path(&active).exists());
+        assert!(!orphan.exists());
+        assert!(!manifest::manifest_path(&orphan).exists());
+    }
+
+    #[test]
+    fn test_check_limits() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        assert!(server.check_limits(b"a", "/tmp/f", 10, 1).is_ok());
+        assert!(server.check_limits(b"a", "/tmp/f", 10, 0).is_err());
+
+        server.options = ServerOptions::new(Some(&[Options::MaxFileSize(5), Options::MinChunkSize(2), Options::MaxChunkSize(4)]));
+        assert!(server.check_limits(b"a", "/tmp/f", 5, 2).is_ok());
+        assert!(server.check_limits(b"a", "/tmp/f", 6, 2).is_err());
+        assert!(server.check_limits(b"a", "/tmp/f", 5, 1).is_err());
+        assert!(server.check_limits(b"a", "/tmp/f", 5, 5).is_err());
+
+        server.options = ServerOptions::new(Some(&[Options::Quota(10), Options::AllowedPath("/srv/files".into())]));
+        server.usage.insert(b"a".to_vec(), 8);
+        assert!(server.check_limits(b"a", "/srv/files/f", 2, 1).is_ok());
+        assert!(server.check_limits(b"a", "/srv/files/f", 3, 1).is_err());
+        assert!(server.check_limits(b"b", "/srv/files/f", 3, 1).is_ok());
+        assert!(server.check_limits(b"b", "/srv/filesystem", 3, 1).is_err());
+        assert!(server.check_limits(b"b", "/srv/files/../f", 3, 1).is_err());
+
+        // A CURVE client's usage follows its key, whatever its router
+        // identity
+        server.channels.set_user(b"c", Some("a".into()));
+        assert!(server.check_limits(b"c", "/srv/files/f", 3, 1).is_err());
+
+        assert_eq!(server.quota(b"a"), Quota { remaining: Some(2), max_file_size: None, allowed_paths: vec!["/srv/files".into()] });
+
+        server.options = ServerOptions::new(Some(&[Options::ReserveSpace(u64::max_value())]));
+        match server.check_limits(b"a", "/tmp/f", 1, 1) {
+            Err(Error::NoSpace) => (),
+            _ => panic!("Expected NoSpace error"),
+        }
+        server.options = ServerOptions::new(None);
+        assert!(server.check_limits(b"a", "/tmp/f", u64::max_value(), 1).is_err());
+
+        // Space that uploads in progress have yet to fill isn't free
+        let tempdir = TempDir::new("server_test_check_limits").unwrap();
+        let available = disk::available(tempdir.path()).unwrap();
+        let path = tempdir.path().join("big");
+        let file = File::create(&mut server.arbitrator, b"b", &path, available, 0, 1 << 20, b"{}").unwrap();
+        server.files.insert(b"b".to_vec(), file);
+        match server.check_limits(b"a", tempdir.path().join("f").to_str().unwrap(), 1 << 20, 1) {
+            Err(Error::NoSpace) => (),
+            _ => panic!("Expected NoSpace error"),
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        assert_eq!(server.stats(), ServerStats::default());
+
+        for &(size, millis, result) in [(1000, 500, true), (3000, 1500, true), (5000, 100, false)].iter() {
+            server.totals.record(&Completion {
+                id: 1,
+                identity: b"a".to_vec(),
+                path: "/tmp/f".into(),
+                size: size,
+                duration: Duration::from_millis(millis),
+                result: if result { Ok(()) } else { Err(Error::FileFail) },
+                retransmits: 0,
+                content_type: None,
+                timings: Timings::default(),
+            });
+        }
+
+        let tempdir = TempDir::new("server_test_stats").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let file = File::create(&mut server.arbitrator, b"a", &path, 1, 0, 1, b"{}").unwrap();
+        server.files.insert(b"a".to_vec(), file);
+
+        assert_eq!(server.stats(), ServerStats {
+            active_transfers: 1,
+            completed: 2,
+            failed: 1,
+            bytes_received: 4000,
+            throughput: 2000,
+            queued: 1,
+        });
+    }
+
+    #[test]
+    fn test_quota_hourly() {
+        ZSys::init();
+
+        let clock = MockClock::new();
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::Quota(100), Options::HourlyQuota(10), Options::Clock(Arc::new(clock.clone()))]));
+        server.usage.insert(b"a".to_vec(), 98);
+        server.hourly.insert(b"a".to_vec(), (clock.now(), 6));
+
+        // Whichever quota has less left applies
+        assert_eq!(server.quota(b"a").remaining, Some(2));
+        server.usage.insert(b"a".to_vec(), 6);
+        assert_eq!(server.quota(b"a").remaining, Some(4));
+        assert!(server.check_limits(b"a", "/tmp/f", 5, 1).is_err());
+        assert_eq!(server.quota(b"b").remaining, Some(10));
+        server.channels.set_user(b"c", Some("a".into()));
+        assert_eq!(server.quota(b"c").remaining, Some(4));
+
+        clock.advance(Duration::new(HOUR_SECS, 0));
+        assert_eq!(server.quota(b"a").remaining, Some(10));
+        assert!(server.check_limits(b"a", "/tmp/f", 5, 1).is_ok());
+    }
+
+    #[test]
+    fn test_is_overloaded_per_client() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::MaxTransfersPerClient(1)]));
+
+        let frames = vec![b"CHANNEL".to_vec(), b"1".to_vec(), b"NEW".to_vec()];
+        let (key, _) = server.channels.open(b"abc".to_vec(), frames).unwrap();
+
+        let tempdir = TempDir::new("server_test_is_overloaded_per_client").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let file = File::create(&mut server.arbitrator, &key, &path, 1, 0, 1, b"{}").unwrap();
+        server.files.insert(key.clone(), file);
+
+        // Another channel from the same client is over the limit, but
+        // the channel's own next request replaces its transfer
+        assert!(server.is_overloaded(b"abc"));
+        assert!(!server.is_overloaded(&key));
+        assert!(!server.is_overloaded(b"def"));
+
+        // As is another connection with the same CURVE key
+        server.channels.set_user(b"abc", Some("key".into()));
+        server.channels.set_user(b"def", Some("key".into()));
+        assert!(server.is_overloaded(b"def"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_path_symlink() {
+        use std::os::unix::fs::symlink;
+
+        ZSys::init();
+
+        let tempdir = TempDir::new("server_test_check_path_symlink").unwrap();
+        let allowed = tempdir.path().join("allowed");
+        fs::create_dir(&allowed).unwrap();
+        fs::create_dir(tempdir.path().join("outside")).unwrap();
+        symlink(tempdir.path().join("outside"), allowed.join("escape")).unwrap();
+        symlink(&allowed, tempdir.path().join("alias")).unwrap();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::AllowedPath(allowed.to_str().unwrap().into())]));
+
+        assert!(server.check_path(allowed.join("sub/f").to_str().unwrap()).is_ok());
+        assert!(server.check_path(tempdir.path().join("alias/f").to_str().unwrap()).is_ok());
+        assert_eq!(server.check_path(allowed.join("escape/f").to_str().unwrap()).unwrap_err().to_string(),
+                   Error::PathNotAllowed.to_string());
+    }
+
+    #[test]
+    fn test_check_symlink() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::AllowedPath("/srv/files".into())]));
+
+        assert!(server.check_symlink(b"abc", "/srv/files/link", b"{}").is_ok());
+        assert!(server.check_symlink(b"abc", "/srv/files/link", b"{\"symlink\":\"sub/f\"}").is_ok());
+        assert!(server.check_symlink(b"abc", "/srv/files/link", b"{\"symlink\":\"/srv/files/f\"}").is_err());
+        assert!(server.check_symlink(b"abc", "/srv/files/link", b"{\"symlink\":\"/etc/passwd\"}").is_err());
+        assert!(server.check_symlink(b"abc", "/srv/files/link", b"{\"symlink\":\"../f\"}").is_err());
+        assert!(server.check_symlink(b"abc", "/srv/files/sub/link", b"{\"symlink\":\"../f\"}").is_err());
+
+        // Nor may they lead out without any allowed paths
+        server.options = ServerOptions::new(None);
+        assert!(server.check_symlink(b"abc", "/srv/files/link", b"{\"symlink\":\"/etc/passwd\"}").is_err());
+    }
+
+    #[test]
+    fn test_remote_path() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::AllowedPath("/srv/files".into())]));
+
+        assert_eq!(server.remote_path(b"abc", "/srv/files/a").unwrap(), "/srv/files/a");
+        assert!(server.remote_path(b"abc", "/srv/files/../a").is_err());
+        assert!(server.remote_path(b"abc", "/etc/passwd").is_err());
+        assert!(server.remote_path(b"abc", "/srv/files/a\x07").is_err());
+    }
+
+    #[test]
+    fn test_remote_action_path() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::AllowedPath("/srv/files".into())]));
+        assert!(server.remote_action_path(b"abc", "/srv/files/a").is_err());
+
+        // Nothing confines the actions without roots
+        server.options = ServerOptions::new(Some(&[Options::RemoteFiles]));
+        assert!(server.remote_action_path(b"abc", "/srv/files/a").is_err());
+
+        server.options = ServerOptions::new(Some(&[Options::AllowedPath("/srv/files".into()), Options::RemoteFiles]));
+        assert_eq!(server.remote_action_path(b"abc", "/srv/files/a").unwrap(), "/srv/files/a");
+        assert!(server.remote_action_path(b"abc", "/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_batch_path() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        let declared = BatchFile { path: "/tmp/a".into(), size: 3, crc: 4 };
+        server.batches.insert(b"abc".to_vec(), vec![(declared, PathBuf::from("/tmp/.a.batch0"))]);
+
+        assert_eq!(server.batch_path(b"abc", "/tmp/a", 3, 4).unwrap(), Some("/tmp/.a.batch0".into()));
+        assert!(server.batch_path(b"abc", "/tmp/a", 3, 5).is_err());
+        assert_eq!(server.batch_path(b"abc", "/tmp/b", 3, 4).unwrap(), None);
+        assert_eq!(server.batch_path(b"def", "/tmp/a", 3, 4).unwrap(), None);
+
+        server.abort_batch(b"abc");
+        assert!(server.batches.is_empty());
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        assert!(server.sanitize_path("/tmp/a\x07").is_err());
+
+        server.options = ServerOptions::new(Some(&[Options::NamePolicy(NamePolicy::Replace)]));
+        assert_eq!(server.sanitize_path("/tmp/a\x07").unwrap(), "/tmp/a_");
+
+        server.set_name_sanitizer(|path| if path.ends_with(".exe") { Err(Error::UnsafeFileName) } else { Ok(path.to_lowercase()) });
+        assert_eq!(server.sanitize_path("/tmp/A").unwrap(), "/tmp/a");
+        assert!(server.sanitize_path("/tmp/a.exe").is_err());
+    }
+
+    #[test]
+    fn test_tenant_path() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        assert_eq!(server.tenant_path(b"a", "/app/config").unwrap(), "/app/config");
+
+        server.options = ServerOptions::new(Some(&[Options::Tenant(b"key".to_vec(), "/srv/tenants/A".into())]));
+        assert!(server.tenant_path(b"a", "/app/config").is_err());
+
+        // Tenants are told apart by their CURVE key, not their
+        // router identity
+        server.channels.set_user(b"a", Some("key".into()));
+        server.channels.set_user(b"b", Some("other".into()));
+        assert_eq!(server.tenant_path(b"a", "/app/config").unwrap(), "/srv/tenants/A/app/config");
+        assert_eq!(server.tenant_path(b"a", "app/./config").unwrap(), "/srv/tenants/A/app/./config");
+        assert!(server.tenant_path(b"a", "/app/../../B/config").is_err());
+        assert!(server.tenant_path(b"b", "/app/config").is_err());
+    }
+
+    #[test]
+    fn test_tenant_path_symlink() {
+        use std::os::unix::fs::symlink;
+
+        ZSys::init();
+
+        let tempdir = TempDir::new("server_test_tenant_path_symlink").unwrap();
+        let root = tempdir.path().join("A");
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(tempdir.path().join("outside")).unwrap();
+        symlink(tempdir.path().join("outside"), root.join("d")).unwrap();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        server.options = ServerOptions::new(Some(&[Options::Tenant(b"key".to_vec(), root.to_str().unwrap().into())]));
+        server.channels.set_user(b"a", Some("key".into()));
+
+        assert!(server.tenant_path(b"a", "/sub/f").is_ok());
+        assert_eq!(server.tenant_path(b"a", "/d/f").unwrap_err().to_string(), Error::PathNotAllowed.to_string());
+        assert!(server.check_symlink(b"a", root.join("link").to_str().unwrap(), b"{\"symlink\":\"d/f\"}").is_err());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        assert!(server.snapshot().is_empty());
+
+        let tempdir = TempDir::new("server_test_snapshot").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let file = File::create(&mut server.arbitrator, "abc".as_bytes(), &path, 2, 0, 1, b"{}").unwrap();
+        server.files.insert("abc".as_bytes().into(), file);
+
+        let snapshot = server.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].id, 0);
+        assert_eq!(snapshot[0].identity, "abc".as_bytes());
+        assert_eq!(snapshot[0].path, path);
+        assert_eq!(snapshot[0].size, 2);
+        assert_eq!(snapshot[0].bytes_done, 0);
+        assert_eq!(snapshot[0].retries, 0);
+        assert!(JsonCodec.encode(&snapshot).is_ok());
+    }
+
+    #[test]
+    fn test_recv_describe() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_describe").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_describe").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+        server.options = ServerOptions::new(Some(&[Options::MaxFileSize(1024)]));
+
+        dealer.send_str("DESCRIBE").unwrap();
+        server.recv(&mut router_dup).unwrap();
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Ok");
+        let desc: Description = JsonCodec.decode(&msg.popbytes().unwrap().unwrap()).unwrap();
+        assert_eq!(desc.version, PROTOCOL_VERSION);
+        assert!(desc.actions.contains(&"DESCRIBE".to_string()));
+        assert!(desc.codecs.contains(&"binary".to_string()));
+        assert_eq!(desc.max_file_size, Some(1024));
+        assert_eq!(desc.max_chunk_size, None);
+    }
+
+    #[test]
+    fn test_recv_authorizer() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_authorizer").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_authorizer").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+        server.set_authorizer(|_| true);
+
+        // Without CURVE the client has no key to authorize
+        dealer.send_str("DESCRIBE").unwrap();
+        server.recv(&mut router_dup).unwrap();
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Identity is not authorized for this action");
+    }
+
+    #[test]
+    fn test_recv_list_transfers() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_list_transfers").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_list_transfers").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+
+        // Start a legacy upload, which gets no reply, to learn the
+        // dealer's identity
+        let tempdir = TempDir::new("server_test_recv_list_transfers").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&path).unwrap();
+        msg.addstr("2").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("{}").unwrap();
+        msg.send(&mut dealer).unwrap();
+        server.recv(&mut router_dup).unwrap();
+        let identity = server.snapshot()[0].identity.clone();
+
+        dealer.send_str("LIST-TRANSFERS").unwrap();
+        server.recv(&mut router_dup).unwrap();
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Identity is not authorized for this action");
+
+        // A router identity is no proof of who the client is
+        server.options = ServerOptions::new(Some(&[Options::Admin(identity.clone())]));
+        dealer.send_str("LIST-TRANSFERS").unwrap();
+        server.recv(&mut router_dup).unwrap();
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+
+        // Its CURVE key is, though inproc peers can't have one
+        server.options = ServerOptions::new(Some(&[Options::Admin(b"key".to_vec())]));
+        server.channels.set_user(&identity, Some("key".into()));
+        assert!(server.is_admin(&identity));
+        let transfers: Vec<TransferState> = JsonCodec.decode(&JsonCodec.encode(&server.snapshot()).unwrap()).unwrap();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].path, path);
+        assert_eq!(transfers[0].size, 2);
+    }
+
+    #[test]
+    fn test_recv_progress() {
+        ZSys::init();
+
+        let mut router = ZSock::new_router("inproc://server_test_recv_progress").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+        let mut dealer = ZSock::new(SocketType::DEALER);
+        dealer.set_identity("def");
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        dealer.connect("inproc://server_test_recv_progress").unwrap();
+
+        let mut server = new_server(router, true);
+
+        let tempdir = TempDir::new("server_test_recv_progress").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let file = File::create(&mut server.arbitrator, "def".as_bytes(), &path, 3, 0, 1, b"{}").unwrap();
+        let id = server.files.insert("def".as_bytes().into(), file);
+        let path = format!("{}/otherfile", tempdir.path().to_str().unwrap());
+        let file = File::create(&mut server.arbitrator, "ghi".as_bytes(), &path, 3, 0, 1, b"{}").unwrap();
+        let other = server.files.insert("ghi".as_bytes().into(), file);
+
+        for &id in [id, other, other + 1].iter() {
+            let msg = ZMsg::new();
+            msg.addstr("PROGRESS").unwrap();
+            msg.addstr(&id.to_string()).unwrap();
+            msg.send(&mut dealer).unwrap();
+            server.recv(&mut router_dup).unwrap();
+        }
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Ok");
+        let progress: Progress = JsonCodec.decode(&msg.popbytes().unwrap().unwrap()).unwrap();
+        assert_eq!(progress, Progress { id: id, bytes_done: 0, chunks_outstanding: 3, retries: 0 });
+
+        // Another client's transfer
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Identity is not authorized for this action");
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+    }
+
+    #[test]
+    fn test_recv_join() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_join").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_join").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+
+        let tempdir = TempDir::new("server_test_recv_join").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let mut file = File::create(&mut server.arbitrator, "def".as_bytes(), &path, 3, 7, 1, b"{}").unwrap();
+        file.set_protocol(Some(PROTOCOL_VERSION));
+        let id = server.files.insert("def".as_bytes().into(), file);
+        let token = server.stripes.offer(b"def", id).unwrap();
+
+        // The CRC, which others may know, or a wrong token is refused
+        for token in ["7", "0123", &token].iter() {
+            let msg = ZMsg::new();
+            msg.addstr("JOIN").unwrap();
+            msg.addstr(&id.to_string()).unwrap();
+            msg.addstr(token).unwrap();
+            msg.send(&mut dealer).unwrap();
+            server.recv(&mut router_dup).unwrap();
+        }
+
+        for _ in 0..2 {
+            let msg = ZMsg::recv(&mut dealer).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        }
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Ok");
+
+        // Chunk requests now take turns between the connections
+        assert!(server.stripes.next(b"def") != b"def");
+        assert_eq!(server.stripes.next(b"def"), b"def");
+    }
+
+    #[test]
+    fn test_shutdown() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_shutdown").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_shutdown").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+
+        let tempdir = TempDir::new("server_test_shutdown").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let file = File::create(&mut server.arbitrator, "def".as_bytes(), &path, 3, 0, 1, b"{}").unwrap();
+        let upload_path = file.get_upload_path().unwrap().to_owned();
+        server.files.insert("def".as_bytes().into(), file);
+
+        // Transfers in progress are left to finish
+        server.shutdown(Shutdown::Drain).unwrap();
+        assert!(!server.is_drained());
+        assert!(upload_path.exists());
+
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&format!("{}/other", tempdir.path().to_str().unwrap())).unwrap();
+        msg.addstr("3").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("{}").unwrap();
+        msg.send(&mut dealer).unwrap();
+        server.recv(&mut router_dup).unwrap();
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Server is shutting down");
+
+        // Or cancelled
+        server.shutdown(Shutdown::Abort).unwrap();
+        assert!(server.is_drained());
+        assert!(!upload_path.exists());
+
+        // Batches and directory uploads count as in progress too
+        server.batches.insert(b"ghi".to_vec(), Vec::new());
+        assert!(!server.is_drained());
+        server.batches.clear();
+        server.dirs.insert(b"ghi".to_vec(), (PathBuf::new(), PathBuf::new()));
+        assert!(!server.is_drained());
+    }
+
+    #[test]
+    fn test_expire() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        let clock = MockClock::new();
+        server.options = ServerOptions::new(Some(&[Options::Heartbeat(100), Options::Clock(Arc::new(clock.clone()))]));
+        server.last_expire = clock.now();
+
+        let tempdir = TempDir::new("server_test_expire").unwrap();
+        let mut upload_paths = Vec::new();
+        for &(router_id, protocol) in [("def", Some(protocol::HEARTBEATS)), ("ghi", Some(protocol::STRIPED_CHUNKS))].iter() {
+            let path = format!("{}/{}", tempdir.path().to_str().unwrap(), router_id);
+            let mut file = File::create(&mut server.arbitrator, router_id.as_bytes(), &path, 3, 0, 1, b"{}").unwrap();
+            file.set_protocol(protocol);
+            upload_paths.push(file.get_upload_path().unwrap().to_owned());
+            server.files.insert(router_id.as_bytes().into(), file);
+            server.last_seen.insert(router_id.as_bytes().into(), clock.now());
+        }
+
+        clock.advance(Duration::from_millis(200));
+        server.expire().unwrap();
+        assert_eq!(server.files.len(), 2);
+
+        // Only a client that was told to PING misses heartbeats
+        clock.advance(Duration::from_millis(100));
+        server.expire().unwrap();
+        assert_eq!(server.files.len(), 1);
+        assert!(!upload_paths[0].exists());
+        assert!(server.files.contains_key(b"ghi"));
+        assert!(!server.last_seen.contains_key(&b"def"[..]));
+    }
+
+    #[test]
+    fn test_expire_batch() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+        let clock = MockClock::new();
+        server.options = ServerOptions::new(Some(&[Options::Heartbeat(100), Options::Clock(Arc::new(clock.clone()))]));
+        server.last_expire = clock.now();
+
+        let tempdir = TempDir::new("server_test_expire_batch").unwrap();
+        let staging = tempdir.path().join(".a.batch0");
+        fs::File::create(&staging).unwrap();
+        let declared = BatchFile { path: tempdir.path().join("a").to_str().unwrap().into(), size: 0, crc: 0 };
+        server.batches.insert(b"abc".to_vec(), vec![(declared, staging.clone())]);
+        server.last_seen.insert(b"abc".to_vec(), clock.now());
+
+        // As is a directory upload
+        let dir_staging = tempdir.path().join(".dir.dir0");
+        fs::create_dir(&dir_staging).unwrap();
+        server.dirs.insert(b"def".to_vec(), (tempdir.path().join("dir"), dir_staging.clone()));
+        server.last_seen.insert(b"def".to_vec(), clock.now());
+
+        clock.advance(Duration::from_millis(200));
+        server.expire().unwrap();
+        assert!(server.batches.contains_key(&b"abc"[..]));
+        assert!(server.dirs.contains_key(&b"def"[..]));
+
+        clock.advance(Duration::from_millis(100));
+        server.expire().unwrap();
+        assert!(server.batches.is_empty());
+        assert!(!staging.exists());
+        assert!(!server.last_seen.contains_key(&b"abc"[..]));
+        assert!(server.dirs.is_empty());
+        assert!(!dir_staging.exists());
+        assert!(!server.last_seen.contains_key(&b"def"[..]));
+    }
+
+    #[test]
+    fn test_restore() {
+        ZSys::init();
+
+        let mut server = new_server(ZSock::new(SocketType::ROUTER), true);
+
+        let tempdir = TempDir::new("server_test_restore").unwrap();
+        let path = tempdir.path().join("file");
+        let upload_path = tempdir.path().join(".file0");
+        fs::File::create(&upload_path).unwrap().set_len(3).unwrap();
+        let manifest = Manifest {
+            version: manifest::STAGING_VERSION,
+            protocol: Some(PROTOCOL_VERSION),
+            path: path.to_str().unwrap().into(),
+            size: 3,
+            crc: 0,
+            chunk_size: 1,
+            options: b"{}".to_vec(),
+            missing: vec![(1, 3)],
+            router_id: Some(b"abc".to_vec()),
+            transfer_id: Some(5),
+            client: Some(b"abc".to_vec()),
+        };
+        manifest.save(&upload_path).unwrap();
+        server.staged.insert(manifest.path.clone(), (upload_path.clone(), manifest.clone()));
+
+        // Only the client that started it can carry on, whatever
+        // router identity another picks
+        server.restore(b"def");
+        assert!(!server.files.contains_key(b"def"));
+        server.channels.set_user(b"abc", Some("mallory".into()));
+        server.restore(b"abc");
+        assert!(!server.files.contains_key(b"abc"));
+        server.channels.set_user(b"abc", None);
+
+        // Nor if its path is no longer allowed
+        let other = TempDir::new("server_test_restore_other").unwrap();
+        let other_upload = other.path().join(".file0");
+        fs::File::create(&other_upload).unwrap().set_len(3).unwrap();
+        let denied = Manifest {
+            path: other.path().join("file").to_str().unwrap().into(),
+            router_id: Some(b"ghi".to_vec()),
+            client: Some(b"ghi".to_vec()),
+            ..manifest
+        };
+        denied.save(&other_upload).unwrap();
+        server.staged.insert(denied.path.clone(), (other_upload.clone(), denied));
+        server.options = ServerOptions::new(Some(&[Options::AllowedPath(tempdir.path().to_str().unwrap().into())]));
+        server.restore(b"ghi");
+        assert!(!server.files.contains_key(b"ghi"));
+        assert!(!other_upload.exists());
+
+        server.restore(b"abc");
+        assert!(server.staged.is_empty());
+        assert_eq!(server.files.active(b"abc"), Some(5));
+        let file = server.files.get(b"abc").unwrap();
+        assert_eq!(file.get_protocol(), Some(PROTOCOL_VERSION));
+        assert_eq!(file.chunks_outstanding(), 2);
+        assert!(file.is_stale(0));
+    }
+
+    #[test]
+    fn test_recv_new() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_new").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr("/path/to/file").unwrap();
+        msg.addstr("abc").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("{}").unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(server.files.iter().count(), 0);
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Invalid request");
+
+        let tempdir = TempDir::new("server_test_recv_new").unwrap();
+
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&format!("{}/testfile", tempdir.path().to_str().unwrap())).unwrap();
+        msg.addstr("10240").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1024").unwrap();
+        msg.addstr("{}").unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(server.files.iter().count(), 1);
+
+        assert!(dealer.recv_str().is_err());
+    }
+
+    #[test]
+    fn test_recv_cancel() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_cancel").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_cancel").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+        let tempdir = TempDir::new("server_test_recv_cancel").unwrap();
+
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&format!("{}/testfile", tempdir.path().to_str().unwrap())).unwrap();
+        msg.addstr("10240").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1024").unwrap();
+        msg.addstr("{}").unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(server.files.iter().count(), 1);
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 1);
+
+        dealer.send_str("CANCEL").unwrap();
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(dealer.recv_str().unwrap().unwrap(), "CANCELLED");
+        assert_eq!(server.files.iter().count(), 0);
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 0);
+
+        // Nothing in progress is nothing to cancel
+        dealer.send_str("CANCEL").unwrap();
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(dealer.recv_str().unwrap().unwrap(), "CANCELLED");
+    }
+
+    #[test]
+    fn test_recv_new_versioned() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new_versioned").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_new_versioned").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+        server.options = ServerOptions::new(Some(&[Options::Compat(Compat::Versioned)]));
+
+        let tempdir = TempDir::new("server_test_recv_new_versioned").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&path).unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("{}").unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(server.files.iter().count(), 0);
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Peer does not support protocol versioning");
+
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&path).unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("{\"protocol\":1}").unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(server.files.iter().count(), 1);
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ACK");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "1");
+        let id = server.files.iter().next().unwrap().0;
+        assert_eq!(msg.popstr().unwrap().unwrap(), id.to_string());
+
+        // The window a client asks for is capped by the server's
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&path).unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("{\"protocol\":7,\"window\":100}").unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        server.recv(&mut router_dup).unwrap();
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ACK");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "7");
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), super::MAX_WINDOW.to_string());
+    }
+
+    #[test]
+    fn test_recv_new_min_protocol() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new_min_protocol").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_new_min_protocol").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+        server.options = ServerOptions::new(Some(&[Options::MinProtocol(PROTOCOL_VERSION + 1)]));
+
+        let tempdir = TempDir::new("server_test_recv_new_min_protocol").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+
+        for options in vec!["{}".to_string(), format!("{{\"protocol\":{}}}", PROTOCOL_VERSION)] {
+            let msg = ZMsg::new();
+            msg.addstr("NEW").unwrap();
+            msg.addstr(&path).unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr("0").unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr(&options).unwrap();
+            msg.send(&mut dealer).unwrap();
+
+            server.recv(&mut router_dup).unwrap();
+            assert_eq!(server.files.iter().count(), 0);
+        }
 
-impl Endpoint for Server {
-    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
-        vec![&mut self.router, &mut self.sink, &mut self.arbitrator_sock]
+        // Legacy clients get a plain error
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), format!("Peer requires protocol version {} or later", PROTOCOL_VERSION + 1));
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "UNSUPPORTED");
+        assert_eq!(msg.popstr().unwrap().unwrap(), (PROTOCOL_VERSION + 1).to_string());
+        assert_eq!(msg.popstr().unwrap().unwrap(), PROTOCOL_VERSION.to_string());
     }
 
-    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
-        // We always expect a router ID as it ties a request to a
-        // file. Its presence is not dependent on the socket type.
-        let router_id = match try!(try!(ZFrame::recv(sock)).data()) {
-            Ok(s) => s.into_bytes(),
-            Err(b) => b,
-        };
+    #[test]
+    fn test_recv_new_delta() {
+        ZSys::init();
 
-        if *sock == self.router {
-            if let Ok(action) = try!(try!(ZFrame::recv(sock)).data()) {
-                match action.as_ref() {
-                    "NEW" => {
-                        let msg = try!(ZMsg::expect_recv(sock, 5, Some(5), false));
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new_delta").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_new_delta").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
 
-                        let path = match msg.popstr().unwrap() {
-                            Ok(p) => p,
-                            Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                        };
+        let mut hashed = ZSock::new_pull("inproc://server_test_recv_new_delta_hashed").unwrap();
+        hashed.set_rcvtimeo(Some(500));
+        let mut hashed_dup = unsafe { ZSock::from_raw(hashed.as_mut_ptr(), false) };
 
-                        let size = match msg.popstr().unwrap() {
-                            Ok(s) => match s.parse::<u64>() {
-                                Ok(u) => u,
-                                Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                            },
-                            Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                        };
+        let mut server = new_server(router, true);
+        server.hashed = hashed;
+        server.hasher = Hasher::new(1, ">inproc://server_test_recv_new_delta_hashed").unwrap();
 
-                        let crc = match msg.popstr().unwrap() {
-                            Ok(s) => match s.parse::<u64>() {
-                                Ok(u) => u,
-                                Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                            },
-                            Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                        };
+        let tempdir = TempDir::new("server_test_recv_new_delta").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().write_all(b"abcd").unwrap();
 
-                        let chunk_size = match msg.popstr().unwrap() {
-                            Ok(s) => match s.parse::<u64>() {
-                                Ok(u) => u,
-                                Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                            },
-                            Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                        };
+        let msg = ZMsg::new();
+        msg.addstr("NEW").unwrap();
+        msg.addstr(&path).unwrap();
+        msg.addstr("4").unwrap();
+        msg.addstr("0").unwrap();
+        msg.addstr("2").unwrap();
+        msg.addstr("{\"protocol\":4,\"delta\":true}").unwrap();
+        msg.send(&mut dealer).unwrap();
 
-                        let options = match msg.popstr().unwrap() {
-                            Ok(s) => s,
-                            Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                        };
+        // The hashes are sent once they've been taken
+        server.recv(&mut router_dup).unwrap();
+        assert!(server.deltas.is_empty());
+        server.recv(&mut hashed_dup).unwrap();
+        assert_eq!(server.files.iter().count(), 0);
+        assert_eq!(server.deltas.len(), 1);
 
-                        let file = match File::create(&mut self.arbitrator, &router_id, &path, size, crc, chunk_size, &options) {
-                            Ok(f) => f,
-                            Err(e) => return self.reply_err(&router_id, e),
-                        };
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "HASHES");
+        assert_eq!(protocol::unpack_u64s(&msg.popbytes().unwrap().unwrap()).unwrap().len(), 2);
 
-                        self.files.insert(router_id, file);
-                    },
-                    "CHUNK" => {
-                        if !self.files.contains_key(&router_id) {
-                            return self.reply_err(&router_id, Error::InvalidRequest);
-                        }
+        let msg = ZMsg::new();
+        msg.addstr("UNCHANGED").unwrap();
+        msg.addbytes(&[0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        msg.send(&mut dealer).unwrap();
 
-                        let msg = try!(ZMsg::expect_recv(sock, 2, Some(2), false));
+        server.recv(&mut router_dup).unwrap();
+        assert!(server.deltas.is_empty());
+        assert_eq!(server.files.iter().count(), 1);
 
-                        let index = match msg.popstr().unwrap() {
-                            Ok(s) => match s.parse::<u64>() {
-                                Ok(u) => u,
-                                Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                            },
-                            Err(_) => return self.reply_err(&router_id, Error::InvalidRequest),
-                        };
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ACK");
 
-                        let chunk = try!(msg.popbytes()).unwrap();
+        // Without a pending delta, UNCHANGED is out of turn
+        let msg = ZMsg::new();
+        msg.addstr("UNCHANGED").unwrap();
+        msg.send(&mut dealer).unwrap();
 
-                        if let Err(e) = self.files.get_mut(&router_id).unwrap().recv(&router_id, index, chunk) {
-                            return self.reply_err(&router_id, e);
-                        }
-                    },
-                    _ => return Err(Error::InvalidRequest.into()),
-                }
-            }
-        }
-        else if *sock == self.sink {
-            if !self.files.contains_key(&router_id) {
-                return Err(Error::InvalidRequest.into());
-            }
+        server.recv(&mut router_dup).unwrap();
+        assert_eq!(ZMsg::recv(&mut dealer).unwrap().popstr().unwrap().unwrap(), "Err");
+    }
 
-            let msg = try!(ZMsg::expect_recv(sock, 2, Some(2), false));
+    #[test]
+    fn test_recv_new_if_exists() {
+        ZSys::init();
 
-            // We can make the assumption here that the data is well
-            // formed, as there are no user-provided fields.
-            let index = msg.popstr().unwrap().unwrap().parse::<u64>().unwrap();
-            let success = if msg.popstr().unwrap().unwrap() == "1" { true } else { false };
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new_if_exists").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_new_if_exists").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
 
-            let mut file = self.files.get_mut(&router_id).unwrap();
+        let mut server = new_server(router, true);
 
-            if let Err(e) = file.sink(&mut self.arbitrator, &router_id, index, success) {
-                return Err(e.into());
-            }
+        let tempdir = TempDir::new("server_test_recv_new_if_exists").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().write_all(b"abcd").unwrap();
 
-            if file.is_error() {
-                try!(ZMsg::new_err(&Error::FileFail.into()));
-                try!(msg.pushbytes(&router_id));
-                try!(msg.send(&mut self.router));
-            }
-            else if file.is_complete() {
-                let msg = match file.save() {
-                    Ok(_) => try!(ZMsg::new_ok()),
-                    Err(e) => try!(ZMsg::new_err(&e.into())),
-                };
-                try!(msg.pushbytes(&router_id));
-                try!(msg.send(&mut self.router));
+        for &(policy, reply) in [("Skip", "Ok"), ("Fail", "EXISTS"), ("Overwrite", "ACK")].iter() {
+            let msg = ZMsg::new();
+            msg.addstr("NEW").unwrap();
+            msg.addstr(&path).unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr("0").unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr(&format!("{{\"protocol\":8,\"if_exists\":\"{}\"}}", policy)).unwrap();
+            msg.send(&mut dealer).unwrap();
+
+            server.recv(&mut router_dup).unwrap();
+            let msg = ZMsg::recv(&mut dealer).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), reply);
+            if policy == "Skip" {
+                assert_eq!(msg.popstr().unwrap().unwrap(), "SKIPPED");
             }
         }
-        else if *sock == self.arbitrator_sock {
-            // Forward messages from Arbitrator to Router sock
-            let msg = try!(ZMsg::recv(sock));
-            try!(msg.pushbytes(&router_id));
-            try!(msg.send(&mut self.router));
-        } else {
-            unreachable!();
-        }
 
-        Ok(())
+        // Only the overwrite started an upload
+        assert_eq!(server.files.iter().count(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use arbitrator::Arbitrator;
-    use czmq::{RawInterface, ZFrame, ZMsg, ZSock, SocketType, ZSys};
-    use error::Error;
-    use file::File;
-    use std::collections::HashMap;
-    use super::*;
-    use tempdir::TempDir;
-    use zdaemon::Endpoint;
 
     #[test]
-    fn test_new() {
+    fn test_recv_new_unchanged() {
         ZSys::init();
 
-        let router = ZSock::new(SocketType::ROUTER);
-        assert!(Server::new(router, 0).is_ok());
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new_unchanged").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_new_unchanged").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut hashed = ZSock::new_pull("inproc://server_test_recv_new_unchanged_hashed").unwrap();
+        hashed.set_rcvtimeo(Some(500));
+        let mut hashed_dup = unsafe { ZSock::from_raw(hashed.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+        server.hashed = hashed;
+        server.hasher = Hasher::new(1, ">inproc://server_test_recv_new_unchanged_hashed").unwrap();
+
+        let tempdir = TempDir::new("server_test_recv_new_unchanged").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().write_all(b"abcd").unwrap();
+        let (crc, _) = hash::hash_file(&path, None).unwrap();
+
+        // Older clients get an upload as before
+        for &(protocol, reply) in [(PROTOCOL_VERSION, "Ok"), (protocol::IF_EXISTS, "ACK")].iter() {
+            let msg = ZMsg::new();
+            msg.addstr("NEW").unwrap();
+            msg.addstr(&path).unwrap();
+            msg.addstr("4").unwrap();
+            msg.addstr(&crc.to_string()).unwrap();
+            msg.addstr("2").unwrap();
+            msg.addstr(&format!("{{\"protocol\":{}}}", protocol)).unwrap();
+            msg.send(&mut dealer).unwrap();
+
+            // The existing file is compared once it's hashed
+            server.recv(&mut router_dup).unwrap();
+            if reply == "Ok" {
+                server.recv(&mut hashed_dup).unwrap();
+            }
+            let msg = ZMsg::recv(&mut dealer).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), reply);
+            if reply == "Ok" {
+                assert_eq!(msg.popstr().unwrap().unwrap(), "UNCHANGED");
+                assert_eq!(server.files.iter().count(), 0);
+            }
+        }
     }
 
     #[test]
-    fn test_reply_err() {
+    fn test_recv_new_busy() {
         ZSys::init();
 
-        let mut dealer = ZSock::new_dealer("inproc://server_test_reply_err").unwrap();
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new_busy").unwrap();
         dealer.set_sndtimeo(Some(500));
         dealer.set_rcvtimeo(Some(500));
-        let mut router = ZSock::new_router("inproc://server_test_reply_err").unwrap();
+        let mut router = ZSock::new_router("inproc://server_test_recv_new_busy").unwrap();
         router.set_sndtimeo(Some(500));
         router.set_rcvtimeo(Some(500));
-
-        dealer.send_str("moo").unwrap();
-        let router_id = match ZFrame::recv(&mut router).unwrap().data().unwrap() {
-            Ok(s) => s.into_bytes(),
-            Err(b) => b,
-        };
-        router.flush();
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
 
         let mut server = new_server(router, true);
-        assert!(server.reply_err(&router_id, Error::InvalidRequest).is_ok());
+        server.options = ServerOptions::new(Some(&[Options::MaxTransfers(1), Options::RetryAfter(3)]));
 
-        let reply = ZFrame::recv(&mut dealer).unwrap().data().unwrap().unwrap();
-        assert_eq!(&reply, "Err");
+        let tempdir = TempDir::new("server_test_recv_new_busy").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        let file = File::create(&mut server.arbitrator, "def".as_bytes(), &path, 1, 0, 1, b"{}").unwrap();
+        server.files.insert("def".as_bytes().into(), file);
+
+        for &options in ["{\"protocol\":1}", "{}"].iter() {
+            let msg = ZMsg::new();
+            msg.addstr("NEW").unwrap();
+            msg.addstr(&path).unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr("0").unwrap();
+            msg.addstr("1").unwrap();
+            msg.addstr(options).unwrap();
+            msg.send(&mut dealer).unwrap();
+
+            server.recv(&mut router_dup).unwrap();
+            assert_eq!(server.files.len(), 1);
+        }
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "BUSY");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "3");
+
+        // Legacy clients get a plain error
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert!(msg.popstr().unwrap().unwrap().starts_with("Server is busy"));
     }
 
     #[test]
-    fn test_recv_new() {
+    fn test_recv_chunk() {
         ZSys::init();
 
-        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_new").unwrap();
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_chunk").unwrap();
         dealer.set_sndtimeo(Some(500));
         dealer.set_rcvtimeo(Some(500));
-        let mut router = ZSock::new_router("inproc://server_test_recv_new").unwrap();
+        let mut router = ZSock::new_router("inproc://server_test_recv_chunk").unwrap();
         router.set_sndtimeo(Some(500));
         router.set_rcvtimeo(Some(500));
         let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
 
+        dealer.send_str("test").unwrap();
+        let router_id = match ZFrame::recv(&mut router).unwrap().data().unwrap() {
+            Ok(s) => s.into_bytes(),
+            Err(b) => b,
+        };
+        router.flush();
+
         let mut server = new_server(router, true);
 
         let msg = ZMsg::new();
-        msg.addstr("NEW").unwrap();
-        msg.addstr("/path/to/file").unwrap();
-        msg.addstr("abc").unwrap();
-        msg.addstr("0").unwrap();
-        msg.addstr("1").unwrap();
-        msg.addstr("{}").unwrap();
+        msg.addstr("CHUNK").unwrap();
         msg.send(&mut dealer).unwrap();
 
         server.recv(&mut router_dup).unwrap();
-        assert_eq!(server.files.len(), 0);
 
         let msg = ZMsg::recv(&mut dealer).unwrap();
         assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
         assert_eq!(msg.popstr().unwrap().unwrap(), "Invalid request");
 
-        let tempdir = TempDir::new("server_test_recv_new").unwrap();
+        let tempdir = TempDir::new("server_test_recv_chunk").unwrap();
+        let file = File::create(&mut server.arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 0, 0, 1, b"{}").unwrap();
+        server.files.insert(router_id, file);
 
         let msg = ZMsg::new();
-        msg.addstr("NEW").unwrap();
-        msg.addstr(&format!("{}/testfile", tempdir.path().to_str().unwrap())).unwrap();
-        msg.addstr("10240").unwrap();
-        msg.addstr("0").unwrap();
-        msg.addstr("1024").unwrap();
-        msg.addstr("{}").unwrap();
+        msg.addstr("CHUNK").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addbytes("bytes".as_bytes()).unwrap();
         msg.send(&mut dealer).unwrap();
 
         server.recv(&mut router_dup).unwrap();
-        assert_eq!(server.files.len(), 1);
 
-        assert!(dealer.recv_str().is_err());
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Chunk index not in file");
     }
 
     #[test]
-    fn test_recv_chunk() {
+    fn test_recv_chunks() {
         ZSys::init();
 
-        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_chunk").unwrap();
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_chunks").unwrap();
         dealer.set_sndtimeo(Some(500));
         dealer.set_rcvtimeo(Some(500));
-        let mut router = ZSock::new_router("inproc://server_test_recv_chunk").unwrap();
+        let mut router = ZSock::new_router("inproc://server_test_recv_chunks").unwrap();
         router.set_sndtimeo(Some(500));
         router.set_rcvtimeo(Some(500));
         let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
@@ -288,8 +4245,14 @@ mod tests {
 
         let mut server = new_server(router, true);
 
+        let tempdir = TempDir::new("server_test_recv_chunks").unwrap();
+        let file = File::create(&mut server.arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 2, 0, 1, b"{}").unwrap();
+        server.files.insert(router_id, file);
+
         let msg = ZMsg::new();
-        msg.addstr("CHUNK").unwrap();
+        msg.addstr("CHUNKS").unwrap();
+        msg.addstr("moo").unwrap();
+        msg.addbytes("a".as_bytes()).unwrap();
         msg.send(&mut dealer).unwrap();
 
         server.recv(&mut router_dup).unwrap();
@@ -298,14 +4261,10 @@ mod tests {
         assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
         assert_eq!(msg.popstr().unwrap().unwrap(), "Invalid request");
 
-        let tempdir = TempDir::new("server_test_recv_chunk").unwrap();
-        let file = File::create(&mut server.arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 0, 0, 1, "{}").unwrap();
-        server.files.insert(router_id, file);
-
         let msg = ZMsg::new();
-        msg.addstr("CHUNK").unwrap();
-        msg.addstr("1").unwrap();
-        msg.addbytes("bytes".as_bytes()).unwrap();
+        msg.addstr("CHUNKS").unwrap();
+        msg.addstr("2").unwrap();
+        msg.addbytes("b".as_bytes()).unwrap();
         msg.send(&mut dealer).unwrap();
 
         server.recv(&mut router_dup).unwrap();
@@ -315,6 +4274,125 @@ mod tests {
         assert_eq!(msg.popstr().unwrap().unwrap(), "Chunk index not in file");
     }
 
+    #[test]
+    fn test_recv_get() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_get").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_get").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut hashed = ZSock::new_pull("inproc://server_test_recv_get_hashed").unwrap();
+        hashed.set_rcvtimeo(Some(500));
+        let mut hashed_dup = unsafe { ZSock::from_raw(hashed.as_mut_ptr(), false) };
+
+        let tempdir = TempDir::new("server_test_recv_get").unwrap();
+        let path = format!("{}/testfile", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        let mut server = new_server(router, true);
+        server.options = ServerOptions::new(Some(&[Options::AllowedPath(tempdir.path().to_str().unwrap().into()), Options::RemoteFiles]));
+        server.hashed = hashed;
+        server.hasher = Hasher::new(1, ">inproc://server_test_recv_get_hashed").unwrap();
+
+        for &(path, options) in [(tempdir.path().join("missing").to_str().unwrap(), "{\"protocol\":2}"), (&path[..], "{}")].iter() {
+            let msg = ZMsg::new();
+            msg.addstr("GET").unwrap();
+            msg.addstr(path).unwrap();
+            msg.addstr(options).unwrap();
+            msg.send(&mut dealer).unwrap();
+
+            server.recv(&mut router_dup).unwrap();
+            assert!(server.downloads.is_empty());
+
+            let msg = ZMsg::recv(&mut dealer).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        }
+
+        let msg = ZMsg::new();
+        msg.addstr("GET").unwrap();
+        msg.addstr(&path).unwrap();
+        msg.addstr("{\"protocol\":2,\"chunk_size\":2}").unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        // The download starts once the file is hashed
+        server.recv(&mut router_dup).unwrap();
+        assert!(server.downloads.is_empty());
+        server.recv(&mut hashed_dup).unwrap();
+        assert_eq!(server.downloads.len(), 1);
+
+        let msg = ZMsg::recv(&mut dealer).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "FILE");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "3");
+        assert_eq!(msg.popstr().unwrap().unwrap(), File::checksum(&path).unwrap().to_string());
+        assert_eq!(msg.popstr().unwrap().unwrap(), "2");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "2");
+
+        // Acknowledging every chunk ends the download
+        let msg = ZMsg::new();
+        msg.addstr("RECEIVED").unwrap();
+        protocol::add_u64(&msg, 0, true).unwrap();
+        protocol::add_u64(&msg, 1, true).unwrap();
+        msg.send(&mut dealer).unwrap();
+
+        server.recv(&mut router_dup).unwrap();
+        assert!(server.downloads.is_empty());
+    }
+
+    #[test]
+    fn test_recv_dir() {
+        ZSys::init();
+
+        let mut dealer = ZSock::new_dealer("inproc://server_test_recv_dir").unwrap();
+        dealer.set_sndtimeo(Some(500));
+        dealer.set_rcvtimeo(Some(500));
+        let mut router = ZSock::new_router("inproc://server_test_recv_dir").unwrap();
+        router.set_sndtimeo(Some(500));
+        router.set_rcvtimeo(Some(500));
+        let mut router_dup = unsafe { ZSock::from_raw(router.as_mut_ptr(), false) };
+
+        let mut server = new_server(router, true);
+
+        let tempdir = TempDir::new("server_test_recv_dir").unwrap();
+        let dest = tempdir.path().join("dest");
+        let mut request = |server: &mut Server, action: &str, path: Option<&Path>| {
+            let msg = ZMsg::new();
+            msg.addstr(action).unwrap();
+            if let Some(path) = path {
+                msg.addstr(path.to_str().unwrap()).unwrap();
+            }
+            msg.send(&mut dealer).unwrap();
+
+            server.recv(&mut router_dup).unwrap();
+            ZMsg::recv(&mut dealer).unwrap().popstr().unwrap().unwrap()
+        };
+
+        // Only paths within the directory can be created
+        assert_eq!(request(&mut server, "MKDIR", Some(&dest.join("a"))), "Err");
+        assert_eq!(request(&mut server, "DIR", Some(&dest)), "Ok");
+        assert_eq!(request(&mut server, "MKDIR", Some(&tempdir.path().join("a"))), "Err");
+        assert_eq!(request(&mut server, "MKDIR", Some(&dest.join("a/b"))), "Ok");
+        assert_eq!(request(&mut server, "MKDIR", Some(&dest.join("../escape"))), "Err");
+        assert!(!dest.exists());
+        assert!(!tempdir.path().join("escape").exists());
+
+        assert_eq!(request(&mut server, "DIR-COMMIT", None), "Ok");
+        assert!(dest.join("a/b").is_dir());
+        assert!(server.dirs.is_empty());
+
+        // An aborted tree leaves the existing one alone
+        assert_eq!(request(&mut server, "DIR", Some(&dest)), "Ok");
+        assert_eq!(request(&mut server, "MKDIR", Some(&dest.join("c"))), "Ok");
+        assert_eq!(request(&mut server, "DIR-ABORT", None), "Ok");
+        assert!(dest.join("a/b").is_dir());
+        assert!(!dest.join("c").exists());
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    }
+
     #[test]
     fn test_recv_sink() {
         ZSys::init();
@@ -325,16 +4403,53 @@ mod tests {
 
         let mut server = new_server(sink, false);
         let tempdir = TempDir::new("server_test_recv_chunk").unwrap();
-        let file = File::create(&mut server.arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1, 0, 1, "{}").unwrap();
+        let file = File::create(&mut server.arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1, 0, 1, b"{}").unwrap();
         server.files.insert("abc".as_bytes().into(), file);
 
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let received_clone = received.clone();
+        server.set_event_listener(move |e| if let Event::ChunkReceived { index, .. } = *e {
+            received_clone.borrow_mut().push(index);
+        });
+
         let msg = ZMsg::new();
         msg.addstr("abc").unwrap();
-        msg.addstr("0").unwrap();
-        msg.addstr("1").unwrap();
+        protocol::add_u64(&msg, 0, true).unwrap();
+        msg.addbytes(&[1]).unwrap();
+        msg.send(&mut worker).unwrap();
+
+        assert!(server.recv(&mut sink_dup).is_ok());
+        assert_eq!(*received.borrow(), vec![0]);
+    }
+
+    #[test]
+    fn test_recv_sink_failed() {
+        ZSys::init();
+
+        let mut worker = ZSock::new_push("inproc://server_test_recv_sink_failed").unwrap();
+        let mut sink = ZSock::new_pull("inproc://server_test_recv_sink_failed").unwrap();
+        let mut sink_dup = unsafe { ZSock::from_raw(sink.as_mut_ptr(), false) };
+
+        let mut server = new_server(sink, false);
+        let tempdir = TempDir::new("server_test_recv_sink_failed").unwrap();
+        let file = File::create(&mut server.arbitrator, "abc".as_bytes(), &format!("{}/testfile", tempdir.path().to_str().unwrap()), 1, 0, 1, b"{\"max_retries\":1}").unwrap();
+        server.files.insert("abc".as_bytes().into(), file);
+
+        let completed = Rc::new(RefCell::new(Vec::new()));
+        let completed_clone = completed.clone();
+        server.set_completion_observer(move |c| completed_clone.borrow_mut().push(c.result.is_ok()));
+
+        let msg = ZMsg::new();
+        msg.addstr("abc").unwrap();
+        protocol::add_u64(&msg, 0, true).unwrap();
+        msg.addbytes(&[0]).unwrap();
         msg.send(&mut worker).unwrap();
 
+        // The failed transfer is reported once, then forgotten
         assert!(server.recv(&mut sink_dup).is_ok());
+        assert_eq!(*completed.borrow(), vec![false]);
+        assert!(!server.files.contains_key(b"abc"));
+        assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 0);
     }
 
     fn new_server(sock: ZSock, is_router: bool) -> Server {
@@ -354,9 +4469,37 @@ mod tests {
         Server {
             router: router,
             sink: sink,
-            files: HashMap::new(),
+            hashed: ZSock::new(SocketType::PULL),
+            hasher: Hasher::new(1, ">inproc://server_test_hashed").unwrap(),
+            files: Transfers::new(),
             arbitrator: arbitrator,
             arbitrator_sock: s_sock,
+            options: ServerOptions::new(None),
+            workers: None,
+            events: EventLog::new(),
+            admin: None,
+            auth: None,
+            authorizer: None,
+            usage: HashMap::new(),
+            hourly: HashMap::new(),
+            sanitizer: None,
+            output: None,
+            recorder: None,
+            staged: HashMap::new(),
+            last_sweep: Instant::now(),
+            sweeping: None,
+            last_seen: HashMap::new(),
+            last_expire: Instant::now(),
+            downloads: HashMap::new(),
+            dirs: HashMap::new(),
+            batches: HashMap::new(),
+            durable_batches: HashSet::new(),
+            deltas: HashMap::new(),
+            lookups: HashMap::new(),
+            channels: Channels::new(),
+            stripes: Stripes::new(),
+            totals: Totals::default(),
+            shutting_down: None,
         }
     }
 }
@@ -0,0 +1,139 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Ed25519 signatures over a transfer's path, size, CRC and digest,
+//! so a server can check who sent a file regardless of the
+//! transport. A CRC is easy to forge, so the digest is what ties a
+//! signature to the file's contents. Each signature carries the time
+//! it expires, which limits how long it could be replayed.
+
+use error::{Error, Result};
+use hash::HashAlgorithm;
+use ring::signature::{self, Ed25519KeyPair};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds that a signature stays valid after it's made
+pub const SIGNATURE_LIFETIME: u64 = 300;
+
+const EXPIRY_LEN: usize = 8;
+
+/// Sign a transfer, which expires after `SIGNATURE_LIFETIME`. `key`
+/// is the 32 byte seed followed by the 32 byte public key, as
+/// produced by libsodium and most ed25519 tools.
+pub fn sign(key: &[u8], path: &str, size: u64, crc: u64, hash: &(HashAlgorithm, Vec<u8>)) -> Result<Vec<u8>> {
+    sign_until(key, path, size, crc, hash, now() + SIGNATURE_LIFETIME)
+}
+
+/// Whether any of the trusted public keys made this signature, and
+/// it hasn't expired. Unsigned digests are never trusted.
+pub fn verify(keys: &[Vec<u8>], path: &str, size: u64, crc: u64, hash: Option<&(HashAlgorithm, Vec<u8>)>, sig: &[u8]) -> bool {
+    verify_at(keys, path, size, crc, hash, sig, now())
+}
+
+// The signature is the expiry, as big endian seconds since the Unix
+// epoch, followed by the ed25519 signature of the message
+fn sign_until(key: &[u8], path: &str, size: u64, crc: u64, hash: &(HashAlgorithm, Vec<u8>), expires: u64) -> Result<Vec<u8>> {
+    if key.len() != 64 {
+        return Err(Error::SigningKey);
+    }
+
+    let pair = try!(Ed25519KeyPair::from_seed_and_public_key(&key[..32], &key[32..]).or(Err(Error::SigningKey)));
+    let mut sig = (0..EXPIRY_LEN).rev().map(|i| (expires >> (i * 8)) as u8).collect::<Vec<u8>>();
+    sig.extend_from_slice(pair.sign(&message(path, size, crc, hash, expires)).as_ref());
+    Ok(sig)
+}
+
+fn verify_at(keys: &[Vec<u8>], path: &str, size: u64, crc: u64, hash: Option<&(HashAlgorithm, Vec<u8>)>, sig: &[u8], now: u64) -> bool {
+    let hash = match hash {
+        Some(h) => h,
+        None => return false,
+    };
+    if sig.len() <= EXPIRY_LEN {
+        return false;
+    }
+
+    let expires = sig[..EXPIRY_LEN].iter().fold(0, |n, &b| n << 8 | b as u64);
+    if expires < now {
+        return false;
+    }
+
+    let msg = message(path, size, crc, hash, expires);
+    keys.iter().any(|key| {
+        signature::UnparsedPublicKey::new(&signature::ED25519, key).verify(&msg, &sig[EXPIRY_LEN..]).is_ok()
+    })
+}
+
+// Fields are NUL separated, as paths can't contain NULs. The digest
+// is hex encoded, so can't either.
+fn message(path: &str, size: u64, crc: u64, hash: &(HashAlgorithm, Vec<u8>), expires: u64) -> Vec<u8> {
+    let digest: String = hash.1.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}\0{}\0{}\0{:?}\0{}\0{}", path, size, crc, hash.0, digest, expires).into_bytes()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use hash::HashAlgorithm;
+    use super::{message, sign, sign_until, verify, verify_at};
+
+    // Key pair from RFC 8032, section 7.1, test 1
+    const SEED: &'static str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC: &'static str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_message() {
+        let hash = (HashAlgorithm::Sha256, vec![0xab, 1]);
+        assert_eq!(message("/tmp/f", 1, 2, &hash, 3), b"/tmp/f\x001\x002\x00Sha256\x00ab01\x003".to_vec());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let mut key = from_hex(SEED);
+        key.extend(from_hex(PUBLIC));
+        let keys = vec![vec![0; 32], from_hex(PUBLIC)];
+        let hash = (HashAlgorithm::Sha256, vec![1; 32]);
+        let other = (HashAlgorithm::Sha256, vec![2; 32]);
+
+        let sig = sign(&key, "/tmp/f", 1, 2, &hash).unwrap();
+        assert!(verify(&keys, "/tmp/f", 1, 2, Some(&hash), &sig));
+        assert!(!verify(&keys, "/tmp/f", 1, 3, Some(&hash), &sig));
+        assert!(!verify(&keys, "/tmp/f", 1, 2, Some(&other), &sig));
+        assert!(!verify(&keys, "/tmp/f", 1, 2, None, &sig));
+        assert!(!verify(&keys[..1], "/tmp/f", 1, 2, Some(&hash), &sig));
+    }
+
+    #[test]
+    fn test_verify_expired() {
+        let mut key = from_hex(SEED);
+        key.extend(from_hex(PUBLIC));
+        let keys = vec![from_hex(PUBLIC)];
+        let hash = (HashAlgorithm::Blake2b, vec![1; 64]);
+
+        let sig = sign_until(&key, "/tmp/f", 1, 2, &hash, 100).unwrap();
+        assert!(verify_at(&keys, "/tmp/f", 1, 2, Some(&hash), &sig, 100));
+        assert!(!verify_at(&keys, "/tmp/f", 1, 2, Some(&hash), &sig, 101));
+
+        // The expiry is signed too
+        let mut later = sig.clone();
+        later[7] = 200;
+        assert!(!verify_at(&keys, "/tmp/f", 1, 2, Some(&hash), &later, 101));
+        assert!(!verify_at(&keys, "/tmp/f", 1, 2, Some(&hash), &sig[..8], 0));
+    }
+
+    #[test]
+    fn test_sign_bad_key() {
+        assert!(sign(&[0; 32], "/tmp/f", 1, 2, &(HashAlgorithm::Sha256, vec![])).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::str;
+
+/// Bytes read from the start of a file, which covers the tar header
+const SNIFF_LEN: usize = 512;
+
+// (offset, magic bytes, MIME type)
+const MAGIC: [(usize, &'static [u8], &'static str); 12] = [
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"#!", "text/x-script"),
+    (257, b"ustar", "application/x-tar"),
+];
+
+/// Guess the MIME type of a file from its first few bytes. Returns
+/// None if the file can't be read.
+pub fn sniff<P: AsRef<Path>>(path: P) -> Option<&'static str> {
+    let fh = match fs::File::open(path) {
+        Ok(fh) => fh,
+        Err(_) => return None,
+    };
+
+    let mut buf = Vec::with_capacity(SNIFF_LEN);
+    if fh.take(SNIFF_LEN as u64).read_to_end(&mut buf).is_err() {
+        return None;
+    }
+
+    Some(sniff_bytes(&buf))
+}
+
+fn sniff_bytes(buf: &[u8]) -> &'static str {
+    for &(offset, magic, mime) in MAGIC.iter() {
+        if buf.len() >= offset + magic.len() && &buf[offset..offset + magic.len()] == magic {
+            return mime;
+        }
+    }
+
+    if is_text(buf) { "text/plain" } else { "application/octet-stream" }
+}
+
+// UTF-8 without NULs, allowing for a multibyte character cut off
+// at the end of the buffer
+fn is_text(buf: &[u8]) -> bool {
+    if buf.contains(&0) {
+        return false;
+    }
+
+    match str::from_utf8(buf) {
+        Ok(_) => true,
+        Err(e) => buf.len() == SNIFF_LEN && e.valid_up_to() + 4 > buf.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use super::{sniff, sniff_bytes};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_sniff_bytes() {
+        assert_eq!(sniff_bytes(b"\x89PNG\r\n\x1a\n\x00\x00"), "image/png");
+        assert_eq!(sniff_bytes(b"%PDF-1.4"), "application/pdf");
+        assert_eq!(sniff_bytes(b"#!/bin/sh\n"), "text/x-script");
+        assert_eq!(sniff_bytes(b"key = \"value\"\n"), "text/plain");
+        assert_eq!(sniff_bytes(b"\x00\x01\x02"), "application/octet-stream");
+
+        let mut tar = vec![b'a'; 300];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff_bytes(&tar), "application/x-tar");
+
+        // A multibyte character split by the read limit is still text
+        let mut text = vec![b'a'; 511];
+        text.push(0xc3);
+        assert_eq!(sniff_bytes(&text), "text/plain");
+    }
+
+    #[test]
+    fn test_sniff() {
+        let tempdir = TempDir::new("sniff_test_sniff").unwrap();
+        let path = tempdir.path().join("file");
+        assert_eq!(sniff(&path), None);
+
+        fs::File::create(&path).unwrap().write_all(b"\x1f\x8b\x08\x00").unwrap();
+        assert_eq!(sniff(&path), Some("application/gzip"));
+    }
+}
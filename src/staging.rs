@@ -0,0 +1,186 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Encryption of temporary upload files, so that partial uploads
+//! can't be read or altered in the spool directory.
+//!
+//! Chunks arrive out of order, so each is sealed on its own with
+//! ChaCha20-Poly1305 and written in place of its plaintext. The key,
+//! and each chunk's nonce and tag, live only in memory, which makes
+//! staged data unreadable once the transfer is gone.
+
+use error::{Error, Result};
+use getrandom;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+pub struct StagingCipher {
+    key: LessSafeKey,
+    sealed: Mutex<Sealed>,
+}
+
+#[derive(Default)]
+struct Sealed {
+    // Every chunk gets a fresh nonce, even one sealed again
+    counter: u64,
+    // The length, nonce and tag of the chunk last sealed at each
+    // offset
+    chunks: BTreeMap<u64, (usize, u64, [u8; TAG_LEN])>,
+}
+
+impl StagingCipher {
+    /// Create a cipher with a random key
+    pub fn new() -> Result<StagingCipher> {
+        let mut key = [0; KEY_LEN];
+        try!(getrandom::getrandom(&mut key).or(Err(Error::FileFail)));
+
+        Ok(StagingCipher {
+            key: LessSafeKey::new(try!(UnboundKey::new(&CHACHA20_POLY1305, &key).or(Err(Error::FileFail)))),
+            sealed: Mutex::new(Sealed::default()),
+        })
+    }
+
+    /// Encrypt a chunk that starts at `offset` in the file, keeping
+    /// its tag to check when the file is decrypted
+    pub fn seal(&self, offset: u64, data: &mut Vec<u8>) -> Result<()> {
+        let mut sealed = self.sealed.lock().unwrap();
+        let counter = sealed.counter;
+        sealed.counter += 1;
+
+        let sealed_tag = try!(self.key.seal_in_place_separate_tag(nonce(counter), Aad::empty(), data).or(Err(Error::ChunkFail)));
+
+        let mut tag = [0; TAG_LEN];
+        tag.copy_from_slice(sealed_tag.as_ref());
+        sealed.chunks.insert(offset, (data.len(), counter, tag));
+        Ok(())
+    }
+
+    /// Decrypt a whole file in place, failing if any of it wasn't
+    /// sealed by this cipher or has changed since
+    pub fn decrypt_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut fh = try!(fs::OpenOptions::new().read(true).write(true).open(path));
+        let sealed = self.sealed.lock().unwrap();
+        let mut buf = Vec::new();
+        let mut end = 0;
+
+        for (&offset, &(len, counter, ref tag)) in sealed.chunks.iter() {
+            if offset != end {
+                return Err(Error::FailChecksum);
+            }
+
+            buf.resize(len, 0);
+            try!(fh.seek(SeekFrom::Start(offset)));
+            try!(fh.read_exact(&mut buf));
+            buf.extend_from_slice(tag);
+
+            if self.key.open_in_place(nonce(counter), Aad::empty(), &mut buf).is_err() {
+                return Err(Error::FailChecksum);
+            }
+
+            try!(fh.seek(SeekFrom::Start(offset)));
+            try!(fh.write_all(&buf[..len]));
+            end = offset + len as u64;
+        }
+
+        if try!(fh.metadata()).len() != end {
+            return Err(Error::FailChecksum);
+        }
+        Ok(())
+    }
+}
+
+// Each counter value is only used once per key
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    for i in 0..8 {
+        nonce[i] = (counter >> (i * 8)) as u8;
+    }
+    Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_seal() {
+        let cipher = StagingCipher::new().unwrap();
+        let plain: Vec<u8> = (0..200).map(|i| i as u8).collect();
+
+        let mut sealed = plain.clone();
+        cipher.seal(0, &mut sealed).unwrap();
+        assert_eq!(sealed.len(), plain.len());
+        assert!(sealed != plain);
+
+        // Sealing the same chunk again uses a new nonce
+        let mut again = plain.clone();
+        cipher.seal(0, &mut again).unwrap();
+        assert!(again != sealed);
+    }
+
+    #[test]
+    fn test_decrypt_file() {
+        let tempdir = TempDir::new("staging_test_decrypt_file").unwrap();
+        let path = tempdir.path().join("file");
+        let cipher = StagingCipher::new().unwrap();
+
+        // Chunks sealed out of order
+        let mut second = b" data".to_vec();
+        cipher.seal(11, &mut second).unwrap();
+        let mut first = b"some secret".to_vec();
+        cipher.seal(0, &mut first).unwrap();
+        let mut fh = fs::File::create(&path).unwrap();
+        fh.write_all(&first).unwrap();
+        fh.write_all(&second).unwrap();
+
+        cipher.decrypt_file(&path).unwrap();
+        let mut content = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "some secret data");
+    }
+
+    #[test]
+    fn test_decrypt_file_altered() {
+        let tempdir = TempDir::new("staging_test_decrypt_file_altered").unwrap();
+        let path = tempdir.path().join("file");
+        let cipher = StagingCipher::new().unwrap();
+
+        let mut data = b"some secret data".to_vec();
+        cipher.seal(0, &mut data).unwrap();
+        let mut fh = fs::OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        fh.write_all(&data).unwrap();
+
+        fh.seek(SeekFrom::Start(3)).unwrap();
+        fh.write_all(&[data[3] ^ 1]).unwrap();
+        match cipher.decrypt_file(&path) {
+            Err(Error::FailChecksum) => (),
+            _ => panic!("Expected FailChecksum error"),
+        }
+
+        // Data that was never sealed is refused too
+        fh.set_len(0).unwrap();
+        fh.seek(SeekFrom::Start(0)).unwrap();
+        fh.write_all(&data).unwrap();
+        fh.write_all(b"extra").unwrap();
+        match cipher.decrypt_file(&path) {
+            Err(Error::FailChecksum) => (),
+            _ => panic!("Expected FailChecksum error"),
+        }
+    }
+}
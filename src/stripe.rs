@@ -0,0 +1,230 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Uploads whose chunks are spread across several connections. Each
+//! extra connection joins a transfer started on the first, and the
+//! server spreads its chunk requests across all of them, so a link
+//! with a long round trip carries several chunks at once.
+
+use chunk::{Chunks, Layout};
+use compress::Algorithm;
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use file;
+use getrandom;
+use handle::Handle;
+use protocol;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// Milliseconds a stripe waits for a request before checking whether
+/// its upload has finished
+const POLL_MILLIS: i32 = 100;
+
+/// What a stripe needs to answer chunk requests for an upload
+pub struct Stripe {
+    pub fh: Arc<Mutex<Handle>>,
+    pub layout: Layout,
+    pub binary: bool,
+    pub compression: Option<Algorithm>,
+    pub sparse: bool,
+}
+
+impl Stripe {
+    /// Join transfer `id` with the token the server ACKed it with,
+    /// on its own thread, and answer the chunk requests the server
+    /// sends it until `done` is set. Yields the chunks and bytes it
+    /// sent.
+    pub fn spawn(self, mut sock: ZSock, id: u64, token: String, done: Arc<AtomicBool>) -> JoinHandle<Result<(u64, u64)>> {
+        thread::spawn(move || {
+            let result = self.run(&mut sock, id, &token, &done);
+            if let Err(ref e) = result {
+                warn!("stripe failed transfer_id={} error={:?}", id, e.to_string());
+            }
+            result
+        })
+    }
+
+    fn run(&self, sock: &mut ZSock, id: u64, token: &str, done: &AtomicBool) -> Result<(u64, u64)> {
+        let msg = ZMsg::new();
+        try!(msg.addstr("JOIN"));
+        try!(msg.addstr(&id.to_string()));
+        try!(msg.addstr(token));
+        try!(msg.send(sock));
+
+        sock.set_rcvtimeo(Some(POLL_MILLIS));
+        let mut sent = (0, 0);
+
+        while !done.load(Ordering::SeqCst) {
+            let msg = match ZMsg::recv(sock) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+
+            match try!(msg.popstr().unwrap_or(Err(Vec::new())).or(Err(Error::InvalidReply))).as_ref() {
+                // Joined
+                "Ok" => (),
+                "Err" => return Err(protocol::pop_err(&msg)),
+                "CHUNK" => {
+                    let index = try!(protocol::pop_u64(&msg, self.binary).ok_or(Error::InvalidReply));
+                    for mut chunk in try!(self.chunks(index, index)) {
+                        try!(chunk.send(sock, &self.layout, self.binary, self.compression, self.sparse));
+                    }
+                    sent.0 += 1;
+                    sent.1 += self.layout.len(index);
+                },
+                "CHUNKS" => {
+                    let (first, last) = try!(file::pop_range(&msg, self.binary));
+                    let reply = ZMsg::new();
+                    try!(reply.addstr("CHUNKS"));
+                    for mut chunk in try!(self.chunks(first, last)) {
+                        try!(chunk.add_to(&reply, &self.layout, self.binary, self.compression, self.sparse));
+                        sent.0 += 1;
+                        sent.1 += self.layout.len(chunk.get_index());
+                    }
+                    try!(reply.send(sock));
+                },
+                _ => return Err(Error::InvalidReply),
+            }
+        }
+
+        Ok(sent)
+    }
+
+    fn chunks(&self, first: u64, last: u64) -> Result<Chunks> {
+        if last < self.layout.count() {
+            Ok(Chunks::new(self.fh.clone(), first, last + 1))
+        } else {
+            Err(Error::ChunkIndex)
+        }
+    }
+}
+
+/// The connections that have joined each upload, by the router ID
+/// of the connection that started it
+pub struct Stripes {
+    owners: HashMap<Vec<u8>, Vec<u8>>,
+    // Each owner's stripes, and which connection is sent the next
+    // chunk request
+    joined: HashMap<Vec<u8>, (Vec<Vec<u8>>, usize)>,
+    // The transfer ID and token each owner's upload can be joined with
+    tokens: HashMap<Vec<u8>, (u64, Vec<u8>)>,
+}
+
+impl Stripes {
+    pub fn new() -> Stripes {
+        Stripes {
+            owners: HashMap::new(),
+            joined: HashMap::new(),
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Give an owner's upload a random token for other connections to
+    /// JOIN it with, replacing any it had
+    pub fn offer(&mut self, owner: &[u8], id: u64) -> Result<String> {
+        let mut random = [0; 16];
+        try!(getrandom::getrandom(&mut random).map_err(io::Error::from));
+        let token: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+        self.tokens.insert(owner.to_vec(), (id, token.clone().into_bytes()));
+        Ok(token)
+    }
+
+    /// Whether a token is the one an owner's upload `id` was offered
+    pub fn admits(&self, owner: &[u8], id: u64, token: &[u8]) -> bool {
+        match self.tokens.get(owner) {
+            // Every byte is compared, so the time taken gives nothing
+            // away
+            Some(&(offered_id, ref offered)) => {
+                offered_id == id && offered.len() == token.len() &&
+                offered.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+            },
+            None => false,
+        }
+    }
+
+    pub fn join(&mut self, owner: &[u8], stripe: &[u8]) {
+        self.owners.insert(stripe.to_vec(), owner.to_vec());
+        self.joined.entry(owner.to_vec()).or_insert((Vec::new(), 0)).0.push(stripe.to_vec());
+    }
+
+    /// Router ID of the connection that started the upload a stripe
+    /// joined
+    pub fn owner(&self, stripe: &[u8]) -> Option<&[u8]> {
+        self.owners.get(stripe).map(|o| &o[..])
+    }
+
+    /// Connection to send an owner's next chunk request to, taking
+    /// turns between the owner and its stripes
+    pub fn next<'a>(&'a mut self, owner: &'a [u8]) -> &'a [u8] {
+        match self.joined.get_mut(owner) {
+            Some(&mut (ref stripes, ref mut turn)) => {
+                *turn = (*turn + 1) % (stripes.len() + 1);
+                if *turn == 0 { owner } else { &stripes[*turn - 1] }
+            },
+            None => owner,
+        }
+    }
+
+    /// Forget an owner's stripes and token once its upload is over
+    pub fn remove(&mut self, owner: &[u8]) {
+        self.tokens.remove(owner);
+        if let Some((stripes, _)) = self.joined.remove(owner) {
+            for stripe in stripes {
+                self.owners.remove(&stripe);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stripes() {
+        let mut stripes = Stripes::new();
+        assert_eq!(stripes.next(b"abc"), b"abc");
+
+        stripes.join(b"abc", b"def");
+        stripes.join(b"abc", b"ghi");
+        assert_eq!(stripes.owner(b"def"), Some(&b"abc"[..]));
+        assert_eq!(stripes.owner(b"abc"), None);
+
+        let turns: Vec<Vec<u8>> = (0..4).map(|_| stripes.next(b"abc").to_vec()).collect();
+        assert_eq!(turns, vec![b"def".to_vec(), b"ghi".to_vec(), b"abc".to_vec(), b"def".to_vec()]);
+
+        stripes.remove(b"abc");
+        assert_eq!(stripes.owner(b"def"), None);
+        assert_eq!(stripes.next(b"abc"), b"abc");
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut stripes = Stripes::new();
+        assert!(!stripes.admits(b"abc", 1, b""));
+
+        let token = stripes.offer(b"abc", 1).unwrap();
+        assert_eq!(token.len(), 32);
+        assert!(stripes.admits(b"abc", 1, token.as_bytes()));
+        assert!(!stripes.admits(b"abc", 2, token.as_bytes()));
+        assert!(!stripes.admits(b"def", 1, token.as_bytes()));
+        assert!(!stripes.admits(b"abc", 1, &token.as_bytes()[1..]));
+
+        // A new upload gets a new token
+        let other = stripes.offer(b"abc", 2).unwrap();
+        assert!(other != token);
+        assert!(!stripes.admits(b"abc", 1, token.as_bytes()));
+
+        stripes.remove(b"abc");
+        assert!(!stripes.admits(b"abc", 2, other.as_bytes()));
+    }
+}
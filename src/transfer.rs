@@ -0,0 +1,195 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use file::File;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::Iter;
+
+pub type TransferId = u64;
+
+/// Server-side transfer state, keyed by transfer ID and indexed by
+/// the router identity of the client that owns each transfer.
+pub struct Transfers {
+    next_id: TransferId,
+    files: HashMap<TransferId, (Vec<u8>, File)>,
+    identities: HashMap<Vec<u8>, Vec<TransferId>>,
+}
+
+impl Transfers {
+    pub fn new() -> Transfers {
+        Transfers {
+            next_id: 0,
+            files: HashMap::new(),
+            identities: HashMap::new(),
+        }
+    }
+
+    /// Add a transfer, which becomes the identity's active transfer
+    pub fn insert(&mut self, router_id: Vec<u8>, file: File) -> TransferId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.identities.entry(router_id.clone()).or_insert_with(Vec::new).push(id);
+        self.files.insert(id, (router_id, file));
+        id
+    }
+
+    /// Add a transfer under the ID it had before a restart, unless
+    /// another transfer has taken it since
+    pub fn insert_with_id(&mut self, id: TransferId, router_id: Vec<u8>, file: File) -> TransferId {
+        if self.files.contains_key(&id) {
+            return self.insert(router_id, file);
+        }
+        self.reserve(id);
+
+        self.identities.entry(router_id.clone()).or_insert_with(Vec::new).push(id);
+        self.files.insert(id, (router_id, file));
+        id
+    }
+
+    /// Give new transfers IDs after `id`, which one from before a
+    /// restart may be resumed under
+    pub fn reserve(&mut self, id: TransferId) {
+        self.next_id = cmp::max(self.next_id, id + 1);
+    }
+
+    /// Take an ID that no transfer will be given, for other work
+    /// that reports back by ID
+    pub fn take_id(&mut self) -> TransferId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// ID of an identity's active transfer
+    pub fn active(&self, router_id: &[u8]) -> Option<TransferId> {
+        self.identities.get(router_id).and_then(|ids| ids.last().cloned())
+    }
+
+    pub fn contains_key(&self, router_id: &[u8]) -> bool {
+        self.active(router_id).is_some()
+    }
+
+    /// Get an identity's active transfer
+    pub fn get(&self, router_id: &[u8]) -> Option<&File> {
+        match self.active(router_id) {
+            Some(id) => self.files.get(&id).map(|&(_, ref f)| f),
+            None => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, router_id: &[u8]) -> Option<&mut File> {
+        match self.active(router_id) {
+            Some(id) => self.files.get_mut(&id).map(|&mut (_, ref mut f)| f),
+            None => None,
+        }
+    }
+
+    pub fn get_by_id(&self, id: TransferId) -> Option<&File> {
+        self.files.get(&id).map(|&(_, ref f)| f)
+    }
+
+    pub fn get_by_id_mut(&mut self, id: TransferId) -> Option<&mut File> {
+        self.files.get_mut(&id).map(|&mut (_, ref mut f)| f)
+    }
+
+    /// Router identity of the client that owns a transfer
+    pub fn identity(&self, id: TransferId) -> Option<&[u8]> {
+        self.files.get(&id).map(|&(ref router_id, _)| &router_id[..])
+    }
+
+    /// Remove a single transfer
+    pub fn remove(&mut self, id: TransferId) -> Option<File> {
+        let (router_id, file) = match self.files.remove(&id) {
+            Some(t) => t,
+            None => return None,
+        };
+
+        let empty = match self.identities.get_mut(&router_id) {
+            Some(ids) => {
+                ids.retain(|i| *i != id);
+                ids.is_empty()
+            },
+            None => false,
+        };
+
+        if empty {
+            self.identities.remove(&router_id);
+        }
+
+        Some(file)
+    }
+
+    /// Remove every transfer owned by an identity
+    pub fn remove_identity(&mut self, router_id: &[u8]) {
+        if let Some(ids) = self.identities.remove(router_id) {
+            for id in ids {
+                self.files.remove(&id);
+            }
+        }
+    }
+
+    /// Number of transfers, including those awaiting verification
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn iter(&self) -> Iter<TransferId, (Vec<u8>, File)> {
+        self.files.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arbitrator::Arbitrator;
+    use czmq::{ZSock, SocketType, ZSys};
+    use file::File;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_transfers() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("transfer_test_transfers").unwrap();
+        let mut arbitrator = Arbitrator::new(ZSock::new(SocketType::ROUTER), 0).unwrap();
+        let mut create = |name: &str, size: u64| {
+            let path = format!("{}/{}", tempdir.path().to_str().unwrap(), name);
+            File::create(&mut arbitrator, b"abc", &path, size, 0, 1, b"{}").unwrap()
+        };
+
+        let mut transfers = Transfers::new();
+        assert!(!transfers.contains_key(b"abc"));
+
+        let first = transfers.insert(b"abc".to_vec(), create("a", 1));
+        let second = transfers.insert(b"abc".to_vec(), create("b", 2));
+        transfers.insert(b"def".to_vec(), create("c", 3));
+        assert!(first != second);
+        assert_eq!(transfers.len(), 3);
+        assert_eq!(transfers.get(b"abc").unwrap().get_size(), 2);
+        assert_eq!(transfers.get_mut(b"def").unwrap().get_size(), 3);
+
+        assert_eq!(transfers.active(b"abc"), Some(second));
+        assert!(transfers.remove(second).is_some());
+        assert!(transfers.remove(second).is_none());
+        assert_eq!(transfers.active(b"abc"), Some(first));
+        assert_eq!(transfers.get_by_id_mut(first).unwrap().get_size(), 1);
+
+        transfers.remove_identity(b"abc");
+        assert!(!transfers.contains_key(b"abc"));
+        assert!(transfers.contains_key(b"def"));
+        assert_eq!(transfers.iter().count(), 1);
+
+        transfers.reserve(9);
+        assert_eq!(transfers.insert_with_id(5, b"ghi".to_vec(), create("d", 4)), 5);
+        assert_eq!(transfers.active(b"ghi"), Some(5));
+        assert_eq!(transfers.insert_with_id(5, b"jkl".to_vec(), create("e", 5)), 10);
+        assert_eq!(transfers.insert(b"jkl".to_vec(), create("f", 6)), 11);
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Batched chunk IO using Linux io_uring. A whole CHUNKS batch is
+//! submitted at once instead of a seek and read/write per chunk.
+
+use error::Result;
+use io_uring::{opcode, squeue, types, IoUring};
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// Maximum number of operations submitted to a ring at once
+const RING_SIZE: usize = 256;
+
+// Set up on a thread's first batch and kept for the ones after
+thread_local!(static RING: RefCell<Option<IoUring>> = RefCell::new(None));
+
+/// Read several (offset, length) ranges of a file
+pub fn read_batch(fh: &fs::File, ranges: &[(u64, u64)]) -> Result<Vec<Vec<u8>>> {
+    let fd = types::Fd(fh.as_raw_fd());
+    let mut bufs: Vec<Vec<u8>> = ranges.iter().map(|&(_, len)| vec![0; len as usize]).collect();
+
+    for (group, bufs) in ranges.chunks(RING_SIZE).zip(bufs.chunks_mut(RING_SIZE)) {
+        let entries: Vec<squeue::Entry> = group.iter().zip(bufs.iter_mut()).map(|(&(offset, _), buf)| {
+            opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32).offset(offset as _).build()
+        }).collect();
+        let lengths: Vec<usize> = bufs.iter().map(|b| b.len()).collect();
+
+        for result in try!(submit(entries, &lengths)) {
+            try!(result);
+        }
+    }
+
+    Ok(bufs)
+}
+
+/// Write several chunks to a file at the given offsets, returning
+/// whether each write succeeded
+pub fn write_batch(fh: &fs::File, writes: &[(u64, &[u8])]) -> Result<Vec<bool>> {
+    let fd = types::Fd(fh.as_raw_fd());
+    let mut success = Vec::with_capacity(writes.len());
+
+    for group in writes.chunks(RING_SIZE) {
+        let entries: Vec<squeue::Entry> = group.iter().map(|&(offset, data)| {
+            opcode::Write::new(fd, data.as_ptr(), data.len() as u32).offset(offset as _).build()
+        }).collect();
+        let lengths: Vec<usize> = group.iter().map(|&(_, data)| data.len()).collect();
+
+        for result in try!(submit(entries, &lengths)) {
+            success.push(result.is_ok());
+        }
+    }
+
+    Ok(success)
+}
+
+// Submit a group of operations to this thread's ring and wait for all
+// of them. Each result is an error unless the operation transferred
+// its full length.
+fn submit(entries: Vec<squeue::Entry>, lengths: &[usize]) -> Result<Vec<io::Result<()>>> {
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.is_none() {
+            *ring = Some(try!(IoUring::new(RING_SIZE as u32)));
+        }
+
+        // A ring that failed may still hold operations the kernel never
+        // took, so the next batch gets a new one
+        let results = submit_to(ring.as_mut().unwrap(), entries, lengths);
+        if results.is_err() {
+            *ring = None;
+        }
+        results
+    })
+}
+
+fn submit_to(ring: &mut IoUring, entries: Vec<squeue::Entry>, lengths: &[usize]) -> Result<Vec<io::Result<()>>> {
+    for (i, entry) in entries.into_iter().enumerate() {
+        // The buffers referenced by each entry are owned by the
+        // caller. They outlive the operations, as nothing below
+        // returns while the kernel may still be using them.
+        unsafe {
+            try!(ring.submission().push(&entry.user_data(i as u64)).or(Err(io::Error::new(io::ErrorKind::Other, "io_uring submission queue full"))));
+        }
+    }
+
+    let mut results: Vec<io::Result<()>> = (0..lengths.len()).map(|_| Ok(())).collect();
+    let mut completed = 0;
+    while completed < lengths.len() {
+        // Entering fails only when the kernel took none of the entries
+        // left to submit. Any it took before are still waited for, as
+        // they use the caller's buffers, and an interrupted wait is
+        // simply retried.
+        if let Err(e) = ring.submit_and_wait(1) {
+            let in_flight = lengths.len() - completed - ring.submission().len();
+            if e.kind() != io::ErrorKind::Interrupted && in_flight == 0 {
+                return Err(e.into());
+            }
+        }
+
+        for cqe in ring.completion() {
+            let i = cqe.user_data() as usize;
+            let res = cqe.result();
+
+            results[i] = if res < 0 {
+                Err(io::Error::from_raw_os_error(-res))
+            } else if res as usize != lengths[i] {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short io_uring transfer"))
+            } else {
+                Ok(())
+            };
+            completed += 1;
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use tempfile::tempfile;
+
+    #[test]
+    fn test_read_write_batch() {
+        let mut fh = tempfile().unwrap();
+        fh.write_all(b"abcdef").unwrap();
+
+        let bufs = read_batch(&fh, &[(0, 2), (4, 2)]).unwrap();
+        assert_eq!(bufs, vec![b"ab".to_vec(), b"ef".to_vec()]);
+        assert!(read_batch(&fh, &[(5, 2)]).is_err());
+
+        assert_eq!(write_batch(&fh, &[(0, b"xy"), (2, b"z")]).unwrap(), vec![true, true]);
+        assert_eq!(read_batch(&fh, &[(0, 4)]).unwrap(), vec![b"xyzd".to_vec()]);
+
+        // Every batch used the same ring
+        let ring = RING.with(|r| r.borrow().as_ref().map(|r| r as *const IoUring)).unwrap();
+        read_batch(&fh, &[(0, 2)]).unwrap();
+        assert_eq!(RING.with(|r| r.borrow().as_ref().map(|r| r as *const IoUring)), Some(ring));
+    }
+}
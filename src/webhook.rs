@@ -0,0 +1,178 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use error::{Error, Result};
+use event::Completion;
+use reqwest::{Client, Url};
+use reqwest::header::CONTENT_TYPE;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::spawn;
+use std::time::Duration;
+
+/// Seconds to wait on a slow webhook receiver
+const TIMEOUT: u64 = 10;
+/// Notifications waiting to be sent, beyond which new ones are
+/// dropped
+const QUEUE_LEN: usize = 1000;
+
+/// POSTs a JSON payload to a URL whenever a transfer completes or
+/// fails, so that other systems can react to deliveries.
+///
+/// Register it with `Server::set_completion_observer`, passing a
+/// closure that calls `notify`. Requests are sent from a background
+/// thread, so a slow receiver never holds up transfers.
+pub struct Webhook {
+    queue: SyncSender<String>,
+    dropped: AtomicUsize,
+}
+
+impl Webhook {
+    /// Create a notifier for `url`, which must be an `http://` or
+    /// `https://` URL.
+    pub fn new(url: &str) -> Result<Webhook> {
+        let url = try!(parse_url(url));
+        let client = try!(Client::builder()
+                              .timeout(Duration::new(TIMEOUT, 0))
+                              .build()
+                              .or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e.to_string()))));
+        let (tx, rx) = sync_channel::<String>(QUEUE_LEN);
+
+        // The thread exits once the Webhook is dropped
+        spawn(move|| {
+            for payload in rx.iter() {
+                // Notifications are best effort
+                if let Err(e) = post(&client, &url, payload) {
+                    warn!("webhook failed url={} error={:?}", url, e);
+                }
+            }
+        });
+
+        Ok(Webhook {
+            queue: tx,
+            dropped: AtomicUsize::new(0),
+        })
+    }
+
+    /// Queue a notification for a finished transfer. It's dropped if
+    /// the receiver has fallen too far behind.
+    pub fn notify(&self, completion: &Completion) {
+        if let Err(TrySendError::Full(_)) = self.queue.try_send(completion.to_json().to_string()) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            warn!("webhook queue full, dropping notification id={}", completion.id);
+        }
+    }
+
+    /// Number of notifications dropped so far because the queue was
+    /// full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn post(client: &Client, url: &Url, payload: String) -> ::std::result::Result<(), String> {
+    let response = try!(client.post(url.clone())
+                              .header(CONTENT_TYPE, "application/json")
+                              .body(payload)
+                              .send()
+                              .or_else(|e| Err(e.to_string())));
+
+    if !response.status().is_success() {
+        return Err(response.status().to_string());
+    }
+    Ok(())
+}
+
+fn parse_url(url: &str) -> Result<Url> {
+    let url = try!(Url::parse(url).or(Err(Error::WebhookUrl)));
+    if (url.scheme() != "http" && url.scheme() != "https") || url.host_str().map_or(true, |h| h.is_empty()) {
+        return Err(Error::WebhookUrl);
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use event::Completion;
+    use file::Timings;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use super::{parse_url, Webhook, QUEUE_LEN};
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(parse_url("http://example.com:8080/hooks/upload?x=1").unwrap().as_str(), "http://example.com:8080/hooks/upload?x=1");
+        assert!(parse_url("https://example.com/").is_ok());
+        assert!(parse_url("ftp://example.com/").is_err());
+        assert!(parse_url("http://:80/").is_err());
+        assert!(parse_url("http://example.com:moo/").is_err());
+    }
+
+    #[test]
+    fn test_notify() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let webhook = Webhook::new(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        webhook.notify(&completion(2));
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = String::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with('}') {
+            let len = stream.read(&mut buf).unwrap();
+            assert!(len > 0);
+            request.push_str(&String::from_utf8_lossy(&buf[..len]));
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.to_lowercase().contains("content-type: application/json\r\n"));
+        assert!(request.contains("\"event\":\"transfer_failed\""));
+        assert!(request.contains("\"id\":2"));
+    }
+
+    #[test]
+    fn test_notify_full() {
+        // A receiver that never answers holds up the first request,
+        // so the rest are dropped once the queue fills rather than
+        // blocking the caller
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let webhook = Webhook::new(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        let start = Instant::now();
+        for id in 0..QUEUE_LEN as u64 * 2 {
+            webhook.notify(&completion(id));
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The queue holds QUEUE_LEN, plus the one being sent if the
+        // thread has taken it yet
+        assert!(webhook.dropped() >= QUEUE_LEN - 1);
+        assert!(webhook.dropped() <= QUEUE_LEN);
+    }
+
+    fn completion(id: u64) -> Completion {
+        Completion {
+            id: id,
+            identity: b"a".to_vec(),
+            path: PathBuf::from("/tmp/f"),
+            size: 1,
+            duration: Duration::new(0, 0),
+            result: Err(Error::FileFail),
+            retransmits: 0,
+            content_type: None,
+            timings: Timings::default(),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2016 ZFilexfer Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use chunk;
+use czmq::{ZMsg, ZSock, ZSys};
+use error::{Error, Result};
+use protocol;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{JoinHandle, spawn};
+
+/// A pool of threads that write received chunks to disk on behalf
+/// of the Server. Each transfer is pinned to one worker, so a
+/// worker's file handles are never shared.
+pub struct WorkerPool {
+    socks: Vec<ZSock>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Ignore failure as it means the thread has already
+        // terminated.
+        for sock in self.socks.iter_mut() {
+            let _ = sock.send_str("$TERM");
+        }
+
+        // A worker that panicked has already logged why
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl WorkerPool {
+    pub fn new(workers: u32) -> Result<WorkerPool> {
+        let mut pool = WorkerPool {
+            socks: Vec::new(),
+            handles: Vec::new(),
+        };
+
+        for _ in 0..workers {
+            let (front, back) = try!(ZSys::create_pipe());
+            front.set_sndtimeo(Some(1000));
+            let worker = try!(Worker::new(back, try!(ZSock::new_push(">inproc://zfilexfer_sink"))));
+
+            pool.socks.push(front);
+            pool.handles.push(spawn(move|| if let Err(e) = worker.run() {
+                error!("worker stopped error={:?}", e.to_string());
+            }));
+        }
+
+        Ok(pool)
+    }
+
+    fn worker(&mut self, router_id: &[u8]) -> &mut ZSock {
+        let hash = router_id.iter().fold(0usize, |acc, b| acc.wrapping_mul(31).wrapping_add(*b as usize));
+        let len = self.socks.len();
+        &mut self.socks[hash % len]
+    }
+
+    /// Hand a chunk to the worker responsible for this transfer
+    pub fn write(&mut self, router_id: &[u8], path: &Path, offset: u64, index: u64, data: Vec<u8>) -> Result<()> {
+        let msg = ZMsg::new();
+        try!(msg.addstr("WRITE"));
+        try!(msg.addbytes(router_id));
+        try!(msg.addstr(try!(path.to_str().ok_or(Error::InvalidFilePath))));
+        try!(protocol::add_u64(&msg, offset, true));
+        try!(protocol::add_u64(&msg, index, true));
+        try!(msg.addbytes(&data));
+        try!(msg.send(self.worker(router_id)));
+        Ok(())
+    }
+
+    /// Release the worker's handle for a finished transfer
+    pub fn close(&mut self, router_id: &[u8], path: &Path) -> Result<()> {
+        let msg = ZMsg::new();
+        try!(msg.addstr("CLOSE"));
+        try!(msg.addstr(try!(path.to_str().ok_or(Error::InvalidFilePath))));
+        try!(msg.send(self.worker(router_id)));
+        Ok(())
+    }
+}
+
+struct Worker {
+    comm: ZSock,
+    sink: ZSock,
+    files: HashMap<PathBuf, fs::File>,
+}
+
+impl Worker {
+    fn new(comm: ZSock, sink: ZSock) -> Result<Worker> {
+        Ok(Worker {
+            comm: comm,
+            sink: sink,
+            files: HashMap::new(),
+        })
+    }
+
+    fn run(mut self) -> Result<()> {
+        loop {
+            let msg = match ZMsg::recv(&mut self.comm) {
+                Ok(msg) => msg,
+                Err(_) => return Ok(()),
+            };
+
+            match self.command(&msg) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                // The sink is how chunks are reported, so without it
+                // the worker is no use
+                Err(Error::Czmq(e)) => return Err(Error::Czmq(e)),
+                Err(e) => warn!("worker ignored command error={:?}", e.to_string()),
+            }
+        }
+    }
+
+    // Carry out a command from the pool, returning false once it
+    // asks the worker to terminate
+    fn command(&mut self, msg: &ZMsg) -> Result<bool> {
+        let cmd = try!(msg.popstr().ok_or(Error::InvalidRequest)).unwrap_or(String::new());
+        match cmd.as_ref() {
+            "WRITE" => {
+                let router_id = try!(try!(msg.popbytes()).ok_or(Error::InvalidRequest));
+                let path = PathBuf::from(try!(try!(msg.popstr().ok_or(Error::InvalidRequest)).or(Err(Error::InvalidFilePath))));
+                let offset = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidRequest));
+                let index = try!(protocol::pop_u64(msg, true).ok_or(Error::InvalidRequest));
+                let data = try!(try!(msg.popbytes()).ok_or(Error::InvalidRequest));
+
+                let success = self.write(path, offset, &data).is_ok();
+                try!(chunk::send_sink(&mut self.sink, &router_id, index, success));
+            },
+            "CLOSE" => {
+                let path = try!(try!(msg.popstr().ok_or(Error::InvalidRequest)).or(Err(Error::InvalidFilePath)));
+                self.files.remove(Path::new(&path));
+            },
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn write(&mut self, path: PathBuf, offset: u64, data: &[u8]) -> Result<()> {
+        if !self.files.contains_key(&path) {
+            let fh = try!(fs::OpenOptions::new().write(true).open(&path));
+            self.files.insert(path.clone(), fh);
+        }
+
+        let fh = self.files.get_mut(&path).unwrap();
+        try!(fh.seek(SeekFrom::Start(offset)));
+        try!(fh.write_all(data));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZMsg, ZSys};
+    use protocol;
+    use std::fs;
+    use std::io::Read;
+    use super::Worker;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_worker_run() {
+        ZSys::init();
+
+        let tempdir = TempDir::new("worker_test_run").unwrap();
+        let path = format!("{}/test", tempdir.path().to_str().unwrap());
+        fs::File::create(&path).unwrap().set_len(6).unwrap();
+
+        let (mut front, back) = ZSys::create_pipe().unwrap();
+        let (sink, mut sink_back) = ZSys::create_pipe().unwrap();
+        let worker = Worker::new(back, sink).unwrap();
+
+        // A malformed command is skipped
+        let msg = ZMsg::new();
+        msg.addstr("WRITE").unwrap();
+        msg.addstr("abc").unwrap();
+        msg.send(&mut front).unwrap();
+
+        let msg = ZMsg::new();
+        msg.addstr("WRITE").unwrap();
+        msg.addstr("abc").unwrap();
+        msg.addstr(&path).unwrap();
+        protocol::add_u64(&msg, 3, true).unwrap();
+        protocol::add_u64(&msg, 1, true).unwrap();
+        msg.addstr("def").unwrap();
+        msg.send(&mut front).unwrap();
+
+        let msg = ZMsg::new();
+        msg.addstr("CLOSE").unwrap();
+        msg.addstr(&path).unwrap();
+        msg.send(&mut front).unwrap();
+
+        front.send_str("$TERM").unwrap();
+        worker.run().unwrap();
+
+        let msg = ZMsg::recv(&mut sink_back).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "abc");
+        assert_eq!(protocol::pop_u64(&msg, true), Some(1));
+        assert_eq!(msg.popbytes().unwrap().unwrap(), vec![1]);
+
+        let mut content = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, vec![0, 0, 0, 100, 101, 102]);
+    }
+}
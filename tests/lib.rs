@@ -7,7 +7,6 @@
 // modified, or distributed except according to those terms.
 
 extern crate czmq;
-extern crate rustc_serialize;
 extern crate tempdir;
 extern crate zdaemon;
 extern crate zfilexfer;
@@ -16,22 +15,39 @@ use czmq::{ZSock, SocketType, ZSys};
 use std::fs;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::thread::spawn;
+#[cfg(feature = "chaos")]
+use std::time::Duration;
 use tempdir::TempDir;
 use zdaemon::Service;
-use zfilexfer::{File, FileOptions, Server};
+#[cfg(feature = "chaos")]
+use zfilexfer::{ChaosConfig, ChaosProxy, RetryPolicy};
+use zfilexfer::{Dir, File, FileOptions, Multiplexer, Replayer, Server, ServerOptions};
 
 #[test]
 fn upload() {
+    // Servers share an inproc sink, so these can't run in parallel
+    upload_to("inproc://test_upload", None);
+    upload_to("inproc://test_upload_workers", Some(2));
+    upload_replay("inproc://test_upload_replay");
+    download("inproc://test_download");
+    upload_dir("inproc://test_upload_dir");
+    upload_multiplexed("inproc://test_upload_multiplexed");
+    #[cfg(feature = "chaos")]
+    upload_chaos("inproc://test_upload_chaos");
+}
+
+fn upload_to(endpoint: &str, workers: Option<u32>) {
     ZSys::init();
 
-    let server = ZSock::new_router("@inproc://test_upload").unwrap();
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
     server.set_rcvtimeo(Some(500));
-    let mut client = ZSock::new_dealer(">inproc://test_upload").unwrap();
+    let mut client = ZSock::new_dealer(&format!(">{}", endpoint)).unwrap();
     client.set_rcvtimeo(Some(500));
 
     let handle = spawn(move|| {
+        let options = workers.map(|n| vec![ServerOptions::Workers(n)]);
         let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
-        service.add_endpoint(Server::new(server, 2).unwrap()).unwrap();
+        service.add_endpoint(Server::new(server, 2, options.as_ref().map(|o| &o[..])).unwrap()).unwrap();
         let _ = service.start(Some(500)); // Give this a timeout so that the test can finish!
     });
 
@@ -58,3 +74,230 @@ fn upload() {
 
     handle.join().unwrap();
 }
+
+// A recorded session reproduces the upload against a fresh server
+fn upload_replay(endpoint: &str) {
+    ZSys::init();
+
+    let tempdir = TempDir::new("test_upload_replay").unwrap();
+    let local_path = tempdir.path().join("local");
+    let remote_path = tempdir.path().join("remote");
+    let recording = tempdir.path().join("session");
+
+    let test_content = "abcdefghijklmnopqrstuvwxyz";
+    fs::File::create(&local_path).unwrap().write_all(test_content.as_bytes()).unwrap();
+
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
+    server.set_rcvtimeo(Some(500));
+    let mut client = ZSock::new_dealer(&format!(">{}", endpoint)).unwrap();
+    client.set_rcvtimeo(Some(500));
+
+    let writer = fs::File::create(&recording).unwrap();
+    let handle = spawn(move|| {
+        let mut server = Server::new(server, 2, None).unwrap();
+        server.set_recorder(Box::new(writer)).unwrap();
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(server).unwrap();
+        let _ = service.start(Some(500));
+    });
+
+    let mut file = File::open(&local_path, Some(&[FileOptions::ChunkSize(5)])).unwrap();
+    file.send(&mut client, &remote_path).unwrap();
+    handle.join().unwrap();
+
+    fs::remove_file(&remote_path).unwrap();
+
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
+    server.set_rcvtimeo(Some(500));
+    let handle = spawn(move|| {
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(Server::new(server, 2, None).unwrap()).unwrap();
+        let _ = service.start(Some(500));
+    });
+
+    // The recording holds the messages but not the file's contents
+    let mut recorded = Vec::new();
+    fs::File::open(&recording).unwrap().read_to_end(&mut recorded).unwrap();
+    assert!(!recorded.windows(5).any(|w| w == b"abcde"));
+
+    // The replayed clients must outlive the server, or their queued
+    // messages are dropped
+    let mut replayer = Replayer::new(Box::new(fs::File::open(&recording).unwrap())).unwrap();
+    assert!(replayer.replay(endpoint).unwrap() > 0);
+    handle.join().unwrap();
+
+    // Zeroed chunks fail verification, so nothing is saved
+    assert!(!remote_path.exists());
+}
+
+fn download(endpoint: &str) {
+    ZSys::init();
+
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
+    server.set_rcvtimeo(Some(500));
+    let mut client = ZSock::new_dealer(&format!(">{}", endpoint)).unwrap();
+    client.set_rcvtimeo(Some(500));
+
+    let handle = spawn(move|| {
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(Server::new(server, 2, None).unwrap()).unwrap();
+        let _ = service.start(Some(500));
+    });
+
+    let tempdir = TempDir::new("test_download").unwrap();
+    let remote_path = tempdir.path().join("remote");
+    let local_path = tempdir.path().join("local");
+
+    let test_content: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    fs::File::create(&remote_path).unwrap().write_all(&test_content).unwrap();
+
+    assert!(File::fetch(&mut client, tempdir.path().join("missing"), &local_path).is_err());
+    File::fetch(&mut client, &remote_path, &local_path).unwrap();
+
+    let mut content = Vec::new();
+    fs::File::open(&local_path).unwrap().read_to_end(&mut content).unwrap();
+    assert_eq!(content, test_content);
+
+    handle.join().unwrap();
+}
+
+fn upload_dir(endpoint: &str) {
+    ZSys::init();
+
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
+    server.set_rcvtimeo(Some(500));
+    let mut client = ZSock::new_dealer(&format!(">{}", endpoint)).unwrap();
+    client.set_rcvtimeo(Some(500));
+
+    let handle = spawn(move|| {
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(Server::new(server, 2, None).unwrap()).unwrap();
+        let _ = service.start(Some(500));
+    });
+
+    let tempdir = TempDir::new("test_upload_dir").unwrap();
+    let local_path = tempdir.path().join("local");
+    let remote_path = tempdir.path().join("remote");
+
+    fs::create_dir_all(local_path.join("conf.d")).unwrap();
+    fs::create_dir_all(local_path.join("empty")).unwrap();
+    fs::File::create(local_path.join("main.conf")).unwrap().write_all(b"main").unwrap();
+    fs::File::create(local_path.join("conf.d/extra.conf")).unwrap().write_all(b"extra").unwrap();
+
+    let mut dir = Dir::open(&local_path, Some(&[FileOptions::ChunkSize(2)])).unwrap();
+    dir.send(&mut client, &remote_path).unwrap();
+    assert_eq!(dir.get_results().len(), 2);
+
+    let mut content = String::new();
+    fs::File::open(remote_path.join("conf.d/extra.conf")).unwrap().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "extra");
+    assert!(remote_path.join("main.conf").is_file());
+    assert!(remote_path.join("empty").is_dir());
+
+    handle.join().unwrap();
+}
+
+// Files sent at once over one connection each get a channel
+fn upload_multiplexed(endpoint: &str) {
+    ZSys::init();
+
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
+    server.set_rcvtimeo(Some(500));
+    let front = ZSock::new_router(&format!("@{}_front", endpoint)).unwrap();
+    let back = ZSock::new_dealer(&format!(">{}", endpoint)).unwrap();
+
+    let server_handle = spawn(move|| {
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(Server::new(server, 2, None).unwrap()).unwrap();
+        let _ = service.start(Some(1000));
+    });
+
+    let mux_handle = spawn(move|| {
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(Multiplexer::new(front, back)).unwrap();
+        let _ = service.start(Some(1000));
+    });
+
+    let tempdir = TempDir::new("test_upload_multiplexed").unwrap();
+    let senders: Vec<_> = (0..3u8).map(|i| {
+        let local_path = tempdir.path().join(format!("local{}", i));
+        let remote_path = tempdir.path().join(format!("remote{}", i));
+        let front = format!(">{}_front", endpoint);
+        fs::File::create(&local_path).unwrap().write_all(&vec![i; 500]).unwrap();
+
+        spawn(move|| {
+            let mut client = ZSock::new_dealer(&front).unwrap();
+            client.set_rcvtimeo(Some(2000));
+            let mut file = File::open(&local_path, Some(&[FileOptions::ChunkSize(10)])).unwrap();
+            file.send(&mut client, &remote_path).unwrap();
+        })
+    }).collect();
+
+    for sender in senders {
+        sender.join().unwrap();
+    }
+
+    for i in 0..3u8 {
+        let mut content = Vec::new();
+        fs::File::open(tempdir.path().join(format!("remote{}", i))).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, vec![i; 500]);
+    }
+
+    server_handle.join().unwrap();
+    mux_handle.join().unwrap();
+}
+
+// Lost, duplicated, reordered and late chunks are recovered by the
+// server requesting them again
+#[cfg(feature = "chaos")]
+fn upload_chaos(endpoint: &str) {
+    ZSys::init();
+
+    let server = ZSock::new_router(&format!("@{}", endpoint)).unwrap();
+    server.set_rcvtimeo(Some(500));
+    let front = ZSock::new_dealer(&format!("@{}_front", endpoint)).unwrap();
+    let back = ZSock::new_dealer(&format!(">{}", endpoint)).unwrap();
+    let mut client = ZSock::new_dealer(&format!(">{}_front", endpoint)).unwrap();
+    client.set_rcvtimeo(Some(5000));
+
+    let server_handle = spawn(move|| {
+        // Dropped chunks are retried without waiting, so the client
+        // doesn't time out
+        let mut retry = RetryPolicy::default();
+        retry.max_retries = 100;
+        retry.base_delay = Duration::new(0, 0);
+
+        let options = [
+            ServerOptions::ChunkTimeout(200),
+            ServerOptions::TimerInterval(50),
+            ServerOptions::RetryPolicy(retry),
+        ];
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(Server::new(server, 4, Some(&options)).unwrap()).unwrap();
+        let _ = service.start(Some(2000));
+    });
+
+    let proxy_handle = spawn(move|| {
+        let config = ChaosConfig { drop: 0.05, duplicate: 0.05, reorder: 0.05, delay: 0.05, ..ChaosConfig::default() };
+        let mut service = Service::new(ZSock::new(SocketType::PAIR)).unwrap();
+        service.add_endpoint(ChaosProxy::new(front, back, config)).unwrap();
+        let _ = service.start(Some(2000));
+    });
+
+    let tempdir = TempDir::new("test_upload_chaos").unwrap();
+    let local_path = tempdir.path().join("local");
+    let remote_path = tempdir.path().join("remote");
+
+    let test_content: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    fs::File::create(&local_path).unwrap().write_all(&test_content).unwrap();
+
+    let mut file = File::open(&local_path, Some(&[FileOptions::ChunkSize(10)])).unwrap();
+    file.send(&mut client, &remote_path).unwrap();
+
+    let mut content = Vec::new();
+    fs::File::open(&remote_path).unwrap().read_to_end(&mut content).unwrap();
+    assert_eq!(content, test_content);
+
+    server_handle.join().unwrap();
+    proxy_handle.join().unwrap();
+}